@@ -1,12 +1,14 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::ops::DerefMut;
 use std::num::ParseIntError;
 
-use jtag_taps::cable::{self, Cable};
+use jtag_taps::cable;
 use jtag_taps::statemachine::JtagSM;
 use jtag_taps::taps::Taps;
 
+use jtag_adi::armv8::Core;
+use jtag_adi::idcode;
+use jtag_adi::target::Target;
 use jtag_adi::{ArmDebugInterface, MemAP};
 
 use clap::Parser;
@@ -25,56 +27,22 @@ struct Args {
     /// Which access port to use
     ap_num: u32,
     #[arg(long)]
-    cpu_base: String,
+    /// Address of the core's debug registers, found from the ROM table if not given
+    cpu_base: Option<String>,
     #[arg(long)]
-    cti_base: String,
+    cti_base: Option<String>,
+    #[arg(long, default_value_t = 0)]
+    /// Which core to use, when the addresses are found from the ROM table
+    core: usize,
     command: Option<String>,
 }
 
-fn cpu_halt<T,U>(mem: &mut MemAP<T>, cti_base: u32)
-    where T: DerefMut<Target=U>,
-          U: Cable + ?Sized
-{
-    // Gate all
-    mem.write(cti_base + 0x140, 0).expect("write ctigate");
-
-    // Enable CTIOUTEN for channel 0
-    mem.write(cti_base + 0x0a0, 1).expect("write ctiouten");
-
-    // Generate HALT to core 0
-    mem.write(cti_base + 0x01c, 1).expect("write ctiouten");
-
-    // ACK the halt
-    mem.write(cti_base + 0x010, 3).expect("write ctiouten");
-    // Wait for ACK
-    while mem.read(cti_base + 0x134).unwrap() != 0 {}
-}
-
-fn cpu_resume<T,U>(mem: &mut MemAP<T>, cti_base: u32)
-    where T: DerefMut<Target=U>,
-          U: Cable + ?Sized
-{
-    // Gate all
-    mem.write(cti_base + 0x140, 0).expect("write ctigate");
-
-    // Enable CTIOUTEN for channel 1
-    mem.write(cti_base + 0x0a4, 2).expect("write ctiouten");
-
-    // Generate resume to core 0
-    mem.write(cti_base + 0x01c, 2).expect("write ctiouten");
-
-    // ACK the resume
-    mem.write(cti_base + 0x010, 3).expect("write ctiouten");
-    // Wait for ACK
-    while mem.read(cti_base + 0x134).unwrap() != 0 {}
-}
-
 fn parse_int(x: &str) -> Result<u32, ParseIntError> {
     if x.starts_with("0x") {
         let len = x.len();
         u32::from_str_radix(&x[2..len], 16)
     } else {
-        str::parse(&x)
+        str::parse(x)
     }
 }
 
@@ -85,67 +53,49 @@ fn main() {
     let mut taps = Taps::new(jtag);
     taps.detect();
 
-    // IDCODE instruction
-    let ir = vec![14];
-    taps.select_tap(0, &ir);
-    let dr = taps.read_dr(32);
-    let idcode = u32::from_le_bytes(dr.try_into().unwrap());
-    assert_eq!(idcode, 0x6ba00477);
+    if let Err(e) = idcode::verify(&mut taps, 0, Some(0x6ba00477)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
     let adi = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
-    let mut mem = MemAP::new(adi.clone(), 0);
-
-    let cpu_base = parse_int(&args.cpu_base).expect("invalid cpu base");
-    let edprsr = mem.read(cpu_base + 0x314).expect("read edprsr");
-    println!("edprsr {:x}", edprsr);
-    assert!(edprsr & 1 == 1);
-
-    // Clear OS lock
-    let oslar = mem.read(cpu_base + 0x300).expect("read oslar");
-    println!("oslar {:x}", oslar);
-    mem.write(cpu_base + 0x300, 0).expect("write oslar");
-
-    // Clear software lock lock
-    let oslar = mem.read(cpu_base + 0xfb4).expect("read oslar");
-    println!("swlck {:x}", oslar);
-    mem.write(cpu_base + 0xfb0, 0xC5ACCE55).expect("write oslar");
-    let oslar = mem.read(cpu_base + 0xfb4).expect("read oslar");
-    println!("swlck {:x}", oslar);
-    assert_eq!(oslar & 2, 0);
-
-    // Enable halting debug
-    let mut edscr = mem.read(cpu_base + 0x088).expect("read edscr");
-    println!("edscr {:x}", edscr);
-    edscr |= 1 << 14;
-    mem.write(cpu_base + 0x088, edscr).expect("write edscr");
-    let edscr = mem.read(cpu_base + 0x088).expect("read edscr");
-    println!("edscr {:x}", edscr);
-
-    //// Unlock CTI
-    let cti_base = parse_int(&args.cti_base).expect("invalid cti base");
-    let ctilsr = mem.read(cti_base + 0xfb4).expect("read cti");
-    println!("ctilsr {:x}", ctilsr);
-    mem.write(cti_base + 0xfb0, 0xC5ACCE55).expect("write cti");
-    let ctilsr = mem.read(cti_base + 0xfb4).expect("read cti");
-    println!("ctilsr {:x}", ctilsr);
-
-    //// Enable CTI
-    let mut cti = mem.read(cti_base).expect("read cti");
-    println!("cti {:x}", cti);
-    cti |= 1;
-    mem.write(cti_base, cti).expect("write cti");
-    let cti = mem.read(cti_base).expect("read cti");
-    println!("cti {:x}", cti);
-    assert_eq!(cti & 1, 1);
+    let mut core = match (args.cpu_base, args.cti_base) {
+        (Some(cpu_base), Some(cti_base)) => {
+            let mem = Rc::new(RefCell::new(MemAP::new(adi.clone(), args.ap_num)));
+            let cpu_base = parse_int(&cpu_base).expect("invalid cpu base");
+            let cti_base = parse_int(&cti_base).expect("invalid cti base");
+            Core::new(mem, cpu_base, cti_base)
+        }
+        _ => {
+            let target = match Target::discover(adi.clone()) {
+                Ok(target) => target,
+                Err(e) => {
+                    eprintln!("Discovery failed: error {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Some((ap, cpu)) = target.core_components(args.core) {
+                println!("core {} on AP {}: debug {:x} cti {:x?}", args.core, ap, cpu.debug, cpu.cti);
+            }
+            match target.into_cores().into_iter().nth(args.core) {
+                Some(core) => core,
+                None => {
+                    eprintln!("No core {} found", args.core);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    core.unlock().expect("unlock");
+    println!("edscr {:x}", core.edscr().expect("read edscr"));
 
     if let Some(cmd) = args.command {
         match cmd.as_str() {
-            "halt" => cpu_halt(&mut mem, cti_base),
-            "resume" => cpu_resume(&mut mem, cti_base),
+            "halt" => core.halt().expect("halt"),
+            "resume" => core.resume().expect("resume"),
             _ => eprintln!("Unknown command"),
         }
     }
 
-    let edscr = mem.read(cpu_base + 0x088).expect("read edscr");
-    println!("edscr {:x}", edscr);
+    println!("edscr {:x}", core.edscr().expect("read edscr"));
 }
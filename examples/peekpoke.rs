@@ -8,7 +8,7 @@ use jtag_taps::cable;
 use jtag_taps::statemachine::JtagSM;
 use jtag_taps::taps::Taps;
 
-use jtag_adi::{ArmDebugInterface, MemAP};
+use jtag_adi::{idcode, ArmDebugInterface, MemAP};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -33,7 +33,7 @@ fn parse_int(x: &str) -> Result<u32, ParseIntError> {
         let len = x.len();
         u32::from_str_radix(&x[2..len], 16)
     } else {
-        str::parse(&x)
+        str::parse(x)
     }
 }
 
@@ -44,15 +44,8 @@ fn main() {
     let mut taps = Taps::new(jtag);
     taps.detect();
 
-    // IDCODE instruction
-    let ir = vec![14];
-    taps.select_tap(args.tap_index, &ir);
-    let dr = taps.read_dr(32);
-    let idcode = u32::from_le_bytes(dr.try_into().unwrap());
-
-    // Verify ARM ID code
-    if idcode != 0x4ba00477 {
-        eprintln!("Warning: unexpected idcode {:x}", idcode);
+    if let Err(e) = idcode::verify(&mut taps, args.tap_index, Some(0x4ba00477)) {
+        eprintln!("Warning: {}", e);
     }
 
     let adi = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
@@ -0,0 +1,47 @@
+/*
+ * C API for jtag-adi.  Build the shared library with
+ * `cargo rustc --lib --release --features ffi --crate-type cdylib` and link against it.
+ *
+ * Every function returning int returns 0 on success.  Positive values are error codes from the
+ * debug interface: 1 for a WAIT ack, 4 for a FAULT ack, 5 for a sticky error in CTRL/STAT, or a
+ * driver-specific code.  Negative values are the JTAG_ADI_ERR_* codes below.
+ *
+ * A session must only be used from one thread at a time.
+ */
+
+#ifndef JTAG_ADI_H
+#define JTAG_ADI_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define JTAG_ADI_ERR_INVALID_ARG (-1)
+#define JTAG_ADI_ERR_CABLE (-2)
+#define JTAG_ADI_ERR_NO_CORE (-3)
+#define JTAG_ADI_ERR_INTERNAL (-4)
+
+typedef struct jtag_adi_session jtag_adi_session;
+
+int jtag_adi_open(const char *cable, uint32_t baud, uint32_t tap_index, uint32_t ap_num,
+                  jtag_adi_session **out);
+void jtag_adi_close(jtag_adi_session *session);
+
+int jtag_adi_read32(jtag_adi_session *session, uint32_t addr, uint32_t *value);
+int jtag_adi_write32(jtag_adi_session *session, uint32_t addr, uint32_t value);
+int jtag_adi_read_block(jtag_adi_session *session, uint32_t addr, uint32_t *buf, size_t count);
+int jtag_adi_write_block(jtag_adi_session *session, uint32_t addr, const uint32_t *buf,
+                         size_t count);
+
+int jtag_adi_core_attach(jtag_adi_session *session, uint32_t debug_base, uint32_t cti_base);
+int jtag_adi_halt(jtag_adi_session *session);
+int jtag_adi_resume(jtag_adi_session *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
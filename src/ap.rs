@@ -0,0 +1,156 @@
+//! Discovery of the access ports behind a DP, and decoding of a MEM-AP's identification and
+//! configuration registers.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{MemAP, MemAPReg, Port, Transport};
+
+/// Register number of the AP identification register, IDR
+const IDR: u8 = 0xfc >> 2;
+
+/// Number of APs addressable with an ADIv5 SELECT.APSEL
+const MAX_APS: u32 = 256;
+
+/// IDR.CLASS of a MEM-AP
+const CLASS_MEM_AP: u32 = 0x8;
+
+/// WAIT acknowledge
+const ACK_WAIT: u8 = 1;
+
+/// BASE.P: a debug entry is present
+const BASE_PRESENT: u32 = 1 << 0;
+/// BASE.Format: set for the ADIv5 format, clear for the legacy format
+const BASE_FORMAT: u32 = 1 << 1;
+/// BASE.BASEADDR
+const BASE_ADDR_MASK: u32 = 0xffff_f000;
+/// Legacy BASE value of a MEM-AP with no debug entries
+const BASE_LEGACY_NONE: u32 = 0xffff_ffff;
+
+/// An access port found by `enumerate_aps`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ApInfo {
+    pub apsel: u32,
+    pub idr: u32,
+}
+
+impl ApInfo {
+    /// IDR.CLASS: 0x8 for a MEM-AP, 0x0 for a JTAG-AP or vendor-defined AP
+    pub fn class(&self) -> u32 {
+        (self.idr >> 13) & 0xf
+    }
+
+    /// IDR.TYPE, the bus a MEM-AP connects to: 1 AHB3, 2 APB2/3, 4 AXI3/4, 5 AHB5, 6 APB4/5,
+    /// 7 AXI5, 8 AHB5 with enhanced HPROT
+    pub fn ap_type(&self) -> u32 {
+        self.idr & 0xf
+    }
+
+    /// IDR.VARIANT
+    pub fn variant(&self) -> u32 {
+        (self.idr >> 4) & 0xf
+    }
+
+    /// IDR.REVISION
+    pub fn revision(&self) -> u32 {
+        self.idr >> 28
+    }
+
+    /// JEP106 designer code of the AP, from IDR.DESIGNER
+    pub fn designer(&self) -> u16 {
+        ((self.idr >> 17) & 0x7ff) as u16
+    }
+
+    pub fn is_mem_ap(&self) -> bool {
+        self.class() == CLASS_MEM_AP
+    }
+}
+
+/// Decoded value of a MEM-AP's CFG register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemApCfg {
+    /// Big-endian memory accesses, which are deprecated
+    pub be: bool,
+    /// Large physical addresses: TAR and BASE are 64 bits
+    pub la: bool,
+    /// Large data: 64-bit data transfers are supported
+    pub ld: bool,
+}
+
+impl From<u32> for MemApCfg {
+    fn from(val: u32) -> Self {
+        Self {
+            be: val & (1 << 0) != 0,
+            la: val & (1 << 1) != 0,
+            ld: val & (1 << 2) != 0,
+        }
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    fn read_ap_reg(&mut self, reg: MemAPReg) -> Result<u32, u8> {
+        self.adi
+            .borrow_mut()
+            .read_adi(self.apsel, Port::AP, reg as u8)
+    }
+
+    /// Read the AP's IDR
+    pub fn idr(&mut self) -> Result<ApInfo, u8> {
+        let idr = self.read_ap_reg(MemAPReg::IDR)?;
+        Ok(ApInfo {
+            apsel: self.apsel,
+            idr,
+        })
+    }
+
+    /// Read and decode the AP's CFG register
+    pub fn cfg(&mut self) -> Result<MemApCfg, u8> {
+        Ok(MemApCfg::from(self.read_ap_reg(MemAPReg::CFG)?))
+    }
+
+    /// Read BASE and return the address of the top-level ROM table, or None if the AP has no
+    /// debug entries.  The upper half of the address is read when CFG.LA is set.
+    pub fn rom_base(&mut self) -> Result<Option<u64>, u8> {
+        let base = self.read_ap_reg(MemAPReg::Base1)?;
+        if base == BASE_LEGACY_NONE || base & (BASE_FORMAT | BASE_PRESENT) == BASE_FORMAT {
+            return Ok(None);
+        }
+        let upper = if self.cfg()?.la {
+            self.read_ap_reg(MemAPReg::Base0)?
+        } else {
+            0
+        };
+        Ok(Some((upper as u64) << 32 | (base & BASE_ADDR_MASK) as u64))
+    }
+}
+
+/// Read the IDR of every AP and return those which are implemented, which have a non-zero IDR.
+///
+/// The reads are made with `read_adi_batch`, so on an `ArmDebugInterface` a full scan is one
+/// pipelined stream with SELECT written before each read, rather than a round trip to the probe
+/// per AP.  Reads that got a WAIT response, and those after them, are retried individually.
+pub fn enumerate_aps<T>(adi: &mut T) -> Result<Vec<ApInfo>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let reads: Vec<_> = (0..MAX_APS).map(|apsel| (apsel, Port::AP, IDR)).collect();
+    let idrs = adi.read_adi_batch(&reads);
+
+    let mut aps = vec![];
+    for (apsel, result) in (0..MAX_APS).zip(idrs) {
+        let idr = match result {
+            Err(ACK_WAIT) => adi.read_adi(apsel, Port::AP, IDR)?,
+            Err(e) => return Err(e),
+            Ok(idr) => idr,
+        };
+        if idr != 0 {
+            aps.push(ApInfo { apsel, idr });
+        }
+    }
+    Ok(aps)
+}
@@ -0,0 +1,188 @@
+//! Preserving the debug state of ARMv7-A cores across power cycles.  The breakpoint, watchpoint
+//! and debug control registers are in the core's power domain and are lost when it is powered
+//! down, so they are saved before and restored after, with the OS lock set while they are
+//! inconsistent.
+//!
+//! Cores with v7.0 debug save and restore through DBGOSSRR; v7.1 cores such as the Cortex-A7
+//! and Cortex-A15 don't implement it, so the registers are read and written directly.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::armv8::ERR_POWERED_DOWN;
+use crate::{MemAP, Transport};
+
+const DBGDIDR: u32 = 0x000;
+const DBGVCR: u32 = 0x01c;
+const DBGDSCR: u32 = 0x088;
+const DBGBVR0: u32 = 0x100;
+const DBGBCR0: u32 = 0x140;
+const DBGWVR0: u32 = 0x180;
+const DBGWCR0: u32 = 0x1c0;
+const DBGOSLAR: u32 = 0x300;
+const DBGOSLSR: u32 = 0x304;
+const DBGOSSRR: u32 = 0x308;
+const DBGPRSR: u32 = 0x314;
+const DBGCLAIMSET: u32 = 0xfa0;
+const DBGCLAIMCLR: u32 = 0xfa4;
+const DBGLAR: u32 = 0xfb0;
+
+const OSLAR_KEY: u32 = 0xC5ACCE55;
+const OSLSR_OSLK: u32 = 1 << 1;
+
+const PRSR_PU: u32 = 1 << 0;
+const PRSR_SPD: u32 = 1 << 1;
+
+/// DBGDIDR.Version of v7.1 debug
+const VERSION_V7_1: u32 = 5;
+
+/// DBGDSCR bits that can be written externally: the interrupt and DCC disables, ITR enable,
+/// halting and monitor debug enables and the DCC access mode
+const DSCR_WRITABLE: u32 = 0x0030_f800;
+
+/// Debug state saved by `Armv7Debug::save`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DebugState {
+    /// The words read from DBGOSSRR, in order
+    SaveRestore(Vec<u32>),
+    /// The registers, read directly
+    Registers {
+        dscr: u32,
+        vcr: u32,
+        claim: u32,
+        /// Value and control of each breakpoint
+        breakpoints: Vec<(u32, u32)>,
+        /// Value and control of each watchpoint
+        watchpoints: Vec<(u32, u32)>,
+    },
+}
+
+/// The external debug registers of an ARMv7-A core
+pub struct Armv7Debug<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+}
+
+impl<T> Armv7Debug<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, base: u32) -> Self {
+        Self { mem, base }
+    }
+
+    fn read(&mut self, reg: u32) -> Result<u32, u8> {
+        self.mem.borrow_mut().read(self.base + reg)
+    }
+
+    fn write(&mut self, reg: u32, val: u32) -> Result<(), u8> {
+        self.mem.borrow_mut().write(self.base + reg, val)
+    }
+
+    /// Return true if the core is powered up
+    pub fn is_powered_up(&mut self) -> Result<bool, u8> {
+        Ok(self.read(DBGPRSR)? & PRSR_PU != 0)
+    }
+
+    /// Return true if the OS lock is set, which blocks external debug
+    pub fn is_os_locked(&mut self) -> Result<bool, u8> {
+        Ok(self.read(DBGOSLSR)? & OSLSR_OSLK != 0)
+    }
+
+    pub fn set_os_lock(&mut self, lock: bool) -> Result<(), u8> {
+        self.write(DBGLAR, 0xC5ACCE55)?;
+        self.write(DBGOSLAR, if lock { OSLAR_KEY } else { 0 })
+    }
+
+    /// Return true if the core implements DBGOSSRR
+    fn has_ossrr(&mut self) -> Result<bool, u8> {
+        Ok((self.read(DBGDIDR)? >> 16) & 0xf < VERSION_V7_1)
+    }
+
+    /// Save the debug state of the powered-up core, before it is powered down.  The OS lock is
+    /// left set, so that external debug doesn't change the state until it is restored.
+    pub fn save(&mut self) -> Result<DebugState, u8> {
+        if !self.is_powered_up()? {
+            return Err(ERR_POWERED_DOWN);
+        }
+        self.set_os_lock(true)?;
+        if self.has_ossrr()? {
+            // The first read after locking gives the number of words to save
+            let count = self.read(DBGOSSRR)?;
+            let words = (0..count)
+                .map(|_| self.read(DBGOSSRR))
+                .collect::<Result<_, _>>()?;
+            return Ok(DebugState::SaveRestore(words));
+        }
+
+        let didr = self.read(DBGDIDR)?;
+        let num_wrps = (didr >> 28) + 1;
+        let num_brps = (didr >> 24 & 0xf) + 1;
+        let breakpoints = (0..num_brps)
+            .map(|i| Ok((self.read(DBGBVR0 + 4 * i)?, self.read(DBGBCR0 + 4 * i)?)))
+            .collect::<Result<_, u8>>()?;
+        let watchpoints = (0..num_wrps)
+            .map(|i| Ok((self.read(DBGWVR0 + 4 * i)?, self.read(DBGWCR0 + 4 * i)?)))
+            .collect::<Result<_, u8>>()?;
+        Ok(DebugState::Registers {
+            dscr: self.read(DBGDSCR)?,
+            vcr: self.read(DBGVCR)?,
+            claim: self.read(DBGCLAIMCLR)?,
+            breakpoints,
+            watchpoints,
+        })
+    }
+
+    /// Restore state saved with `save` after the core has powered up again, then clear the OS
+    /// lock
+    pub fn restore(&mut self, state: &DebugState) -> Result<(), u8> {
+        if !self.is_powered_up()? {
+            return Err(ERR_POWERED_DOWN);
+        }
+        self.set_os_lock(true)?;
+        match state {
+            DebugState::SaveRestore(words) => {
+                // As for saving, the sequence starts with a read of the count
+                self.read(DBGOSSRR)?;
+                for &w in words {
+                    self.write(DBGOSSRR, w)?;
+                }
+            }
+            DebugState::Registers {
+                dscr,
+                vcr,
+                claim,
+                breakpoints,
+                watchpoints,
+            } => {
+                for (i, &(value, control)) in breakpoints.iter().enumerate() {
+                    self.write(DBGBVR0 + 4 * i as u32, value)?;
+                    self.write(DBGBCR0 + 4 * i as u32, control)?;
+                }
+                for (i, &(value, control)) in watchpoints.iter().enumerate() {
+                    self.write(DBGWVR0 + 4 * i as u32, value)?;
+                    self.write(DBGWCR0 + 4 * i as u32, control)?;
+                }
+                self.write(DBGVCR, *vcr)?;
+                self.write(DBGCLAIMCLR, 0xff)?;
+                self.write(DBGCLAIMSET, *claim)?;
+                let current = self.read(DBGDSCR)?;
+                self.write(DBGDSCR, current & !DSCR_WRITABLE | dscr & DSCR_WRITABLE)?;
+            }
+        }
+        self.set_os_lock(false)
+    }
+
+    /// Restore `state` if the core has been powered down since DBGPRSR was last read, and is
+    /// now powered up.  Returns true if the state was restored.  Reading DBGPRSR while the core
+    /// is powered up clears its sticky power-down bit, so this should be the only thing polling
+    /// it.
+    pub fn restore_after_power_down(&mut self, state: &DebugState) -> Result<bool, u8> {
+        let prsr = self.read(DBGPRSR)?;
+        if prsr & PRSR_SPD == 0 || prsr & PRSR_PU == 0 {
+            return Ok(false);
+        }
+        self.restore(state)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,1932 @@
+//! Run control for ARMv8-A cores through the external debug interface.  Halting and resuming is
+//! done through the core's Cross Trigger Interface, and registers are accessed by injecting
+//! instructions through the Instruction Transfer Register while the core is halted.  The core is
+//! assumed to be executing in AArch64 state.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::image;
+use crate::memory::ERR_ADDRESS_RANGE;
+use crate::register::Register;
+use crate::{MemAP, Transport, ERR_STICKY, ERR_TIMEOUT};
+
+use self::regs::{CtiControl, CtiGate, Edeccr, Edecr, Edprcr, Edprsr};
+
+// External debug registers, relative to the core's debug base
+const DBGBVR0: u32 = 0x400;
+const DBGBCR0: u32 = 0x408;
+const DBGWVR0: u32 = 0x800;
+const DBGWCR0: u32 = 0x808;
+const EDWAR_LO: u32 = 0x030;
+const EDWAR_HI: u32 = 0x034;
+const DBGDTRRX: u32 = 0x080;
+const EDITR: u32 = 0x084;
+const EDSCR: u32 = 0x088;
+const DBGDTRTX: u32 = 0x08c;
+const EDRCR: u32 = 0x090;
+const EDPCSR_LO: u32 = 0x0a0;
+const EDCIDSR: u32 = 0x0a4;
+const EDVIDSR: u32 = 0x0a8;
+const EDPCSR_HI: u32 = 0x0ac;
+const OSLAR: u32 = 0x300;
+const EDDFR: u32 = 0xd28;
+const LAR: u32 = 0xfb0;
+const LSR: u32 = 0xfb4;
+
+// CTI registers, relative to the CTI base
+const CTIINTACK: u32 = 0x010;
+const CTIAPPPULSE: u32 = 0x01c;
+const CTIOUTEN0: u32 = 0x0a0;
+const CTITRIGOUTSTATUS: u32 = 0x134;
+
+const EDSCR_ERR: u32 = 1 << 6;
+const EDSCR_MA: u32 = 1 << 20;
+const EDSCR_ITE: u32 = 1 << 24;
+const EDSCR_TXFULL: u32 = 1 << 29;
+const EDSCR_RXFULL: u32 = 1 << 30;
+
+/// External debug and CTI registers with named fields.  The external debug registers are
+/// relative to a core's debug base and the CTI registers to its CTI base.
+pub mod regs {
+    use crate::register;
+
+    register! {
+        /// External Debug Execution Control Register
+        pub struct Edecr @ 0x024 {
+            /// OS unlock catch
+            osuce, set_osuce: 0;
+            /// Reset catch
+            rce, set_rce: 1;
+            /// Halting step
+            ss, set_ss: 2;
+        }
+    }
+
+    register! {
+        /// External Debug Status and Control Register
+        pub struct Edscr @ 0x088 {
+            status, set_status: 0..=5;
+            err, set_err: 6;
+            /// SError interrupt pending
+            a, set_a: 7;
+            el, set_el: 8..=9;
+            rw, set_rw: 10..=13;
+            /// Halting debug enable
+            hde, set_hde: 14;
+            sdd, set_sdd: 16;
+            ns, set_ns: 18;
+            sc2, set_sc2: 19;
+            /// Memory access mode
+            ma, set_ma: 20;
+            tda, set_tda: 21;
+            intdis, set_intdis: 22..=23;
+            ite, set_ite: 24;
+            pipeadv, set_pipeadv: 25;
+            txu, set_txu: 26;
+            rxo, set_rxo: 27;
+            ito, set_ito: 28;
+            txfull, set_txfull: 29;
+            rxfull, set_rxfull: 30;
+            tfo, set_tfo: 31;
+        }
+    }
+
+    register! {
+        /// External Debug Exception Catch Control Register.  Each field has one bit per exception
+        /// level, bit n for ELn.
+        pub struct Edeccr @ 0x098 {
+            /// Halt on taking an exception to a Secure exception level
+            se, set_se: 0..=3;
+            /// Halt on taking an exception to a Non-secure exception level
+            nse, set_nse: 4..=7;
+            /// Halt on an exception return to a Secure exception level, from ARMv8.2
+            sr, set_sr: 8..=11;
+            /// Halt on an exception return to a Non-secure exception level, from ARMv8.2
+            nsr, set_nsr: 12..=15;
+        }
+    }
+
+    register! {
+        /// External Debug Power/Reset Control Register
+        pub struct Edprcr @ 0x310 {
+            /// Core no power down request
+            corenpdrq, set_corenpdrq: 0;
+            /// Warm reset request
+            cwrr, set_cwrr: 1;
+            /// Core powerup request
+            corepureq, set_corepureq: 3;
+        }
+    }
+
+    register! {
+        /// External Debug Processor Status Register
+        pub struct Edprsr @ 0x314 {
+            /// Core powered up
+            pu, set_pu: 0;
+            /// Sticky core power down, cleared by reading the register
+            spd, set_spd: 1;
+            /// Core in reset
+            r, set_r: 2;
+            /// Sticky core reset, cleared by reading the register
+            sr, set_sr: 3;
+            halted, set_halted: 4;
+            /// OS lock
+            oslk, set_oslk: 5;
+            /// OS double lock
+            dlk, set_dlk: 6;
+            epmad, set_epmad: 7;
+            sdad, set_sdad: 8;
+            /// Sticky debug restart, cleared by reading the register
+            sdr, set_sdr: 11;
+        }
+    }
+
+    register! {
+        /// CTI Control Register
+        pub struct CtiControl @ 0x000 {
+            /// Global enable
+            glben, set_glben: 0;
+        }
+    }
+
+    register! {
+        /// CTI Channel Gate Enable Register
+        pub struct CtiGate @ 0x140 {
+            /// One bit per channel, set to pass events on the channel to the cross trigger matrix
+            en, set_en: 0..=3;
+        }
+    }
+}
+
+/// How long to wait for the core to come out of reset and halt, to restart or finish a step, or
+/// to complete an instruction or DTR transfer
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Error returned when the core is not powered up
+pub const ERR_POWERED_DOWN: u8 = 0x10;
+/// Error returned when the software lock could not be cleared
+pub const ERR_LOCKED: u8 = 0x11;
+/// Error returned when an instruction executed through the ITR generated an exception
+pub const ERR_INSTRUCTION: u8 = 0x12;
+/// Error returned when all of the hardware breakpoints are in use
+pub const ERR_NO_BREAKPOINT: u8 = 0x13;
+/// Error returned when an access needs a higher exception level than the core is halted in
+pub const ERR_EXCEPTION_LEVEL: u8 = 0x14;
+
+/// DBGBCR enable
+const BCR_E: u32 = 1 << 0;
+/// DBGBCR matching at EL2, EL1 and EL0 in both security states: HMC, SSC 0b00 and PMC 0b11
+const BCR_ALL_LEVELS: u32 = 1 << 13 | 3 << 1;
+const BCR_BAS_SHIFT: u32 = 5;
+
+/// `HLT #0` in each instruction set.  With halting debug enabled, HLT halts the core where BRK
+/// would take a debug exception to the OS.
+const HLT_A64: u32 = 0xd4400000;
+const HLT_A32: u32 = 0xe1000070;
+const HLT_T32: u16 = 0xba80;
+/// `HLT #0xf000`, the A64 semihosting call
+const SEMIHOST_A64: u32 = 0xd45e0000;
+
+/// Instruction set of the code at a breakpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstructionSet {
+    A64,
+    A32,
+    T32,
+}
+
+/// How a breakpoint is implemented
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// A breakpoint register, which works in read-only memory but is limited in number
+    Hardware,
+    /// An HLT instruction patched into memory
+    Software,
+    /// A breakpoint register if one is free, otherwise an instruction
+    Any,
+}
+
+/// Where a breakpoint set with `Core::set_breakpoint` is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placement {
+    /// Index of the breakpoint register
+    Hardware(usize),
+    /// The instruction that the HLT replaced, and its instruction set
+    Software(InstructionSet, u32),
+}
+
+/// Outcome of `Core::run_to`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunToResult {
+    /// The core halted at the requested address
+    Reached,
+    /// The core halted somewhere else first
+    Stopped { pc: u64, reason: Option<HaltReason> },
+    /// The core didn't halt in time, and was halted by the debugger
+    TimedOut { pc: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Breakpoint {
+    addr: u64,
+    placement: Placement,
+}
+
+/// Encode an `MRS Xt, <sysreg>` instruction
+pub const fn mrs(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32, rt: u32) -> u32 {
+    0xd5200000 | op0 << 19 | op1 << 16 | crn << 12 | crm << 8 | op2 << 5 | rt
+}
+
+/// Encode an `MSR <sysreg>, Xt` instruction
+pub const fn msr(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32, rt: u32) -> u32 {
+    0xd5000000 | op0 << 19 | op1 << 16 | crn << 12 | crm << 8 | op2 << 5 | rt
+}
+
+/// `MSR DBGDTR_EL0, Xt` with Xt = 0
+const MSR_DBGDTR_EL0: u32 = msr(2, 3, 0, 4, 0, 0);
+/// `MRS Xt, DBGDTR_EL0` with Xt = 0
+const MRS_DBGDTR_EL0: u32 = mrs(2, 3, 0, 4, 0, 0);
+/// `MRS X0, DLR_EL0`
+const MRS_X0_DLR_EL0: u32 = mrs(3, 3, 4, 5, 1, 0);
+/// `MRS X0, DSPSR_EL0`
+const MRS_X0_DSPSR_EL0: u32 = mrs(3, 3, 4, 5, 0, 0);
+/// `MSR DLR_EL0, X0`
+const MSR_DLR_EL0_X0: u32 = msr(3, 3, 4, 5, 1, 0);
+/// `MSR DSPSR_EL0, X0`
+const MSR_DSPSR_EL0_X0: u32 = msr(3, 3, 4, 5, 0, 0);
+
+/// `UMOV X0, Vn.D[i]`
+const fn umov_x0(n: u32, i: u32) -> u32 {
+    0x4e083c00 | i << 20 | n << 5
+}
+/// `INS Vn.D[i], X0`
+const fn ins_x0(n: u32, i: u32) -> u32 {
+    0x4e081c00 | i << 20 | n
+}
+
+/// `MOV X0, SP`
+const MOV_X0_SP: u32 = 0x910003e0;
+/// `AT S1E1R, X0`
+const AT_S1E1R_X0: u32 = 0xd5087800;
+/// `AT S1E2R, X0`
+const AT_S1E2R_X0: u32 = 0xd50c7800;
+/// `AT S12E1R, X0`
+const AT_S12E1R_X0: u32 = 0xd50c7880;
+/// `ISB`
+const ISB: u32 = 0xd5033fdf;
+/// `DSB ISH`
+const DSB_ISH: u32 = 0xd5033b9f;
+/// `DC CVAU, X0`
+const DC_CVAU_X0: u32 = 0xd50b7b20;
+/// `IC IVAU, X0`
+const IC_IVAU_X0: u32 = 0xd50b7520;
+/// `DC IVAC, X0`
+const DC_IVAC_X0: u32 = 0xd5087620;
+/// `IC IALLU`
+const IC_IALLU: u32 = 0xd508751f;
+/// `DSB SY`
+const DSB_SY: u32 = 0xd5033f9f;
+
+const PAR_F: u64 = 1 << 0;
+const PAR_PA_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Translation regime used by `Core::translate_in`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Regime {
+    /// The EL1&0 regime, using the stage 1 tables of the OS
+    El1,
+    /// The EL2 regime, using the stage 1 tables of the hypervisor
+    El2,
+    /// The EL1&0 regime of a guest, through its stage 1 tables and then the hypervisor's
+    /// stage 2 tables, giving the physical address behind a guest virtual address
+    El1Stage12,
+}
+
+/// Why a core entered Debug state, from EDSCR.STATUS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HaltReason {
+    /// A breakpoint, with the index of its comparator if it is a hardware breakpoint
+    Breakpoint(Option<usize>),
+    /// Halt request from the debugger or another core through the CTI
+    ExternalRequest,
+    /// Halting step completed normally
+    Step,
+    /// Halting step stopped on a Load-Exclusive instruction
+    StepExclusive,
+    /// Halting step stopped without syndrome information, for example after an exception
+    StepNoSyndrome,
+    OsUnlockCatch,
+    ResetCatch,
+    /// A watchpoint, with the index of its comparator and the data address that triggered it,
+    /// where they are known
+    Watchpoint {
+        index: Option<usize>,
+        addr: Option<u64>,
+    },
+    /// An `HLT` instruction other than a breakpoint or semihosting call
+    HltInstruction,
+    /// A semihosting call: `HLT #0xf000`, or `BKPT #0xab` on Cortex-M
+    Semihosting,
+    /// Software accessed a debug register while EDSCR.TDA was set
+    SoftwareAccess,
+    ExceptionCatch,
+    /// A status value not defined by the architecture, or no reason was recorded
+    Unknown(u8),
+}
+
+impl HaltReason {
+    /// Decode EDSCR.STATUS.  Returns None if the core isn't in Debug state.  STATUS doesn't say
+    /// which breakpoint or watchpoint was hit, or whether an HLT is a semihosting call; see
+    /// `Core::halt_reason`.
+    pub fn from_status(status: u8) -> Option<Self> {
+        let reason = match status {
+            0b000001 | 0b000010 => return None,
+            0b000111 => HaltReason::Breakpoint(None),
+            0b010011 => HaltReason::ExternalRequest,
+            0b011011 => HaltReason::Step,
+            0b011111 => HaltReason::StepExclusive,
+            0b100011 => HaltReason::OsUnlockCatch,
+            0b100111 => HaltReason::ResetCatch,
+            0b101011 => HaltReason::Watchpoint {
+                index: None,
+                addr: None,
+            },
+            0b101111 => HaltReason::HltInstruction,
+            0b110011 => HaltReason::SoftwareAccess,
+            0b110111 => HaltReason::ExceptionCatch,
+            0b111011 => HaltReason::StepNoSyndrome,
+            status => HaltReason::Unknown(status),
+        };
+        Some(reason)
+    }
+}
+
+/// Kind of data access that triggered a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// An atomic read-modify-write
+    ReadWrite,
+    Unknown,
+}
+
+/// Details of the access that triggered a watchpoint, from `Core::watchpoint_hit` or
+/// `CortexM::watchpoint_hit`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchpointHit {
+    /// Index of the watchpoint comparator, if known
+    pub index: Option<usize>,
+    /// Data address accessed, if known
+    pub addr: Option<u64>,
+    pub access: AccessKind,
+    /// Value being written, if known
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for WatchpointHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.access, self.value) {
+            (AccessKind::Write, Some(value)) => write!(f, "write of 0x{:x}", value)?,
+            (AccessKind::Write, None) => write!(f, "write")?,
+            (AccessKind::Read, _) => write!(f, "read")?,
+            (AccessKind::ReadWrite, _) => write!(f, "atomic access")?,
+            (AccessKind::Unknown, _) => write!(f, "access")?,
+        }
+        match (self.access, self.addr) {
+            (AccessKind::Read, Some(addr)) => write!(f, " of 0x{:x}", addr)?,
+            (_, Some(addr)) => write!(f, " to 0x{:x}", addr)?,
+            (_, None) => {}
+        }
+        match self.index {
+            Some(index) => write!(f, " triggered WP{}", index),
+            None => write!(f, " triggered a watchpoint"),
+        }
+    }
+}
+
+/// Classify the A64 instruction `instr` as a load or store.  Returns the kind of access, and for
+/// a single general purpose register the transfer register Rt and the access size in bytes.
+fn a64_access(instr: u32) -> (AccessKind, Option<(u32, u32)>) {
+    // Loads and stores have op0 x1x0
+    if instr & 0x0a00_0000 != 0x0800_0000 {
+        return (AccessKind::Unknown, None);
+    }
+    let simd = instr & (1 << 26) != 0;
+    match (instr >> 27) & 7 {
+        // Load register (literal)
+        0b011 if instr & (1 << 24) == 0 => (AccessKind::Read, None),
+        // Load and store register: size in [31:30] and opc in [23:22]
+        0b111 => {
+            let size = instr >> 30;
+            let opc = (instr >> 22) & 3;
+            let atomic = instr & (1 << 24) == 0 && instr & (1 << 21) != 0 && instr & 0xc00 == 0;
+            let kind = if atomic && !simd {
+                AccessKind::ReadWrite
+            } else if opc == 0 || (simd && size == 0 && opc == 2) {
+                AccessKind::Write
+            } else {
+                // A load, a sign-extending load, or a prefetch
+                AccessKind::Read
+            };
+            let reg = (!simd).then_some((instr & 0x1f, 1 << size));
+            (kind, reg)
+        }
+        // Exclusives, pairs and SIMD structures all have the L bit at 22
+        _ if instr & (1 << 22) != 0 => (AccessKind::Read, None),
+        _ => (AccessKind::Write, None),
+    }
+}
+
+/// Exception levels at which `Core::set_exception_catch` halts the core, one bit per level,
+/// bit n for ELn.  Only the levels the core implements in each security state can be caught, such
+/// as EL1 and EL3 when Secure and EL1 and EL2 when Non-secure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExceptionCatch {
+    /// Taking an exception to a Secure exception level, such as an abort or an SMC to EL3
+    pub secure: u8,
+    /// Taking an exception to a Non-secure exception level
+    pub non_secure: u8,
+    /// Returning from an exception to a Secure exception level, from ARMv8.2
+    pub secure_return: u8,
+    /// Returning from an exception to a Non-secure exception level, from ARMv8.2
+    pub non_secure_return: u8,
+}
+
+impl ExceptionCatch {
+    /// Also halt on taking an exception to `el` in the given security state
+    pub fn entry(mut self, el: u8, secure: bool) -> Self {
+        match secure {
+            true => self.secure |= 1 << el,
+            false => self.non_secure |= 1 << el,
+        }
+        self
+    }
+
+    /// Also halt on an exception return to `el` in the given security state
+    pub fn exit(mut self, el: u8, secure: bool) -> Self {
+        match secure {
+            true => self.secure_return |= 1 << el,
+            false => self.non_secure_return |= 1 << el,
+        }
+        self
+    }
+}
+
+impl From<Edeccr> for ExceptionCatch {
+    fn from(reg: Edeccr) -> Self {
+        Self {
+            secure: reg.se() as u8,
+            non_secure: reg.nse() as u8,
+            secure_return: reg.sr() as u8,
+            non_secure_return: reg.nsr() as u8,
+        }
+    }
+}
+
+impl From<ExceptionCatch> for Edeccr {
+    fn from(catch: ExceptionCatch) -> Self {
+        let mut reg = Edeccr::default();
+        reg.set_se(catch.secure as u32);
+        reg.set_nse(catch.non_secure as u32);
+        reg.set_sr(catch.secure_return as u32);
+        reg.set_nsr(catch.non_secure_return as u32);
+        reg
+    }
+}
+
+/// Exception level and security state of a halted core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SecurityState {
+    pub el: u8,
+    pub secure: bool,
+    /// Halting in Secure state is prohibited, so only Non-secure state can be debugged
+    pub secure_debug_disabled: bool,
+}
+
+/// Lowest exception level that can access the system register with encoding `op0` and `op1`
+fn sysreg_min_el(op0: u32, op1: u32) -> u8 {
+    match (op0, op1) {
+        (2 | 3, 3) => 0,
+        // EL2 registers, and the EL12 and EL02 aliases used from EL2
+        (2 | 3, 4) | (3, 5) => 2,
+        (3, 6) => 3,
+        _ => 1,
+    }
+}
+
+/// Decoded value of EDSCR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edscr {
+    /// Raw STATUS field
+    pub status: u8,
+    /// An instruction executed through the ITR or a memory access mode transfer failed
+    pub err: bool,
+    /// An SError interrupt is pending
+    pub serror_pending: bool,
+    /// Exception level, valid in Debug state
+    pub el: u8,
+    /// Execution state of each exception level, one bit per level, set for AArch64
+    pub rw: u8,
+    pub hde: bool,
+    /// Secure debug is disabled
+    pub sdd: bool,
+    /// Non-secure state
+    pub ns: bool,
+    pub sc2: bool,
+    pub ma: bool,
+    pub tda: bool,
+    pub intdis: u8,
+    pub ite: bool,
+    pub pipeadv: bool,
+    pub txu: bool,
+    pub rxo: bool,
+    pub ito: bool,
+    pub txfull: bool,
+    pub rxfull: bool,
+    pub tfo: bool,
+}
+
+impl Edscr {
+    /// Why the core is halted, or None if it isn't
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        HaltReason::from_status(self.status)
+    }
+
+    /// Return true if the core is in Debug state
+    pub fn is_halted(&self) -> bool {
+        self.halt_reason().is_some()
+    }
+}
+
+impl From<u32> for Edscr {
+    fn from(val: u32) -> Self {
+        let bit = |n: u32| val & (1 << n) != 0;
+        Self {
+            status: (val & 0x3f) as u8,
+            err: bit(6),
+            serror_pending: bit(7),
+            el: ((val >> 8) & 3) as u8,
+            rw: ((val >> 10) & 0xf) as u8,
+            hde: bit(14),
+            sdd: bit(16),
+            ns: bit(18),
+            sc2: bit(19),
+            ma: bit(20),
+            tda: bit(21),
+            intdis: ((val >> 22) & 3) as u8,
+            ite: bit(24),
+            pipeadv: bit(25),
+            txu: bit(26),
+            rxo: bit(27),
+            ito: bit(28),
+            txfull: bit(29),
+            rxfull: bit(30),
+            tfo: bit(31),
+        }
+    }
+}
+
+/// A PC sample with the context it was taken in, from EDPCSR, EDCIDSR and EDVIDSR
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PcSample {
+    pub pc: u64,
+    /// CONTEXTIDR of the sampled code, normally the process ID under Linux
+    pub context_id: u32,
+    /// VMID of the sampled code, when it was running in a guest
+    pub vmid: u16,
+    /// Sampled in Non-secure state
+    pub non_secure: bool,
+    /// Sampled at EL2
+    pub el2: bool,
+    /// Sampled at EL3
+    pub el3: bool,
+    /// Sampled at EL0 in the EL2&0 translation regime, as a host process under VHE
+    pub hypervisor: bool,
+}
+
+/// EL1 system registers captured by `Core::read_context`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct El1Regs {
+    pub sctlr: u64,
+    pub tcr: u64,
+    pub ttbr0: u64,
+    pub ttbr1: u64,
+    pub mair: u64,
+    pub vbar: u64,
+    pub esr: u64,
+    pub far: u64,
+    pub elr: u64,
+    pub spsr: u64,
+}
+
+/// EL2 system registers captured by `Core::read_context` and `Core::read_el2_regs`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct El2Regs {
+    /// Hypervisor configuration, including whether stage 2 translation is enabled (VM)
+    pub hcr: u64,
+    /// Stage 2 translation table base and VMID of the current guest
+    pub vttbr: u64,
+    pub vtcr: u64,
+    pub sctlr: u64,
+    pub tcr: u64,
+    pub ttbr0: u64,
+    pub vbar: u64,
+    pub esr: u64,
+    pub far: u64,
+    /// Intermediate physical address of the last stage 2 fault
+    pub hpfar: u64,
+    pub elr: u64,
+    pub spsr: u64,
+}
+
+impl El2Regs {
+    /// Stage 2 translation is enabled for the EL1&0 regime
+    pub fn stage2_enabled(&self) -> bool {
+        self.hcr & 1 != 0
+    }
+
+    /// VMID of the current guest, from VTTBR_EL2
+    pub fn vmid(&self) -> u16 {
+        (self.vttbr >> 48) as u16
+    }
+
+    /// Faulting intermediate physical address recorded in HPFAR_EL2, with the page offset
+    /// taken from FAR_EL2
+    pub fn fault_ipa(&self) -> u64 {
+        (self.hpfar >> 4 & 0xff_ffff_ffff) << 12 | self.far & 0xfff
+    }
+}
+
+/// Register context of a halted core
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Context {
+    pub x: [u64; 31],
+    /// Stack pointer of the exception level the core is halted in
+    pub sp: u64,
+    /// SP_EL0, SP_EL1 and SP_EL2, where accessible from the current exception level
+    pub sp_el: [Option<u64>; 3],
+    pub pc: u64,
+    pub pstate: u64,
+    /// Exception level the core is halted in
+    pub el: u8,
+    /// EL1 system registers, if the core is halted at EL1 or above
+    pub el1: Option<El1Regs>,
+    /// EL2 system registers, if the core is halted at EL2 or above
+    pub el2: Option<El2Regs>,
+}
+
+/// A watchpoint comparator that was enabled when `Core::export_state` was called
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SavedWatchpoint {
+    pub index: usize,
+    /// DBGWVR
+    pub value: u64,
+    /// DBGWCR
+    pub control: u32,
+}
+
+/// Location and debug configuration of a core, saved with `Core::export_state`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoreState {
+    pub debug_base: u32,
+    pub cti_base: u32,
+    /// EDECR, without the halting step bit
+    pub edecr: u32,
+    pub exception_catch: ExceptionCatch,
+    pub no_power_down: bool,
+    pub watchpoints: Vec<SavedWatchpoint>,
+    breakpoints: Vec<Breakpoint>,
+    isa: InstructionSet,
+}
+
+/// Functions for controlling an ARMv8-A core
+pub struct Core<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    debug_base: u32,
+    cti_base: u32,
+    breakpoints: Vec<Breakpoint>,
+    /// Number of breakpoint registers, read when first needed
+    num_hw_breakpoints: Option<usize>,
+    isa: InstructionSet,
+    /// The core has powered down since its debug state was last initialized
+    power_lost: bool,
+    /// Keep EDPRCR.CORENPDRQ set
+    no_power_down: bool,
+    /// Exception catch set with `set_exception_catch`, restored after a power down
+    exception_catch: ExceptionCatch,
+}
+
+impl<T> Core<T>
+where
+    T: Transport + ?Sized,
+{
+    /// `debug_base` and `cti_base` are the addresses of the core's external debug registers and
+    /// its CTI, both accessed through `mem`.
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, debug_base: u32, cti_base: u32) -> Self {
+        Self {
+            mem,
+            debug_base,
+            cti_base,
+            breakpoints: vec![],
+            num_hw_breakpoints: None,
+            isa: InstructionSet::A64,
+            power_lost: false,
+            no_power_down: false,
+            exception_catch: ExceptionCatch::default(),
+        }
+    }
+
+    /// Recreate a core from saved state.  The breakpoints are taken over as they were, so that
+    /// they can be cleared later, and EDECR and the watchpoints are written again.  The core's
+    /// debug logic keeps its configuration while it stays powered, so `unlock` only needs to be
+    /// called again if it has been powered down, and it must be powered up.
+    pub fn from_state(mem: Rc<RefCell<MemAP<T>>>, state: CoreState) -> Result<Self, u8> {
+        let mut core = Self::new(mem, state.debug_base, state.cti_base);
+        core.breakpoints = state.breakpoints;
+        core.isa = state.isa;
+        core.exception_catch = state.exception_catch;
+        core.no_power_down = state.no_power_down;
+        core.write_dbg_reg(Edecr::from_bits(state.edecr))?;
+        for wp in &state.watchpoints {
+            let reg = 16 * wp.index as u32;
+            core.write_dbg(DBGWVR0 + reg, wp.value as u32)?;
+            core.write_dbg(DBGWVR0 + reg + 4, (wp.value >> 32) as u32)?;
+            core.write_dbg(DBGWCR0 + reg, wp.control)?;
+        }
+        Ok(core)
+    }
+
+    /// Save the core's debug configuration: the breakpoints set through it, exception catch and
+    /// power-down settings, EDECR and any enabled watchpoints.  The core must be powered up.
+    pub fn export_state(&mut self) -> Result<CoreState, u8> {
+        let mut edecr = self.read_dbg_reg::<Edecr>()?;
+        edecr.set_ss(false);
+        let num_watchpoints = ((self.read_dbg(EDDFR)? >> 20) & 0xf) as usize + 1;
+        let mut watchpoints = vec![];
+        for index in 0..num_watchpoints {
+            let reg = 16 * index as u32;
+            let control = self.read_dbg(DBGWCR0 + reg)?;
+            if control & BCR_E == 0 {
+                continue;
+            }
+            let lo = self.read_dbg(DBGWVR0 + reg)?;
+            let hi = self.read_dbg(DBGWVR0 + reg + 4)?;
+            watchpoints.push(SavedWatchpoint {
+                index,
+                value: (hi as u64) << 32 | lo as u64,
+                control,
+            });
+        }
+        Ok(CoreState {
+            debug_base: self.debug_base,
+            cti_base: self.cti_base,
+            edecr: edecr.bits(),
+            exception_catch: self.exception_catch,
+            no_power_down: self.no_power_down,
+            watchpoints,
+            breakpoints: self.breakpoints.clone(),
+            isa: self.isa,
+        })
+    }
+
+    fn read_dbg_reg<R: Register>(&mut self) -> Result<R, u8> {
+        Ok(R::from_bits(self.read_dbg(R::OFFSET)?))
+    }
+
+    fn write_dbg_reg<R: Register>(&mut self, reg: R) -> Result<(), u8> {
+        self.write_dbg(R::OFFSET, reg.bits())
+    }
+
+    fn modify_dbg_reg<R, F>(&mut self, f: F) -> Result<(), u8>
+    where
+        R: Register,
+        F: FnOnce(&mut R),
+    {
+        let mut reg = self.read_dbg_reg::<R>()?;
+        f(&mut reg);
+        self.write_dbg_reg(reg)
+    }
+
+    fn read_dbg(&mut self, reg: u32) -> Result<u32, u8> {
+        let result = self.mem.borrow_mut().read(self.debug_base + reg);
+        result.map_err(|e| self.access_error(e))
+    }
+
+    fn write_dbg(&mut self, reg: u32, val: u32) -> Result<(), u8> {
+        let result = self.mem.borrow_mut().write(self.debug_base + reg, val);
+        result.map_err(|e| self.access_error(e))
+    }
+
+    /// Work out why an access to a debug register failed.  Most debug registers fault while the
+    /// core is powered down, leaving a sticky error that would fail every later access, so the
+    /// error is cleared and reported as `ERR_POWERED_DOWN` if that was the cause.
+    fn access_error(&mut self, e: u8) -> u8 {
+        if e != ERR_STICKY {
+            return e;
+        }
+        let mut mem = self.mem.borrow_mut();
+        if mem.clear_sticky_errors().is_err() {
+            return e;
+        }
+        // EDPRSR is in the debug power domain, so it can be read while the core is off
+        match mem.read_register::<Edprsr>(self.debug_base) {
+            Ok(edprsr) if !edprsr.pu() => {
+                self.power_lost = true;
+                ERR_POWERED_DOWN
+            }
+            _ => e,
+        }
+    }
+
+    /// Read EDPRSR, remembering if the core has powered down since the last read, which clears
+    /// the sticky power-down bit
+    fn read_edprsr(&mut self) -> Result<Edprsr, u8> {
+        let edprsr = self.read_dbg_reg::<Edprsr>()?;
+        if edprsr.spd() || !edprsr.pu() {
+            self.power_lost = true;
+        }
+        Ok(edprsr)
+    }
+
+    fn write_cti(&mut self, reg: u32, val: u32) -> Result<(), u8> {
+        self.mem.borrow_mut().write(self.cti_base + reg, val)
+    }
+
+    /// Prepare the core for external debug.  This clears the OS lock and software lock, enables
+    /// halting debug and enables the CTI.  The core must be powered up.
+    pub fn unlock(&mut self) -> Result<(), u8> {
+        if !self.read_edprsr()?.pu() {
+            return Err(ERR_POWERED_DOWN);
+        }
+
+        self.write_dbg(OSLAR, 0)?;
+        self.write_dbg(LAR, 0xC5ACCE55)?;
+        if self.read_dbg(LSR)? & 2 != 0 {
+            return Err(ERR_LOCKED);
+        }
+
+        self.modify_dbg_reg(|edscr: &mut regs::Edscr| edscr.set_hde(true))?;
+
+        self.write_cti(LAR, 0xC5ACCE55)?;
+        self.mem
+            .borrow_mut()
+            .modify_register(self.cti_base, |cti: &mut CtiControl| cti.set_glben(true))?;
+        self.power_lost = false;
+        Ok(())
+    }
+
+    /// Return true if the core's power domain is on.  Most debug registers are inaccessible
+    /// while it is off.
+    pub fn is_powered_up(&mut self) -> Result<bool, u8> {
+        Ok(self.read_edprsr()?.pu())
+    }
+
+    /// Request that the core isn't powered down, by setting EDPRCR.CORENPDRQ, so that it stays
+    /// debuggable through WFI.  Whether the request is honoured depends on the power
+    /// controller.
+    pub fn set_no_power_down(&mut self, enable: bool) -> Result<(), u8> {
+        self.no_power_down = enable;
+        self.modify_dbg_reg(|edprcr: &mut Edprcr| edprcr.set_corenpdrq(enable))
+    }
+
+    /// Set the exception levels at which the core halts with `HaltReason::ExceptionCatch` on
+    /// taking an exception or returning from one, through EDECCR.  Exception catch doesn't say
+    /// which exception was taken; ESR of the level caught has the syndrome.
+    pub fn set_exception_catch(&mut self, catch: ExceptionCatch) -> Result<(), u8> {
+        self.exception_catch = catch;
+        self.write_dbg_reg(Edeccr::from(catch))
+    }
+
+    /// Read the exception catch settings from EDECCR
+    pub fn exception_catch(&mut self) -> Result<ExceptionCatch, u8> {
+        Ok(ExceptionCatch::from(self.read_dbg_reg::<Edeccr>()?))
+    }
+
+    /// Return true if the core is powered up.  If it has been powered down since its debug
+    /// state was initialized, which resets the OS lock and the breakpoint registers, the state
+    /// is initialized again with `unlock`, and the hardware breakpoints, the no power down
+    /// request and the exception catch settings are restored.
+    pub fn check_power(&mut self) -> Result<bool, u8> {
+        if !self.read_edprsr()?.pu() {
+            return Ok(false);
+        }
+        if self.power_lost {
+            self.unlock()?;
+            if self.no_power_down {
+                self.set_no_power_down(true)?;
+            }
+            if self.exception_catch != ExceptionCatch::default() {
+                self.set_exception_catch(self.exception_catch)?;
+            }
+            let hardware: Vec<Breakpoint> = self
+                .breakpoints
+                .iter()
+                .filter(|bp| matches!(bp.placement, Placement::Hardware(_)))
+                .copied()
+                .collect();
+            for bp in hardware {
+                self.insert_breakpoint(bp.addr, bp.placement)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Warm reset the core with EDPRCR.CWRR and halt it on the first instruction of its reset
+    /// vector, so that boot code can be debugged from the start.  Only the core is reset; use
+    /// `reset_and_halt_with` to reset the whole system.
+    pub fn reset_and_halt(&mut self) -> Result<(), u8> {
+        self.do_reset_and_halt(|core| {
+            core.modify_dbg_reg(|edprcr: &mut Edprcr| edprcr.set_cwrr(true))
+        })
+    }
+
+    /// Like `reset_and_halt`, with the reset asserted by `reset`, for example through a vendor
+    /// reset controller or the cable's system reset line
+    pub fn reset_and_halt_with<F>(&mut self, reset: F) -> Result<(), u8>
+    where
+        F: FnOnce() -> Result<(), u8>,
+    {
+        self.do_reset_and_halt(|_| reset())
+    }
+
+    /// Wait until the core has been through reset.  Accesses can fail while it is in reset, so
+    /// errors are retried until `RESET_TIMEOUT`.
+    fn wait_reset(&mut self) -> Result<(), u8> {
+        let start = Instant::now();
+        loop {
+            if let Ok(edprsr) = self.read_dbg_reg::<Edprsr>() {
+                if edprsr.pu() && !edprsr.r() && edprsr.sr() {
+                    return Ok(());
+                }
+            }
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+
+    fn do_reset_and_halt<F>(&mut self, reset: F) -> Result<(), u8>
+    where
+        F: FnOnce(&mut Self) -> Result<(), u8>,
+    {
+        let mut edecr = self.read_dbg_reg::<Edecr>()?;
+        edecr.set_rce(true);
+        self.write_dbg_reg(edecr)?;
+        // Clear the sticky reset bit, so that the reset can be seen below
+        self.read_edprsr()?;
+
+        // The core may go into reset before the request is acknowledged
+        if reset(self).is_err() {
+            self.mem.borrow_mut().clear_sticky_errors()?;
+        }
+        self.wait_reset()?;
+
+        // A cold reset sets the OS lock and clears the breakpoints, so initialize the debug state
+        // again.  If the reset also reset the debug logic, the reset catch was lost and the core
+        // is running; halt it as soon as possible, although it may have executed some
+        // instructions by then.
+        self.power_lost = true;
+        self.check_power()?;
+        if !self.is_halted()? {
+            self.halt()?;
+        }
+        let start = Instant::now();
+        while !self.is_halted()? {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+
+        edecr.set_rce(false);
+        self.write_dbg_reg(edecr)
+    }
+
+    /// Return true if the core is halted
+    pub fn is_halted(&mut self) -> Result<bool, u8> {
+        Ok(self.read_edprsr()?.halted())
+    }
+
+    /// Sample the PC of the running core without halting it, through EDPCSR.  Returns None if
+    /// no sample is available, which is the case while the core is halted or if sampling is
+    /// prohibited.
+    pub fn sample_pc(&mut self) -> Result<Option<u64>, u8> {
+        // Reading the low half captures the high half
+        let lo = self.read_dbg(EDPCSR_LO)?;
+        if lo == 0xffffffff {
+            return Ok(None);
+        }
+        let hi = self.read_dbg(EDPCSR_HI)?;
+        // Bits 55:32 of the PC, sign-extended
+        let pc = ((hi as u64 & 0xff_ffff) << 32 | lo as u64) << 8;
+        Ok(Some(((pc as i64) >> 8) as u64))
+    }
+
+    /// Sample the PC of the running core with its context ID and VMID.  Like `sample_pc`,
+    /// returns None if no sample is available.
+    pub fn sample(&mut self) -> Result<Option<PcSample>, u8> {
+        // Reading EDPCSRlo captures the other sample registers
+        let Some(pc) = self.sample_pc()? else {
+            return Ok(None);
+        };
+        let context_id = self.read_dbg(EDCIDSR)?;
+        let vidsr = self.read_dbg(EDVIDSR)?;
+        Ok(Some(PcSample {
+            pc,
+            context_id,
+            vmid: vidsr as u16,
+            non_secure: vidsr & 1 << 31 != 0,
+            el2: vidsr & 1 << 30 != 0,
+            el3: vidsr & 1 << 29 != 0,
+            hypervisor: vidsr & 1 << 28 != 0,
+        }))
+    }
+
+    /// Read the raw value of EDSCR
+    pub fn edscr(&mut self) -> Result<u32, u8> {
+        self.read_dbg(EDSCR)
+    }
+
+    /// Read and decode EDSCR
+    pub fn read_edscr(&mut self) -> Result<Edscr, u8> {
+        Ok(Edscr::from(self.read_dbg(EDSCR)?))
+    }
+
+    /// Return why the core is halted, or None if it is running.  A hardware breakpoint is
+    /// reported with the index of its breakpoint register, and a watchpoint with the address
+    /// from EDWAR.  An HLT is reported as `Breakpoint(None)` if it is a software breakpoint and
+    /// `Semihosting` if it is a semihosting call, which is checked by reading the instruction.
+    pub fn halt_reason(&mut self) -> Result<Option<HaltReason>, u8> {
+        let reason = match self.read_edscr()?.halt_reason() {
+            Some(HaltReason::Breakpoint(_)) => {
+                let bp = self
+                    .breakpoint_at_pc()?
+                    .map(|i| self.breakpoints[i].placement);
+                match bp {
+                    Some(Placement::Hardware(n)) => HaltReason::Breakpoint(Some(n)),
+                    _ => HaltReason::Breakpoint(None),
+                }
+            }
+            Some(HaltReason::Watchpoint { .. }) => {
+                let lo = self.read_dbg(EDWAR_LO)? as u64;
+                let hi = self.read_dbg(EDWAR_HI)? as u64;
+                HaltReason::Watchpoint {
+                    index: None,
+                    addr: Some(hi << 32 | lo),
+                }
+            }
+            Some(HaltReason::HltInstruction) => {
+                if self.breakpoint_at_pc()?.is_some() {
+                    HaltReason::Breakpoint(None)
+                } else {
+                    let pc = self.read_pc()?;
+                    match self.read_memory(pc, 1) {
+                        Ok(instr) if instr[0] == SEMIHOST_A64 => HaltReason::Semihosting,
+                        _ => HaltReason::HltInstruction,
+                    }
+                }
+            }
+            reason => return Ok(reason),
+        };
+        Ok(Some(reason))
+    }
+
+    /// Return the details of the access that triggered the watchpoint `reason`, as returned by
+    /// `halt_reason`, or None if `reason` isn't a watchpoint.  The address is from EDWAR, and the
+    /// kind of access and the value written are found by decoding the instruction at the PC,
+    /// which is the one that triggered the watchpoint.  Watchpoints aren't set by this crate, so
+    /// the index isn't known.
+    pub fn watchpoint_hit(&mut self, reason: HaltReason) -> Result<Option<WatchpointHit>, u8> {
+        let HaltReason::Watchpoint { index, addr } = reason else {
+            return Ok(None);
+        };
+        let pc = self.read_pc()?;
+        let (access, reg) = match self.read_memory(pc, 1) {
+            Ok(instr) => a64_access(instr[0]),
+            Err(_) => (AccessKind::Unknown, None),
+        };
+        let value = match (access, reg) {
+            // XZR
+            (AccessKind::Write, Some((31, _))) => Some(0),
+            (AccessKind::Write, Some((rt, 8))) => Some(self.read_reg(rt)?),
+            (AccessKind::Write, Some((rt, bytes))) => {
+                Some(self.read_reg(rt)? & ((1 << (8 * bytes)) - 1))
+            }
+            _ => None,
+        };
+        Ok(Some(WatchpointHit {
+            index,
+            addr,
+            access,
+            value,
+        }))
+    }
+
+    fn cti_pulse(&mut self, channel: u32) -> Result<(), u8> {
+        // Gate all channels so the event isn't broadcast to other cores
+        self.write_cti(CtiGate::OFFSET, 0)?;
+
+        // Route the channel to the trigger output: 0 is debug request, 1 is restart
+        self.write_cti(CTIOUTEN0 + 4 * channel, 1 << channel)?;
+        self.write_cti(CTIAPPPULSE, 1 << channel)?;
+
+        // ACK the event and wait for the trigger output to drop
+        self.write_cti(CTIINTACK, 3)?;
+        let start = Instant::now();
+        while self
+            .mem
+            .borrow_mut()
+            .read(self.cti_base + CTITRIGOUTSTATUS)?
+            != 0
+        {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Request the core to halt
+    pub fn halt(&mut self) -> Result<(), u8> {
+        self.cti_pulse(0)
+    }
+
+    /// Request the core to halt and wait for it to do so.  Returns `ERR_TIMEOUT` if it hasn't
+    /// halted within `RESET_TIMEOUT`.
+    pub fn halt_and_wait(&mut self) -> Result<(), u8> {
+        self.halt()?;
+        let start = Instant::now();
+        while !self.is_halted()? {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restart a halted core.  If there is a breakpoint at the PC, the instruction there is
+    /// stepped over first so that the core doesn't halt on it again immediately.
+    pub fn resume(&mut self) -> Result<(), u8> {
+        if self.breakpoint_at_pc()?.is_some() {
+            self.step()?;
+        }
+        self.restart()
+    }
+
+    /// Restart the halted core through the CTI and wait until it has left Debug state, so that a
+    /// following `is_halted` sees the next halt rather than the one being restarted from.
+    /// Returns `ERR_TIMEOUT` if the core doesn't restart within `RESET_TIMEOUT`.
+    fn restart(&mut self) -> Result<(), u8> {
+        // Clear EDPRSR.SDR, so that it shows this restart
+        self.read_edprsr()?;
+        self.cti_pulse(1)?;
+        let start = Instant::now();
+        while !self.read_edprsr()?.sdr() {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute a single instruction on a halted core and wait for it to halt again.  A breakpoint
+    /// at the PC is removed while the instruction executes.
+    pub fn step(&mut self) -> Result<(), u8> {
+        let Some(i) = self.breakpoint_at_pc()? else {
+            return self.step_instruction();
+        };
+        let bp = self.breakpoints[i];
+        self.remove_breakpoint(&bp)?;
+        let result = self.step_instruction();
+        let bp = self.insert_breakpoint(bp.addr, bp.placement)?;
+        self.breakpoints[i] = bp;
+        result
+    }
+
+    /// Step with EDECR.SS, restarting the core with `restart` rather than with `resume`, which
+    /// would step over a breakpoint at the PC again.  The halt is only waited for once the
+    /// restart has been seen, so the halt the step started from isn't mistaken for its end.
+    fn step_instruction(&mut self) -> Result<(), u8> {
+        let mut edecr = self.read_dbg_reg::<Edecr>()?;
+        edecr.set_ss(true);
+        self.write_dbg_reg(edecr)?;
+        let result = self.restart().and_then(|()| {
+            let start = Instant::now();
+            while !self.is_halted()? {
+                if start.elapsed() > RESET_TIMEOUT {
+                    return Err(ERR_TIMEOUT);
+                }
+            }
+            Ok(())
+        });
+        edecr.set_ss(false);
+        self.write_dbg_reg(edecr)?;
+        result
+    }
+
+    /// Execute `instr` on the halted core and wait for it to complete.  Returns `ERR_TIMEOUT` if
+    /// it doesn't complete within `RESET_TIMEOUT`.
+    pub fn execute(&mut self, instr: u32) -> Result<(), u8> {
+        self.write_dbg(EDITR, instr)?;
+        let start = Instant::now();
+        loop {
+            let edscr = self.read_dbg(EDSCR)?;
+            if edscr & EDSCR_ERR != 0 {
+                // Clear the sticky error
+                self.write_dbg(EDRCR, 1 << 2)?;
+                return Err(ERR_INSTRUCTION);
+            }
+            if edscr & EDSCR_ITE != 0 {
+                return Ok(());
+            }
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+
+    /// Wait for and return the value written by a preceding `MSR DBGDTR_EL0, Xt`
+    fn read_dtr(&mut self) -> Result<u64, u8> {
+        let start = Instant::now();
+        while self.read_dbg(EDSCR)? & EDSCR_TXFULL == 0 {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        let hi = self.read_dbg(DBGDTRRX)?;
+        let lo = self.read_dbg(DBGDTRTX)?;
+        Ok((hi as u64) << 32 | lo as u64)
+    }
+
+    /// Place `val` in DBGDTR_EL0 for a subsequent `MRS Xt, DBGDTR_EL0`
+    fn write_dtr(&mut self, val: u64) -> Result<(), u8> {
+        let start = Instant::now();
+        while self.read_dbg(EDSCR)? & EDSCR_RXFULL != 0 {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        self.write_dbg(DBGDTRTX, (val >> 32) as u32)?;
+        self.write_dbg(DBGDTRRX, val as u32)
+    }
+
+    /// Read a word written to DBGDTRTX_EL0 by software on the core, if there is one.  Unlike the
+    /// register accessors, this is for communicating with a running core.
+    pub fn dcc_read(&mut self) -> Result<Option<u32>, u8> {
+        if self.read_dbg(EDSCR)? & EDSCR_TXFULL == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_dbg(DBGDTRTX)?))
+    }
+
+    /// Send a word to software on the core, which reads it from DBGDTRRX_EL0.  Returns false
+    /// without writing if the previous word hasn't been read yet.
+    pub fn dcc_write(&mut self, val: u32) -> Result<bool, u8> {
+        if self.read_dbg(EDSCR)? & EDSCR_RXFULL != 0 {
+            return Ok(false);
+        }
+        self.write_dbg(DBGDTRRX, val)?;
+        Ok(true)
+    }
+
+    /// Read general purpose register X`n` of a halted core
+    pub fn read_reg(&mut self, n: u32) -> Result<u64, u8> {
+        assert!(n < 31);
+        self.execute(MSR_DBGDTR_EL0 | n)?;
+        self.read_dtr()
+    }
+
+    /// Write `val` to general purpose register X`n` of a halted core
+    pub fn write_reg(&mut self, n: u32, val: u64) -> Result<(), u8> {
+        assert!(n < 31);
+        self.write_dtr(val)?;
+        self.execute(MRS_DBGDTR_EL0 | n)
+    }
+
+    /// Execute `instr`, which must leave its result in X0, and return the result.  X0 is
+    /// preserved.
+    pub fn read_via_x0(&mut self, instr: u32) -> Result<u64, u8> {
+        let x0 = self.read_reg(0)?;
+        self.execute(instr)?;
+        let val = self.read_reg(0);
+        self.write_reg(0, x0)?;
+        val
+    }
+
+    /// Return the exception level and security state the core is halted in
+    pub fn security_state(&mut self) -> Result<SecurityState, u8> {
+        let edscr = self.read_edscr()?;
+        Ok(SecurityState {
+            el: edscr.el,
+            secure: !edscr.ns,
+            secure_debug_disabled: edscr.sdd,
+        })
+    }
+
+    /// Return `ERR_EXCEPTION_LEVEL` if the core isn't halted at `el` or above
+    fn require_el(&mut self, el: u8) -> Result<(), u8> {
+        if self.read_edscr()?.el < el {
+            return Err(ERR_EXCEPTION_LEVEL);
+        }
+        Ok(())
+    }
+
+    /// Read the system register encoded by `op0`, `op1`, `crn`, `crm` and `op2`.  Returns
+    /// `ERR_EXCEPTION_LEVEL` rather than executing the MRS if the core is halted at an exception
+    /// level that can't access the register.
+    pub fn read_sysreg(
+        &mut self,
+        op0: u32,
+        op1: u32,
+        crn: u32,
+        crm: u32,
+        op2: u32,
+    ) -> Result<u64, u8> {
+        self.require_el(sysreg_min_el(op0, op1))?;
+        self.read_via_x0(mrs(op0, op1, crn, crm, op2, 0))
+    }
+
+    /// Write `val` to the system register encoded by `op0`, `op1`, `crn`, `crm` and `op2`.  X0
+    /// is preserved.  Like `read_sysreg`, accesses that the current exception level can't make
+    /// return `ERR_EXCEPTION_LEVEL`.
+    pub fn write_sysreg(
+        &mut self,
+        op0: u32,
+        op1: u32,
+        crn: u32,
+        crm: u32,
+        op2: u32,
+        val: u64,
+    ) -> Result<(), u8> {
+        self.require_el(sysreg_min_el(op0, op1))?;
+        let x0 = self.read_reg(0)?;
+        self.write_reg(0, val)?;
+        let result = self.execute(msr(op0, op1, crn, crm, op2, 0));
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    /// Read the address the core will resume execution from
+    pub fn read_pc(&mut self) -> Result<u64, u8> {
+        self.read_via_x0(MRS_X0_DLR_EL0)
+    }
+
+    /// Set the address the core will resume execution from
+    pub fn write_pc(&mut self, pc: u64) -> Result<(), u8> {
+        let x0 = self.read_reg(0)?;
+        self.write_reg(0, pc)?;
+        self.execute(MSR_DLR_EL0_X0)?;
+        self.write_reg(0, x0)
+    }
+
+    /// Read the saved process state the core will resume with
+    pub fn read_pstate(&mut self) -> Result<u64, u8> {
+        self.read_via_x0(MRS_X0_DSPSR_EL0)
+    }
+
+    /// Set the process state the core will resume with
+    pub fn write_pstate(&mut self, pstate: u64) -> Result<(), u8> {
+        let x0 = self.read_reg(0)?;
+        self.write_reg(0, pstate)?;
+        self.execute(MSR_DSPSR_EL0_X0)?;
+        self.write_reg(0, x0)
+    }
+
+    /// Read X0-X30 of a halted core
+    pub fn read_regs(&mut self) -> Result<Vec<u64>, u8> {
+        (0..31).map(|n| self.read_reg(n)).collect()
+    }
+
+    /// Read SIMD and floating point register V`n` of a halted core.  Floating point must be
+    /// enabled by CPACR_EL1 (and CPTR_ELx) at the exception level the core is halted in, or this
+    /// fails with `ERR_INSTRUCTION`.
+    pub fn read_vreg(&mut self, n: u32) -> Result<u128, u8> {
+        assert!(n < 32);
+        let x0 = self.read_reg(0)?;
+        let result = (|| {
+            let lo = self.read_into_x0(umov_x0(n, 0))?;
+            let hi = self.read_into_x0(umov_x0(n, 1))?;
+            Ok((hi as u128) << 64 | lo as u128)
+        })();
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    /// Write `val` to SIMD and floating point register V`n` of a halted core
+    pub fn write_vreg(&mut self, n: u32, val: u128) -> Result<(), u8> {
+        assert!(n < 32);
+        let x0 = self.read_reg(0)?;
+        let result = (|| {
+            self.write_reg(0, val as u64)?;
+            self.execute(ins_x0(n, 0))?;
+            self.write_reg(0, (val >> 64) as u64)?;
+            self.execute(ins_x0(n, 1))
+        })();
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    /// Read V0-V31 of a halted core
+    pub fn read_vregs(&mut self) -> Result<Vec<u128>, u8> {
+        (0..32).map(|n| self.read_vreg(n)).collect()
+    }
+
+    pub fn read_fpcr(&mut self) -> Result<u64, u8> {
+        self.read_sysreg(3, 3, 4, 4, 0)
+    }
+
+    pub fn read_fpsr(&mut self) -> Result<u64, u8> {
+        self.read_sysreg(3, 3, 4, 4, 1)
+    }
+
+    pub fn write_fpcr(&mut self, val: u64) -> Result<(), u8> {
+        self.write_sysreg(3, 3, 4, 4, 0, val)
+    }
+
+    pub fn write_fpsr(&mut self, val: u64) -> Result<(), u8> {
+        self.write_sysreg(3, 3, 4, 4, 1, val)
+    }
+
+    /// Execute `instr`, which must leave its result in X0, and return the result without
+    /// restoring X0
+    fn read_into_x0(&mut self, instr: u32) -> Result<u64, u8> {
+        self.execute(instr)?;
+        self.read_reg(0)
+    }
+
+    /// Capture the register context of a halted core: the general purpose registers, stack
+    /// pointers, PC, PSTATE and the main EL1 system registers.  X0 is saved once and used for
+    /// all of the system register reads, which is much faster than reading each with
+    /// `read_sysreg`.
+    pub fn read_context(&mut self) -> Result<Context, u8> {
+        let el = ((self.read_dbg(EDSCR)? >> 8) & 3) as u8;
+        let mut x = [0; 31];
+        for (n, x) in x.iter_mut().enumerate() {
+            *x = self.read_reg(n as u32)?;
+        }
+
+        let result = self.read_context_x0(el, x);
+        self.write_reg(0, x[0])?;
+        result
+    }
+
+    fn read_context_x0(&mut self, el: u8, x: [u64; 31]) -> Result<Context, u8> {
+        let mut sp_el = [None; 3];
+        for (n, op1) in [0, 4, 6].into_iter().enumerate() {
+            // SP_ELn is only accessible from higher exception levels
+            if el as usize > n {
+                sp_el[n] = Some(self.read_into_x0(mrs(3, op1, 4, 1, 0, 0))?);
+            }
+        }
+
+        let el1 = if el >= 1 {
+            let mut read = |crn, crm, op2| self.read_into_x0(mrs(3, 0, crn, crm, op2, 0));
+            Some(El1Regs {
+                sctlr: read(1, 0, 0)?,
+                tcr: read(2, 0, 2)?,
+                ttbr0: read(2, 0, 0)?,
+                ttbr1: read(2, 0, 1)?,
+                mair: read(10, 2, 0)?,
+                vbar: read(12, 0, 0)?,
+                esr: read(5, 2, 0)?,
+                far: read(6, 0, 0)?,
+                elr: read(4, 0, 1)?,
+                spsr: read(4, 0, 0)?,
+            })
+        } else {
+            None
+        };
+        let el2 = if el >= 2 {
+            Some(self.read_el2_regs_x0()?)
+        } else {
+            None
+        };
+
+        Ok(Context {
+            x,
+            sp: self.read_into_x0(MOV_X0_SP)?,
+            sp_el,
+            pc: self.read_into_x0(MRS_X0_DLR_EL0)?,
+            pstate: self.read_into_x0(MRS_X0_DSPSR_EL0)?,
+            el,
+            el1,
+            el2,
+        })
+    }
+
+    /// Read the EL2 system registers of a core halted at EL2 or EL3, to inspect the state of a
+    /// hypervisor and the stage 2 translation of its current guest.  X0 is preserved.
+    pub fn read_el2_regs(&mut self) -> Result<El2Regs, u8> {
+        self.require_el(2)?;
+        let x0 = self.read_reg(0)?;
+        let result = self.read_el2_regs_x0();
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    fn read_el2_regs_x0(&mut self) -> Result<El2Regs, u8> {
+        let mut read = |crn, crm, op2| self.read_into_x0(mrs(3, 4, crn, crm, op2, 0));
+        Ok(El2Regs {
+            hcr: read(1, 1, 0)?,
+            vttbr: read(2, 1, 0)?,
+            vtcr: read(2, 1, 2)?,
+            sctlr: read(1, 0, 0)?,
+            tcr: read(2, 0, 2)?,
+            ttbr0: read(2, 0, 0)?,
+            vbar: read(12, 0, 0)?,
+            esr: read(5, 2, 0)?,
+            far: read(6, 0, 0)?,
+            hpfar: read(6, 0, 4)?,
+            elr: read(4, 0, 1)?,
+            spsr: read(4, 0, 0)?,
+        })
+    }
+
+    /// Translate virtual address `va` to a physical address with the stage 1 tables of the
+    /// exception level the core is halted in, or those of EL2 when halted in EL3.  Returns None
+    /// if the translation faults.  Use `translate_in` with `Regime::El1Stage12` to translate a
+    /// guest's addresses from a hypervisor.
+    pub fn translate(&mut self, va: u64) -> Result<Option<u64>, u8> {
+        let regime = match (self.read_dbg(EDSCR)? >> 8) & 3 {
+            0 | 1 => Regime::El1,
+            _ => Regime::El2,
+        };
+        self.translate_in(va, regime)
+    }
+
+    /// Translate virtual address `va` in `regime` by executing an AT instruction and reading the
+    /// result from PAR_EL1.  Returns None if the translation faults.  The core must be halted at
+    /// or above the regime's exception level, otherwise `ERR_EXCEPTION_LEVEL` is returned.  X0
+    /// and PAR_EL1 are preserved.
+    pub fn translate_in(&mut self, va: u64, regime: Regime) -> Result<Option<u64>, u8> {
+        let (at, el) = match regime {
+            Regime::El1 => (AT_S1E1R_X0, 1),
+            Regime::El2 => (AT_S1E2R_X0, 2),
+            Regime::El1Stage12 => (AT_S12E1R_X0, 2),
+        };
+        self.require_el(el)?;
+        let x0 = self.read_reg(0)?;
+        let result = (|| -> Result<u64, u8> {
+            let par = self.read_into_x0(mrs(3, 0, 7, 4, 0, 0))?;
+            self.write_reg(0, va)?;
+            self.execute(at)?;
+            self.execute(ISB)?;
+            let result = self.read_into_x0(mrs(3, 0, 7, 4, 0, 0))?;
+            self.write_reg(0, par)?;
+            self.execute(msr(3, 0, 7, 4, 0, 0))?;
+            Ok(result)
+        })();
+        self.write_reg(0, x0)?;
+
+        let par = result?;
+        if par & PAR_F != 0 {
+            return Ok(None);
+        }
+        Ok(Some(par & PAR_PA_MASK | va & 0xfff))
+    }
+
+    /// Make `len` bytes of code written at virtual address `addr` visible to instruction
+    /// fetch.  Writes through a MEM-AP bypass the core's caches, so without this the core may
+    /// execute stale instructions from its instruction cache, or the new code may still be
+    /// hidden behind stale data cache lines.  The data cache is cleaned and the instruction
+    /// cache invalidated to the Point of Unification for each line.  X0 is preserved.
+    pub fn sync_icache(&mut self, addr: u64, len: u64) -> Result<(), u8> {
+        if len == 0 {
+            return Ok(());
+        }
+        let x0 = self.read_reg(0)?;
+        let result = (|| {
+            // CTR_EL0.DminLine and IminLine are log2 of the line sizes in words
+            let ctr = self.read_into_x0(mrs(3, 3, 0, 0, 1, 0))?;
+            let dline = 4 << ((ctr >> 16) & 0xf);
+            let iline = 4 << (ctr & 0xf);
+            let end = addr + len;
+
+            for (line, op) in [(dline, DC_CVAU_X0), (iline, IC_IVAU_X0)] {
+                let mut va = addr & !(line - 1);
+                while va < end {
+                    self.write_reg(0, va)?;
+                    self.execute(op)?;
+                    va += line;
+                }
+                self.execute(DSB_ISH)?;
+            }
+            self.execute(ISB)
+        })();
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    /// Discard the cached copies of `len` bytes at `addr`, which were written behind the core's
+    /// back: the data cache lines are invalidated to the Point of Coherency without being
+    /// written back, and the whole instruction cache is invalidated.  The core must be halted at
+    /// EL1 or above.  X0 is preserved.
+    fn invalidate_caches(&mut self, addr: u64, len: u64) -> Result<(), u8> {
+        self.require_el(1)?;
+        let x0 = self.read_reg(0)?;
+        let result = (|| {
+            let ctr = self.read_into_x0(mrs(3, 3, 0, 0, 1, 0))?;
+            let line = 4 << ((ctr >> 16) & 0xf);
+            let mut va = addr & !(line - 1);
+            while va < addr + len {
+                self.write_reg(0, va)?;
+                self.execute(DC_IVAC_X0)?;
+                va += line;
+            }
+            self.execute(DSB_SY)?;
+            self.execute(IC_IALLU)?;
+            self.execute(DSB_SY)?;
+            self.execute(ISB)
+        })();
+        self.write_reg(0, x0)?;
+        result
+    }
+
+    /// Boot a program entirely over the debug interface: halt the core, write each
+    /// (physical address, payload) pair in `images` through `mem`, make the caches coherent
+    /// with the new contents and resume the core at `entry`, in the exception level it halted
+    /// in.  The images are typically a kernel, device tree and initrd, or a bare-metal program.
+    ///
+    /// `mem` is the MEM-AP of the system bus, which may be the one the core's debug registers
+    /// are accessed through.  The core should be halted with its MMU off, as it is out of
+    /// reset, so that physical and virtual addresses are the same.
+    pub fn load_and_run(
+        &mut self,
+        mem: &RefCell<MemAP<T>>,
+        images: &[(u64, &[u8])],
+        entry: u64,
+    ) -> Result<(), u8> {
+        self.load_and_run_with_args(mem, images, entry, &[])
+    }
+
+    /// Like `load_and_run`, also setting X0 upwards to `args` before resuming.  For example, the
+    /// AArch64 Linux boot protocol expects the address of the device tree in X0 and zero in X1 to
+    /// X3.
+    pub fn load_and_run_with_args(
+        &mut self,
+        mem: &RefCell<MemAP<T>>,
+        images: &[(u64, &[u8])],
+        entry: u64,
+        args: &[u64],
+    ) -> Result<(), u8> {
+        if !self.is_halted()? {
+            self.halt_and_wait()?;
+        }
+
+        for &(addr, data) in images {
+            let phys = u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?;
+            image::patch_region(&mut mem.borrow_mut(), phys, data, false)?;
+        }
+        for &(addr, data) in images {
+            self.invalidate_caches(addr, data.len() as u64)?;
+        }
+
+        for (n, &arg) in args.iter().enumerate() {
+            self.write_reg(n as u32, arg)?;
+        }
+        self.write_pc(entry)?;
+        self.resume()
+    }
+
+    /// Read `count` words at virtual address `addr` by executing loads on the halted core.  This
+    /// is slower than reading through a MEM-AP, but the accesses go through the core's MMU and
+    /// caches, so they see what software running on the core sees.  A fault is reported as
+    /// `ERR_INSTRUCTION`.  X0 and X1 are preserved.
+    ///
+    /// The loads are generated by the core in memory access mode (EDSCR.MA), where each read of
+    /// DBGDTRTX returns a word and starts the load of the next one, so no instructions are
+    /// injected per word.
+    pub fn read_memory(&mut self, addr: u64, count: usize) -> Result<Vec<u32>, u8> {
+        let x0 = self.read_reg(0)?;
+        let x1 = self.read_reg(1)?;
+        let result = (|| {
+            let mut data = Vec::with_capacity(count);
+            if count == 0 {
+                return Ok(data);
+            }
+            self.write_reg(0, addr)?;
+            let edscr = self.read_dbg(EDSCR)?;
+            self.write_dbg(EDSCR, edscr | EDSCR_MA)?;
+            // The first read only starts the first load
+            self.read_dbg(DBGDTRTX)?;
+            for _ in 1..count {
+                data.push(self.read_dbg(DBGDTRTX)?);
+            }
+            // Leave memory access mode before reading the last word, so that no further load is
+            // started
+            self.write_dbg(EDSCR, edscr & !EDSCR_MA)?;
+            data.push(self.read_dbg(DBGDTRTX)?);
+            Ok(data)
+        })();
+        let end = self.end_memory_access();
+        let result = result.and_then(|data| end.map(|_| data));
+        self.write_reg(0, x0)?;
+        self.write_reg(1, x1)?;
+        result
+    }
+
+    /// Write `data` at virtual address `addr` by executing stores on the halted core.  Like
+    /// `read_memory`, the accesses go through the core's MMU and caches and are generated in
+    /// memory access mode, where each write to DBGDTRRX stores a word.  X0 and X1 are
+    /// preserved.
+    pub fn write_memory(&mut self, addr: u64, data: &[u32]) -> Result<(), u8> {
+        let x0 = self.read_reg(0)?;
+        let x1 = self.read_reg(1)?;
+        let result = (|| {
+            self.write_reg(0, addr)?;
+            let edscr = self.read_dbg(EDSCR)?;
+            self.write_dbg(EDSCR, edscr | EDSCR_MA)?;
+            for &val in data {
+                self.write_dbg(DBGDTRRX, val)?;
+            }
+            Ok(())
+        })();
+        let end = self.end_memory_access();
+        let result = result.and(end);
+        self.write_reg(0, x0)?;
+        self.write_reg(1, x1)?;
+        result
+    }
+
+    /// Leave memory access mode, if a transfer didn't already, and check for an abort.  An
+    /// abort ends the transfer, and later DTR accesses are ignored until the error is cleared.
+    fn end_memory_access(&mut self) -> Result<(), u8> {
+        let edscr = self.read_dbg(EDSCR)?;
+        if edscr & EDSCR_MA != 0 {
+            self.write_dbg(EDSCR, edscr & !EDSCR_MA)?;
+        }
+        if edscr & EDSCR_ERR != 0 {
+            self.write_dbg(EDRCR, 1 << 2)?;
+            return Err(ERR_INSTRUCTION);
+        }
+        Ok(())
+    }
+
+    /// Set the instruction set that software breakpoints are written for, A64 by default
+    pub fn set_instruction_set(&mut self, isa: InstructionSet) {
+        self.isa = isa;
+    }
+
+    /// Return the number of breakpoint registers
+    pub fn num_hw_breakpoints(&mut self) -> Result<usize, u8> {
+        if let Some(n) = self.num_hw_breakpoints {
+            return Ok(n);
+        }
+        let n = ((self.read_dbg(EDDFR)? >> 12) & 0xf) as usize + 1;
+        self.num_hw_breakpoints = Some(n);
+        Ok(n)
+    }
+
+    /// Return the addresses of the breakpoints that are set
+    pub fn breakpoints(&self) -> Vec<u64> {
+        self.breakpoints.iter().map(|bp| bp.addr).collect()
+    }
+
+    /// Set a breakpoint at `addr`.  Software breakpoints are written through the core with
+    /// `write_memory`, so the core must be halted and the code mapped writable; the caches are
+    /// made coherent afterwards with `sync_icache`.  Setting a breakpoint where there already
+    /// is one does nothing.
+    pub fn set_breakpoint(&mut self, addr: u64, kind: BreakpointKind) -> Result<(), u8> {
+        if self.breakpoints.iter().any(|bp| bp.addr == addr) {
+            return Ok(());
+        }
+        let free = (0..self.num_hw_breakpoints()?).find(|i| {
+            !self
+                .breakpoints
+                .iter()
+                .any(|bp| bp.placement == Placement::Hardware(*i))
+        });
+        let placement = match (kind, free) {
+            (BreakpointKind::Hardware, None) => return Err(ERR_NO_BREAKPOINT),
+            (BreakpointKind::Hardware | BreakpointKind::Any, Some(i)) => Placement::Hardware(i),
+            _ => Placement::Software(self.isa, 0),
+        };
+        let bp = self.insert_breakpoint(addr, placement)?;
+        self.breakpoints.push(bp);
+        Ok(())
+    }
+
+    /// Remove the breakpoint at `addr`, restoring the original instruction of a software
+    /// breakpoint
+    pub fn clear_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if let Some(i) = self.breakpoints.iter().position(|bp| bp.addr == addr) {
+            let bp = self.breakpoints[i];
+            self.remove_breakpoint(&bp)?;
+            self.breakpoints.remove(i);
+        }
+        Ok(())
+    }
+
+    /// Remove all of the breakpoints
+    pub fn clear_breakpoints(&mut self) -> Result<(), u8> {
+        while let Some(bp) = self.breakpoints.last().copied() {
+            self.remove_breakpoint(&bp)?;
+            self.breakpoints.pop();
+        }
+        Ok(())
+    }
+
+    /// Return the index in `breakpoints` of the breakpoint at the PC, if any
+    fn breakpoint_at_pc(&mut self) -> Result<Option<usize>, u8> {
+        if self.breakpoints.is_empty() {
+            return Ok(None);
+        }
+        let pc = self.read_pc()?;
+        Ok(self.breakpoints.iter().position(|bp| bp.addr == pc))
+    }
+
+    /// Arm a breakpoint register, or patch in an HLT instruction and return the instruction it
+    /// replaced
+    fn insert_breakpoint(&mut self, addr: u64, placement: Placement) -> Result<Breakpoint, u8> {
+        let placement = match placement {
+            Placement::Hardware(i) => {
+                let bas = match self.isa {
+                    InstructionSet::T32 if addr & 2 != 0 => 0b1100,
+                    InstructionSet::T32 => 0b0011,
+                    _ => 0b1111,
+                };
+                let reg = 16 * i as u32;
+                self.write_dbg(DBGBVR0 + reg, addr as u32 & !3)?;
+                self.write_dbg(DBGBVR0 + reg + 4, (addr >> 32) as u32)?;
+                self.write_dbg(DBGBCR0 + reg, bas << BCR_BAS_SHIFT | BCR_ALL_LEVELS | BCR_E)?;
+                placement
+            }
+            Placement::Software(isa, _) => {
+                let word = addr & !3;
+                let original = self.read_memory(word, 1)?[0];
+                let patched = match isa {
+                    InstructionSet::A64 => HLT_A64,
+                    InstructionSet::A32 => HLT_A32,
+                    InstructionSet::T32 => {
+                        let shift = 8 * (addr & 2) as u32;
+                        original & !(0xffff << shift) | (HLT_T32 as u32) << shift
+                    }
+                };
+                self.write_memory(word, &[patched])?;
+                self.sync_icache(word, 4)?;
+                Placement::Software(isa, original)
+            }
+        };
+        Ok(Breakpoint { addr, placement })
+    }
+
+    fn remove_breakpoint(&mut self, bp: &Breakpoint) -> Result<(), u8> {
+        match bp.placement {
+            Placement::Hardware(i) => self.write_dbg(DBGBCR0 + 16 * i as u32, 0),
+            Placement::Software(isa, original) => {
+                let word = bp.addr & !3;
+                let original = if isa == InstructionSet::T32 {
+                    // Leave the other halfword alone, which may hold another breakpoint
+                    let mask = 0xffff << (8 * (bp.addr & 2) as u32);
+                    self.read_memory(word, 1)?[0] & !mask | original & mask
+                } else {
+                    original
+                };
+                self.write_memory(word, &[original])?;
+                self.sync_icache(word, 4)
+            }
+        }
+    }
+
+    /// Run a halted core until it reaches `addr`, using a temporary breakpoint of either kind.
+    /// If the core hasn't halted within `timeout` it is halted, so that it is always halted
+    /// when this returns successfully; `ERR_TIMEOUT` is returned if it won't halt.  A breakpoint
+    /// already set at `addr` is left in place.
+    pub fn run_to(&mut self, addr: u64, timeout: Duration) -> Result<RunToResult, u8> {
+        let temporary = !self.breakpoints.iter().any(|bp| bp.addr == addr);
+        self.set_breakpoint(addr, BreakpointKind::Any)?;
+
+        let result = (|| {
+            self.resume()?;
+            let start = Instant::now();
+            while !self.is_halted()? {
+                if start.elapsed() > timeout {
+                    self.halt_and_wait()?;
+                    return Ok(RunToResult::TimedOut {
+                        pc: self.read_pc()?,
+                    });
+                }
+            }
+
+            let pc = self.read_pc()?;
+            let reason = self.halt_reason()?;
+            let hit = matches!(
+                reason,
+                Some(HaltReason::Breakpoint(_) | HaltReason::HltInstruction)
+            );
+            if pc == addr && hit {
+                Ok(RunToResult::Reached)
+            } else {
+                Ok(RunToResult::Stopped { pc, reason })
+            }
+        })();
+
+        if temporary {
+            self.clear_breakpoint(addr)?;
+        }
+        result
+    }
+}
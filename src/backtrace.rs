@@ -0,0 +1,62 @@
+//! Stack backtraces of halted ARMv8-A cores, found by walking the AArch64 frame records.  Each
+//! frame record is a pair of 64-bit words holding the caller's frame pointer and the return
+//! address, with X29 pointing at the newest.  Code must be built with frame pointers for this to
+//! work.
+//!
+//! Frame records are read through a MemAP, so they must be at physical addresses below 4GB; the
+//! walk stops at the first frame pointer that isn't.
+
+use crate::armv8::Core;
+use crate::{MemAP, Transport};
+
+/// Read the frame record at `fp`, returning the next frame pointer and the return address
+fn read_frame<T>(mem: &mut MemAP<T>, fp: u64) -> Result<(u64, u64), u8>
+where
+    T: Transport + ?Sized,
+{
+    let words = mem.read_memory(fp as u32, 4)?;
+    let next = (words[1] as u64) << 32 | words[0] as u64;
+    let lr = (words[3] as u64) << 32 | words[2] as u64;
+    Ok((next, lr))
+}
+
+/// Return the PC of the halted `core` followed by the return addresses of up to `max_depth`
+/// frames, reading the stack through `mem`.
+///
+/// If `use_lr` is true, X30 is included as the first return address.  This is correct when the
+/// core is halted in a leaf function which hasn't pushed a frame record, but adds a bogus entry
+/// otherwise.
+pub fn backtrace<T>(
+    core: &mut Core<T>,
+    mem: &mut MemAP<T>,
+    max_depth: usize,
+    use_lr: bool,
+) -> Result<Vec<u64>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut trace = vec![core.read_pc()?];
+    if use_lr {
+        trace.push(core.read_reg(30)?);
+    }
+
+    let mut fp = core.read_reg(29)?;
+    while trace.len() <= max_depth {
+        if fp == 0 || fp & 0xf != 0 || fp > u32::MAX as u64 - 16 {
+            break;
+        }
+        let (next, lr) = read_frame(mem, fp)?;
+        if lr == 0 {
+            break;
+        }
+        trace.push(lr);
+
+        // The stack grows down, so older frames are at higher addresses.  Anything else is a
+        // corrupt or looping chain.
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    Ok(trace)
+}
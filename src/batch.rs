@@ -0,0 +1,207 @@
+//! Batches of DP and AP transactions run as one pipelined stream.  A JTAG-DP returns the result
+//! of each transaction in the scan that requests the next one, so a batch of any mix of reads
+//! and writes can be shifted without waiting for each result, generalizing
+//! `read_adi_pipelined` and `write_adi_pipelined`, which take only reads or only writes of one
+//! AP.  SELECT is written in the stream wherever a transaction needs a different AP or bank from
+//! the one before it.
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::DerefMut;
+
+use jtag_taps::cable::Cable;
+
+use crate::{ArmDebugInterface, DPReg, Port};
+
+/// OK acknowledge
+const ACK_OK: u8 = 2;
+
+/// SELECT.DPBANKSEL
+const DPBANKSEL_MASK: u32 = 0xf;
+
+/// A transaction queued on a `Batch`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Op {
+    apsel: u32,
+    port: Port,
+    reg: u8,
+    /// Value to write, or None for a read
+    write: Option<u32>,
+}
+
+/// A scan in the stream sent to the DP
+#[derive(Clone, Copy)]
+enum Scan {
+    /// Write SELECT
+    Select(u32),
+    /// The transaction at this index
+    Op(usize),
+    /// Read RDBUFF, to collect the result of the last transaction
+    Rdbuff,
+}
+
+/// The 35-bit DR value requesting a transaction
+fn request(reg: u8, write: Option<u32>) -> [u8; 5] {
+    let dr = (write.unwrap_or(0) as u64) << 3 | ((reg & 3) << 1) as u64 | write.is_none() as u64;
+    let bytes = dr.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]]
+}
+
+/// The SELECT value `op` needs given the current value `select`, or None if it doesn't depend
+/// on SELECT.  Registers are numbered as for `read_adi`: the bank is `reg >> 2`, which for the
+/// DP is DPBANKSEL and only applies to CTRL/STAT.
+fn select_for(op: &Op, select: Option<u32>) -> Option<u32> {
+    let bank = (op.reg >> 2) as u32;
+    match op.port {
+        Port::AP => {
+            let dpbank = select.map_or(0, |s| s & DPBANKSEL_MASK);
+            Some(op.apsel << 24 | bank << 4 | dpbank)
+        }
+        Port::DP if op.reg & 3 == DPReg::CtrlStat as u8 => {
+            Some(select.unwrap_or(0) & !DPBANKSEL_MASK | bank)
+        }
+        Port::DP => None,
+    }
+}
+
+/// A sequence of DP and AP reads and writes, run in order by `execute`
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a read of register `reg` of AP `apsel` and `port`.  Returns the index of its
+    /// result.
+    pub fn read(&mut self, apsel: u32, port: Port, reg: u8) -> usize {
+        self.push(apsel, port, reg, None)
+    }
+
+    /// Queue a write of `val` to register `reg` of AP `apsel` and `port`.  Returns the index of
+    /// its result.
+    pub fn write(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> usize {
+        self.push(apsel, port, reg, Some(val))
+    }
+
+    fn push(&mut self, apsel: u32, port: Port, reg: u8, write: Option<u32>) -> usize {
+        self.ops.push(Op {
+            apsel,
+            port,
+            reg,
+            write,
+        });
+        self.ops.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Run the queued transactions on `adi` as one stream and empty the batch.  Returns one
+    /// result per transaction, in the order they were queued: the value read, or for a write
+    /// the value written.
+    ///
+    /// Once a transaction gets an acknowledgement other than OK, the DP may have dropped the
+    /// transactions after it, so they are all reported with the same error.  The caller should
+    /// retry them, after an `abort_transaction` if the AP is stuck.
+    pub fn execute<T, U>(&mut self, adi: &mut ArmDebugInterface<T>) -> Vec<Result<u32, u8>>
+    where
+        T: DerefMut<Target = U>,
+        U: Cable + ?Sized,
+    {
+        let ops = core::mem::take(&mut self.ops);
+        let mut scans = vec![];
+        let mut select = adi.current_select();
+        for (i, op) in ops.iter().enumerate() {
+            if let Some(needed) = select_for(op, select) {
+                if select != Some(needed) {
+                    scans.push(Scan::Select(needed));
+                    select = Some(needed);
+                }
+            }
+            if op.port == Port::DP && op.reg == DPReg::Select as u8 {
+                select = op.write.or(select);
+            }
+            scans.push(Scan::Op(i));
+        }
+        if scans.is_empty() {
+            return vec![];
+        }
+        scans.push(Scan::Rdbuff);
+
+        let mut results = vec![Ok(0); ops.len()];
+        let mut failed = None;
+        // Scans whose captures haven't been collected yet
+        let mut queued = VecDeque::new();
+        for (n, &scan) in scans.iter().enumerate() {
+            let (port, dr) = match scan {
+                Scan::Select(val) => (Port::DP, request(DPReg::Select as u8, Some(val))),
+                Scan::Op(i) => (ops[i].port, request(ops[i].reg, ops[i].write)),
+                Scan::Rdbuff => (Port::DP, request(DPReg::Rdbuff as u8, None)),
+            };
+            adi.write_instruction(adi.ir.port(port));
+            // The rate limit throttles the host only, the cable may still send the queued scans
+            // together
+            adi.pace();
+            if !adi.taps.borrow_mut().queue_dr_read_write(&dr, 3) {
+                // Make room by collecting what has been queued so far
+                collect(adi, &ops, &scans, &mut queued, &mut results, &mut failed);
+                let result = adi.taps.borrow_mut().queue_dr_read_write(&dr, 3);
+                assert!(result);
+            }
+            adi.idle_after(port);
+            queued.push_back(n);
+        }
+        collect(adi, &ops, &scans, &mut queued, &mut results, &mut failed);
+
+        // SELECT is unknown if any of the writes to it failed
+        adi.lastbank = match (failed, select) {
+            (None, Some(select)) => select,
+            _ => 0xff,
+        };
+        results
+    }
+}
+
+/// Collect the captures of the `queued` scans.  Each capture holds the acknowledgement and
+/// result of the scan before it.
+fn collect<T, U>(
+    adi: &mut ArmDebugInterface<T>,
+    ops: &[Op],
+    scans: &[Scan],
+    queued: &mut VecDeque<usize>,
+    results: &mut [Result<u32, u8>],
+    failed: &mut Option<u8>,
+) where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    while let Some(n) = queued.pop_front() {
+        let dr = adi.taps.borrow_mut().finish_dr_read(35);
+        let capture = ArmDebugInterface::<T>::parse_ack(dr);
+        adi.pacing.record(capture.err().unwrap_or(ACK_OK));
+        if n == 0 {
+            // The result of whatever ran before the stream
+            continue;
+        }
+        if let Err(ack) = capture {
+            *failed = failed.or(Some(ack));
+        }
+        if let Scan::Op(i) = scans[n - 1] {
+            results[i] = match (*failed, ops[i].write) {
+                (Some(ack), _) => Err(ack),
+                (None, None) => capture,
+                (None, Some(val)) => Ok(val),
+            };
+        }
+    }
+}
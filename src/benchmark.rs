@@ -0,0 +1,67 @@
+//! Measuring memory access throughput, to compare cables and tune TCK and pacing settings.
+
+use std::time::{Duration, Instant};
+
+use crate::{MemAP, Transport};
+
+/// Number of TCK cycles per DR scan of a DPACC or APACC transaction, not counting TAP state
+/// transitions
+const SCAN_BITS: f64 = 35.0;
+
+/// Results of `MemAP::benchmark`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchmarkReport {
+    pub words: usize,
+    pub read_time: Duration,
+    pub write_time: Duration,
+    /// Fraction of transactions that got a WAIT, if the transport measures it
+    pub wait_ratio: Option<f32>,
+}
+
+impl BenchmarkReport {
+    pub fn read_words_per_sec(&self) -> f64 {
+        self.words as f64 / self.read_time.as_secs_f64()
+    }
+
+    pub fn write_words_per_sec(&self) -> f64 {
+        self.words as f64 / self.write_time.as_secs_f64()
+    }
+
+    /// Return the fraction of TCK cycles at `tck_hz` spent shifting transaction data during the
+    /// read and write passes.  The rest is lost to TAP state transitions, retries and USB
+    /// latency.
+    pub fn tck_utilization(&self, tck_hz: u32) -> (f64, f64) {
+        let bits = |words_per_sec: f64| words_per_sec * SCAN_BITS / tck_hz as f64;
+        (
+            bits(self.read_words_per_sec()),
+            bits(self.write_words_per_sec()),
+        )
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Measure sustained read and write throughput over `size` bytes at `addr`, which must be
+    /// word aligned RAM.  The region is read and then written back with the same contents, so it
+    /// is left unchanged unless the target modifies it in between.
+    pub fn benchmark(&mut self, addr: u32, size: usize) -> Result<BenchmarkReport, u8> {
+        let words = size / 4;
+
+        let start = Instant::now();
+        let data = self.read_memory(addr, words)?;
+        let read_time = start.elapsed();
+
+        let start = Instant::now();
+        self.write_memory(addr, &data)?;
+        let write_time = start.elapsed();
+
+        Ok(BenchmarkReport {
+            words,
+            read_time,
+            write_time,
+            wait_ratio: self.adi.borrow().wait_ratio(),
+        })
+    }
+}
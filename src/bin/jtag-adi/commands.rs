@@ -0,0 +1,431 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader};
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "svd")]
+use std::rc::Rc;
+use std::time::Duration;
+
+use jtag_adi::ap;
+use jtag_adi::armv8::Core;
+#[cfg(feature = "elf")]
+use jtag_adi::elf::{ElfError, Symbols};
+use jtag_adi::memory::ERR_ADDRESS_RANGE;
+use jtag_adi::memtest;
+use jtag_adi::profile::{self, FunctionHits};
+use jtag_adi::rom_table::{Snapshot, CLASS_CORESIGHT, CLASS_ROM_TABLE};
+#[cfg(feature = "svd")]
+use jtag_adi::svd::{Device, Registers, SvdError};
+use jtag_adi::{MemAP, Transport};
+
+/// Error from a command
+#[derive(Debug)]
+pub enum CommandError {
+    /// An access to the target failed with the given error code
+    Access(u8),
+    /// The file couldn't be read or written
+    File(PathBuf, io::Error),
+    /// The command needs the core to be halted
+    NotHalted,
+    /// The SVD file is invalid or doesn't have the named peripheral
+    #[cfg(feature = "svd")]
+    Svd(SvdError),
+    /// The ELF file couldn't be loaded
+    #[cfg(feature = "elf")]
+    Elf(PathBuf, ElfError),
+}
+
+impl CommandError {
+    fn file(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        |e| CommandError::File(path.to_path_buf(), e)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Access(e) => write!(f, "{}", e),
+            CommandError::File(path, e) => write!(f, "{}: {}", path.display(), e),
+            CommandError::NotHalted => write!(f, "core is not halted"),
+            #[cfg(feature = "svd")]
+            CommandError::Svd(e) => write!(f, "{}", e),
+            #[cfg(feature = "elf")]
+            CommandError::Elf(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl From<u8> for CommandError {
+    fn from(e: u8) -> Self {
+        CommandError::Access(e)
+    }
+}
+
+#[cfg(feature = "svd")]
+impl From<SvdError> for CommandError {
+    fn from(e: SvdError) -> Self {
+        match e {
+            SvdError::Access(e) => CommandError::Access(e),
+            e => CommandError::Svd(e),
+        }
+    }
+}
+
+/// Parse `x` as hex if it starts with 0x, otherwise as decimal
+pub fn parse_int(x: &str) -> Result<u32, ParseIntError> {
+    if let Some(hex) = x.strip_prefix("0x").or_else(|| x.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        x.parse()
+    }
+}
+
+pub fn aps<T>(adi: &mut T) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    for ap in ap::enumerate_aps(adi)? {
+        let kind = if ap.is_mem_ap() { "MEM-AP" } else { "AP" };
+        println!(
+            "{:3}: {} type {:x} (IDR {:08x}, designer {:03x})",
+            ap.apsel,
+            kind,
+            ap.ap_type(),
+            ap.idr,
+            ap.designer()
+        );
+    }
+    Ok(())
+}
+
+/// Return `addr` if given, otherwise the address of the ROM table from the MEM-AP's BASE
+/// register, or 0 if BASE has no debug entries
+pub fn rom_table_addr<T>(mem: &mut MemAP<T>, addr: Option<u32>) -> Result<u32, u8>
+where
+    T: Transport + ?Sized,
+{
+    if let Some(addr) = addr {
+        return Ok(addr);
+    }
+    match mem.rom_base()? {
+        Some(base) => u32::try_from(base).map_err(|_| ERR_ADDRESS_RANGE),
+        None => Ok(0),
+    }
+}
+
+/// Print the components found by walking the ROM table at `base`, or the differences from the
+/// scan saved in `diff`.  The scan is saved to `save` if given.
+pub fn scan<T>(
+    mem: &mut MemAP<T>,
+    base: u32,
+    save: Option<&Path>,
+    diff: Option<&Path>,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let snapshot = Snapshot::scan(mem, base)?;
+    if let Some(path) = save {
+        let mut file = fs::File::create(path).map_err(CommandError::file(path))?;
+        snapshot
+            .write(&mut file)
+            .map_err(CommandError::file(path))?;
+    }
+    if let Some(path) = diff {
+        let file = fs::File::open(path).map_err(CommandError::file(path))?;
+        let earlier =
+            Snapshot::read(&mut BufReader::new(file)).map_err(CommandError::file(path))?;
+        for change in earlier.diff(&snapshot) {
+            println!("{}", change);
+        }
+        return Ok(());
+    }
+
+    for c in snapshot.components {
+        let indent = "    ".repeat(c.depth);
+        let (designer, part) = c.part();
+        match c.class {
+            CLASS_ROM_TABLE => println!("{}ROM table at {:08x}", indent, c.base),
+            CLASS_CORESIGHT => println!(
+                "{}{:08x}: {} (designer {:03x} part {:03x} arch {} aff {:08x} {:08x})",
+                indent,
+                c.base,
+                c.device_type(),
+                designer,
+                part,
+                c.dev_arch(),
+                c.devaff[0],
+                c.devaff[1],
+            ),
+            class => println!("{}{:08x}: class {:x}", indent, c.base, class),
+        }
+    }
+    Ok(())
+}
+
+/// Print the DP registers and the registers of the MEM-AP
+pub fn state<T>(mem: &mut MemAP<T>) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    print!("{}", mem.dump_state());
+    Ok(())
+}
+
+pub fn peek<T>(mem: &mut MemAP<T>, addr: u32) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let val = mem.read(addr)?;
+    println!("0x{:x} = 0x{:x}", addr, val);
+    Ok(())
+}
+
+pub fn poke<T>(mem: &mut MemAP<T>, addr: u32, value: u32) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    mem.write(addr, value)?;
+    Ok(())
+}
+
+/// Run all of the RAM test patterns over `count` words starting at `addr` and print the failures
+pub fn memtest<T>(mem: &mut MemAP<T>, addr: u32, count: usize) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let failures = memtest::run_all(mem, addr, count)?;
+    for f in &failures {
+        println!(
+            "{:08x}: {:?} wrote {:08x} read {:08x} (bits {:08x})",
+            f.address,
+            f.pattern,
+            f.expected,
+            f.actual,
+            f.bits()
+        );
+    }
+    println!("{} failures", failures.len());
+    Ok(())
+}
+
+/// Print `count` words starting at `addr` as a hexdump
+pub fn dump<T>(mem: &mut MemAP<T>, addr: u32, count: usize) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let data = mem.read_memory(addr, count)?;
+    for (i, line) in data.chunks(4).enumerate() {
+        let words: Vec<String> = line.iter().map(|x| format!("{:08x}", x)).collect();
+        println!("{:08x}: {}", addr.wrapping_add(16 * i as u32), words.join(" "));
+    }
+    Ok(())
+}
+
+/// Save `count` words starting at `addr` to `path`
+pub fn dump_to_file<T>(
+    mem: &mut MemAP<T>,
+    addr: u32,
+    count: usize,
+    path: &Path,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let data = mem.read_memory(addr, count)?;
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    fs::write(path, bytes).map_err(CommandError::file(path))?;
+    Ok(())
+}
+
+/// Write the contents of `path` to memory at `addr`.  The file is padded with zeros to a
+/// multiple of 4 bytes.  If `core` is given, its caches are made coherent with the new contents
+/// so that it can execute them; the core must be halted.
+pub fn load<T>(
+    mem: &RefCell<MemAP<T>>,
+    addr: u32,
+    path: &Path,
+    core: Option<&mut Core<T>>,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let mut bytes = fs::read(path).map_err(CommandError::file(path))?;
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    let data: Vec<u32> = bytes
+        .chunks(4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    mem.borrow_mut().write_memory(addr, &data)?;
+    // The core may use the same MemAP
+    if let Some(core) = core {
+        core.sync_icache(addr as u64, bytes.len() as u64)?;
+    }
+    println!("Wrote {} bytes to 0x{:x}", bytes.len(), addr);
+    Ok(())
+}
+
+/// Halt `core`, write the file at `path` to `addr` and resume the core at `entry`
+pub fn load_and_run<T>(
+    mem: &RefCell<MemAP<T>>,
+    addr: u32,
+    path: &Path,
+    entry: u32,
+    core: &mut Core<T>,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let bytes = fs::read(path).map_err(CommandError::file(path))?;
+    core.load_and_run(mem, &[(addr as u64, &bytes)], entry as u64)?;
+    println!(
+        "Wrote {} bytes to 0x{:x}, running from 0x{:x}",
+        bytes.len(),
+        addr,
+        entry
+    );
+    Ok(())
+}
+
+/// Print every register of the peripheral `name` described in the SVD file at `svd`
+#[cfg(feature = "svd")]
+pub fn dump_peripheral<T>(
+    mem: Rc<RefCell<MemAP<T>>>,
+    svd: &Path,
+    name: &str,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    let text = fs::read_to_string(svd).map_err(CommandError::file(svd))?;
+    let device = Device::parse(&text)?;
+    let mut regs = Registers::new(mem, device);
+    for val in regs.dump_peripheral(name)? {
+        println!("{}", val);
+    }
+    Ok(())
+}
+
+/// Number of functions listed by `profile`
+const PROFILE_TOP: usize = 20;
+/// Deepest stack walked by `profile`
+const PROFILE_DEPTH: usize = 64;
+
+/// Profile the running `core` for `duration`, print the functions with the most samples and
+/// write the folded stacks to `folded` if given.  With `stacks`, the stack is sampled by
+/// halting the core and reading its frame records through `mem`.  Functions are named from
+/// the ELF file `elf` if given.
+pub fn profile<T>(
+    core: &mut Core<T>,
+    mem: &RefCell<MemAP<T>>,
+    duration: Duration,
+    interval: Duration,
+    stacks: bool,
+    folded: Option<&Path>,
+    elf: Option<&Path>,
+) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    // Load the symbols first so that a bad ELF file doesn't waste the run
+    #[cfg(feature = "elf")]
+    let symbols = match elf {
+        Some(path) => {
+            Some(Symbols::load(path).map_err(|e| CommandError::Elf(path.to_path_buf(), e))?)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "elf"))]
+    if elf.is_some() {
+        eprintln!("Warning: built without ELF support, functions are shown as addresses");
+    }
+
+    let profile = if stacks {
+        profile::sample_stacks(
+            core,
+            &mut mem.borrow_mut(),
+            duration,
+            interval,
+            PROFILE_DEPTH,
+        )?
+    } else {
+        profile::sample_pcs(core, duration, interval)?
+    };
+    println!("{} samples, {} missed", profile.samples(), profile.missed());
+
+    let name = |addr: u64| -> String {
+        #[cfg(feature = "elf")]
+        if let Some(symbols) = &symbols {
+            return profile::symbol_name(symbols)(addr);
+        }
+        profile::address_name(addr)
+    };
+
+    if let Some(path) = folded {
+        let mut file = fs::File::create(path).map_err(CommandError::file(path))?;
+        profile
+            .write_folded(&mut file, name)
+            .map_err(CommandError::file(path))?;
+    }
+    let samples = profile.samples().max(1) as f64;
+    println!("{:>7} {:>7}  function", "self%", "total%");
+    for FunctionHits { name, own, total } in profile.functions(name).iter().take(PROFILE_TOP) {
+        println!(
+            "{:>6.1}% {:>6.1}%  {}",
+            100.0 * *own as f64 / samples,
+            100.0 * *total as f64 / samples,
+            name
+        );
+    }
+    Ok(())
+}
+
+pub fn halt<T>(core: &mut Core<T>) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    core.halt()?;
+    println!("halted: {}", core.is_halted()?);
+    Ok(())
+}
+
+pub fn resume<T>(core: &mut Core<T>) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    core.resume()?;
+    println!("halted: {}", core.is_halted()?);
+    Ok(())
+}
+
+pub fn step<T>(core: &mut Core<T>) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    core.step()?;
+    println!("pc  {:016x}", core.read_pc()?);
+    Ok(())
+}
+
+pub fn regs<T>(core: &mut Core<T>) -> Result<(), CommandError>
+where
+    T: Transport + ?Sized,
+{
+    if !core.is_halted()? {
+        return Err(CommandError::NotHalted);
+    }
+    for (i, x) in core.read_regs()?.iter().enumerate() {
+        println!("x{:<2} {:016x}", i, x);
+    }
+    println!("pc  {:016x}", core.read_pc()?);
+    println!("psr {:016x}", core.read_pstate()?);
+    if let Some(reason) = core.halt_reason()? {
+        match core.watchpoint_hit(reason)? {
+            Some(hit) => println!("halted by {}", hit),
+            None => println!("halted by {:?}", reason),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,574 @@
+//! Command line tool for peeking, poking and controlling targets through an ARM Debug Interface.
+
+use std::cell::RefCell;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+use jtag_taps::cable;
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+use jtag_adi::armv8::Core;
+use jtag_adi::csw::ERR_AP_DISABLED;
+#[cfg(feature = "description")]
+use jtag_adi::description::TargetDescription;
+use jtag_adi::idcode::{self, IdcodeError};
+use jtag_adi::remote::{self, RemoteDap};
+use jtag_adi::soc::{self, Arch, Soc};
+use jtag_adi::wire_log::ScanFormatter;
+use jtag_adi::{ArmDebugInterface, MemAP, RateLimit, Transport};
+
+mod commands;
+#[cfg(feature = "shell")]
+mod shell;
+#[cfg(feature = "tui")]
+mod tui;
+
+use commands::{parse_int, CommandError};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, required_unless_present = "remote")]
+    cable: Option<String>,
+    #[arg(short, long, required_unless_present = "remote")]
+    baud: Option<u32>,
+    #[arg(short, long, default_value_t = 0)]
+    /// Which JTAG TAP to use
+    tap_index: usize,
+    #[arg(short, long)]
+    /// Which access port to use, default 0
+    ap_num: Option<u32>,
+    #[arg(long)]
+    /// Which access port to use for core debug registers, default the same as --ap-num
+    debug_ap: Option<u32>,
+    #[arg(long, conflicts_with = "remote")]
+    /// Limit DAP transactions to this many per second, for targets that lock up when accessed
+    /// too quickly
+    max_rate: Option<u32>,
+    #[arg(long, default_value_t = 1, requires = "max_rate")]
+    /// Number of transactions allowed back to back under --max-rate
+    burst: u32,
+    #[arg(long, conflicts_with = "remote")]
+    /// Print every JTAG scan made to the DAP, with its decoded DPACC or APACC access, to stderr
+    log_scans: bool,
+    #[arg(long, default_value_t = 0, conflicts_with = "remote")]
+    /// TCK cycles to give the DAP after each AP access, for targets with slow APs
+    idle_clocks: usize,
+    #[arg(long)]
+    /// Keep the MEM-APs' CSW as the target left it, instead of setting up 32-bit privileged
+    /// data accesses
+    keep_csw: bool,
+    #[arg(long, conflicts_with = "cable")]
+    /// Use a debug interface shared by `jtag-adi serve` at this address instead of a cable
+    remote: Option<String>,
+    #[cfg(feature = "compression")]
+    #[arg(long, requires = "remote")]
+    /// Compress block transfers with the remote server
+    compress: bool,
+    #[cfg(feature = "description")]
+    #[arg(long)]
+    /// TOML or YAML target description supplying AP numbers and core addresses
+    target: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Args, Debug)]
+struct CoreArgs {
+    #[arg(long, value_parser = parse_int)]
+    /// Address of the core's external debug registers
+    cpu_base: Option<u32>,
+    #[arg(long, value_parser = parse_int)]
+    /// Address of the core's CTI
+    cti_base: Option<u32>,
+    #[arg(long, default_value_t = 0)]
+    /// Which core of the target description or detected SoC to use
+    core: usize,
+}
+
+impl CoreArgs {
+    fn open<T>(&self, mem: Rc<RefCell<MemAP<T>>>) -> Result<Core<T>, CommandError>
+    where
+        T: Transport + ?Sized,
+    {
+        let (Some(cpu_base), Some(cti_base)) = (self.cpu_base, self.cti_base) else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--cpu-base and --cti-base are required",
+                )
+                .exit();
+        };
+        let mut core = Core::new(mem, cpu_base, cti_base);
+        core.unlock()?;
+        Ok(core)
+    }
+
+    /// Fill in any addresses not given on the command line from `layout`
+    fn apply(&mut self, layout: &Layout) {
+        if let Some((cpu_base, cti_base)) = layout.cores.get(self.core) {
+            self.cpu_base = self.cpu_base.or(Some(*cpu_base));
+            self.cti_base = self.cti_base.or(Some(*cti_base));
+        }
+    }
+}
+
+#[cfg(feature = "shell")]
+#[derive(clap::Args, Debug)]
+struct ShellArgs {
+    #[arg(long, value_parser = parse_int)]
+    /// Address of the core's external debug registers
+    cpu_base: Option<u32>,
+    #[arg(long, value_parser = parse_int)]
+    /// Address of the core's CTI
+    cti_base: Option<u32>,
+}
+
+#[cfg(feature = "shell")]
+impl ShellArgs {
+    /// Use the first core of `layout` if none was given on the command line
+    fn apply(&mut self, layout: &Layout) {
+        if let Some((cpu_base, cti_base)) = layout.cores.first() {
+            self.cpu_base = self.cpu_base.or(Some(*cpu_base));
+            self.cti_base = self.cti_base.or(Some(*cti_base));
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the access ports
+    Aps,
+    /// Print the DP registers and the registers of the MEM-AP, for bug reports
+    State,
+    /// List the CoreSight components found by walking a ROM table
+    Scan {
+        #[arg(value_parser = parse_int)]
+        /// Address of the ROM table, by default the one in the MEM-AP's BASE register
+        addr: Option<u32>,
+        #[arg(long)]
+        /// Save the components found to this file
+        save: Option<PathBuf>,
+        #[arg(long)]
+        /// Show the differences from a scan saved with --save instead of the components
+        diff: Option<PathBuf>,
+    },
+    /// Read a word from memory
+    Peek {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+    },
+    /// Write a word to memory
+    Poke {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+        #[arg(value_parser = parse_int)]
+        value: u32,
+    },
+    /// Hexdump a range of memory, or save it to a file
+    Dump {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+        #[arg(value_parser = parse_int)]
+        /// Number of words to read
+        count: u32,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the contents of a binary file to memory
+    Load {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+        file: PathBuf,
+        #[arg(long)]
+        /// Clean and invalidate the caches of a halted ARMv8 core so it can execute the file
+        sync_core: bool,
+        #[arg(long, value_parser = parse_int)]
+        /// Halt the ARMv8 core, load the file and resume the core at this address
+        entry: Option<u32>,
+        #[command(flatten)]
+        core: CoreArgs,
+    },
+    /// Test a range of RAM with walking bit, address and checkerboard patterns.  The contents of
+    /// the range are destroyed.
+    Memtest {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+        #[arg(value_parser = parse_int)]
+        /// Number of words to test
+        count: u32,
+    },
+    /// Halt an ARMv8 core
+    Halt(CoreArgs),
+    /// Resume a halted ARMv8 core
+    Resume(CoreArgs),
+    /// Single-step a halted ARMv8 core
+    Step(CoreArgs),
+    /// Print the registers of a halted ARMv8 core
+    Regs(CoreArgs),
+    /// Sample the PC of a running ARMv8 core and print the functions it spends most time in
+    Profile {
+        #[arg(long, default_value_t = 1000)]
+        /// How long to sample for, in milliseconds
+        duration: u64,
+        #[arg(long, default_value_t = 1000)]
+        /// Time between samples, in microseconds
+        interval: u64,
+        #[arg(long)]
+        /// Sample whole stacks by briefly halting the core, rather than only the PC
+        stacks: bool,
+        #[arg(long)]
+        /// Write folded stacks for inferno or flamegraph.pl to this file
+        folded: Option<PathBuf>,
+        #[cfg(feature = "elf")]
+        #[arg(long)]
+        /// ELF file to name functions from
+        elf: Option<PathBuf>,
+        #[command(flatten)]
+        core: CoreArgs,
+    },
+    #[cfg(feature = "shell")]
+    /// Start an interactive shell
+    Shell(ShellArgs),
+    #[cfg(feature = "tui")]
+    /// Show a live view of memory and the debug interface status
+    View {
+        #[arg(value_parser = parse_int)]
+        addr: u32,
+        #[arg(long, default_value_t = 500)]
+        /// Refresh interval in milliseconds
+        interval: u64,
+    },
+    #[cfg(feature = "svd")]
+    /// Print the registers of a peripheral described in an SVD file
+    Peripheral {
+        #[arg(long)]
+        svd: PathBuf,
+        name: String,
+    },
+    /// Check the cable, DP, AP and, given a scratch RAM address, memory access in turn
+    SelfTest {
+        #[arg(value_parser = parse_int)]
+        scratch: Option<u32>,
+    },
+    /// Share the debug interface with other tools over TCP
+    Serve {
+        #[arg(default_value = "127.0.0.1:7545")]
+        listen: String,
+    },
+}
+
+/// Debug layout of the target, from a target description or the SoC database
+struct Layout {
+    mem_ap: u32,
+    debug_ap: Option<u32>,
+    /// Debug and CTI base of each core
+    cores: Vec<(u32, u32)>,
+}
+
+impl From<&Soc> for Layout {
+    fn from(soc: &Soc) -> Self {
+        Self {
+            mem_ap: soc.mem_ap,
+            debug_ap: Some(soc.debug_ap),
+            cores: soc
+                .cores
+                .iter()
+                .map(|c| (c.debug_base, c.cti_base))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "description")]
+impl From<&TargetDescription> for Layout {
+    fn from(target: &TargetDescription) -> Self {
+        Self {
+            mem_ap: target.mem_ap,
+            debug_ap: target.debug_ap,
+            cores: target
+                .cores
+                .iter()
+                .map(|c| (c.debug_base, c.cti_base))
+                .collect(),
+        }
+    }
+}
+
+/// Load the target description given with --target, if any
+#[cfg(feature = "description")]
+fn load_description(args: &Args) -> Option<Layout> {
+    let path = args.target.as_ref()?;
+    let target = TargetDescription::load(path).unwrap_or_else(|e| {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("{}: {}", path.display(), e),
+            )
+            .exit()
+    });
+    Some(Layout::from(&target))
+}
+
+impl Args {
+    /// Return true if the command needs core addresses which weren't given on the command line
+    fn needs_core(&self) -> bool {
+        match &self.command {
+            Command::Halt(core)
+            | Command::Resume(core)
+            | Command::Step(core)
+            | Command::Regs(core)
+            | Command::Profile { core, .. } => core.cpu_base.is_none() || core.cti_base.is_none(),
+            Command::Load {
+                sync_core: true,
+                core,
+                ..
+            }
+            | Command::Load {
+                entry: Some(_),
+                core,
+                ..
+            } => core.cpu_base.is_none() || core.cti_base.is_none(),
+            #[cfg(feature = "shell")]
+            Command::Shell(shell) => shell.cpu_base.is_none() || shell.cti_base.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Fill in any options not given on the command line from `layout`
+    fn apply(&mut self, layout: &Layout) {
+        self.ap_num = self.ap_num.or(Some(layout.mem_ap));
+        self.debug_ap = self.debug_ap.or(layout.debug_ap);
+        match &mut self.command {
+            Command::Halt(core)
+            | Command::Resume(core)
+            | Command::Step(core)
+            | Command::Regs(core)
+            | Command::Load { core, .. }
+            | Command::Profile { core, .. } => core.apply(layout),
+            #[cfg(feature = "shell")]
+            Command::Shell(shell) => shell.apply(layout),
+            _ => {}
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = Args::parse();
+    #[cfg(feature = "description")]
+    let mut layout = load_description(&args);
+    #[cfg(not(feature = "description"))]
+    let mut layout = None;
+    let adi: Rc<RefCell<dyn Transport>> = if let Some(remote) = &args.remote {
+        if let Command::SelfTest { .. } = args.command {
+            eprintln!("Error: self-test needs a local cable");
+            return ExitCode::FAILURE;
+        }
+        let dap = RemoteDap::connect(remote).expect("connect");
+        #[cfg(feature = "compression")]
+        let dap = {
+            let mut dap = dap;
+            if args.compress {
+                dap.set_compression(true)
+                    .expect("server doesn't support compression");
+            }
+            dap
+        };
+        Rc::new(RefCell::new(dap))
+    } else {
+        let cable = cable::new_from_string(args.cable.as_ref().unwrap(), args.baud.unwrap())
+            .expect("cable");
+        let jtag = JtagSM::new(cable);
+        let mut taps = Taps::new(jtag);
+        taps.detect();
+
+        let idcode = match idcode::verify(&mut taps, args.tap_index, None) {
+            Ok(idcode) => idcode.raw,
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                match e {
+                    IdcodeError::UnknownDap(found) => found.raw,
+                    _ => 0,
+                }
+            }
+        };
+
+        let mut dap = ArmDebugInterface::new(taps);
+        if let Command::SelfTest { scratch } = args.command {
+            let apsel = args.ap_num.unwrap_or(0);
+            return match dap.self_test(apsel, scratch) {
+                Ok(test) => {
+                    println!(
+                        "IDCODE {:08x}, AP {} IDR {:08x}: ok",
+                        test.idcode, apsel, test.idr
+                    );
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Self test failed: {}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        dap.set_rate_limit(args.max_rate.map(|per_second| RateLimit {
+            per_second,
+            burst: args.burst,
+        }));
+        dap.set_idle_clocks(args.idle_clocks);
+        if args.log_scans {
+            let mut formatter = ScanFormatter::new();
+            dap.set_scan_hook(Some(Box::new(move |scan| {
+                eprintln!("{}", formatter.format(scan))
+            })));
+        }
+        let adi: Rc<RefCell<dyn Transport>> = Rc::new(RefCell::new(dap));
+
+        // Without a description, see if the SoC is one we know the layout of
+        if layout.is_none() && args.needs_core() {
+            if let Some(soc) = soc::identify(&adi, idcode) {
+                eprintln!("Detected {}", soc.name);
+                if soc.arch != Arch::Armv8A {
+                    eprintln!("Warning: core commands only support ARMv8-A cores");
+                }
+                layout = Some(Layout::from(soc));
+            }
+        }
+        adi
+    };
+    if let Some(layout) = &layout {
+        args.apply(layout);
+    }
+
+    if let Command::Serve { listen } = &args.command {
+        let listener = TcpListener::bind(listen).expect("listen");
+        remote::serve(&mut *adi.borrow_mut(), listener).expect("serve");
+        return ExitCode::SUCCESS;
+    }
+
+    let ap_num = args.ap_num.unwrap_or(0);
+    let new_mem = |ap| match args.keep_csw {
+        true => MemAP::new_keep_csw(adi.clone(), ap),
+        false => MemAP::new(adi.clone(), ap),
+    };
+    let mem = Rc::new(RefCell::new(new_mem(ap_num)));
+    let debug_mem = match args.debug_ap {
+        Some(ap) if ap != ap_num => Rc::new(RefCell::new(new_mem(ap))),
+        _ => mem.clone(),
+    };
+    mem.borrow_mut().set_preflight(true);
+    debug_mem.borrow_mut().set_preflight(true);
+    let aps = [mem.clone(), debug_mem.clone()];
+
+    let result = match args.command {
+        Command::Aps => commands::aps(&mut *adi.borrow_mut()),
+        Command::State => commands::state(&mut mem.borrow_mut()),
+        Command::Scan { addr, save, diff } => {
+            let mut mem = mem.borrow_mut();
+            commands::rom_table_addr(&mut mem, addr)
+                .map_err(CommandError::from)
+                .and_then(|addr| commands::scan(&mut mem, addr, save.as_deref(), diff.as_deref()))
+        }
+        Command::Peek { addr } => commands::peek(&mut mem.borrow_mut(), addr),
+        Command::Poke { addr, value } => commands::poke(&mut mem.borrow_mut(), addr, value),
+        Command::Dump {
+            addr,
+            count,
+            output: Some(path),
+        } => commands::dump_to_file(&mut mem.borrow_mut(), addr, count as usize, &path),
+        Command::Dump { addr, count, .. } => {
+            commands::dump(&mut mem.borrow_mut(), addr, count as usize)
+        }
+        Command::Load {
+            addr,
+            file,
+            entry: Some(entry),
+            core,
+            ..
+        } => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::load_and_run(&mem, addr, &file, entry, &mut c)),
+        Command::Load {
+            addr,
+            file,
+            sync_core: false,
+            ..
+        } => commands::load(&mem, addr, &file, None),
+        Command::Load {
+            addr, file, core, ..
+        } => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::load(&mem, addr, &file, Some(&mut c))),
+        Command::Memtest { addr, count } => {
+            commands::memtest(&mut mem.borrow_mut(), addr, count as usize)
+        }
+        Command::Halt(core) => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::halt(&mut c)),
+        Command::Resume(core) => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::resume(&mut c)),
+        Command::Step(core) => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::step(&mut c)),
+        Command::Regs(core) => core
+            .open(debug_mem)
+            .and_then(|mut c| commands::regs(&mut c)),
+        Command::Profile {
+            duration,
+            interval,
+            stacks,
+            folded,
+            #[cfg(feature = "elf")]
+            elf,
+            core,
+        } => {
+            #[cfg(not(feature = "elf"))]
+            let elf: Option<PathBuf> = None;
+            core.open(debug_mem).and_then(|mut c| {
+                commands::profile(
+                    &mut c,
+                    &mem,
+                    Duration::from_millis(duration),
+                    Duration::from_micros(interval),
+                    stacks,
+                    folded.as_deref(),
+                    elf.as_deref(),
+                )
+            })
+        }
+        #[cfg(feature = "shell")]
+        Command::Shell(args) => {
+            shell::run(mem, debug_mem, args.cpu_base.zip(args.cti_base)).map_err(CommandError::from)
+        }
+        #[cfg(feature = "tui")]
+        Command::View { addr, interval } => {
+            tui::run(adi, mem, addr, Duration::from_millis(interval)).map_err(CommandError::from)
+        }
+        #[cfg(feature = "svd")]
+        Command::Peripheral { svd, name } => commands::dump_peripheral(mem, &svd, &name),
+        Command::SelfTest { .. } | Command::Serve { .. } => unreachable!(),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Explain an AP disabled by the authentication signals, the commonest confusing
+            // failure on locked-down parts
+            let reason = aps.iter().find_map(|mem| mem.borrow().ap_disabled());
+            match reason {
+                Some(reason) if matches!(e, CommandError::Access(ERR_AP_DISABLED)) => {
+                    eprintln!("Error: {}", reason)
+                }
+                _ => eprintln!("Error: {}", e),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Interactive shell.  The debug interface stays open between commands, so cached bank selects
+//! and CSW/TAR values carry over from one command to the next.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use jtag_adi::armv8::Core;
+use jtag_adi::{MemAP, Transport};
+
+use crate::commands::{self, parse_int, CommandError};
+
+const HELP: &str = "\
+md <addr> [count]        display memory words
+mw <addr> <value>        write a memory word
+scan [addr]              walk the ROM table at addr
+core <cpu_base> <cti_base>
+                         select the ARMv8 core for run control
+halt                     halt the selected core
+resume                   resume the selected core
+step                     single-step the selected core
+regs                     print the registers of the selected core
+help                     show this message
+quit                     exit the shell";
+
+struct Shell<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    /// MemAP used to reach the core debug registers
+    debug_mem: Rc<RefCell<MemAP<T>>>,
+    core: Option<Core<T>>,
+}
+
+fn arg(args: &[&str], index: usize) -> Result<u32, String> {
+    let x = args.get(index).ok_or("missing argument")?;
+    parse_int(x).map_err(|e| format!("bad argument {}: {}", x, e))
+}
+
+fn opt_arg(args: &[&str], index: usize, default: u32) -> Result<u32, String> {
+    if args.len() > index {
+        arg(args, index)
+    } else {
+        Ok(default)
+    }
+}
+
+impl<T> Shell<T>
+where
+    T: Transport + ?Sized,
+{
+    fn core(&mut self) -> Result<&mut Core<T>, String> {
+        self.core
+            .as_mut()
+            .ok_or_else(|| "no core selected, use the core command".to_string())
+    }
+
+    fn select_core(&mut self, cpu_base: u32, cti_base: u32) -> Result<(), u8> {
+        let mut core = Core::new(self.debug_mem.clone(), cpu_base, cti_base);
+        core.unlock()?;
+        self.core = Some(core);
+        Ok(())
+    }
+
+    /// Run a single command line.  Returns false if the shell should exit.
+    fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(cmd) = args.first() else {
+            return Ok(true);
+        };
+
+        let ack = |e: CommandError| match e {
+            CommandError::Access(e) => format!("error {}", e),
+            e => e.to_string(),
+        };
+        match *cmd {
+            "md" => {
+                let addr = arg(&args, 1)?;
+                let count = opt_arg(&args, 2, 16)?;
+                commands::dump(&mut self.mem.borrow_mut(), addr, count as usize).map_err(ack)?;
+            }
+            "mw" => {
+                let addr = arg(&args, 1)?;
+                let value = arg(&args, 2)?;
+                commands::poke(&mut self.mem.borrow_mut(), addr, value).map_err(ack)?;
+            }
+            "scan" => {
+                let addr = opt_arg(&args, 1, 0)?;
+                commands::scan(&mut self.mem.borrow_mut(), addr, None, None).map_err(ack)?;
+            }
+            "core" => {
+                let cpu_base = arg(&args, 1)?;
+                let cti_base = arg(&args, 2)?;
+                self.select_core(cpu_base, cti_base)
+                    .map_err(|e| ack(e.into()))?;
+            }
+            "halt" => commands::halt(self.core()?).map_err(ack)?,
+            "resume" => commands::resume(self.core()?).map_err(ack)?,
+            "step" => commands::step(self.core()?).map_err(ack)?,
+            "regs" => commands::regs(self.core()?).map_err(ack)?,
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(false),
+            _ => return Err(format!("unknown command {}, try help", cmd)),
+        }
+        Ok(true)
+    }
+}
+
+/// Run the shell until the user exits.  If `core` is given, it is the debug and CTI base of the
+/// core to use for run control commands, which are accessed through `debug_mem`.
+pub fn run<T>(
+    mem: Rc<RefCell<MemAP<T>>>,
+    debug_mem: Rc<RefCell<MemAP<T>>>,
+    core: Option<(u32, u32)>,
+) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut shell = Shell {
+        mem,
+        debug_mem,
+        core: None,
+    };
+    if let Some((cpu_base, cti_base)) = core {
+        shell.select_core(cpu_base, cti_base)?;
+    }
+
+    let mut rl = DefaultEditor::new().expect("readline");
+    loop {
+        match rl.readline("adi> ") {
+            Ok(line) => {
+                let _ = rl.add_history_entry(line.as_str());
+                match shell.execute(&line) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Terminal UI showing a live hexdump of a memory window along with the DP and AP status.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::DefaultTerminal;
+
+use jtag_adi::{MemAP, Port, Transport};
+
+/// Height of the status panel, including its border
+const STATUS_HEIGHT: u16 = 5;
+
+fn flag(name: &str, val: bool) -> String {
+    format!("{}={}", name, val as u8)
+}
+
+fn memory_lines(addr: u32, data: &[u32]) -> Vec<String> {
+    data.chunks(4)
+        .enumerate()
+        .map(|(i, line)| {
+            let words: Vec<String> = line.iter().map(|x| format!("{:08x}", x)).collect();
+            let ascii: String = line
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .map(|c| if c.is_ascii_graphic() { c as char } else { '.' })
+                .collect();
+            format!(
+                "{:08x}: {}  {}",
+                addr.wrapping_add(16 * i as u32),
+                words.join(" "),
+                ascii
+            )
+        })
+        .collect()
+}
+
+fn status_lines<T>(adi: &mut T, apsel: u32) -> Vec<String>
+where
+    T: Transport + ?Sized,
+{
+    let mut lines = vec![];
+    match adi.read_ctrl_stat() {
+        Ok(cs) => lines.push(format!(
+            "CTRL/STAT  {} {} {} {} {} {} {}",
+            flag("CSYSPWRUPACK", cs.csyspwrupack),
+            flag("CDBGPWRUPACK", cs.cdbgpwrupack),
+            flag("STICKYERR", cs.stickyerr),
+            flag("STICKYCMP", cs.stickycmp),
+            flag("STICKYORUN", cs.stickyorun),
+            flag("WDATAERR", cs.wdataerr),
+            flag("READOK", cs.readok),
+        )),
+        Err(e) => lines.push(format!("CTRL/STAT  error {}", e)),
+    }
+    match adi.read_adi(apsel, Port::AP, 0) {
+        Ok(csw) => lines.push(format!(
+            "CSW {:08x} Size={} AddrInc={} {} {} {} Prot={:02x}",
+            csw,
+            8 << (csw & 7),
+            (csw >> 4) & 3,
+            flag("DeviceEn", csw & (1 << 6) != 0),
+            flag("TrInProg", csw & (1 << 7) != 0),
+            flag("SPIDEN", csw & (1 << 23) != 0),
+            (csw >> 24) & 0x7f,
+        )),
+        Err(e) => lines.push(format!("CSW error {}", e)),
+    }
+    lines
+}
+
+fn event_loop<T>(
+    terminal: &mut DefaultTerminal,
+    adi: Rc<RefCell<T>>,
+    mem: Rc<RefCell<MemAP<T>>>,
+    mut addr: u32,
+    interval: Duration,
+) where
+    T: Transport + ?Sized,
+{
+    loop {
+        let size = terminal.size().expect("terminal size");
+        let rows = size.height.saturating_sub(STATUS_HEIGHT + 2).max(1) as u32;
+
+        let memory = match mem.borrow_mut().read_memory(addr, 4 * rows as usize) {
+            Ok(data) => memory_lines(addr, &data),
+            Err(e) => vec![format!("error {} reading {:08x}", e, addr)],
+        };
+        let apsel = mem.borrow().apsel();
+        let status = status_lines(&mut *adi.borrow_mut(), apsel);
+
+        terminal
+            .draw(|frame| {
+                let [top, bottom] =
+                    Layout::vertical([Constraint::Min(3), Constraint::Length(STATUS_HEIGHT)])
+                        .areas(frame.area());
+                let title = format!("Memory (AP {}) - q quit, arrows/pgup/pgdn scroll", apsel);
+                frame.render_widget(
+                    Paragraph::new(memory.join("\n")).block(Block::bordered().title(title)),
+                    top,
+                );
+                frame.render_widget(
+                    Paragraph::new(status.join("\n")).block(Block::bordered().title("Status")),
+                    bottom,
+                );
+            })
+            .expect("draw");
+
+        if event::poll(interval).expect("poll") {
+            if let Event::Key(key) = event::read().expect("read event") {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return,
+                    KeyCode::Up => addr = addr.wrapping_sub(16),
+                    KeyCode::Down => addr = addr.wrapping_add(16),
+                    KeyCode::PageUp => addr = addr.wrapping_sub(16 * rows),
+                    KeyCode::PageDown => addr = addr.wrapping_add(16 * rows),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Show memory starting at `addr`, refreshing every `interval` until the user quits
+pub fn run<T>(
+    adi: Rc<RefCell<T>>,
+    mem: Rc<RefCell<MemAP<T>>>,
+    addr: u32,
+    interval: Duration,
+) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut terminal = ratatui::init();
+    event_loop(&mut terminal, adi, mem, addr & !0xf, interval);
+    ratatui::restore();
+    Ok(())
+}
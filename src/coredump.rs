@@ -0,0 +1,182 @@
+//! ELF core files of halted ARMv8-A targets, which GDB can load together with the program's ELF
+//! file to examine a crash offline.  The core file holds the registers of one core in an
+//! NT_PRSTATUS note, its SIMD and floating point registers in an NT_FPREGSET note if they are
+//! accessible, and the chosen memory regions as PT_LOAD segments.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::armv8::{Core, ERR_INSTRUCTION};
+use crate::{MemAP, Transport};
+
+const EM_AARCH64: u16 = 183;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+const SIGTRAP: u32 = 5;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+/// Size of `struct elf_prstatus` on AArch64 Linux
+const PRSTATUS_SIZE: usize = 392;
+/// Offset of `pr_reg` in `struct elf_prstatus`
+const PRSTATUS_REG: usize = 112;
+/// Offset of `pr_cursig` in `struct elf_prstatus`
+const PRSTATUS_CURSIG: usize = 12;
+
+/// Error generating a core file
+#[derive(Debug)]
+pub enum CoredumpError {
+    /// Error from the debug interface
+    Access(u8),
+    Io(io::Error),
+    /// A memory region isn't word aligned
+    Unaligned(Range<u32>),
+}
+
+impl fmt::Display for CoredumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoredumpError::Access(e) => write!(f, "access error {}", e),
+            CoredumpError::Io(e) => write!(f, "{}", e),
+            CoredumpError::Unaligned(r) => {
+                write!(f, "region {:#x}..{:#x} isn't word aligned", r.start, r.end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CoredumpError {}
+
+impl From<u8> for CoredumpError {
+    fn from(e: u8) -> Self {
+        CoredumpError::Access(e)
+    }
+}
+
+impl From<io::Error> for CoredumpError {
+    fn from(e: io::Error) -> Self {
+        CoredumpError::Io(e)
+    }
+}
+
+/// Encode a note with owner "CORE"
+fn note(kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend(5u32.to_le_bytes());
+    out.extend((desc.len() as u32).to_le_bytes());
+    out.extend(kind.to_le_bytes());
+    out.extend(b"CORE\0\0\0\0");
+    out.extend(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+    out
+}
+
+fn prstatus(regs: &[u64]) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    desc[..4].copy_from_slice(&SIGTRAP.to_le_bytes());
+    desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&(SIGTRAP as u16).to_le_bytes());
+    for (i, reg) in regs.iter().enumerate() {
+        let offset = PRSTATUS_REG + 8 * i;
+        desc[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+    desc
+}
+
+fn fpregset(vregs: &[u128], fpsr: u32, fpcr: u32) -> Vec<u8> {
+    let mut desc = vec![];
+    for v in vregs {
+        desc.extend(v.to_le_bytes());
+    }
+    desc.extend(fpsr.to_le_bytes());
+    desc.extend(fpcr.to_le_bytes());
+    desc.extend([0; 8]);
+    desc
+}
+
+fn phdr(kind: u32, offset: u64, addr: u64, size: u64) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend(kind.to_le_bytes());
+    // PF_R | PF_W | PF_X
+    out.extend(7u32.to_le_bytes());
+    out.extend(offset.to_le_bytes());
+    out.extend(addr.to_le_bytes());
+    out.extend(addr.to_le_bytes());
+    out.extend(size.to_le_bytes());
+    out.extend(size.to_le_bytes());
+    out.extend(1u64.to_le_bytes());
+    out
+}
+
+fn ehdr(phnum: u16) -> Vec<u8> {
+    let mut out = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    out.resize(16, 0);
+    out.extend(ET_CORE.to_le_bytes());
+    out.extend(EM_AARCH64.to_le_bytes());
+    out.extend(1u32.to_le_bytes());
+    // Entry point, program header offset and section header offset
+    out.extend(0u64.to_le_bytes());
+    out.extend((EHDR_SIZE as u64).to_le_bytes());
+    out.extend(0u64.to_le_bytes());
+    out.extend(0u32.to_le_bytes());
+    out.extend((EHDR_SIZE as u16).to_le_bytes());
+    out.extend((PHDR_SIZE as u16).to_le_bytes());
+    out.extend(phnum.to_le_bytes());
+    out.extend([0; 6]);
+    out
+}
+
+/// Write an ELF core file of the halted `core` to `writer`, including the memory `regions`, read
+/// through `mem`.  Region bounds must be word aligned.
+pub fn generate_coredump<T, W>(
+    core: &mut Core<T>,
+    mem: &mut MemAP<T>,
+    regions: &[Range<u32>],
+    writer: &mut W,
+) -> Result<(), CoredumpError>
+where
+    T: Transport + ?Sized,
+    W: Write,
+{
+    if let Some(r) = regions.iter().find(|r| (r.start | r.end) & 3 != 0) {
+        return Err(CoredumpError::Unaligned(r.clone()));
+    }
+
+    let ctx = core.read_context()?;
+    let mut regs = ctx.x.to_vec();
+    regs.extend([ctx.sp, ctx.pc, ctx.pstate]);
+    let mut notes = note(NT_PRSTATUS, &prstatus(&regs));
+
+    // Floating point may be disabled at the current exception level
+    match core.read_vregs() {
+        Ok(vregs) => {
+            let fpsr = core.read_fpsr()? as u32;
+            let fpcr = core.read_fpcr()? as u32;
+            notes.extend(note(NT_FPREGSET, &fpregset(&vregs, fpsr, fpcr)));
+        }
+        Err(ERR_INSTRUCTION) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let phnum = 1 + regions.len();
+    let mut offset = (EHDR_SIZE + PHDR_SIZE * phnum) as u64;
+    writer.write_all(&ehdr(phnum as u16))?;
+    writer.write_all(&phdr(PT_NOTE, offset, 0, notes.len() as u64))?;
+    offset += notes.len() as u64;
+    for r in regions {
+        let size = (r.end - r.start) as u64;
+        writer.write_all(&phdr(PT_LOAD, offset, r.start as u64, size))?;
+        offset += size;
+    }
+
+    writer.write_all(&notes)?;
+    for r in regions {
+        let words = mem.read_memory(r.start, ((r.end - r.start) / 4) as usize)?;
+        let bytes: Vec<u8> = words.iter().flat_map(|x| x.to_le_bytes()).collect();
+        writer.write_all(&bytes)?;
+    }
+    Ok(())
+}
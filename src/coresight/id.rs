@@ -0,0 +1,309 @@
+//! Decoding of the DEVTYPE and DEVARCH identification registers of CoreSight components.
+
+use std::fmt;
+
+/// JEP106 code of ARM, the architect of the architectures in `Architecture`
+pub const ARCHITECT_ARM: u16 = 0x23b;
+
+/// Major type of a component, from DEVTYPE.MAJOR
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceClass {
+    Miscellaneous,
+    TraceSink,
+    TraceLink,
+    TraceSource,
+    DebugControl,
+    DebugLogic,
+    PerformanceMonitor,
+    Reserved(u8),
+}
+
+impl From<u32> for DeviceClass {
+    fn from(devtype: u32) -> Self {
+        match devtype & 0xf {
+            0 => DeviceClass::Miscellaneous,
+            1 => DeviceClass::TraceSink,
+            2 => DeviceClass::TraceLink,
+            3 => DeviceClass::TraceSource,
+            4 => DeviceClass::DebugControl,
+            5 => DeviceClass::DebugLogic,
+            6 => DeviceClass::PerformanceMonitor,
+            major => DeviceClass::Reserved(major as u8),
+        }
+    }
+}
+
+impl fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceClass::Miscellaneous => write!(f, "Misc"),
+            DeviceClass::TraceSink => write!(f, "Trace sink"),
+            DeviceClass::TraceLink => write!(f, "Trace link"),
+            DeviceClass::TraceSource => write!(f, "Trace source"),
+            DeviceClass::DebugControl => write!(f, "Debug control"),
+            DeviceClass::DebugLogic => write!(f, "Debug logic"),
+            DeviceClass::PerformanceMonitor => write!(f, "Performance monitor"),
+            DeviceClass::Reserved(major) => write!(f, "Reserved {:x}", major),
+        }
+    }
+}
+
+/// Type of a component, from DEVTYPE.MAJOR and DEVTYPE.SUB.  Sub-types the architecture
+/// doesn't define are `Other`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceType {
+    /// A validation component
+    Validation,
+    Tpiu,
+    Etb,
+    /// A basic trace router, which writes trace to system memory
+    TraceRouter,
+    /// A funnel or router
+    Funnel,
+    Filter,
+    /// A FIFO or large trace buffer, such as an ETF
+    Fifo,
+    /// Trace of a processor, such as an ETM
+    CpuTrace,
+    DspTrace,
+    /// Trace of a coprocessor or engine
+    EngineTrace,
+    BusTrace,
+    /// Software trace, such as an STM or ITM
+    SoftwareTrace,
+    /// A trigger matrix, such as a CTI
+    TriggerMatrix,
+    DebugAuthentication,
+    PowerRequestor,
+    CpuDebug,
+    DspDebug,
+    EngineDebug,
+    BusDebug,
+    /// Memory debug logic, such as BIST
+    MemoryDebug,
+    CpuPmu,
+    DspPmu,
+    EnginePmu,
+    BusPmu,
+    /// Performance monitor of a memory or MMU
+    MemoryPmu,
+    /// A sub-type not defined for its class, or the "other" sub-type 0
+    Other(DeviceClass, u8),
+}
+
+impl DeviceType {
+    pub fn class(&self) -> DeviceClass {
+        use DeviceType::*;
+        match self {
+            Validation => DeviceClass::Miscellaneous,
+            Tpiu | Etb | TraceRouter => DeviceClass::TraceSink,
+            Funnel | Filter | Fifo => DeviceClass::TraceLink,
+            CpuTrace | DspTrace | EngineTrace | BusTrace | SoftwareTrace => {
+                DeviceClass::TraceSource
+            }
+            TriggerMatrix | DebugAuthentication | PowerRequestor => DeviceClass::DebugControl,
+            CpuDebug | DspDebug | EngineDebug | BusDebug | MemoryDebug => DeviceClass::DebugLogic,
+            CpuPmu | DspPmu | EnginePmu | BusPmu | MemoryPmu => DeviceClass::PerformanceMonitor,
+            Other(class, _) => *class,
+        }
+    }
+}
+
+impl From<u32> for DeviceType {
+    fn from(devtype: u32) -> Self {
+        use DeviceType::*;
+        let class = DeviceClass::from(devtype);
+        let sub = ((devtype >> 4) & 0xf) as u8;
+        match (class, sub) {
+            (DeviceClass::Miscellaneous, 4) => Validation,
+            (DeviceClass::TraceSink, 1) => Tpiu,
+            (DeviceClass::TraceSink, 2) => Etb,
+            (DeviceClass::TraceSink, 3) => TraceRouter,
+            (DeviceClass::TraceLink, 1) => Funnel,
+            (DeviceClass::TraceLink, 2) => Filter,
+            (DeviceClass::TraceLink, 3) => Fifo,
+            (DeviceClass::TraceSource, 1) => CpuTrace,
+            (DeviceClass::TraceSource, 2) => DspTrace,
+            (DeviceClass::TraceSource, 3) => EngineTrace,
+            (DeviceClass::TraceSource, 4) => BusTrace,
+            (DeviceClass::TraceSource, 6) => SoftwareTrace,
+            (DeviceClass::DebugControl, 1) => TriggerMatrix,
+            (DeviceClass::DebugControl, 2) => DebugAuthentication,
+            (DeviceClass::DebugControl, 3) => PowerRequestor,
+            (DeviceClass::DebugLogic, 1) => CpuDebug,
+            (DeviceClass::DebugLogic, 2) => DspDebug,
+            (DeviceClass::DebugLogic, 3) => EngineDebug,
+            (DeviceClass::DebugLogic, 4) => BusDebug,
+            (DeviceClass::DebugLogic, 5) => MemoryDebug,
+            (DeviceClass::PerformanceMonitor, 1) => CpuPmu,
+            (DeviceClass::PerformanceMonitor, 2) => DspPmu,
+            (DeviceClass::PerformanceMonitor, 3) => EnginePmu,
+            (DeviceClass::PerformanceMonitor, 4) => BusPmu,
+            (DeviceClass::PerformanceMonitor, 5) => MemoryPmu,
+            (class, sub) => Other(class, sub),
+        }
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DeviceType::*;
+        let name = match self {
+            Validation => "Validation",
+            Tpiu => "TPIU",
+            Etb => "ETB",
+            TraceRouter | Funnel => "Router",
+            Filter => "Filter",
+            Fifo => "FIFO",
+            CpuTrace | CpuDebug | CpuPmu => "CPU",
+            DspTrace | DspDebug | DspPmu => "DSP",
+            EngineTrace | EngineDebug | EnginePmu => "Coprocessor",
+            BusTrace | BusDebug | BusPmu => "Bus",
+            SoftwareTrace => "Software",
+            TriggerMatrix => "Trigger Matrix",
+            DebugAuthentication => "Debug Authentication",
+            PowerRequestor => "Power Requestor",
+            MemoryDebug | MemoryPmu => "Memory",
+            Other(DeviceClass::Miscellaneous, _) => return write!(f, "Misc"),
+            Other(DeviceClass::Reserved(_), _) => return write!(f, "Other"),
+            Other(..) => "Other",
+        };
+        write!(f, "{}: {}", self.class(), name)
+    }
+}
+
+/// An architecture identified by DEVARCH, for those defined by ARM
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Architecture {
+    Ras,
+    Itm,
+    Dwt,
+    Fpb,
+    /// ARMv8-M processor debug
+    DebugV8M,
+    /// ARMv8-R processor debug
+    DebugV8R,
+    PcSampling,
+    Etm,
+    Cti,
+    /// ARMv8-A processor debug, with the minor version: 0 for v8.0, 1 for v8.1 and so on
+    DebugV8A(u8),
+    Pmu,
+    MemApV2,
+    JtagApV2,
+    TraceRouter,
+    PowerRequestor,
+    UnknownApV2,
+    Hsstp,
+    Stm,
+    Ela,
+    RomTable,
+}
+
+impl Architecture {
+    /// Identify ARCHID of an ARM architecture
+    pub fn from_archid(archid: u16) -> Option<Self> {
+        use Architecture::*;
+        Some(match archid {
+            0x0a00 => Ras,
+            0x1a01 => Itm,
+            0x1a02 => Dwt,
+            0x1a03 => Fpb,
+            0x2a04 => DebugV8M,
+            0x6a05 => DebugV8R,
+            0x0a10 => PcSampling,
+            0x4a13 => Etm,
+            0x1a14 => Cti,
+            0x6a15..=0xfa15 if archid & 0xfff == 0xa15 => DebugV8A((archid >> 12) as u8 - 6),
+            0x2a16 => Pmu,
+            0x0a17 => MemApV2,
+            0x0a27 => JtagApV2,
+            0x0a31 => TraceRouter,
+            0x0a37 => PowerRequestor,
+            0x0a47 => UnknownApV2,
+            0x0a50 => Hsstp,
+            0x0a63 => Stm,
+            0x0a75 => Ela,
+            0x0af7 => RomTable,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Architecture::*;
+        match self {
+            Ras => write!(f, "RAS"),
+            Itm => write!(f, "ITM"),
+            Dwt => write!(f, "DWT"),
+            Fpb => write!(f, "FPB"),
+            DebugV8M => write!(f, "ARMv8-M debug"),
+            DebugV8R => write!(f, "ARMv8-R debug"),
+            PcSampling => write!(f, "PC sampling"),
+            Etm => write!(f, "ETM"),
+            Cti => write!(f, "CTI"),
+            DebugV8A(minor) => write!(f, "ARMv8.{}-A debug", minor),
+            Pmu => write!(f, "PMU"),
+            MemApV2 => write!(f, "MEM-AP v2"),
+            JtagApV2 => write!(f, "JTAG-AP v2"),
+            TraceRouter => write!(f, "Trace router"),
+            PowerRequestor => write!(f, "Power requestor"),
+            UnknownApV2 => write!(f, "Unknown AP v2"),
+            Hsstp => write!(f, "HSSTP"),
+            Stm => write!(f, "STM"),
+            Ela => write!(f, "ELA"),
+            RomTable => write!(f, "ROM table"),
+        }
+    }
+}
+
+/// Decoded value of DEVARCH
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DevArch {
+    /// JEP106 code of the architect, continuation code in bits [10:7]
+    pub architect: u16,
+    /// The register is implemented and the other fields are valid
+    pub present: bool,
+    pub revision: u8,
+    pub archid: u16,
+}
+
+impl From<u32> for DevArch {
+    fn from(val: u32) -> Self {
+        Self {
+            architect: (val >> 21) as u16,
+            present: val & (1 << 20) != 0,
+            revision: ((val >> 16) & 0xf) as u8,
+            archid: val as u16,
+        }
+    }
+}
+
+impl DevArch {
+    /// The architecture, if DEVARCH is present and it is one defined by ARM
+    pub fn architecture(&self) -> Option<Architecture> {
+        if !self.present || self.architect != ARCHITECT_ARM {
+            return None;
+        }
+        Architecture::from_archid(self.archid)
+    }
+}
+
+impl fmt::Display for DevArch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.architecture() {
+            Some(arch) => write!(f, "{} r{}", arch, self.revision),
+            None if !self.present => write!(f, "none"),
+            None => write!(
+                f,
+                "architect {:03x} archid {:04x} r{}",
+                self.architect, self.archid, self.revision
+            ),
+        }
+    }
+}
@@ -0,0 +1,3 @@
+//! Definitions shared by CoreSight components.
+
+pub mod id;
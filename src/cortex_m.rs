@@ -0,0 +1,473 @@
+//! Run control and reset for Cortex-M cores through the debug registers in the System Control
+//! Space, accessed through the MemAP of the core's AHB-AP.
+//!
+//! The core can be debugged by halting it, or in monitor mode, where breakpoints and watchpoints
+//! take the DebugMonitor exception instead, handled by a debug monitor running on the target.
+//! See `CortexM::set_debug_mode`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::armv8::{AccessKind, HaltReason, WatchpointHit};
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+const AIRCR: u32 = 0xe000ed0c;
+const DHCSR: u32 = 0xe000edf0;
+const DCRSR: u32 = 0xe000edf4;
+const DCRDR: u32 = 0xe000edf8;
+const DEMCR: u32 = 0xe000edfc;
+const DFSR: u32 = 0xe000ed30;
+const DWT_PCSR: u32 = 0xe000101c;
+const DWT_CTRL: u32 = 0xe0001000;
+const DWT_COMP0: u32 = 0xe0001020;
+const DWT_FUNCTION0: u32 = 0xe0001028;
+const DWT_DEVARCH: u32 = 0xe0001fbc;
+const FP_CTRL: u32 = 0xe0002000;
+const FP_COMP0: u32 = 0xe0002008;
+
+const AIRCR_VECTKEY: u32 = 0x05fa << 16;
+const AIRCR_VECTRESET: u32 = 1 << 0;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+const DHCSR_DBGKEY: u32 = 0xa05f << 16;
+const DHCSR_C_DEBUGEN: u32 = 1 << 0;
+const DHCSR_C_HALT: u32 = 1 << 1;
+const DHCSR_C_MASKINTS: u32 = 1 << 3;
+const DHCSR_S_REGRDY: u32 = 1 << 16;
+const DHCSR_S_HALT: u32 = 1 << 17;
+const DHCSR_S_RESET_ST: u32 = 1 << 25;
+
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+const DEMCR_MON_EN: u32 = 1 << 16;
+const DEMCR_MON_PEND: u32 = 1 << 17;
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+const DFSR_HALTED: u32 = 1 << 0;
+const DFSR_BKPT: u32 = 1 << 1;
+const DFSR_DWTTRAP: u32 = 1 << 2;
+const DFSR_VCATCH: u32 = 1 << 3;
+const DFSR_EXTERNAL: u32 = 1 << 4;
+
+const DCRSR_REGWNR: u32 = 1 << 16;
+
+/// DWT_FUNCTION.MATCHED, cleared by reading the register
+const DWT_FUNCTION_MATCHED: u32 = 1 << 24;
+/// Spacing of the DWT comparators
+const DWT_STRIDE: u32 = 16;
+/// DEVARCH.ARCHID of an ARMv8-M DWT, which encodes the comparator function differently
+const DWT_ARCHID_V8M: u32 = 0x1a02;
+
+const FP_COMP_ENABLE: u32 = 1 << 0;
+/// FP_CTRL.KEY, which must be set for a write to FP_CTRL.ENABLE to take effect
+const FP_CTRL_KEY: u32 = 1 << 1;
+const FP_CTRL_ENABLE: u32 = 1 << 0;
+/// Address bits of an FPBv1 comparator, which compares word addresses
+const FP_COMP_V1_ADDR: u32 = 0x1fff_fffc;
+
+/// `BKPT #0xab`, the Thumb semihosting call
+const SEMIHOST_T32: u16 = 0xbeab;
+/// DCRSR selector of the PC
+const REG_PC: u16 = 15;
+
+/// DCRSR selector of FPSCR
+const REG_FPSCR: u16 = 0x21;
+/// DCRSR selector of S0
+const REG_S0: u16 = 0x40;
+
+/// How long to wait for the core to come out of reset and, with reset-catch, halt.  Also bounds
+/// the wait for a core register transfer, which only stalls if the core is stuck.
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How to reset the core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Reset the whole system with AIRCR.SYSRESETREQ
+    System,
+    /// Reset only the core with AIRCR.VECTRESET.  This is only implemented by ARMv7-M cores.
+    Core,
+}
+
+/// How debug events, such as breakpoints and watchpoints, are handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugMode {
+    /// The core halts, with DHCSR.C_DEBUGEN set
+    Halting,
+    /// The core takes the DebugMonitor exception and keeps running, with DEMCR.MON_EN set and
+    /// DHCSR.C_DEBUGEN clear
+    Monitor,
+}
+
+/// Functions for controlling a Cortex-M core
+pub struct CortexM<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+}
+
+impl<T> CortexM<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>) -> Self {
+        Self { mem }
+    }
+
+    /// MemAP through which the core is accessed
+    pub fn mem(&self) -> Rc<RefCell<MemAP<T>>> {
+        self.mem.clone()
+    }
+
+    fn read(&mut self, addr: u32) -> Result<u32, u8> {
+        self.mem.borrow_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u32, val: u32) -> Result<(), u8> {
+        self.mem.borrow_mut().write(addr, val)
+    }
+
+    /// Read the raw value of DHCSR
+    pub fn dhcsr(&mut self) -> Result<u32, u8> {
+        self.read(DHCSR)
+    }
+
+    /// Return true if the core is halted
+    pub fn is_halted(&mut self) -> Result<bool, u8> {
+        Ok(self.read(DHCSR)? & DHCSR_S_HALT != 0)
+    }
+
+    /// Return why the core is halted, or None if it is running.  The reason is decoded from
+    /// DFSR, which is cleared so that the next halt is reported correctly.  DFSR doesn't
+    /// distinguish a step from a halt request, so both are reported as `ExternalRequest`.
+    ///
+    /// A breakpoint is reported with the index of the FPB comparator at the PC, if any, and a
+    /// watchpoint with the index and address of the DWT comparator that matched.
+    pub fn halt_reason(&mut self) -> Result<Option<HaltReason>, u8> {
+        if !self.is_halted()? {
+            return Ok(None);
+        }
+        let dfsr = self.read(DFSR)?;
+        self.write(DFSR, dfsr)?;
+        let reason = if dfsr & DFSR_BKPT != 0 {
+            if self.at_semihosting_call()? {
+                HaltReason::Semihosting
+            } else {
+                let pc = self.read_core_reg(REG_PC)?;
+                HaltReason::Breakpoint(self.fpb_comparator_at(pc)?)
+            }
+        } else if dfsr & DFSR_DWTTRAP != 0 {
+            self.dwt_match()?
+        } else if dfsr & DFSR_VCATCH != 0 {
+            HaltReason::ExceptionCatch
+        } else if dfsr & (DFSR_HALTED | DFSR_EXTERNAL) != 0 {
+            HaltReason::ExternalRequest
+        } else {
+            HaltReason::Unknown(0)
+        };
+        Ok(Some(reason))
+    }
+
+    /// Return true if the halted core is at a semihosting call
+    pub(crate) fn at_semihosting_call(&mut self) -> Result<bool, u8> {
+        let pc = self.read_core_reg(REG_PC)?;
+        let word = self.read(pc & !3)?;
+        Ok((word >> (8 * (pc & 2))) as u16 == SEMIHOST_T32)
+    }
+
+    /// Return the index of the enabled FPB code comparator matching `pc`
+    fn fpb_comparator_at(&mut self, pc: u32) -> Result<Option<usize>, u8> {
+        let ctrl = self.read(FP_CTRL)?;
+        let num_code = (ctrl >> 8) & 0x70 | (ctrl >> 4) & 0xf;
+        let v1 = ctrl >> 28 == 0;
+        for i in 0..num_code {
+            let comp = self.read(FP_COMP0 + 4 * i)?;
+            if comp & FP_COMP_ENABLE == 0 {
+                continue;
+            }
+            let hit = if v1 {
+                comp & FP_COMP_V1_ADDR == pc & FP_COMP_V1_ADDR
+            } else {
+                comp & !FP_COMP_ENABLE == pc
+            };
+            if hit {
+                return Ok(Some(i as usize));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the DWT comparator that matched, clearing its MATCHED flag
+    fn dwt_match(&mut self) -> Result<HaltReason, u8> {
+        let num_comp = self.read(DWT_CTRL)? >> 28;
+        for i in 0..num_comp {
+            let function = self.read(DWT_FUNCTION0 + DWT_STRIDE * i)?;
+            if function & DWT_FUNCTION_MATCHED != 0 {
+                let comp = self.read(DWT_COMP0 + DWT_STRIDE * i)?;
+                return Ok(HaltReason::Watchpoint {
+                    index: Some(i as usize),
+                    addr: Some(comp as u64),
+                });
+            }
+        }
+        Ok(HaltReason::Watchpoint {
+            index: None,
+            addr: None,
+        })
+    }
+
+    /// Return the details of the access that triggered the watchpoint `reason`, as returned by
+    /// `halt_reason`, or None if `reason` isn't a watchpoint.  The address is that of the DWT
+    /// comparator, and the kind of access is known if the comparator only matches reads or only
+    /// writes.  The value written isn't known.
+    pub fn watchpoint_hit(&mut self, reason: HaltReason) -> Result<Option<WatchpointHit>, u8> {
+        let HaltReason::Watchpoint { index, addr } = reason else {
+            return Ok(None);
+        };
+        let access = match index {
+            Some(i) => self.dwt_access(i as u32)?,
+            None => AccessKind::Unknown,
+        };
+        Ok(Some(WatchpointHit {
+            index,
+            addr,
+            access,
+            value: None,
+        }))
+    }
+
+    /// Return the kind of access DWT comparator `i` matches
+    fn dwt_access(&mut self, i: u32) -> Result<AccessKind, u8> {
+        let function = self.read(DWT_FUNCTION0 + DWT_STRIDE * i)? & 0xf;
+        let v8m = self.read(DWT_DEVARCH)? & 0xffff == DWT_ARCHID_V8M;
+        let access = match (v8m, function) {
+            // ARMv8-M FUNCTION.MATCH, for address, value and address with value comparisons
+            (true, 0b0101 | 0b1001 | 0b1101) => AccessKind::Write,
+            (true, 0b0110 | 0b1010 | 0b1110) => AccessKind::Read,
+            // ARMv7-M FUNCTION
+            (false, 0b0101) => AccessKind::Read,
+            (false, 0b0110) => AccessKind::Write,
+            _ => AccessKind::Unknown,
+        };
+        Ok(access)
+    }
+
+    /// Enable the DWT, which `sample_pc` needs
+    pub fn enable_pc_sampling(&mut self) -> Result<(), u8> {
+        let demcr = self.read(DEMCR)?;
+        self.write(DEMCR, demcr | DEMCR_TRCENA)
+    }
+
+    /// Sample the PC of the running core through DWT_PCSR, without halting it.  Returns None if
+    /// no sample is available, such as while the core is halted.
+    pub fn sample_pc(&mut self) -> Result<Option<u32>, u8> {
+        match self.read(DWT_PCSR)? {
+            0xffffffff => Ok(None),
+            pc => Ok(Some(pc)),
+        }
+    }
+
+    /// Select how debug events are handled.  For `Monitor`, halting debug is disabled, which
+    /// resumes the core if it is halted, and the DWT and FPB are enabled so that the
+    /// comparators can be used by the monitor.  The DebugMonitor exception must be enabled and
+    /// given a priority by the target.  `halt`, `resume` and the resets enable halting debug
+    /// again.
+    pub fn set_debug_mode(&mut self, mode: DebugMode) -> Result<(), u8> {
+        let demcr = self.read(DEMCR)?;
+        match mode {
+            DebugMode::Halting => {
+                self.write(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN)?;
+                self.write(DEMCR, demcr & !DEMCR_MON_EN)
+            }
+            DebugMode::Monitor => {
+                self.write(DHCSR, DHCSR_DBGKEY)?;
+                self.write(DEMCR, demcr | DEMCR_MON_EN | DEMCR_TRCENA)?;
+                self.write(FP_CTRL, FP_CTRL_KEY | FP_CTRL_ENABLE)
+            }
+        }
+    }
+
+    /// Return how debug events are handled, or None if debug is disabled.  Halting debug takes
+    /// precedence over the monitor when both are enabled.
+    pub fn debug_mode(&mut self) -> Result<Option<DebugMode>, u8> {
+        let mode = if self.read(DHCSR)? & DHCSR_C_DEBUGEN != 0 {
+            Some(DebugMode::Halting)
+        } else if self.read(DEMCR)? & DEMCR_MON_EN != 0 {
+            Some(DebugMode::Monitor)
+        } else {
+            None
+        };
+        Ok(mode)
+    }
+
+    /// Pend the DebugMonitor exception, the monitor mode equivalent of `halt`
+    pub fn pend_monitor(&mut self) -> Result<(), u8> {
+        let demcr = self.read(DEMCR)?;
+        self.write(DEMCR, demcr | DEMCR_MON_PEND)
+    }
+
+    /// Request the core to halt, enabling halting debug if needed
+    pub fn halt(&mut self) -> Result<(), u8> {
+        self.write(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN | DHCSR_C_HALT)
+    }
+
+    /// Restart a halted core, leaving halting debug enabled
+    pub fn resume(&mut self) -> Result<(), u8> {
+        self.write(DHCSR, DHCSR_DBGKEY | DHCSR_C_DEBUGEN)
+    }
+
+    /// Resume a halted core with interrupts masked and wait for it to halt again, for running
+    /// a short routine that ends in a `BKPT`.  The debug events recorded in DFSR by the run are
+    /// cleared, so the halt isn't reported to the user.  Returns `ERR_TIMEOUT` if the core
+    /// doesn't halt within `timeout`, after halting it.
+    pub fn run_masked(&mut self, timeout: Duration) -> Result<(), u8> {
+        let dfsr = self.read(DFSR)?;
+        let debugen = DHCSR_DBGKEY | DHCSR_C_DEBUGEN;
+        // C_MASKINTS may only be changed while the core is halted
+        self.write(DHCSR, debugen | DHCSR_C_HALT | DHCSR_C_MASKINTS)?;
+        self.write(DHCSR, debugen | DHCSR_C_MASKINTS)?;
+
+        let start = Instant::now();
+        let mut result = Ok(());
+        while !self.is_halted()? {
+            if start.elapsed() > timeout {
+                self.write(DHCSR, debugen | DHCSR_C_HALT | DHCSR_C_MASKINTS)?;
+                result = Err(ERR_TIMEOUT);
+            }
+        }
+
+        self.write(DHCSR, debugen | DHCSR_C_HALT)?;
+        let events = self.read(DFSR)? & !dfsr;
+        self.write(DFSR, events)?;
+        result
+    }
+
+    /// Wait for the core register transfer requested through DCRSR to complete.  Returns
+    /// `ERR_TIMEOUT` if it doesn't within `RESET_TIMEOUT`.
+    fn wait_regrdy(&mut self) -> Result<(), u8> {
+        let start = Instant::now();
+        while self.read(DHCSR)? & DHCSR_S_REGRDY == 0 {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the core register selected by `sel`, using the DCRSR encoding.  The core must be
+    /// halted.
+    pub fn read_core_reg(&mut self, sel: u16) -> Result<u32, u8> {
+        self.write(DCRSR, sel as u32)?;
+        self.wait_regrdy()?;
+        self.read(DCRDR)
+    }
+
+    /// Write `val` to the core register selected by `sel`, using the DCRSR encoding.  The core
+    /// must be halted.
+    pub fn write_core_reg(&mut self, sel: u16, val: u32) -> Result<(), u8> {
+        self.write(DCRDR, val)?;
+        self.write(DCRSR, DCRSR_REGWNR | sel as u32)?;
+        self.wait_regrdy()
+    }
+
+    /// Read single precision floating point register S`n` of a core with an FPU
+    pub fn read_sreg(&mut self, n: u16) -> Result<u32, u8> {
+        assert!(n < 32);
+        self.read_core_reg(REG_S0 + n)
+    }
+
+    pub fn write_sreg(&mut self, n: u16, val: u32) -> Result<(), u8> {
+        assert!(n < 32);
+        self.write_core_reg(REG_S0 + n, val)
+    }
+
+    /// Read double precision floating point register D`n`, which is S`2n` and S`2n+1`
+    pub fn read_dreg(&mut self, n: u16) -> Result<u64, u8> {
+        assert!(n < 16);
+        let lo = self.read_sreg(2 * n)?;
+        let hi = self.read_sreg(2 * n + 1)?;
+        Ok((hi as u64) << 32 | lo as u64)
+    }
+
+    pub fn write_dreg(&mut self, n: u16, val: u64) -> Result<(), u8> {
+        assert!(n < 16);
+        self.write_sreg(2 * n, val as u32)?;
+        self.write_sreg(2 * n + 1, (val >> 32) as u32)
+    }
+
+    pub fn read_fpscr(&mut self) -> Result<u32, u8> {
+        self.read_core_reg(REG_FPSCR)
+    }
+
+    pub fn write_fpscr(&mut self, val: u32) -> Result<(), u8> {
+        self.write_core_reg(REG_FPSCR, val)
+    }
+
+    /// Reset the core and let it run
+    pub fn reset(&mut self, kind: ResetKind) -> Result<(), u8> {
+        self.do_reset(kind, false)
+    }
+
+    /// Reset the core with reset-catch enabled, so it halts before executing the first
+    /// instruction of its reset handler
+    pub fn reset_and_halt(&mut self, kind: ResetKind) -> Result<(), u8> {
+        self.do_reset(kind, true)
+    }
+
+    /// Read DHCSR until the core has been through reset.  Accesses can fail while the system is
+    /// in reset, so errors are cleared and retried until `RESET_TIMEOUT`.
+    fn wait_reset(&mut self) -> Result<u32, u8> {
+        let start = Instant::now();
+        loop {
+            match self.read(DHCSR) {
+                Ok(dhcsr) if dhcsr & DHCSR_S_RESET_ST != 0 => return Ok(dhcsr),
+                Ok(_) => {}
+                Err(_) => {
+                    let _ = self.mem.borrow_mut().clear_sticky_errors();
+                }
+            }
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+
+    fn do_reset(&mut self, kind: ResetKind, catch: bool) -> Result<(), u8> {
+        let demcr = self.read(DEMCR)?;
+        let debugen = DHCSR_DBGKEY | DHCSR_C_DEBUGEN;
+        if catch {
+            self.write(DHCSR, debugen)?;
+            self.write(DEMCR, demcr | DEMCR_VC_CORERESET)?;
+        } else {
+            self.write(DEMCR, demcr & !DEMCR_VC_CORERESET)?;
+        }
+
+        // Clear S_RESET_ST, which is cleared by reading, so the reset can be seen below
+        self.read(DHCSR)?;
+
+        let request = match kind {
+            ResetKind::System => AIRCR_SYSRESETREQ,
+            ResetKind::Core => AIRCR_VECTRESET,
+        };
+        // The system may go into reset before the write is acknowledged
+        if self.write(AIRCR, AIRCR_VECTKEY | request).is_err() {
+            self.mem.borrow_mut().clear_sticky_errors()?;
+        }
+        let dhcsr = self.wait_reset()?;
+
+        if catch {
+            // On parts where the system reset also resets the debug logic, C_DEBUGEN and the
+            // vector catch are lost and the core starts running.  Enable them again and halt the
+            // core as soon as possible; it may have executed some instructions by then.
+            if dhcsr & DHCSR_C_DEBUGEN == 0 {
+                self.write(DHCSR, debugen | DHCSR_C_HALT)?;
+            }
+            let start = Instant::now();
+            while !self.is_halted()? {
+                if start.elapsed() > RESET_TIMEOUT {
+                    return Err(ERR_TIMEOUT);
+                }
+            }
+        }
+
+        // Put the vector catch setting back the way it was
+        self.write(DEMCR, demcr)
+    }
+}
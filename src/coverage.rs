@@ -0,0 +1,143 @@
+//! Code coverage from ETM instruction trace, enabled with the `elf` feature.  The trace is
+//! decoded against the program's ELF file into the set of executed address ranges, which can
+//! be summarized per function or written as an lcov tracefile.
+//!
+//! The crate doesn't read DWARF line information, so the lcov output has function records only,
+//! all under one source file name given by the caller.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::elf::{self, ElfError, Symbols};
+use crate::etm::{DecodeStats, EtmDecoder};
+
+/// The code of a program, at the addresses it is linked to run at
+struct CodeImage {
+    segments: Vec<elf::Segment>,
+}
+
+impl CodeImage {
+    fn read_u32(&self, addr: u64) -> Option<u32> {
+        let s = self.segments.iter().find(|s| {
+            s.virtual_address <= addr && addr < s.virtual_address + s.data.len() as u64
+        })?;
+        let offset = (addr - s.virtual_address) as usize;
+        let bytes = s.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Coverage of one function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// Number of bytes of the function that were executed
+    pub executed: u64,
+}
+
+/// A set of executed address ranges
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    /// Disjoint ranges, by start address, with the end address as value
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode `trace`, the ETM trace of one source, against the ELF file `elf_data`
+    pub fn from_trace(elf_data: &[u8], trace: &[u8]) -> Result<(Self, DecodeStats), ElfError> {
+        let image = CodeImage {
+            segments: elf::load_segments(elf_data)?,
+        };
+        let mut coverage = Self::new();
+        let mut decoder = EtmDecoder::new(|addr| image.read_u32(addr));
+        decoder.decode(trace, |range| coverage.add(range));
+        Ok((coverage, decoder.stats()))
+    }
+
+    /// Mark `range` as executed
+    pub fn add(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut start = range.start;
+        let mut end = range.end;
+        // Merge with a range that starts before and overlaps or touches this one
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e >= start {
+                start = s;
+                end = end.max(e);
+            }
+        }
+        // Absorb the ranges that start inside this one
+        let inside: Vec<u64> = self.ranges.range(start..=end).map(|(&s, _)| s).collect();
+        for s in inside {
+            end = end.max(self.ranges.remove(&s).unwrap());
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// The executed ranges, in address order
+    pub fn ranges(&self) -> Vec<Range<u64>> {
+        self.ranges.iter().map(|(&s, &e)| s..e).collect()
+    }
+
+    pub fn is_executed(&self, addr: u64) -> bool {
+        self.ranges
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(_, &end)| addr < end)
+    }
+
+    /// Return the number of bytes of `range` that were executed
+    pub fn executed_bytes(&self, range: Range<u64>) -> u64 {
+        let before = self.ranges.range(..range.start).next_back();
+        before
+            .into_iter()
+            .chain(self.ranges.range(range.clone()))
+            .map(|(&s, &e)| e.min(range.end).saturating_sub(s.max(range.start)))
+            .sum()
+    }
+
+    /// Return the coverage of every function in `symbols`, in address order
+    pub fn functions(&self, symbols: &Symbols) -> Vec<FunctionCoverage> {
+        symbols
+            .iter()
+            .filter(|s| s.text && s.size > 0)
+            .map(|s| FunctionCoverage {
+                name: s.name.clone(),
+                address: s.address,
+                size: s.size,
+                executed: self.executed_bytes(s.address..s.address + s.size),
+            })
+            .collect()
+    }
+
+    /// Write the function coverage as an lcov tracefile, with `source` as the file name
+    pub fn write_lcov(
+        &self,
+        out: &mut dyn Write,
+        symbols: &Symbols,
+        source: &str,
+    ) -> io::Result<()> {
+        let functions = self.functions(symbols);
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source)?;
+        for f in &functions {
+            writeln!(out, "FN:0,{}", f.name)?;
+        }
+        for f in &functions {
+            writeln!(out, "FNDA:{},{}", (f.executed > 0) as u32, f.name)?;
+        }
+        writeln!(out, "FNF:{}", functions.len())?;
+        let hit = functions.iter().filter(|f| f.executed > 0).count();
+        writeln!(out, "FNH:{}", hit)?;
+        writeln!(out, "end_of_record")
+    }
+}
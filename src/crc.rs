@@ -0,0 +1,215 @@
+//! CRC-32 of target memory, for verifying large images quickly.  Reading memory back over JTAG
+//! to compare it on the host is slow, so when a halted core is available a small routine is
+//! loaded into scratch RAM and run on the core to checksum the memory in place; only the
+//! result is read back.  Without a core, the memory is read and checksummed on the host.
+//!
+//! The checksum is the common CRC-32 (IEEE 802.3, reflected, as used by zlib), so it can be
+//! compared with `crc32` of the host's copy of the data.
+
+use std::time::{Duration, Instant};
+
+use crate::armv8::{Core, ERR_INSTRUCTION};
+use crate::cortex_m::CortexM;
+use crate::image::read_bytes;
+use crate::memory::ERR_ADDRESS_RANGE;
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+const POLY: u32 = 0xedb88320;
+
+/// A64 routine computing the CRC-32 of X1 bytes at X0 into W0, ending with `HLT #0`.  Uses
+/// X0-X6.
+const STUB_A64: [u32; 17] = [
+    0x2a3f03e2, // mvn w2, wzr
+    0x52906403, // mov w3, #0x8320
+    0x72bdb703, // movk w3, #0xedb8, lsl #16
+    0xb4000181, // 1: cbz x1, 3f
+    0x38401404, // ldrb w4, [x0], #1
+    0x4a040042, // eor w2, w2, w4
+    0x52800105, // mov w5, #8
+    0x7200005f, // 2: tst w2, #1
+    0x53017c42, // lsr w2, w2, #1
+    0x4a030046, // eor w6, w2, w3
+    0x1a8210c2, // csel w2, w6, w2, ne
+    0x710004a5, // subs w5, w5, #1
+    0x54ffff61, // b.ne 2b
+    0xd1000421, // sub x1, x1, #1
+    0x17fffff5, // b 1b
+    0x2a2203e0, // 3: mvn w0, w2
+    0xd4400000, // hlt #0
+];
+
+/// Thumb-2 routine computing the CRC-32 of R1 bytes at R0 into R0, ending with `BKPT #0`.  Uses
+/// R0-R5 and the flags.
+const STUB_T32: [u16; 20] = [
+    0xf06f, 0x0200, // mvn r2, #0
+    0xf248, 0x3320, // movw r3, #0x8320
+    0xf6ce, 0x53b8, // movt r3, #0xedb8
+    0xb151, // 1: cbz r1, 3f
+    0xf810, 0x4b01, // ldrb r4, [r0], #1
+    0x4062, // eors r2, r4
+    0x2508, // movs r5, #8
+    0x0852, // 2: lsrs r2, r2, #1
+    0xbf28, // it cs
+    0x405a, // eorcs r2, r3
+    0x1e6d, // subs r5, r5, #1
+    0xd1fa, // bne 2b
+    0x1e49, // subs r1, r1, #1
+    0xe7f3, // b 1b
+    0x43d0, // 3: mvns r0, r2
+    0xbe00, // bkpt #0
+];
+
+/// Entries of the byte-at-a-time lookup table
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE: [u32; 256] = table();
+
+/// Compute the CRC-32 of `data` on the host
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+/// How long to let a stub run for `len` bytes before giving up.  The stubs take a few tens of
+/// cycles per byte.
+fn stub_timeout(len: u64) -> Duration {
+    Duration::from_millis(1000 + len / 1024)
+}
+
+/// A halted core which can run the CRC-32 stub
+pub trait CrcStub {
+    /// Load the stub at `scratch` and run it over `len` bytes at `addr`.  The core's registers
+    /// are restored afterwards and it is left halted.
+    fn run_crc32(&mut self, scratch: u64, addr: u64, len: u64) -> Result<u32, u8>;
+}
+
+impl<T> CrcStub for Core<T>
+where
+    T: Transport + ?Sized,
+{
+    fn run_crc32(&mut self, scratch: u64, addr: u64, len: u64) -> Result<u32, u8> {
+        let regs = (0..7)
+            .map(|n| self.read_reg(n))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pc = self.read_pc()?;
+        let pstate = self.read_pstate()?;
+        // PSTATE.nRW: the stub is A64 code
+        if pstate & (1 << 4) != 0 {
+            return Err(ERR_INSTRUCTION);
+        }
+
+        let result = (|| -> Result<u32, u8> {
+            self.write_memory(scratch, &STUB_A64)?;
+            self.sync_icache(scratch, 4 * STUB_A64.len() as u64)?;
+            self.write_reg(0, addr)?;
+            self.write_reg(1, len)?;
+            self.write_pc(scratch)?;
+            // Run with D, A, I and F masked, so the OS can't take the core away from the stub
+            self.write_pstate(pstate | 0xf << 6)?;
+            self.resume()?;
+
+            let timeout = stub_timeout(len);
+            let start = Instant::now();
+            while !self.is_halted()? {
+                if start.elapsed() > timeout {
+                    self.halt_and_wait()?;
+                    return Err(ERR_TIMEOUT);
+                }
+            }
+            Ok(self.read_reg(0)? as u32)
+        })();
+
+        for (n, &val) in regs.iter().enumerate() {
+            self.write_reg(n as u32, val)?;
+        }
+        self.write_pc(pc)?;
+        self.write_pstate(pstate)?;
+        result
+    }
+}
+
+impl<T> CrcStub for CortexM<T>
+where
+    T: Transport + ?Sized,
+{
+    fn run_crc32(&mut self, scratch: u64, addr: u64, len: u64) -> Result<u32, u8> {
+        let scratch = u32::try_from(scratch).map_err(|_| ERR_ADDRESS_RANGE)?;
+        let addr = u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?;
+        let len = u32::try_from(len).map_err(|_| ERR_ADDRESS_RANGE)?;
+        // R0-R5, PC and xPSR
+        let sels = [0, 1, 2, 3, 4, 5, 15, 16];
+        let regs = sels
+            .iter()
+            .map(|&sel| self.read_core_reg(sel))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let result = (|| -> Result<u32, u8> {
+            let code: Vec<u32> = STUB_T32
+                .chunks(2)
+                .map(|x| x[0] as u32 | (x[1] as u32) << 16)
+                .collect();
+            self.mem().borrow_mut().write_memory(scratch, &code)?;
+            self.write_core_reg(0, addr)?;
+            self.write_core_reg(1, len)?;
+            self.write_core_reg(15, scratch)?;
+            // xPSR.T, with the rest of the execution state cleared
+            self.write_core_reg(16, 1 << 24)?;
+            self.run_masked(stub_timeout(len as u64))?;
+            self.read_core_reg(0)
+        })();
+
+        for (&sel, &val) in sels.iter().zip(&regs) {
+            self.write_core_reg(sel, val)?;
+        }
+        result
+    }
+}
+
+/// A halted core to checksum memory with, and the address of scratch RAM its stub can be
+/// loaded at.  The scratch RAM must not overlap the memory being checksummed.
+pub struct TargetCrc<'a> {
+    pub core: &'a mut dyn CrcStub,
+    pub scratch: u64,
+}
+
+/// Compute the CRC-32 of `len` bytes at `addr`.  If `target` is given, the stub is run on its
+/// core, which must see memory at the same addresses as `mem`; if that fails the memory is
+/// read through `mem` and checksummed on the host instead.
+pub fn crc32_memory<T>(
+    mem: &mut MemAP<T>,
+    addr: u32,
+    len: usize,
+    target: Option<&mut TargetCrc>,
+) -> Result<u32, u8>
+where
+    T: Transport + ?Sized,
+{
+    if let Some(target) = target {
+        let result = target
+            .core
+            .run_crc32(target.scratch, addr as u64, len as u64);
+        if let Ok(crc) = result {
+            return Ok(crc);
+        }
+    }
+    Ok(crc32(&read_bytes(mem, addr, len)?))
+}
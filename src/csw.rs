@@ -0,0 +1,288 @@
+//! Named settings of a MEM-AP's CSW.Prot and CSW.Cache bits.  What the bits mean depends on the
+//! bus behind the AP, so a `CswPreset` is turned into the bits for a particular AP type, while a
+//! `CswProfile` holds the bits themselves.  A profile only changes the bits in its mask, so
+//! profiles can be applied one after another, for example `NonSecure` then `PrivilegedData`.
+//!
+//! CSW also reports whether the AP may access memory at all.  With `MemAP::set_preflight` these
+//! bits are checked before each access, so that an AP disabled by the authentication signals
+//! fails with `ERR_AP_DISABLED` and a reason, rather than with a bus fault.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{MemAP, MemAPReg, Port, Transport};
+
+/// Error returned by a `MemAP` access with preflight checks when the AP is disabled.  See
+/// `MemAP::ap_disabled`.
+pub const ERR_AP_DISABLED: u8 = 0x48;
+
+/// CSW.DeviceEn: the AP may access memory, usually driven by DBGEN
+const CSW_DEVICE_EN: u32 = 1 << 6;
+/// CSW.SPIDEN, SDeviceEn in ADIv6: the AP may make Secure accesses
+const CSW_SPIDEN: u32 = 1 << 23;
+/// CSW.AddrInc and CSW.Size
+const CSW_INC_SIZE_MASK: u32 = 0x37;
+/// CSW.Size of a 32-bit access, with no address increment
+const CSW_WORD: u32 = 2;
+
+/// AHB-AP CSW.HPROT bits, and CSW.SProt which makes the access Non-secure when set
+const AHB_DATA: u32 = 1 << 24;
+const AHB_PRIVILEGED: u32 = 1 << 25;
+const AHB_BUFFERABLE: u32 = 1 << 26;
+const AHB_CACHEABLE: u32 = 1 << 27;
+const AHB_NONSEC: u32 = 1 << 30;
+
+/// AXI-AP CSW.Prot bits, CSW.Cache and CSW.Domain
+const AXI_PRIVILEGED: u32 = 1 << 28;
+const AXI_NONSEC: u32 = 1 << 29;
+const AXI_INSTRUCTION: u32 = 1 << 30;
+const AXI_CACHE_MASK: u32 = 0xf << 24;
+/// Write-back, read and write allocate
+const AXI_CACHE_WB: u32 = 0xf << 24;
+const AXI_DOMAIN_MASK: u32 = 3 << 21;
+const AXI_DOMAIN_INNER: u32 = 1 << 21;
+const AXI_DOMAIN_SYSTEM: u32 = 3 << 21;
+
+/// APB-AP CSW.Prot bits
+const APB_PRIVILEGED: u32 = 1 << 28;
+const APB_NONSEC: u32 = 1 << 29;
+
+/// Bits to set in CSW, under a mask of the bits to change
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CswProfile {
+    pub bits: u32,
+    pub mask: u32,
+}
+
+impl CswProfile {
+    pub const fn new(bits: u32, mask: u32) -> Self {
+        Self {
+            bits: bits & mask,
+            mask,
+        }
+    }
+
+    /// Return `csw` with the bits under the mask replaced
+    pub fn apply(&self, csw: u32) -> u32 {
+        csw & !self.mask | self.bits & self.mask
+    }
+
+    /// Return the profile that applies `self` then `other`
+    pub fn then(&self, other: CswProfile) -> Self {
+        Self::new(other.apply(self.bits), self.mask | other.mask)
+    }
+}
+
+/// Settings for common kinds of access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CswPreset {
+    /// A privileged data access, as made by an OS kernel
+    PrivilegedData,
+    /// A Non-secure access
+    NonSecure,
+    /// A cacheable access, coherent with the caches of the cores where the bus supports it
+    CacheableCoherent,
+    /// A non-cacheable, non-bufferable access, for peripheral registers
+    Device,
+}
+
+impl CswPreset {
+    pub const ALL: [CswPreset; 4] = [
+        CswPreset::PrivilegedData,
+        CswPreset::NonSecure,
+        CswPreset::CacheableCoherent,
+        CswPreset::Device,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CswPreset::PrivilegedData => "privileged-data",
+            CswPreset::NonSecure => "non-secure",
+            CswPreset::CacheableCoherent => "cacheable-coherent",
+            CswPreset::Device => "device",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// The CSW bits of this preset for an AP of type `ap_type`, as from `ApInfo::ap_type`.  The
+    /// profile is empty if the bus has no bits for it, such as caching on APB.
+    pub fn profile(&self, ap_type: u32) -> CswProfile {
+        match (ap_type, self) {
+            // AHB3, AHB5 and AHB5 with enhanced HPROT
+            (1 | 5 | 8, CswPreset::PrivilegedData) => {
+                CswProfile::new(AHB_DATA | AHB_PRIVILEGED, AHB_DATA | AHB_PRIVILEGED)
+            }
+            (1 | 5 | 8, CswPreset::NonSecure) => CswProfile::new(AHB_NONSEC, AHB_NONSEC),
+            (1 | 5 | 8, CswPreset::CacheableCoherent) => CswProfile::new(
+                AHB_BUFFERABLE | AHB_CACHEABLE,
+                AHB_BUFFERABLE | AHB_CACHEABLE,
+            ),
+            (1 | 5 | 8, CswPreset::Device) => CswProfile::new(0, AHB_BUFFERABLE | AHB_CACHEABLE),
+            // AXI3/4 and AXI5
+            (4 | 7, CswPreset::PrivilegedData) => {
+                CswProfile::new(AXI_PRIVILEGED, AXI_PRIVILEGED | AXI_INSTRUCTION)
+            }
+            (4 | 7, CswPreset::NonSecure) => CswProfile::new(AXI_NONSEC, AXI_NONSEC),
+            (4 | 7, CswPreset::CacheableCoherent) => CswProfile::new(
+                AXI_CACHE_WB | AXI_DOMAIN_INNER,
+                AXI_CACHE_MASK | AXI_DOMAIN_MASK,
+            ),
+            (4 | 7, CswPreset::Device) => {
+                CswProfile::new(AXI_DOMAIN_SYSTEM, AXI_CACHE_MASK | AXI_DOMAIN_MASK)
+            }
+            // APB2/3 and APB4/5
+            (2 | 6, CswPreset::PrivilegedData) => CswProfile::new(APB_PRIVILEGED, APB_PRIVILEGED),
+            (2 | 6, CswPreset::NonSecure) => CswProfile::new(APB_NONSEC, APB_NONSEC),
+            _ => CswProfile::default(),
+        }
+    }
+}
+
+/// The CSW bits a `MemAP` programs when it is created, for an AP of type `ap_type`: 32-bit
+/// accesses, no address increment, and a privileged data access.  The other bits, such as the
+/// security of the access, are left as they are.
+pub fn initial_profile(ap_type: u32) -> CswProfile {
+    CswProfile::new(CSW_WORD, CSW_INC_SIZE_MASK).then(CswPreset::PrivilegedData.profile(ap_type))
+}
+
+impl fmt::Display for CswPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Why an access failed with `ERR_AP_DISABLED`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApDisabled {
+    /// CSW.DeviceEn is clear, so the AP can't access memory at all
+    Device,
+    /// CSW.SPIDEN is clear, so the AP can't make the Secure access asked for
+    Secure,
+}
+
+impl fmt::Display for ApDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApDisabled::Device => write!(f, "MEM-AP disabled by DBGEN (CSW.DeviceEn is clear)"),
+            ApDisabled::Secure => write!(
+                f,
+                "Secure accesses disabled by SPIDEN (CSW.SPIDEN is clear); \
+                 use a Non-secure access instead"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ApDisabled {}
+
+/// State of the preflight checks of a `MemAP`
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Preflight {
+    /// The Non-secure bit of CSW for the AP's type, once read from IDR
+    nonsec: Option<CswProfile>,
+}
+
+/// Custom profiles registered by name, alongside the presets
+#[derive(Clone, Debug, Default)]
+pub struct CswProfiles {
+    custom: Vec<(String, CswProfile)>,
+}
+
+impl CswProfiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `profile` as `name`, replacing any custom profile of that name.  A custom
+    /// profile hides a preset of the same name.
+    pub fn register(&mut self, name: &str, profile: CswProfile) {
+        match self.custom.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = profile,
+            None => self.custom.push((name.to_string(), profile)),
+        }
+    }
+
+    /// Look up the profile called `name` for an AP of type `ap_type`
+    pub fn get(&self, name: &str, ap_type: u32) -> Option<CswProfile> {
+        match self.custom.iter().find(|(n, _)| n == name) {
+            Some((_, profile)) => Some(*profile),
+            None => CswPreset::from_name(name).map(|p| p.profile(ap_type)),
+        }
+    }
+
+    /// The names of the custom profiles and the presets
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.custom
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .chain(CswPreset::ALL.iter().map(|p| p.name()))
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Write the bits of `profile` to CSW, leaving the rest of it as it is.  They stay set for
+    /// every later access.
+    pub fn apply_csw_profile(&mut self, profile: CswProfile) -> Result<(), u8> {
+        let csw = self.csw()?;
+        self.write_csw(profile.apply(csw))
+    }
+
+    /// Apply `preset` with the bits for this AP's type, read from its IDR
+    pub fn apply_csw_preset(&mut self, preset: CswPreset) -> Result<(), u8> {
+        let ap_type = self.idr()?.ap_type();
+        self.apply_csw_profile(preset.profile(ap_type))
+    }
+
+    /// Enable or disable preflight checks.  When enabled, CSW is read before each memory access,
+    /// and the access fails with `ERR_AP_DISABLED` if DeviceEn is clear, or if it is a Secure
+    /// access and SPIDEN is clear.
+    pub fn set_preflight(&mut self, enable: bool) {
+        self.preflight = enable.then(Preflight::default);
+    }
+
+    /// Return why the last access that failed with `ERR_AP_DISABLED` wasn't allowed
+    pub fn ap_disabled(&self) -> Option<ApDisabled> {
+        self.disabled
+    }
+
+    /// Check that the AP may make the access set up in CSW, if preflight checks are enabled
+    pub(crate) fn preflight(&mut self) -> Result<(), u8> {
+        let Some(preflight) = self.preflight else {
+            return Ok(());
+        };
+        let csw = self
+            .adi
+            .borrow_mut()
+            .read_adi(self.apsel, Port::AP, MemAPReg::CSW as u8)?;
+        if csw & CSW_DEVICE_EN == 0 {
+            self.disabled = Some(ApDisabled::Device);
+            return Err(ERR_AP_DISABLED);
+        }
+
+        let nonsec = match preflight.nonsec {
+            Some(nonsec) => nonsec,
+            None => {
+                let nonsec = CswPreset::NonSecure.profile(self.idr()?.ap_type());
+                self.preflight = Some(Preflight {
+                    nonsec: Some(nonsec),
+                });
+                nonsec
+            }
+        };
+        // Buses without a Non-secure bit have no Secure accesses to check
+        let secure = nonsec.mask != 0 && csw & nonsec.mask != nonsec.bits;
+        if secure && csw & CSW_SPIDEN == 0 {
+            self.disabled = Some(ApDisabled::Secure);
+            return Err(ERR_AP_DISABLED);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Byte stream over the Debug Communications Channel of an ARMv8-A core.  Software on the core
+//! sends a byte by writing it to DBGDTRTX_EL0 and receives one by reading DBGDTRRX_EL0, one
+//! byte per word in bits 7:0.  This is the convention used by the Linux `hvc_dcc` console, and
+//! needs nothing on the target beyond a few instructions, so it suits flash loaders and test
+//! agents.  The DTR full flags provide flow control in both directions.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::armv8::Core;
+use crate::Transport;
+
+/// How long to sleep between polls while waiting for the core
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+fn access_error(e: u8) -> io::Error {
+    io::Error::other(format!("access error {}", e))
+}
+
+/// `Read` and `Write` over the DCC of a running core
+pub struct DccChannel<'a, T: ?Sized> {
+    core: &'a mut Core<T>,
+    timeout: Option<Duration>,
+}
+
+impl<'a, T> DccChannel<'a, T>
+where
+    T: Transport + ?Sized,
+{
+    /// Communicate with `core`.  Reads and writes wait for the core indefinitely until a timeout
+    /// is set.
+    pub fn new(core: &'a mut Core<T>) -> Self {
+        Self {
+            core,
+            timeout: None,
+        }
+    }
+
+    /// Set how long a read waits for the first byte, and a write for the core to take a byte,
+    /// before failing with `ErrorKind::TimedOut`.  None waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Call `op` until it returns a value, sleeping between attempts, or until the timeout
+    fn poll<R>(
+        &mut self,
+        mut op: impl FnMut(&mut Core<T>) -> Result<Option<R>, u8>,
+    ) -> io::Result<R> {
+        let start = Instant::now();
+        loop {
+            if let Some(r) = op(self.core).map_err(access_error)? {
+                return Ok(r);
+            }
+            if self.timeout.is_some_and(|t| start.elapsed() > t) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl<T> Read for DccChannel<'_, T>
+where
+    T: Transport + ?Sized,
+{
+    /// Wait for at least one byte, then return as many as are available without waiting
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.poll(|core| core.dcc_read())? as u8;
+        let mut n = 1;
+        while n < buf.len() {
+            match self.core.dcc_read().map_err(access_error)? {
+                Some(word) => buf[n] = word as u8,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<T> Write for DccChannel<'_, T>
+where
+    T: Transport + ?Sized,
+{
+    /// Wait for the core to take at least one byte.  Returns early with the number of bytes
+    /// written if it stops taking them before the timeout.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (n, &b) in buf.iter().enumerate() {
+            match self.poll(|core| Ok(core.dcc_write(b as u32)?.then_some(()))) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && n > 0 => return Ok(n),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
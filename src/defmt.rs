@@ -0,0 +1,94 @@
+//! Decoding of defmt log frames, enabled with the `defmt` feature.  Targets logging with defmt
+//! send compact binary frames, usually over an RTT up channel, which are turned back into text
+//! with the format strings stored in the program's ELF file.
+
+use std::fmt;
+
+use defmt_decoder::{DecodeError, StreamDecoder, Table};
+
+use crate::rtt::Rtt;
+use crate::{MemAP, Transport};
+
+/// Read the defmt format string table from the contents of an ELF file.  Returns None if the
+/// program doesn't use defmt.
+pub fn parse_table(elf: &[u8]) -> Result<Option<Table>, String> {
+    Table::parse(elf).map_err(|e| e.to_string())
+}
+
+/// A decoded log message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLine {
+    pub level: Option<&'static str>,
+    /// Timestamp, if the program defines one, in the format it specifies
+    pub timestamp: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(t) = &self.timestamp {
+            write!(f, "{} ", t)?;
+        }
+        if let Some(level) = self.level {
+            write!(f, "{:5} ", level.to_uppercase())?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Decoder for a stream of defmt frames.  Frames may be split across reads; the incomplete
+/// part is kept until the rest arrives.
+pub struct DefmtLog<'a> {
+    decoder: Box<dyn StreamDecoder + 'a>,
+    malformed: usize,
+}
+
+impl<'a> DefmtLog<'a> {
+    pub fn new(table: &'a Table) -> Self {
+        Self {
+            decoder: table.new_stream_decoder(),
+            malformed: 0,
+        }
+    }
+
+    /// Number of frames which couldn't be decoded
+    pub fn malformed(&self) -> usize {
+        self.malformed
+    }
+
+    /// Add `data` to the stream and return the messages completed by it
+    pub fn decode(&mut self, data: &[u8]) -> Vec<LogLine> {
+        self.decoder.received(data);
+        let mut lines = vec![];
+        loop {
+            match self.decoder.decode() {
+                Ok(frame) => lines.push(LogLine {
+                    level: frame.level().map(|l| l.as_str()),
+                    timestamp: frame.display_timestamp().map(|t| t.to_string()),
+                    message: frame.display_message().to_string(),
+                }),
+                Err(DecodeError::UnexpectedEof) => break,
+                // Leave the rest for the next call, in case the decoder can't skip the bad frame
+                Err(DecodeError::Malformed) => {
+                    self.malformed += 1;
+                    break;
+                }
+            }
+        }
+        lines
+    }
+
+    /// Read RTT up channel `channel` and decode what the target has written to it
+    pub fn poll<T>(
+        &mut self,
+        mem: &mut MemAP<T>,
+        rtt: &Rtt,
+        channel: usize,
+    ) -> Result<Vec<LogLine>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let data = rtt.read(mem, channel)?;
+        Ok(self.decode(&data))
+    }
+}
@@ -0,0 +1,142 @@
+//! Target description files, enabled with the `description` feature.  A description records the
+//! debug infrastructure of a board, so tools can be pointed at a file instead of being given the
+//! same addresses on every command line.  Files may be TOML:
+//!
+//! ```toml
+//! name = "zcu102"
+//! idcode = 0x5ba00477
+//! mem_ap = 0
+//! debug_ap = 1
+//! rom_base = 0x80000000
+//! quirks = ["no-cti-gate"]
+//!
+//! [[cores]]
+//! name = "a53-0"
+//! debug_base = 0x80410000
+//! cti_base = 0x80420000
+//! ```
+//!
+//! or the equivalent YAML.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::soc::Soc;
+
+/// Error loading a target description
+#[derive(Debug)]
+pub enum DescriptionError {
+    Io(io::Error),
+    /// The file isn't valid TOML or YAML, or doesn't match the expected layout
+    Parse(String),
+    /// The file extension isn't .toml, .yaml or .yml
+    UnknownFormat,
+}
+
+impl fmt::Display for DescriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptionError::Io(e) => write!(f, "{}", e),
+            DescriptionError::Parse(e) => write!(f, "bad target description: {}", e),
+            DescriptionError::UnknownFormat => write!(f, "target description must be TOML or YAML"),
+        }
+    }
+}
+
+impl std::error::Error for DescriptionError {}
+
+impl From<io::Error> for DescriptionError {
+    fn from(e: io::Error) -> Self {
+        DescriptionError::Io(e)
+    }
+}
+
+/// The debug registers of one core
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoreDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Address of the core's external debug registers
+    pub debug_base: u32,
+    /// Address of the core's CTI
+    pub cti_base: u32,
+}
+
+/// Description of a target's debug infrastructure
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Expected JTAG IDCODE of the debug port
+    #[serde(default)]
+    pub idcode: Option<u32>,
+    /// AP used for system memory
+    #[serde(default)]
+    pub mem_ap: u32,
+    /// AP used for the debug components, if it is different from `mem_ap`
+    #[serde(default)]
+    pub debug_ap: Option<u32>,
+    /// Address of the top-level ROM table
+    #[serde(default)]
+    pub rom_base: Option<u32>,
+    #[serde(default)]
+    pub cores: Vec<CoreDescription>,
+    /// Free-form workarounds needed by the target, interpreted by the tools using it
+    #[serde(default)]
+    pub quirks: Vec<String>,
+}
+
+impl TargetDescription {
+    pub fn from_toml(text: &str) -> Result<Self, DescriptionError> {
+        toml::from_str(text).map_err(|e| DescriptionError::Parse(e.to_string()))
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, DescriptionError> {
+        serde_yaml::from_str(text).map_err(|e| DescriptionError::Parse(e.to_string()))
+    }
+
+    /// Load a description from `path`, choosing the format from the file extension
+    pub fn load(path: &Path) -> Result<Self, DescriptionError> {
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            Some("yaml") | Some("yml") => Self::from_yaml(&text),
+            _ => Err(DescriptionError::UnknownFormat),
+        }
+    }
+
+    /// Return the AP to use for the debug components
+    pub fn debug_ap(&self) -> u32 {
+        self.debug_ap.unwrap_or(self.mem_ap)
+    }
+
+    pub fn has_quirk(&self, quirk: &str) -> bool {
+        self.quirks.iter().any(|q| q == quirk)
+    }
+}
+
+impl From<&Soc> for TargetDescription {
+    fn from(soc: &Soc) -> Self {
+        Self {
+            name: Some(soc.name.to_string()),
+            idcode: Some(soc.idcode),
+            mem_ap: soc.mem_ap,
+            debug_ap: Some(soc.debug_ap),
+            rom_base: Some(soc.rom_base),
+            cores: soc
+                .cores
+                .iter()
+                .map(|c| CoreDescription {
+                    name: None,
+                    debug_base: c.debug_base,
+                    cti_base: c.cti_base,
+                })
+                .collect(),
+            quirks: vec![],
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! A snapshot of the DAP's state for bug reports.  `MemAP::dump_state` reads the DP registers
+//! and the registers of the MEM-AP, and the result prints as one register per line.  Each
+//! register is read separately, so a register that can't be read doesn't hide the others.
+
+use std::fmt;
+
+use crate::{CtrlStat, DPReg, MemAP, MemAPReg, Port, Transport};
+
+/// DPIDR is read at the address of ABORT
+const DPIDR: u8 = 0;
+
+/// DP and AP registers read by `MemAP::dump_state`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDump {
+    pub dpidr: Result<u32, u8>,
+    pub ctrl_stat: Result<CtrlStat, u8>,
+    pub select: Result<u32, u8>,
+    pub apsel: u32,
+    pub csw: Result<u32, u8>,
+    pub tar: Result<u32, u8>,
+    pub idr: Result<u32, u8>,
+    pub base: Result<u32, u8>,
+    pub cfg: Result<u32, u8>,
+    /// CSW and TAR as cached by the `MemAP`, which should match the registers
+    pub cached_csw: u32,
+    pub cached_tar: u32,
+}
+
+/// Format the value of a register, or the error reading it
+fn value(f: &mut fmt::Formatter, name: &str, val: Result<u32, u8>) -> fmt::Result {
+    match val {
+        Ok(val) => write!(f, "{:<10} 0x{:08x}", name, val),
+        Err(e) => write!(f, "{:<10} error {}", name, e),
+    }
+}
+
+impl fmt::Display for StateDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        value(f, "DPIDR", self.dpidr)?;
+        writeln!(f)?;
+        value(f, "CTRL/STAT", self.ctrl_stat.map(u32::from))?;
+        if let Ok(cs) = self.ctrl_stat {
+            let flags = [
+                (cs.csyspwrupack, "CSYSPWRUPACK"),
+                (cs.csyspwrupreq, "CSYSPWRUPREQ"),
+                (cs.cdbgpwrupack, "CDBGPWRUPACK"),
+                (cs.cdbgpwrupreq, "CDBGPWRUPREQ"),
+                (cs.cdbgrstack, "CDBGRSTACK"),
+                (cs.cdbgrstreq, "CDBGRSTREQ"),
+                (cs.wdataerr, "WDATAERR"),
+                (cs.readok, "READOK"),
+                (cs.stickyerr, "STICKYERR"),
+                (cs.stickycmp, "STICKYCMP"),
+                (cs.stickyorun, "STICKYORUN"),
+                (cs.orundetect, "ORUNDETECT"),
+            ];
+            for (_, name) in flags.iter().filter(|(set, _)| *set) {
+                write!(f, " {}", name)?;
+            }
+        }
+        writeln!(f)?;
+        value(f, "SELECT", self.select)?;
+        writeln!(f)?;
+        writeln!(f, "AP {}", self.apsel)?;
+        value(f, "CSW", self.csw)?;
+        if self.csw.is_ok_and(|csw| csw != self.cached_csw) {
+            write!(f, " (cached 0x{:08x})", self.cached_csw)?;
+        }
+        writeln!(f)?;
+        value(f, "TAR", self.tar)?;
+        if self.tar.is_ok_and(|tar| tar != self.cached_tar) {
+            write!(f, " (cached 0x{:08x})", self.cached_tar)?;
+        }
+        writeln!(f)?;
+        value(f, "IDR", self.idr)?;
+        writeln!(f)?;
+        value(f, "BASE", self.base)?;
+        writeln!(f)?;
+        value(f, "CFG", self.cfg)?;
+        writeln!(f)
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Read the DP registers and the registers of this MEM-AP, for diagnostics.  The AP is
+    /// selected before SELECT is read, so SELECT shows the value written for this AP.  Nothing
+    /// is written apart from SELECT, and the `MemAP`'s cached CSW and TAR are left alone.
+    pub fn dump_state(&mut self) -> StateDump {
+        let apsel = self.apsel;
+        let mut adi = self.adi.borrow_mut();
+        let dpidr = adi.read_adi(apsel, Port::DP, DPIDR);
+        let ctrl_stat = adi.read_ctrl_stat();
+        let select = adi.read_adi(apsel, Port::DP, DPReg::Select as u8);
+        let mut ap = |reg: MemAPReg| adi.read_adi(apsel, Port::AP, reg as u8);
+        StateDump {
+            dpidr,
+            ctrl_stat,
+            select,
+            apsel,
+            csw: ap(MemAPReg::CSW),
+            tar: ap(MemAPReg::TAR),
+            idr: ap(MemAPReg::IDR),
+            base: ap(MemAPReg::Base1),
+            cfg: ap(MemAPReg::CFG),
+            cached_csw: self.csw,
+            cached_tar: self.tar,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Symbol lookup in ELF files, enabled with the `elf` feature.  Used to symbolize addresses read
+//! from the target and to find the addresses of variables by name, and to find the contents of
+//! the loadable segments.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use object::elf::{FileHeader32, FileHeader64, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::{Endianness, FileKind, Object, ObjectSymbol, SymbolKind};
+
+/// Error loading an ELF file
+#[derive(Debug)]
+pub enum ElfError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "{}", e),
+            ElfError::Parse(e) => write!(f, "bad ELF file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<io::Error> for ElfError {
+    fn from(e: io::Error) -> Self {
+        ElfError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// True for functions, false for data
+    pub text: bool,
+}
+
+/// The symbol table of an ELF file
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        let file = object::File::parse(data).map_err(|e| ElfError::Parse(e.to_string()))?;
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .filter(|s| matches!(s.kind(), SymbolKind::Text | SymbolKind::Data))
+            .filter_map(|s| {
+                Some(Symbol {
+                    name: s.name().ok()?.to_string(),
+                    address: s.address(),
+                    size: s.size(),
+                    text: s.kind() == SymbolKind::Text,
+                })
+            })
+            .filter(|s| !s.name.is_empty())
+            .collect();
+        symbols.sort_by_key(|s| s.address);
+        Ok(Self { symbols })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ElfError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// The symbols, in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Return the symbol containing `addr` and the offset of `addr` from its start
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let i = self.symbols.partition_point(|s| s.address <= addr);
+        let sym = self.symbols[..i]
+            .iter()
+            .rev()
+            .find(|s| addr < s.address + s.size.max(1))?;
+        Some((sym, addr - sym.address))
+    }
+
+    /// Return the address of the symbol called `name`
+    pub fn address(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.address)
+    }
+
+    /// Format `addr` as `symbol+offset`, or as a bare address if no symbol contains it
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, offset)) => format!("{}+{:#x}", sym.name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// The contents of a loadable segment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Physical load address
+    pub address: u64,
+    /// Address the segment is linked to run at
+    pub virtual_address: u64,
+    /// The bytes present in the file.  The zero-filled part of the segment is not included.
+    pub data: Vec<u8>,
+}
+
+fn parse_segments<Elf>(data: &[u8]) -> Result<Vec<Segment>, ElfError>
+where
+    Elf: FileHeader<Endian = Endianness>,
+{
+    let parse_error = |e: object::Error| ElfError::Parse(e.to_string());
+    let header = Elf::parse(data).map_err(parse_error)?;
+    let endian = header.endian().map_err(parse_error)?;
+    let mut segments = vec![];
+    for ph in header.program_headers(endian, data).map_err(parse_error)? {
+        if ph.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        let bytes = ph
+            .data(endian, data)
+            .map_err(|_| ElfError::Parse("segment data out of range".to_string()))?;
+        if !bytes.is_empty() {
+            segments.push(Segment {
+                address: ph.p_paddr(endian).into(),
+                virtual_address: ph.p_vaddr(endian).into(),
+                data: bytes.to_vec(),
+            });
+        }
+    }
+    Ok(segments)
+}
+
+/// Return the loadable segments of an ELF file, at their physical addresses
+pub fn load_segments(data: &[u8]) -> Result<Vec<Segment>, ElfError> {
+    match FileKind::parse(data) {
+        Ok(FileKind::Elf32) => parse_segments::<FileHeader32<Endianness>>(data),
+        Ok(FileKind::Elf64) => parse_segments::<FileHeader64<Endianness>>(data),
+        Ok(_) => Err(ElfError::Parse("not an ELF file".to_string())),
+        Err(e) => Err(ElfError::Parse(e.to_string())),
+    }
+}
+
+/// Return the entry point of an ELF file
+pub fn entry_point(data: &[u8]) -> Result<u64, ElfError> {
+    let file = object::File::parse(data).map_err(|e| ElfError::Parse(e.to_string()))?;
+    Ok(file.entry())
+}
@@ -0,0 +1,439 @@
+//! Decoding of ETMv4 instruction trace from AArch64 cores.  The trace only records the outcome
+//! of branches (atoms) and the targets of indirect branches and exceptions, so the decoder
+//! follows the program through an image of its code, reporting each range of instructions
+//! that was executed.
+//!
+//! The trace must be the byte stream of a single trace source, such as the output of a
+//! `trace::Deformatter` for the ETM's trace ID.  Only what a typical Cortex-A configuration
+//! generates is handled: A64 instructions, no speculation, conditional instruction or Q
+//! packets, and 8-bit VMIDs.  Unsupported packets make the decoder drop the trace up to the next
+//! synchronization point.
+
+use std::ops::Range;
+
+/// Number of zero bytes before the final 0x80 of an A-sync packet
+const ASYNC_ZEROS: usize = 11;
+/// Longest run of instructions followed without finding a branch
+const MAX_WALK: usize = 0x10000;
+
+/// ETMv4 trace unit registers with named fields, relative to the trace unit's base, for
+/// programming the ETM whose trace is decoded here
+pub mod regs {
+    use crate::register;
+
+    register! {
+        /// Programming Control Register
+        pub struct TrcPrgctlr @ 0x004 {
+            /// Trace unit enable
+            en, set_en: 0;
+        }
+    }
+
+    register! {
+        /// Trace Status Register
+        pub struct TrcStatr @ 0x00c {
+            idle, set_idle: 0;
+            /// Programmers' model stable
+            pmstable, set_pmstable: 1;
+        }
+    }
+
+    register! {
+        /// Trace Configuration Register
+        pub struct TrcConfigr @ 0x010 {
+            /// Branch broadcast
+            bb, set_bb: 3;
+            /// Cycle counting
+            cci, set_cci: 4;
+            /// Context ID tracing
+            cid, set_cid: 6;
+            /// VMID tracing
+            vmid, set_vmid: 7;
+            cond, set_cond: 8..=10;
+            /// Global timestamps
+            ts, set_ts: 11;
+            /// Return stack
+            rs, set_rs: 12;
+            /// Q elements
+            qe, set_qe: 13..=14;
+            vmidopt, set_vmidopt: 15;
+            da, set_da: 16;
+            dv, set_dv: 17;
+        }
+    }
+
+    register! {
+        /// Trace ID Register
+        pub struct TrcTraceidr @ 0x040 {
+            traceid, set_traceid: 0..=6;
+        }
+    }
+
+    register! {
+        /// OS Lock Access Register
+        pub struct TrcOslar @ 0x300 {
+            oslk, set_oslk: 0;
+        }
+    }
+}
+
+/// How the instruction ending a run of instructions changes the flow of execution
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Waypoint {
+    /// A branch to a target known from the instruction
+    Direct(u64),
+    /// A branch to a target given by an address packet
+    Indirect,
+}
+
+/// Classify the A64 instruction `insn` at `pc`, returning None if it isn't a branch
+fn waypoint(pc: u64, insn: u32) -> Option<Waypoint> {
+    let offset = |bits: u32, shift: u32| {
+        let imm = (insn >> shift) & ((1 << bits) - 1);
+        // Sign extend the word offset and scale it to bytes
+        let imm = ((imm << (32 - bits)) as i32 >> (32 - bits)) as i64 * 4;
+        Waypoint::Direct(pc.wrapping_add(imm as u64))
+    };
+    if insn & 0x7c00_0000 == 0x1400_0000 {
+        // B, BL
+        Some(offset(26, 0))
+    } else if insn & 0xff00_0010 == 0x5400_0000 || insn & 0x7e00_0000 == 0x3400_0000 {
+        // B.cond, CBZ, CBNZ
+        Some(offset(19, 5))
+    } else if insn & 0x7e00_0000 == 0x3600_0000 {
+        // TBZ, TBNZ
+        Some(offset(14, 5))
+    } else if insn & 0xfe00_0000 == 0xd600_0000 {
+        // BR, BLR, RET, ERET and their authenticating forms
+        Some(Waypoint::Indirect)
+    } else {
+        None
+    }
+}
+
+/// Return the length of a field of up to `max` bytes starting at `start`, where each byte but
+/// the last has bit 7 set.  Returns None if the field isn't complete.
+fn field_len(buf: &[u8], start: usize, max: usize) -> Option<usize> {
+    for i in 0..max {
+        if buf.get(start + i)? & 0x80 == 0 {
+            return Some(i + 1);
+        }
+    }
+    Some(max)
+}
+
+/// Length of the context information at `start` of an address with context or context packet
+fn context_len(buf: &[u8], start: usize) -> Option<usize> {
+    let info = *buf.get(start)?;
+    let mut len = 1;
+    if info & 0x40 != 0 {
+        // VMID
+        len += 1;
+    }
+    if info & 0x80 != 0 {
+        // CONTEXTID
+        len += 4;
+    }
+    Some(len)
+}
+
+/// Counts of trace that couldn't be decoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Packets decoded
+    pub packets: u64,
+    /// Atoms which couldn't be followed, because the address was unknown or the code wasn't in
+    /// the image
+    pub lost_atoms: u64,
+    /// Times the decoder dropped trace to resynchronize on an unsupported packet
+    pub resyncs: u64,
+}
+
+/// A streaming ETMv4 decoder.  `code` returns the instruction at an address, or None if it
+/// isn't in the image.
+pub struct EtmDecoder<F> {
+    code: F,
+    synced: bool,
+    buf: Vec<u8>,
+    /// Address history, newest first, used by short and exact match address packets
+    history: [u64; 3],
+    /// Address of the next instruction to execute, if known
+    addr: Option<u64>,
+    /// An exception packet was seen, so the next address is its preferred return address
+    exception: bool,
+    stats: DecodeStats,
+}
+
+impl<F> EtmDecoder<F>
+where
+    F: FnMut(u64) -> Option<u32>,
+{
+    pub fn new(code: F) -> Self {
+        Self {
+            code,
+            synced: false,
+            buf: vec![],
+            history: [0; 3],
+            addr: None,
+            exception: false,
+            stats: DecodeStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> DecodeStats {
+        self.stats
+    }
+
+    /// Add `data` to the stream, calling `executed` with each range of instructions executed
+    pub fn decode(&mut self, data: &[u8], mut executed: impl FnMut(Range<u64>)) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+        loop {
+            if !self.synced {
+                let sync = self.buf[pos..].windows(ASYNC_ZEROS + 1).position(|w| {
+                    w[ASYNC_ZEROS] == 0x80 && w[..ASYNC_ZEROS].iter().all(|&b| b == 0)
+                });
+                match sync {
+                    Some(i) => {
+                        pos += i + ASYNC_ZEROS + 1;
+                        self.synced = true;
+                        self.addr = None;
+                        self.exception = false;
+                    }
+                    None => {
+                        // Keep enough to find a sync split across calls
+                        pos = pos.max(self.buf.len().saturating_sub(ASYNC_ZEROS));
+                        break;
+                    }
+                }
+            }
+            match self.packet(pos, &mut executed) {
+                Some(0) => {
+                    self.stats.resyncs += 1;
+                    self.synced = false;
+                    pos += 1;
+                }
+                Some(len) => {
+                    self.stats.packets += 1;
+                    pos += len;
+                }
+                None => break,
+            }
+        }
+        self.buf.drain(..pos);
+    }
+
+    /// Decode the packet at `pos`, returning its length, 0 if it isn't supported, or None if it
+    /// isn't complete
+    fn packet(&mut self, pos: usize, executed: &mut impl FnMut(Range<u64>)) -> Option<usize> {
+        let buf = &self.buf[pos..];
+        let header = *buf.first()?;
+        match header {
+            0x00 => match *buf.get(1)? {
+                // A-sync
+                0x00 => {
+                    let len = buf.iter().position(|&b| b != 0)?;
+                    if buf[len] != 0x80 {
+                        return Some(0);
+                    }
+                    Some(len + 1)
+                }
+                // Discard, overflow
+                0x03 | 0x05 => {
+                    self.addr = None;
+                    Some(2)
+                }
+                _ => Some(0),
+            },
+            // Trace info
+            0x01 => {
+                let plctl = *buf.get(1)?;
+                let mut len = 1 + field_len(buf, 1, 1)?;
+                for section in 0..4 {
+                    if plctl & 1 << section != 0 {
+                        len += field_len(buf, len, 5)?;
+                    }
+                }
+                self.addr = None;
+                Some(len)
+            }
+            // Timestamp, with a cycle count if bit 0 is set
+            0x02 | 0x03 => {
+                let mut len = 1 + field_len(buf, 1, 9)?;
+                if header & 1 != 0 {
+                    len += field_len(buf, len, 3)?;
+                }
+                Some(len)
+            }
+            // Trace on
+            0x04 => {
+                self.addr = None;
+                Some(1)
+            }
+            // Function return, exception return, ETE timestamp marker
+            0x05 | 0x07 | 0x88 => Some(1),
+            0x06 => {
+                let len = 1 + field_len(buf, 1, 2)?;
+                self.exception = true;
+                Some(len)
+            }
+            // Cycle count formats 2, 1 and 3
+            0x0c | 0x0d => Some(2),
+            0x0e => Some(1 + field_len(buf, 1, 3)?),
+            0x0f..=0x1f => Some(1),
+            // Data synchronization markers
+            0x20..=0x2c => Some(1),
+            // Commit
+            0x2d => Some(1 + field_len(buf, 1, 5)?),
+            // Event
+            0x70..=0x7f => Some(1),
+            // Context
+            0x80 => Some(1),
+            0x81 => Some(1 + context_len(buf, 1)?),
+            // Address with context, 32-bit and 64-bit, IS0
+            0x82 | 0x85 => {
+                let n = if header == 0x82 { 4 } else { 8 };
+                let len = 1 + n + context_len(buf, 1 + n)?;
+                if buf.len() < len {
+                    return None;
+                }
+                let addr = self.long_address(&buf[1..1 + n]);
+                self.address(addr, executed);
+                Some(len)
+            }
+            // Exact match
+            0x90..=0x92 => {
+                let addr = self.history[(header & 3) as usize];
+                self.address(addr, executed);
+                Some(1)
+            }
+            // Short address, IS0
+            0x95 => {
+                let b1 = *buf.get(1)?;
+                let mut addr = self.history[0] & !0x1fc | (b1 as u64 & 0x7f) << 2;
+                let mut len = 2;
+                if b1 & 0x80 != 0 {
+                    addr = addr & !0x1fe00 | (*buf.get(2)? as u64) << 9;
+                    len = 3;
+                }
+                self.address(addr, executed);
+                Some(len)
+            }
+            // Long address, 32-bit and 64-bit, IS0
+            0x9a | 0x9d => {
+                let n = if header == 0x9a { 4 } else { 8 };
+                if buf.len() < 1 + n {
+                    return None;
+                }
+                let addr = self.long_address(&buf[1..1 + n]);
+                self.address(addr, executed);
+                Some(1 + n)
+            }
+            // Atom format 6: between 3 and 23 E atoms, followed by an E or N atom
+            0xc0..=0xd4 | 0xe0..=0xf4 => {
+                let count = (header & 0x1f) as u32 + 3;
+                let last = if header & 0x20 == 0 { 1 << count } else { 0 };
+                self.atoms(((1 << count) - 1) | last, count + 1, executed);
+                Some(1)
+            }
+            // Atom format 5
+            0xd5..=0xd7 | 0xf5 => {
+                let pattern = match (header >> 3) & 4 | header & 3 {
+                    5 => 0b11110,
+                    1 => 0b00000,
+                    2 => 0b01010,
+                    _ => 0b10101,
+                };
+                self.atoms(pattern, 5, executed);
+                Some(1)
+            }
+            // Atom format 2
+            0xd8..=0xdb => {
+                self.atoms(header as u32 & 3, 2, executed);
+                Some(1)
+            }
+            // Atom format 4
+            0xdc..=0xdf => {
+                let pattern = [0b1110, 0b0000, 0b1010, 0b0101][(header & 3) as usize];
+                self.atoms(pattern, 4, executed);
+                Some(1)
+            }
+            // Atom format 1
+            0xf6 | 0xf7 => {
+                self.atoms(header as u32 & 1, 1, executed);
+                Some(1)
+            }
+            // Atom format 3
+            0xf8..=0xff => {
+                self.atoms(header as u32 & 7, 3, executed);
+                Some(1)
+            }
+            _ => Some(0),
+        }
+    }
+
+    /// Decode the payload of a long address packet, which replaces all but the upper bits of a
+    /// 32-bit address
+    fn long_address(&self, payload: &[u8]) -> u64 {
+        let mut addr = (payload[0] as u64 & 0x7f) << 2
+            | (payload[1] as u64 & 0x7f) << 9
+            | (payload[2] as u64) << 16
+            | (payload[3] as u64) << 24;
+        if payload.len() == 8 {
+            addr |= (u32::from_le_bytes(payload[4..8].try_into().unwrap()) as u64) << 32;
+        } else {
+            addr |= self.history[0] & !0xffff_ffff;
+        }
+        addr
+    }
+
+    fn address(&mut self, addr: u64, executed: &mut impl FnMut(Range<u64>)) {
+        self.history = [addr, self.history[0], self.history[1]];
+        if self.exception {
+            // The instructions before the preferred return address were executed.  The next
+            // address is the exception vector.
+            self.exception = false;
+            if let Some(start) = self.addr.filter(|&a| a < addr) {
+                executed(start..addr);
+            }
+            self.addr = None;
+        } else {
+            self.addr = Some(addr);
+        }
+    }
+
+    /// Follow `count` atoms, oldest in bit 0 of `pattern`, with a set bit for E
+    fn atoms(&mut self, pattern: u32, count: u32, executed: &mut impl FnMut(Range<u64>)) {
+        for i in 0..count {
+            let taken = pattern >> i & 1 != 0;
+            let Some(start) = self.addr else {
+                self.stats.lost_atoms += 1;
+                continue;
+            };
+            let mut pc = start;
+            let mut found = None;
+            for _ in 0..MAX_WALK {
+                let Some(insn) = (self.code)(pc) else { break };
+                found = waypoint(pc, insn);
+                if found.is_some() {
+                    break;
+                }
+                pc += 4;
+            }
+            match found {
+                Some(wp) => {
+                    executed(start..pc + 4);
+                    self.addr = match (taken, wp) {
+                        (false, _) => Some(pc + 4),
+                        (true, Waypoint::Direct(target)) => Some(target),
+                        (true, Waypoint::Indirect) => None,
+                    };
+                }
+                None => {
+                    self.stats.lost_atoms += 1;
+                    self.addr = None;
+                }
+            }
+        }
+    }
+}
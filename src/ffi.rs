@@ -0,0 +1,255 @@
+//! C API, enabled with the `ffi` feature.  See `include/jtag_adi.h` for the declarations.  The
+//! crate is only built as an rlib by default; build the shared library with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! A session is an opaque handle owning the JTAG cable, the debug interface and a single MemAP.
+//! Every function returns 0 on success.  Positive return values are the error codes returned by
+//! the rest of this crate (a JTAG ack, 5 for a sticky error, or a module-specific code such as
+//! `armv8::ERR_LOCKED`).  Negative values are errors from the C API itself.
+//!
+//! Sessions are not thread safe; each one must only be used from one thread at a time.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+use jtag_taps::cable;
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+use crate::armv8::Core;
+use crate::{ArmDebugInterface, MemAP, Transport};
+
+/// A null pointer or otherwise unusable argument was passed
+pub const JTAG_ADI_ERR_INVALID_ARG: c_int = -1;
+/// The cable could not be opened
+pub const JTAG_ADI_ERR_CABLE: c_int = -2;
+/// A run control function was called before `jtag_adi_core_attach`
+pub const JTAG_ADI_ERR_NO_CORE: c_int = -3;
+/// An unexpected internal error occurred.  The session should be closed.
+pub const JTAG_ADI_ERR_INTERNAL: c_int = -4;
+
+/// Opaque session handle
+pub struct Session {
+    mem: Rc<RefCell<MemAP<dyn Transport>>>,
+    core: Option<Core<dyn Transport>>,
+}
+
+fn status(result: Result<(), u8>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as c_int,
+    }
+}
+
+/// Run `f`, making sure a panic doesn't unwind into the caller
+fn guard<F>(f: F) -> c_int
+where
+    F: FnOnce() -> c_int,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(JTAG_ADI_ERR_INTERNAL)
+}
+
+/// Run `f` on the session behind `session`
+fn with_session<F>(session: *mut Session, f: F) -> c_int
+where
+    F: FnOnce(&mut Session) -> c_int,
+{
+    // SAFETY: the caller guarantees `session` is null or a live handle from `jtag_adi_open`
+    match unsafe { session.as_mut() } {
+        Some(session) => guard(|| f(session)),
+        None => JTAG_ADI_ERR_INVALID_ARG,
+    }
+}
+
+/// Open `cable` at `baud`, select TAP `tap_index` and access memory through AP `ap_num`.  On
+/// success the new session is stored in `*out`.
+///
+/// # Safety
+///
+/// `cable` must be a NUL-terminated string and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_open(
+    cable: *const c_char,
+    baud: u32,
+    tap_index: u32,
+    ap_num: u32,
+    out: *mut *mut Session,
+) -> c_int {
+    if cable.is_null() || out.is_null() {
+        return JTAG_ADI_ERR_INVALID_ARG;
+    }
+    let Ok(name) = CStr::from_ptr(cable).to_str() else {
+        return JTAG_ADI_ERR_INVALID_ARG;
+    };
+    *out = ptr::null_mut();
+
+    guard(|| {
+        let Ok(cable) = cable::new_from_string(name, baud) else {
+            return JTAG_ADI_ERR_CABLE;
+        };
+        let jtag = JtagSM::new(cable);
+        let mut taps = Taps::new(jtag);
+        taps.detect();
+
+        // IDCODE instruction
+        let ir = vec![14];
+        taps.select_tap(tap_index as usize, &ir);
+
+        let adi: Rc<RefCell<dyn Transport>> = Rc::new(RefCell::new(ArmDebugInterface::new(taps)));
+        let mem = Rc::new(RefCell::new(MemAP::new(adi, ap_num)));
+        let session = Box::new(Session { mem, core: None });
+        *out = Box::into_raw(session);
+        0
+    })
+}
+
+/// Close a session and release the cable
+///
+/// # Safety
+///
+/// `session` must be null or a handle from `jtag_adi_open` which hasn't been closed.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_close(session: *mut Session) {
+    if !session.is_null() {
+        let session = Box::from_raw(session);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(session)));
+    }
+}
+
+/// Read the word at `addr` into `*value`
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open` and `value` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_read32(
+    session: *mut Session,
+    addr: u32,
+    value: *mut u32,
+) -> c_int {
+    if value.is_null() {
+        return JTAG_ADI_ERR_INVALID_ARG;
+    }
+    with_session(session, |s| match s.mem.borrow_mut().read(addr) {
+        Ok(x) => {
+            *value = x;
+            0
+        }
+        Err(e) => e as c_int,
+    })
+}
+
+/// Write `value` to the word at `addr`
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_write32(session: *mut Session, addr: u32, value: u32) -> c_int {
+    with_session(session, |s| status(s.mem.borrow_mut().write(addr, value)))
+}
+
+/// Read `count` consecutive words starting at `addr` into `buf`
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open` and `buf` must be valid for writes of `count`
+/// words.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_read_block(
+    session: *mut Session,
+    addr: u32,
+    buf: *mut u32,
+    count: usize,
+) -> c_int {
+    if buf.is_null() && count > 0 {
+        return JTAG_ADI_ERR_INVALID_ARG;
+    }
+    with_session(session, |s| {
+        match s.mem.borrow_mut().read_memory(addr, count) {
+            Ok(data) => {
+                ptr::copy_nonoverlapping(data.as_ptr(), buf, count);
+                0
+            }
+            Err(e) => e as c_int,
+        }
+    })
+}
+
+/// Write `count` words from `buf` to consecutive addresses starting at `addr`
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open` and `buf` must be valid for reads of `count`
+/// words.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_write_block(
+    session: *mut Session,
+    addr: u32,
+    buf: *const u32,
+    count: usize,
+) -> c_int {
+    if buf.is_null() && count > 0 {
+        return JTAG_ADI_ERR_INVALID_ARG;
+    }
+    let data = if count > 0 {
+        slice::from_raw_parts(buf, count)
+    } else {
+        &[]
+    };
+    with_session(session, |s| {
+        status(s.mem.borrow_mut().write_memory(addr, data))
+    })
+}
+
+/// Select the ARMv8 core whose external debug registers are at `debug_base` and whose CTI is at
+/// `cti_base` for the run control functions, and unlock its debug registers
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_core_attach(
+    session: *mut Session,
+    debug_base: u32,
+    cti_base: u32,
+) -> c_int {
+    with_session(session, |s| {
+        let mut core = Core::new(s.mem.clone(), debug_base, cti_base);
+        let result = core.unlock();
+        if result.is_ok() {
+            s.core = Some(core);
+        }
+        status(result)
+    })
+}
+
+/// Halt the attached core
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_halt(session: *mut Session) -> c_int {
+    with_session(session, |s| match &mut s.core {
+        Some(core) => status(core.halt()),
+        None => JTAG_ADI_ERR_NO_CORE,
+    })
+}
+
+/// Resume the attached core
+///
+/// # Safety
+///
+/// `session` must be a handle from `jtag_adi_open`.
+#[no_mangle]
+pub unsafe extern "C" fn jtag_adi_resume(session: *mut Session) -> c_int {
+    with_session(session, |s| match &mut s.core {
+        Some(core) => status(core.resume()),
+        None => JTAG_ADI_ERR_NO_CORE,
+    })
+}
@@ -0,0 +1,411 @@
+//! Driver for memory-mapped parallel NOR flash that implements the Common Flash Interface.  The
+//! device's geometry and command set are read with a CFI query, and both the AMD/Fujitsu and
+//! Intel/Sharp command sets are supported, using buffered programming where the device has a
+//! write buffer.
+//!
+//! The device must be a single x16 part on a 16-bit bus, which is the common arrangement on SoC
+//! boards, mapped at `base` with the MEM-AP able to make halfword accesses to it.
+
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{FlashDriver, ERR_ALIGNMENT, ERR_ERASE, ERR_NO_FLASH, ERR_PROGRAM, ERR_PROTECTED};
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+const PROGRAM_TIMEOUT: Duration = Duration::from_secs(1);
+
+// CFI query, addresses in device words
+const QUERY_ADDR: u32 = 0x55;
+const QUERY_CMD: u16 = 0x98;
+const QUERY_QRY: u32 = 0x10;
+const QUERY_COMMAND_SET: u32 = 0x13;
+const QUERY_DEVICE_SIZE: u32 = 0x27;
+const QUERY_WRITE_BUFFER: u32 = 0x2a;
+const QUERY_NUM_REGIONS: u32 = 0x2c;
+const QUERY_REGIONS: u32 = 0x2d;
+
+// AMD command set
+const AMD_UNLOCK1: u32 = 0x555;
+const AMD_UNLOCK2: u32 = 0x2aa;
+const AMD_RESET: u16 = 0xf0;
+const AMD_ERASE_SETUP: u16 = 0x80;
+const AMD_SECTOR_ERASE: u16 = 0x30;
+const AMD_PROGRAM: u16 = 0xa0;
+const AMD_WRITE_BUFFER: u16 = 0x25;
+const AMD_BUFFER_CONFIRM: u16 = 0x29;
+const AMD_WRITE_BUFFER_ABORT_RESET: u16 = 0xf0;
+const AMD_DQ6_TOGGLE: u16 = 1 << 6;
+const AMD_DQ5_TIMEOUT: u16 = 1 << 5;
+const AMD_DQ1_ABORT: u16 = 1 << 1;
+
+// Intel command set
+const INTEL_READ_ARRAY: u16 = 0xff;
+const INTEL_READ_STATUS: u16 = 0x70;
+const INTEL_CLEAR_STATUS: u16 = 0x50;
+const INTEL_ERASE: u16 = 0x20;
+const INTEL_CONFIRM: u16 = 0xd0;
+const INTEL_PROGRAM: u16 = 0x40;
+const INTEL_WRITE_BUFFER: u16 = 0xe8;
+const INTEL_LOCK_SETUP: u16 = 0x60;
+const INTEL_SR_READY: u16 = 1 << 7;
+const INTEL_SR_ERASE: u16 = 1 << 5;
+const INTEL_SR_PROGRAM: u16 = 1 << 4;
+const INTEL_SR_VPP: u16 = 1 << 3;
+const INTEL_SR_LOCKED: u16 = 1 << 1;
+
+/// Primary vendor command set, from the CFI query
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandSet {
+    /// Intel/Sharp extended or standard command set
+    Intel,
+    /// AMD/Fujitsu standard command set
+    Amd,
+}
+
+/// A run of equally sized erase blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EraseRegion {
+    pub blocks: u32,
+    /// Size of each block in bytes
+    pub block_size: u32,
+}
+
+/// Device parameters read from the CFI query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CfiInfo {
+    pub command_set: CommandSet,
+    /// Device size in bytes
+    pub size: u32,
+    /// Size of the write buffer in bytes, 0 if there isn't one
+    pub write_buffer: u32,
+    /// Erase regions in address order
+    pub regions: Vec<EraseRegion>,
+}
+
+/// A CFI NOR flash device
+pub struct CfiFlash<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+    info: CfiInfo,
+}
+
+impl<T> CfiFlash<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Query the device mapped at `base`.  Returns `ERR_NO_FLASH` if it doesn't answer the CFI
+    /// query, uses an unsupported command set, or reports a geometry that doesn't fit in the
+    /// address space above `base`.
+    pub fn probe(mem: Rc<RefCell<MemAP<T>>>, base: u32) -> Result<Self, u8> {
+        let mut flash = Self {
+            mem,
+            base,
+            info: CfiInfo {
+                command_set: CommandSet::Amd,
+                size: 0,
+                write_buffer: 0,
+                regions: vec![],
+            },
+        };
+        // Return to read array mode first, in case the device was left in another mode
+        flash.command(0, AMD_RESET)?;
+        flash.command(0, INTEL_READ_ARRAY)?;
+
+        flash.command(QUERY_ADDR, QUERY_CMD)?;
+        let info = flash.read_query();
+        flash.command(0, AMD_RESET)?;
+        flash.command(0, INTEL_READ_ARRAY)?;
+        let info = info?;
+        // Checked here so that the address arithmetic elsewhere can't overflow
+        if base.checked_add(info.size).is_none() {
+            return Err(ERR_NO_FLASH);
+        }
+        flash.info = info;
+        Ok(flash)
+    }
+
+    fn read_query(&mut self) -> Result<CfiInfo, u8> {
+        let mut byte = |offset| Ok::<_, u8>(self.read_word(offset)? as u8 as u32);
+        if [byte(QUERY_QRY)?, byte(QUERY_QRY + 1)?, byte(QUERY_QRY + 2)?] != [0x51, 0x52, 0x59] {
+            return Err(ERR_NO_FLASH);
+        }
+        let command_set = match byte(QUERY_COMMAND_SET)? | byte(QUERY_COMMAND_SET + 1)? << 8 {
+            1 | 3 => CommandSet::Intel,
+            2 => CommandSet::Amd,
+            _ => return Err(ERR_NO_FLASH),
+        };
+        let size = 1u32
+            .checked_shl(byte(QUERY_DEVICE_SIZE)?)
+            .ok_or(ERR_NO_FLASH)?;
+        let buffer_bits = byte(QUERY_WRITE_BUFFER)? | byte(QUERY_WRITE_BUFFER + 1)? << 8;
+        let write_buffer = if buffer_bits == 0 {
+            0
+        } else {
+            1u32.checked_shl(buffer_bits).ok_or(ERR_NO_FLASH)?
+        };
+
+        let mut regions = vec![];
+        let mut total = 0u32;
+        for i in 0..byte(QUERY_NUM_REGIONS)? {
+            let at = QUERY_REGIONS + 4 * i;
+            let blocks = (byte(at)? | byte(at + 1)? << 8) + 1;
+            let units = byte(at + 2)? | byte(at + 3)? << 8;
+            // A size field of 0 means 128 bytes, otherwise it is in units of 256 bytes
+            let block_size = if units == 0 { 128 } else { units * 256 };
+            // The regions must fit in the device
+            total = blocks
+                .checked_mul(block_size)
+                .and_then(|len| total.checked_add(len))
+                .filter(|&total| total <= size)
+                .ok_or(ERR_NO_FLASH)?;
+            regions.push(EraseRegion { blocks, block_size });
+        }
+        Ok(CfiInfo {
+            command_set,
+            size,
+            write_buffer,
+            regions,
+        })
+    }
+
+    pub fn info(&self) -> &CfiInfo {
+        &self.info
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Read the device word at word offset `offset`
+    fn read_word(&mut self, offset: u32) -> Result<u16, u8> {
+        self.mem.borrow_mut().read_halfword(self.base + 2 * offset)
+    }
+
+    /// Write `cmd` to word offset `offset`
+    fn command(&mut self, offset: u32, cmd: u16) -> Result<(), u8> {
+        self.mem
+            .borrow_mut()
+            .write_halfword(self.base + 2 * offset, cmd)
+    }
+
+    fn amd_unlock(&mut self) -> Result<(), u8> {
+        self.command(AMD_UNLOCK1, 0xaa)?;
+        self.command(AMD_UNLOCK2, 0x55)
+    }
+
+    /// Wait for an AMD embedded operation to finish.  DQ6 toggles on every read while the
+    /// operation is running.
+    fn amd_wait(&mut self, offset: u32, timeout: Duration, err: u8) -> Result<(), u8> {
+        let start = Instant::now();
+        loop {
+            let a = self.read_word(offset)?;
+            let b = self.read_word(offset)?;
+            if (a ^ b) & AMD_DQ6_TOGGLE == 0 {
+                return Ok(());
+            }
+            if b & (AMD_DQ5_TIMEOUT | AMD_DQ1_ABORT) != 0 {
+                // Check again in case the operation finished between the reads
+                let c = self.read_word(offset)?;
+                if (b ^ c) & AMD_DQ6_TOGGLE == 0 {
+                    return Ok(());
+                }
+                self.amd_unlock()?;
+                self.command(AMD_UNLOCK1, AMD_WRITE_BUFFER_ABORT_RESET)?;
+                return Err(err);
+            }
+            if start.elapsed() > timeout {
+                self.command(0, AMD_RESET)?;
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+
+    /// Wait for an Intel operation to finish and check its status
+    fn intel_wait(&mut self, offset: u32, timeout: Duration) -> Result<(), u8> {
+        let start = Instant::now();
+        self.command(offset, INTEL_READ_STATUS)?;
+        let status = loop {
+            let status = self.read_word(offset)?;
+            if status & INTEL_SR_READY != 0 {
+                break status;
+            }
+            if start.elapsed() > timeout {
+                self.command(offset, INTEL_READ_ARRAY)?;
+                return Err(ERR_TIMEOUT);
+            }
+        };
+        self.command(offset, INTEL_CLEAR_STATUS)?;
+        self.command(offset, INTEL_READ_ARRAY)?;
+        if status & INTEL_SR_LOCKED != 0 {
+            Err(ERR_PROTECTED)
+        } else if status & INTEL_SR_ERASE != 0 {
+            Err(ERR_ERASE)
+        } else if status & (INTEL_SR_PROGRAM | INTEL_SR_VPP) != 0 {
+            Err(ERR_PROGRAM)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Clear the lock bit of an Intel block.  On many parts this unlocks every block.
+    fn intel_unlock(&mut self, offset: u32) -> Result<(), u8> {
+        self.command(offset, INTEL_LOCK_SETUP)?;
+        self.command(offset, INTEL_CONFIRM)?;
+        self.intel_wait(offset, ERASE_TIMEOUT)
+    }
+
+    /// Program one device word at word offset `offset`
+    fn program_word(&mut self, offset: u32, val: u16) -> Result<(), u8> {
+        match self.info.command_set {
+            CommandSet::Amd => {
+                self.amd_unlock()?;
+                self.command(AMD_UNLOCK1, AMD_PROGRAM)?;
+                self.command(offset, val)?;
+                self.amd_wait(offset, PROGRAM_TIMEOUT, ERR_PROGRAM)
+            }
+            CommandSet::Intel => {
+                self.command(offset, INTEL_PROGRAM)?;
+                self.command(offset, val)?;
+                self.intel_wait(offset, PROGRAM_TIMEOUT)
+            }
+        }
+    }
+
+    /// Program `data` starting at word offset `offset` through the write buffer.  The words
+    /// must all lie in one write buffer page and one erase block.
+    fn program_buffer(&mut self, offset: u32, data: &[u16]) -> Result<(), u8> {
+        let block = self.block_at(self.base + 2 * offset).ok_or(ERR_ALIGNMENT)?;
+        let block = (block.start - self.base) / 2;
+        let count = data.len() as u16 - 1;
+        match self.info.command_set {
+            CommandSet::Amd => {
+                self.amd_unlock()?;
+                self.command(block, AMD_WRITE_BUFFER)?;
+                self.command(block, count)?;
+            }
+            CommandSet::Intel => {
+                // Wait for a buffer to be available
+                let start = Instant::now();
+                loop {
+                    self.command(block, INTEL_WRITE_BUFFER)?;
+                    if self.read_word(block)? & INTEL_SR_READY != 0 {
+                        break;
+                    }
+                    if start.elapsed() > PROGRAM_TIMEOUT {
+                        self.command(block, INTEL_READ_ARRAY)?;
+                        return Err(ERR_TIMEOUT);
+                    }
+                }
+                self.command(block, count)?;
+            }
+        }
+        for (i, &val) in data.iter().enumerate() {
+            self.command(offset + i as u32, val)?;
+        }
+        let last = offset + count as u32;
+        match self.info.command_set {
+            CommandSet::Amd => {
+                self.command(block, AMD_BUFFER_CONFIRM)?;
+                self.amd_wait(last, PROGRAM_TIMEOUT, ERR_PROGRAM)
+            }
+            CommandSet::Intel => {
+                self.command(block, INTEL_CONFIRM)?;
+                self.intel_wait(block, PROGRAM_TIMEOUT)
+            }
+        }
+    }
+}
+
+impl<T> FlashDriver for CfiFlash<T>
+where
+    T: Transport + ?Sized,
+{
+    fn region(&self) -> Range<u32> {
+        self.base..self.base + self.info.size
+    }
+
+    /// Return the address range of the erase block containing `addr`
+    fn block_at(&self, addr: u32) -> Option<Range<u32>> {
+        let mut start = self.base;
+        for region in &self.info.regions {
+            let end = start + region.blocks * region.block_size;
+            if (start..end).contains(&addr) {
+                let block = start + (addr - start) / region.block_size * region.block_size;
+                return Some(block..block + region.block_size);
+            }
+            start = end;
+        }
+        None
+    }
+
+    /// Erase the block containing `addr`
+    fn erase_block(&mut self, addr: u32) -> Result<(), u8> {
+        let block = self.block_at(addr).ok_or(ERR_ALIGNMENT)?;
+        let offset = (block.start - self.base) / 2;
+        match self.info.command_set {
+            CommandSet::Amd => {
+                self.amd_unlock()?;
+                self.command(AMD_UNLOCK1, AMD_ERASE_SETUP)?;
+                self.amd_unlock()?;
+                self.command(offset, AMD_SECTOR_ERASE)?;
+                self.amd_wait(offset, ERASE_TIMEOUT, ERR_ERASE)
+            }
+            CommandSet::Intel => {
+                self.intel_unlock(offset)?;
+                self.command(offset, INTEL_ERASE)?;
+                self.command(offset, INTEL_CONFIRM)?;
+                self.intel_wait(offset, ERASE_TIMEOUT)
+            }
+        }
+    }
+
+    /// Program `data` at `addr`, which must already be erased.  Both must be halfword aligned,
+    /// and lie within the device.
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), u8> {
+        if addr & 1 != 0 || data.len() & 1 != 0 {
+            return Err(ERR_ALIGNMENT);
+        }
+        let end = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| addr.checked_add(len));
+        if addr < self.base || end.is_none_or(|end| end > self.region().end) {
+            return Err(ERR_ALIGNMENT);
+        }
+        let words: Vec<u16> = data
+            .chunks(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .collect();
+        let mut offset = (addr - self.base) / 2;
+        let mut words = &words[..];
+        let page = self.info.write_buffer / 2;
+        while !words.is_empty() {
+            if page <= 1 {
+                self.program_word(offset, words[0])?;
+                offset += 1;
+                words = &words[1..];
+                continue;
+            }
+            // A buffer write can't cross a write buffer page, and pages don't cross blocks
+            let room = (page - offset % page) as usize;
+            let (chunk, rest) = words.split_at(room.min(words.len()));
+            self.program_buffer(offset, chunk)?;
+            offset += chunk.len() as u32;
+            words = rest;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, u8> {
+        let first = addr & !3;
+        let words = (addr - first) as usize + len;
+        let data = self
+            .mem
+            .borrow_mut()
+            .read_memory(first, words.div_ceil(4))?;
+        let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        Ok(bytes[(addr - first) as usize..][..len].to_vec())
+    }
+}
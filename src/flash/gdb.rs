@@ -0,0 +1,151 @@
+//! Flash support for a GDB remote protocol server: the memory-map XML which tells GDB where
+//! flash is, and handling of the `vFlashErase`, `vFlashWrite` and `vFlashDone` packets GDB
+//! sends when `load` writes to it.  This crate has no GDB server itself; a server passes
+//! `qXfer:memory-map:read` requests and vFlash packets through to these helpers.
+
+use std::ops::Range;
+
+use super::{FlashDriver, ERR_ALIGNMENT, ERR_NO_FLASH};
+
+/// Build the `memory-map` XML for `ram` and the regions of `flash`.  Devices with erase blocks
+/// of several sizes are described as one region per run of equal-sized blocks.
+pub fn memory_map(ram: &[Range<u32>], flash: &[&dyn FlashDriver]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \
+         \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n<memory-map>\n",
+    );
+    for r in ram {
+        xml += &format!(
+            "<memory type=\"ram\" start=\"{:#x}\" length=\"{:#x}\"/>\n",
+            r.start,
+            r.end - r.start
+        );
+    }
+    for f in flash {
+        let region = f.region();
+        let mut addr = region.start;
+        while addr < region.end {
+            let Some(block) = f.block_at(addr) else { break };
+            let size = block.end - block.start;
+            let start = addr;
+            // Extend over following blocks of the same size
+            addr = block.end;
+            while let Some(b) = f.block_at(addr).filter(|b| b.end - b.start == size) {
+                addr = b.end;
+            }
+            xml += &format!(
+                "<memory type=\"flash\" start=\"{:#x}\" length=\"{:#x}\">\
+                 <property name=\"blocksize\">{:#x}</property></memory>\n",
+                start,
+                addr - start,
+                size
+            );
+        }
+    }
+    xml += "</memory-map>\n";
+    xml
+}
+
+/// Handles vFlash packets for a set of flash devices.  Writes are buffered until `vFlashDone`,
+/// then programmed and verified.
+pub struct FlashHandler {
+    drivers: Vec<Box<dyn FlashDriver>>,
+    pending: Vec<(u32, Vec<u8>)>,
+}
+
+fn parse_hex(s: &[u8]) -> Option<u32> {
+    u32::from_str_radix(std::str::from_utf8(s).ok()?, 16).ok()
+}
+
+fn reply(result: Result<(), u8>) -> Vec<u8> {
+    match result {
+        Ok(()) => b"OK".to_vec(),
+        Err(e) => format!("E{:02x}", e).into_bytes(),
+    }
+}
+
+impl FlashHandler {
+    pub fn new(drivers: Vec<Box<dyn FlashDriver>>) -> Self {
+        Self {
+            drivers,
+            pending: vec![],
+        }
+    }
+
+    /// The flash devices, for building the memory map
+    pub fn drivers(&self) -> Vec<&dyn FlashDriver> {
+        self.drivers.iter().map(|d| d.as_ref()).collect()
+    }
+
+    fn driver_for(&mut self, range: Range<u32>) -> Result<&mut dyn FlashDriver, u8> {
+        let d = self
+            .drivers
+            .iter_mut()
+            .find(|d| {
+                let r = d.region();
+                r.start <= range.start && range.end <= r.end
+            })
+            .ok_or(ERR_NO_FLASH)?;
+        Ok(d.as_mut())
+    }
+
+    fn erase(&mut self, addr: u32, len: u32) -> Result<(), u8> {
+        let end = addr.checked_add(len).ok_or(ERR_ALIGNMENT)?;
+        self.driver_for(addr..end)?.erase(addr..end)
+    }
+
+    fn write(&mut self, addr: u32, escaped: &[u8]) {
+        // Undo the remote protocol's binary escaping
+        let mut data = Vec::with_capacity(escaped.len());
+        let mut bytes = escaped.iter();
+        while let Some(&b) = bytes.next() {
+            match b {
+                b'}' => data.extend(bytes.next().map(|x| x ^ 0x20)),
+                _ => data.push(b),
+            }
+        }
+        match self.pending.last_mut() {
+            Some((a, d)) if *a + d.len() as u32 == addr => d.extend(data),
+            _ => self.pending.push((addr, data)),
+        }
+    }
+
+    fn done(&mut self) -> Result<(), u8> {
+        for (addr, data) in std::mem::take(&mut self.pending) {
+            let driver = self.driver_for(addr..addr + data.len() as u32)?;
+            driver.program(addr, &data)?;
+            driver.verify(addr, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Handle a packet, given without the framing or checksum.  Returns the reply, or `None` if
+    /// it isn't a vFlash packet.
+    pub fn handle(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if let Some(args) = packet.strip_prefix(b"vFlashErase:") {
+            let mut args = args.split(|&b| b == b',');
+            let result = match (
+                args.next().and_then(parse_hex),
+                args.next().and_then(parse_hex),
+            ) {
+                (Some(addr), Some(len)) => self.erase(addr, len),
+                _ => return Some(b"E01".to_vec()),
+            };
+            Some(reply(result))
+        } else if let Some(args) = packet.strip_prefix(b"vFlashWrite:") {
+            let Some(colon) = args.iter().position(|&b| b == b':') else {
+                return Some(b"E01".to_vec());
+            };
+            let Some(addr) = parse_hex(&args[..colon]) else {
+                return Some(b"E01".to_vec());
+            };
+            self.write(addr, &args[colon + 1..]);
+            Some(b"OK".to_vec())
+        } else if packet == b"vFlashDone" {
+            Some(reply(self.done()))
+        } else {
+            None
+        }
+    }
+}
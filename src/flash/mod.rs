@@ -0,0 +1,68 @@
+//! Flash programming through a MEM-AP, by driving the flash device or its controller directly
+//! rather than running an algorithm on the target.  Each driver implements `FlashDriver`, so
+//! tools can program any of them the same way.
+
+use std::ops::Range;
+
+pub mod cfi;
+pub mod gdb;
+pub mod spi;
+pub mod zynq_qspi;
+
+/// Error returned when no supported flash device was found
+pub const ERR_NO_FLASH: u8 = 0x50;
+/// Error returned when the device reported an erase failure
+pub const ERR_ERASE: u8 = 0x51;
+/// Error returned when the device reported a program failure
+pub const ERR_PROGRAM: u8 = 0x52;
+/// Error returned when the sector being modified is locked
+pub const ERR_PROTECTED: u8 = 0x53;
+/// Error returned when an address or length isn't aligned as the device requires
+pub const ERR_ALIGNMENT: u8 = 0x54;
+/// Error returned when flash contents don't match what was programmed
+pub const ERR_VERIFY: u8 = 0x55;
+
+/// Operations common to flash devices.  Drivers are created with a driver-specific `probe`.
+pub trait FlashDriver {
+    /// Addresses covered by the device
+    fn region(&self) -> Range<u32>;
+
+    /// Return the address range of the erase block containing `addr`
+    fn block_at(&self, addr: u32) -> Option<Range<u32>>;
+
+    /// Erase the block containing `addr`
+    fn erase_block(&mut self, addr: u32) -> Result<(), u8>;
+
+    /// Program `data` at `addr`, which must already be erased
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), u8>;
+
+    /// Read `len` bytes at `addr`
+    fn read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, u8>;
+
+    /// Erase every block which overlaps `range`
+    fn erase(&mut self, range: Range<u32>) -> Result<(), u8> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let block = self.block_at(addr).ok_or(ERR_ALIGNMENT)?;
+            self.erase_block(addr)?;
+            addr = block.end;
+        }
+        Ok(())
+    }
+
+    /// Check that the contents at `addr` match `data`.  Returns `ERR_VERIFY` if they don't.
+    fn verify(&mut self, addr: u32, data: &[u8]) -> Result<(), u8> {
+        if self.read(addr, data.len())? != data {
+            return Err(ERR_VERIFY);
+        }
+        Ok(())
+    }
+
+    /// Erase the blocks covering `data`, program it at `addr` and verify it.  Data in the erased
+    /// blocks outside `addr..addr + data.len()` is lost.
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), u8> {
+        self.erase(addr..addr + data.len() as u32)?;
+        self.program(addr, data)?;
+        self.verify(addr, data)
+    }
+}
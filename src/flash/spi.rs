@@ -0,0 +1,148 @@
+//! Generic SPI NOR flash, driven through any controller that implements `SpiBus`.  Only the
+//! basic commands common to SPI NOR parts are used: JEDEC ID, page program, 64KB sector erase
+//! and single-bit reads with 3-byte addresses, so the first 16MB of the device is accessible.
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use super::{FlashDriver, ERR_ALIGNMENT, ERR_NO_FLASH};
+use crate::ERR_TIMEOUT;
+
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_READ: u8 = 0x03;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0xd8;
+
+const STATUS_WIP: u8 = 1 << 0;
+
+const PAGE_SIZE: u32 = 256;
+const SECTOR_SIZE: u32 = 64 * 1024;
+/// Largest device addressable with 3-byte addresses
+const MAX_SIZE: u32 = 16 * 1024 * 1024;
+/// Largest read done in one transfer
+const READ_CHUNK: usize = 4096;
+
+const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A SPI controller that can run one transaction with chip select held asserted
+pub trait SpiBus {
+    /// Assert chip select, send `tx`, then clock in `rx.len()` bytes and deassert chip select
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), u8>;
+}
+
+/// A SPI NOR flash device.  Addresses are offsets into the device.
+pub struct SpiNor<B> {
+    bus: B,
+    jedec_id: [u8; 3],
+    size: u32,
+}
+
+impl<B> SpiNor<B>
+where
+    B: SpiBus,
+{
+    /// Read the JEDEC ID of the device on `bus`.  Returns `ERR_NO_FLASH` if nothing answers.
+    pub fn probe(mut bus: B) -> Result<Self, u8> {
+        let mut id = [0; 3];
+        bus.transfer(&[CMD_READ_ID], &mut id)?;
+        if id == [0; 3] || id == [0xff; 3] {
+            return Err(ERR_NO_FLASH);
+        }
+        // The last ID byte is log2 of the size on most parts
+        let size = 1u32.checked_shl(id[2] as u32).unwrap_or(MAX_SIZE);
+        Ok(Self {
+            bus,
+            jedec_id: id,
+            size: size.min(MAX_SIZE),
+        })
+    }
+
+    /// Manufacturer and device ID bytes
+    pub fn jedec_id(&self) -> [u8; 3] {
+        self.jedec_id
+    }
+
+    pub fn into_bus(self) -> B {
+        self.bus
+    }
+
+    fn command(&mut self, cmd: u8, addr: u32, data: &[u8]) -> Result<(), u8> {
+        let mut tx = vec![cmd, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8];
+        tx.extend_from_slice(data);
+        self.bus.transfer(&tx, &mut [])
+    }
+
+    fn write_enable(&mut self) -> Result<(), u8> {
+        self.bus.transfer(&[CMD_WRITE_ENABLE], &mut [])
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> Result<(), u8> {
+        let start = Instant::now();
+        loop {
+            let mut status = [0];
+            self.bus.transfer(&[CMD_READ_STATUS], &mut status)?;
+            if status[0] & STATUS_WIP == 0 {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+}
+
+impl<B> FlashDriver for SpiNor<B>
+where
+    B: SpiBus,
+{
+    fn region(&self) -> Range<u32> {
+        0..self.size
+    }
+
+    fn block_at(&self, addr: u32) -> Option<Range<u32>> {
+        if addr >= self.size {
+            return None;
+        }
+        let start = addr & !(SECTOR_SIZE - 1);
+        Some(start..start + SECTOR_SIZE)
+    }
+
+    fn erase_block(&mut self, addr: u32) -> Result<(), u8> {
+        let block = self.block_at(addr).ok_or(ERR_ALIGNMENT)?;
+        self.write_enable()?;
+        self.command(CMD_SECTOR_ERASE, block.start, &[])?;
+        self.wait_ready(ERASE_TIMEOUT)
+    }
+
+    fn program(&mut self, addr: u32, data: &[u8]) -> Result<(), u8> {
+        if addr as u64 + data.len() as u64 > self.size as u64 {
+            return Err(ERR_ALIGNMENT);
+        }
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            // A page program wraps within the page, so it mustn't cross a page boundary
+            let room = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+            let (chunk, rest) = data.split_at(room.min(data.len()));
+            self.write_enable()?;
+            self.command(CMD_PAGE_PROGRAM, addr, chunk)?;
+            self.wait_ready(PROGRAM_TIMEOUT)?;
+            addr += chunk.len() as u32;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, u8> {
+        let mut result = vec![0; len];
+        for (i, chunk) in result.chunks_mut(READ_CHUNK).enumerate() {
+            let at = addr + (i * READ_CHUNK) as u32;
+            let tx = [CMD_READ, (at >> 16) as u8, (at >> 8) as u8, at as u8];
+            self.bus.transfer(&tx, chunk)?;
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,135 @@
+//! `SpiBus` for the Zynq-7000 Quad-SPI controller in I/O mode, so a QSPI boot flash can be
+//! programmed with `SpiNor` through register accesses alone.  Only a single flash on the lower
+//! chip select is supported, in single-bit mode.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::spi::SpiBus;
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+/// Base address of the controller
+pub const QSPI_BASE: u32 = 0xe000d000;
+
+const CONFIG: u32 = 0x00;
+const INTR_STATUS: u32 = 0x04;
+const EN: u32 = 0x14;
+const TXD0: u32 = 0x1c;
+const RX_DATA: u32 = 0x20;
+/// TXD1, TXD2 and TXD3 send 1, 2 and 3 bytes
+const TXD1: u32 = 0x80;
+const LQSPI_CFG: u32 = 0xa0;
+
+const CONFIG_MSTREN: u32 = 1 << 0;
+const CONFIG_BAUD_DIV_SHIFT: u32 = 3;
+const CONFIG_FIFO_WIDTH_32: u32 = 3 << 6;
+const CONFIG_PCS: u32 = 1 << 10;
+const CONFIG_MANUAL_CS: u32 = 1 << 14;
+const CONFIG_MAN_START_EN: u32 = 1 << 15;
+const CONFIG_MAN_START: u32 = 1 << 16;
+const CONFIG_HOLDB_DR: u32 = 1 << 19;
+const CONFIG_IFMODE: u32 = 1 << 31;
+
+const INTR_RX_NOT_EMPTY: u32 = 1 << 4;
+
+const LQSPI_CFG_LQ_MODE: u32 = 1 << 31;
+
+/// Depth of the TX and RX FIFOs in words
+const FIFO_DEPTH: usize = 63;
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The Zynq-7000 QSPI controller
+pub struct ZynqQspi<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+    config: u32,
+}
+
+impl<T> ZynqQspi<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Put the controller at `base` into I/O mode, with the SPI clock at the reference clock
+    /// divided by 2^(`baud_div` + 1).  The QSPI reference clock and MIO pins must already be
+    /// set up, as the boot ROM does when booting from QSPI.
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, base: u32, baud_div: u32) -> Result<Self, u8> {
+        let config = CONFIG_IFMODE
+            | CONFIG_HOLDB_DR
+            | CONFIG_MAN_START_EN
+            | CONFIG_MANUAL_CS
+            | CONFIG_FIFO_WIDTH_32
+            | (baud_div & 7) << CONFIG_BAUD_DIV_SHIFT
+            | CONFIG_MSTREN;
+        let mut qspi = Self { mem, base, config };
+        qspi.write(EN, 0)?;
+        let lqspi = qspi.read(LQSPI_CFG)?;
+        qspi.write(LQSPI_CFG, lqspi & !LQSPI_CFG_LQ_MODE)?;
+        qspi.write(CONFIG, config | CONFIG_PCS)?;
+        qspi.write(EN, 1)?;
+        Ok(qspi)
+    }
+
+    fn read(&mut self, reg: u32) -> Result<u32, u8> {
+        self.mem.borrow_mut().read(self.base + reg)
+    }
+
+    fn write(&mut self, reg: u32, val: u32) -> Result<(), u8> {
+        self.mem.borrow_mut().write(self.base + reg, val)
+    }
+
+    /// Send up to a FIFO's worth of bytes and return the bytes received
+    fn exchange(&mut self, tx: &[u8]) -> Result<Vec<u8>, u8> {
+        let words: Vec<&[u8]> = tx.chunks(4).collect();
+        for word in &words {
+            let mut val = [0; 4];
+            val[..word.len()].copy_from_slice(word);
+            let reg = if word.len() == 4 {
+                TXD0
+            } else {
+                TXD1 + 4 * (word.len() as u32 - 1)
+            };
+            self.write(reg, u32::from_le_bytes(val))?;
+        }
+        self.write(CONFIG, self.config | CONFIG_MAN_START)?;
+
+        let mut rx = Vec::with_capacity(tx.len());
+        for word in &words {
+            let start = Instant::now();
+            while self.read(INTR_STATUS)? & INTR_RX_NOT_EMPTY == 0 {
+                if start.elapsed() > TRANSFER_TIMEOUT {
+                    return Err(ERR_TIMEOUT);
+                }
+            }
+            // A partial word is received into the upper bytes
+            let val = self.read(RX_DATA)? >> (8 * (4 - word.len()));
+            rx.extend_from_slice(&val.to_le_bytes()[..word.len()]);
+        }
+        Ok(rx)
+    }
+}
+
+impl<T> SpiBus for ZynqQspi<T>
+where
+    T: Transport + ?Sized,
+{
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), u8> {
+        let mut out = tx.to_vec();
+        out.resize(tx.len() + rx.len(), 0);
+
+        self.write(CONFIG, self.config)?;
+        let result = (|| -> Result<Vec<u8>, u8> {
+            let mut received = Vec::with_capacity(out.len());
+            for chunk in out.chunks(4 * FIFO_DEPTH) {
+                received.extend(self.exchange(chunk)?);
+            }
+            Ok(received)
+        })();
+        self.write(CONFIG, self.config | CONFIG_PCS)?;
+
+        let received = result?;
+        rx.copy_from_slice(&received[tx.len()..]);
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! Reading and decoding the JTAG IDCODE of a DAP.  `verify` reads the IDCODE of a TAP and checks
+//! that it is an ARM DAP, or a particular one, returning an `IdcodeError` describing what was
+//! found otherwise.
+
+use core::fmt;
+use core::ops::DerefMut;
+
+use jtag_taps::cable::Cable;
+use jtag_taps::taps::Taps;
+
+use crate::wire_log::IR_IDCODE;
+
+/// JEP106 code of ARM, in the form of `IdCode::designer`
+pub const DESIGNER_ARM: u16 = 0x23b;
+
+/// A DAP designed by ARM, identified by the part number of its IDCODE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KnownDap {
+    pub part: u16,
+    pub name: &'static str,
+}
+
+/// The DAPs designed by ARM, as reported in IDCODE and DPIDR
+pub const KNOWN_DAPS: &[KnownDap] = &[
+    KnownDap {
+        part: 0xba00,
+        name: "JTAG-DP",
+    },
+    KnownDap {
+        part: 0xba01,
+        name: "SW-DP",
+    },
+    KnownDap {
+        part: 0xba02,
+        name: "SW-DP v2",
+    },
+    KnownDap {
+        part: 0xbb11,
+        name: "Cortex-M0 SW-DP",
+    },
+    KnownDap {
+        part: 0xbc11,
+        name: "Cortex-M0+ SW-DP",
+    },
+];
+
+/// A few JEP106 designers that make ARM-based parts, for naming the designer of an IDCODE
+const MANUFACTURERS: &[(u16, &str)] = &[
+    (0x00e, "Freescale"),
+    (0x015, "NXP"),
+    (0x017, "Texas Instruments"),
+    (0x020, "STMicroelectronics"),
+    (0x049, "Xilinx"),
+    (DESIGNER_ARM, "ARM"),
+    (0x244, "Nordic Semiconductor"),
+];
+
+/// Decoded value of IDCODE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdCode {
+    pub raw: u32,
+    /// Revision of the part
+    pub version: u8,
+    pub part: u16,
+    /// JEP106 code of the designer, continuation code in bits [10:7]
+    pub designer: u16,
+}
+
+impl From<u32> for IdCode {
+    fn from(raw: u32) -> Self {
+        Self {
+            raw,
+            version: (raw >> 28) as u8,
+            part: (raw >> 12) as u16,
+            designer: ((raw >> 1) & 0x7ff) as u16,
+        }
+    }
+}
+
+impl IdCode {
+    /// Name of the designer, if it is one of a few well known ones
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        MANUFACTURERS
+            .iter()
+            .find(|(code, _)| *code == self.designer)
+            .map(|(_, name)| *name)
+    }
+
+    /// The ARM DAP with this IDCODE, if it is one
+    pub fn known_dap(&self) -> Option<&'static KnownDap> {
+        if self.designer != DESIGNER_ARM || self.raw & 1 == 0 {
+            return None;
+        }
+        KNOWN_DAPS.iter().find(|dap| dap.part == self.part)
+    }
+}
+
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x} (", self.raw)?;
+        match self.manufacturer() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "designer {:03x}", self.designer)?,
+        }
+        match self.known_dap() {
+            Some(dap) => write!(f, " {}", dap.name)?,
+            None => write!(f, " part {:04x}", self.part)?,
+        }
+        write!(f, " r{})", self.version)
+    }
+}
+
+/// Why an IDCODE didn't pass `verify`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdcodeError {
+    /// The scan didn't return 32 bits
+    Unreadable,
+    /// The IDCODE isn't that of a known ARM DAP.  All zeros or all ones usually means the cable
+    /// isn't connected to the target, or the target isn't powered.
+    UnknownDap(IdCode),
+    /// The IDCODE differs from the one expected
+    Mismatch { expected: u32, found: IdCode },
+}
+
+impl fmt::Display for IdcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdcodeError::Unreadable => write!(f, "couldn't read IDCODE"),
+            IdcodeError::UnknownDap(found) => {
+                write!(f, "IDCODE {} isn't a known ARM DAP", found)
+            }
+            IdcodeError::Mismatch { expected, found } => {
+                write!(f, "expected IDCODE 0x{:08x}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl core::error::Error for IdcodeError {}
+
+/// Select the TAP at `tap_index` with the IDCODE instruction and read its IDCODE
+pub fn read<T, U>(taps: &mut Taps<T>, tap_index: usize) -> Result<IdCode, IdcodeError>
+where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    taps.select_tap(tap_index, &[IR_IDCODE]);
+    let dr = taps.read_dr(32);
+    let raw = dr
+        .try_into()
+        .map(u32::from_le_bytes)
+        .map_err(|_| IdcodeError::Unreadable)?;
+    Ok(IdCode::from(raw))
+}
+
+/// Read the IDCODE of the TAP at `tap_index` and check that it is `expected`, or any known ARM
+/// DAP if None
+pub fn verify<T, U>(
+    taps: &mut Taps<T>,
+    tap_index: usize,
+    expected: Option<u32>,
+) -> Result<IdCode, IdcodeError>
+where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    let found = read(taps, tap_index)?;
+    match expected {
+        Some(expected) if found.raw != expected => Err(IdcodeError::Mismatch { expected, found }),
+        None if found.known_dap().is_none() => Err(IdcodeError::UnknownDap(found)),
+        _ => Ok(found),
+    }
+}
@@ -0,0 +1,257 @@
+//! Firmware images: loading raw binary, Intel HEX and (with the `elf` feature) ELF files into
+//! segments, and helpers to check a region of target memory against an image or patch it, for
+//! manufacturing and bring-up scripts.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::crc::{self, TargetCrc};
+use crate::flash::ERR_VERIFY;
+use crate::{MemAP, Transport};
+
+/// Format of an image file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Raw bytes, loaded at the address given
+    Binary,
+    /// Intel HEX, at the addresses in the file
+    IntelHex,
+    /// The PT_LOAD segments of an ELF file, at their physical addresses
+    #[cfg(feature = "elf")]
+    Elf,
+}
+
+/// Error loading or checking an image
+#[derive(Debug)]
+pub enum ImageError {
+    /// Error from the debug interface
+    Access(u8),
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Access(e) => write!(f, "access error {}", e),
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::Parse(e) => write!(f, "bad image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<u8> for ImageError {
+    fn from(e: u8) -> Self {
+        ImageError::Access(e)
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+/// Contiguous bytes of an image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// A byte of target memory which differs from the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub address: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+fn parse_hex_byte(s: &str, line: usize) -> Result<u8, ImageError> {
+    u8::from_str_radix(s, 16).map_err(|_| ImageError::Parse(format!("line {}: bad hex", line)))
+}
+
+/// Parse the contents of an Intel HEX file
+pub fn parse_intel_hex(text: &str) -> Result<Vec<Segment>, ImageError> {
+    let mut segments: Vec<Segment> = vec![];
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .filter(|r| r.len() >= 10 && r.len() % 2 == 0 && r.is_ascii())
+            .ok_or_else(|| ImageError::Parse(format!("line {}: bad record", line_no)))?;
+        let bytes = (0..record.len())
+            .step_by(2)
+            .map(|j| parse_hex_byte(&record[j..j + 2], line_no))
+            .collect::<Result<Vec<u8>, _>>()?;
+        if bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+            return Err(ImageError::Parse(format!("line {}: bad checksum", line_no)));
+        }
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 {
+            return Err(ImageError::Parse(format!("line {}: bad length", line_no)));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..4 + len];
+        match bytes[3] {
+            0 => {
+                let address = base.wrapping_add(offset);
+                match segments.last_mut() {
+                    Some(s) if s.address.wrapping_add(s.data.len() as u32) == address => {
+                        s.data.extend_from_slice(data)
+                    }
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            1 => break,
+            2 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            4 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses
+            3 | 5 => {}
+            t => {
+                return Err(ImageError::Parse(format!(
+                    "line {}: bad record type {}",
+                    line_no, t
+                )))
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// Load the image at `path`.  A binary image is placed at `addr`; for the other formats `addr`
+/// is added to the addresses in the file.
+pub fn load_image(path: &Path, format: Format, addr: u32) -> Result<Vec<Segment>, ImageError> {
+    let mut segments = match format {
+        Format::Binary => {
+            return Ok(vec![Segment {
+                address: addr,
+                data: fs::read(path)?,
+            }])
+        }
+        Format::IntelHex => parse_intel_hex(&fs::read_to_string(path)?)?,
+        #[cfg(feature = "elf")]
+        Format::Elf => crate::elf::load_segments(&fs::read(path)?)
+            .map_err(|e| ImageError::Parse(e.to_string()))?
+            .into_iter()
+            .map(|s| Segment {
+                address: s.address as u32,
+                data: s.data,
+            })
+            .collect(),
+    };
+    for s in &mut segments {
+        s.address = s.address.wrapping_add(addr);
+    }
+    Ok(segments)
+}
+
+/// Read `len` bytes at `addr`, which need not be aligned
+pub fn read_bytes<T>(mem: &mut MemAP<T>, addr: u32, len: usize) -> Result<Vec<u8>, u8>
+where
+    T: Transport + ?Sized,
+{
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    let first = addr & !3;
+    let words = (addr - first) as usize + len;
+    let data = mem.read_memory(first, words.div_ceil(4))?;
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    Ok(bytes[(addr - first) as usize..][..len].to_vec())
+}
+
+/// Compare target memory with the image at `path`, loaded as by `load_image`, and return the
+/// bytes which differ
+pub fn verify_file<T>(
+    mem: &mut MemAP<T>,
+    addr: u32,
+    path: &Path,
+    format: Format,
+) -> Result<Vec<Mismatch>, ImageError>
+where
+    T: Transport + ?Sized,
+{
+    let mut mismatches = vec![];
+    for segment in load_image(path, format, addr)? {
+        let actual = read_bytes(mem, segment.address, segment.data.len())?;
+        for (i, (&expected, &actual)) in segment.data.iter().zip(&actual).enumerate() {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    address: segment.address + i as u32,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Check that the memory at `addr` matches `data` by comparing CRC-32s, which is much faster
+/// than `verify_file` for large images when `target` is given, but can't say where they
+/// differ.  Returns `ERR_VERIFY` if they don't match.
+pub fn verify_crc<T>(
+    mem: &mut MemAP<T>,
+    addr: u32,
+    data: &[u8],
+    target: Option<&mut TargetCrc>,
+) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    if crc::crc32_memory(mem, addr, data.len(), target)? != crc::crc32(data) {
+        return Err(ERR_VERIFY);
+    }
+    Ok(())
+}
+
+/// Write `bytes` to RAM at `addr`, which need not be aligned; the bytes around a partial word
+/// at either end are preserved.  If `verify` is true, the region is read back and
+/// `ERR_VERIFY` returned if it differs.
+pub fn patch_region<T>(mem: &mut MemAP<T>, addr: u32, bytes: &[u8], verify: bool) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let first = addr & !3;
+    let end = addr + bytes.len() as u32;
+    let count = (end - first).div_ceil(4) as usize;
+
+    let mut data = vec![0; 4 * count];
+    // Fetch the partial words at the ends
+    let head = (addr - first) as usize;
+    if head != 0 {
+        data[..4].copy_from_slice(&mem.read(first)?.to_le_bytes());
+    }
+    if end & 3 != 0 {
+        let last = first + 4 * (count as u32 - 1);
+        data[4 * (count - 1)..].copy_from_slice(&mem.read(last)?.to_le_bytes());
+    }
+    data[head..head + bytes.len()].copy_from_slice(bytes);
+
+    let words: Vec<u32> = data
+        .chunks(4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    mem.write_memory(first, &words)?;
+
+    if verify && read_bytes(mem, addr, bytes.len())? != bytes {
+        return Err(ERR_VERIFY);
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Interleaving of AP transactions across several APs.  Switching APs only takes a SELECT write
+//! in the stream of a `Batch`, so it doesn't wait for the transactions already queued.
+//! `ArmDebugInterface::run_interleaved` runs a mixed sequence of AP transactions as one batch,
+//! and `Scheduler` builds such a sequence from independent per-AP queues, for example a RAM
+//! transfer through an AHB-AP alongside debug register accesses through an APB-AP.
+
+use std::ops::DerefMut;
+
+use jtag_taps::cable::Cable;
+
+use crate::batch::Batch;
+use crate::{ArmDebugInterface, Port};
+
+/// Most transactions taken from one AP's queue before moving on to the next AP
+const SLICE: usize = 32;
+
+/// A transaction on a register of an AP.  The register is numbered as for `read_adi`, the
+/// address divided by four.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApOp {
+    Read(u8),
+    Write(u8, u32),
+}
+
+impl<T, U> ArmDebugInterface<T>
+where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    /// Run `ops`, each a transaction on AP `apsel`, as a single pipelined stream, writing SELECT
+    /// between transactions on different APs or register banks.  Returns one result per
+    /// transaction, as from `Batch::execute`.
+    pub fn run_interleaved(&mut self, ops: &[(u32, ApOp)]) -> Vec<Result<u32, u8>> {
+        let mut batch = Batch::new();
+        for &(apsel, op) in ops {
+            match op {
+                ApOp::Read(reg) => batch.read(apsel, Port::AP, reg),
+                ApOp::Write(reg, val) => batch.write(apsel, Port::AP, reg, val),
+            };
+        }
+        batch.execute(self)
+    }
+}
+
+/// Independent queues of transactions on several APs, run interleaved as one stream.
+/// Transactions on the same AP run in the order they were queued, but may be reordered
+/// relative to those on other APs.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    queues: Vec<(u32, Vec<ApOp>)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `op` on AP `apsel`.  Returns the index of its result among the results for that
+    /// AP.
+    pub fn push(&mut self, apsel: u32, op: ApOp) -> usize {
+        let queue = match self.queues.iter().position(|(ap, _)| *ap == apsel) {
+            Some(i) => &mut self.queues[i].1,
+            None => {
+                self.queues.push((apsel, vec![]));
+                &mut self.queues.last_mut().unwrap().1
+            }
+        };
+        queue.push(op);
+        queue.len() - 1
+    }
+
+    /// Return true if nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Order the queued transactions into one stream, taking up to `SLICE` transactions from
+    /// each AP in turn so that a long transfer on one AP doesn't hold up the others.  Returns
+    /// the stream and the index of the queue each transaction came from.
+    fn interleave(&self) -> (Vec<(u32, ApOp)>, Vec<usize>) {
+        let mut stream = vec![];
+        let mut from = vec![];
+        let total = self.queues.iter().map(|(_, ops)| ops.len()).sum();
+        let mut offset = 0;
+        while stream.len() < total {
+            for (q, (apsel, ops)) in self.queues.iter().enumerate() {
+                for &op in ops.iter().skip(offset).take(SLICE) {
+                    stream.push((*apsel, op));
+                    from.push(q);
+                }
+            }
+            offset += SLICE;
+        }
+        (stream, from)
+    }
+
+    /// Run everything queued on `adi`, emptying the queues.  Returns the APs with the results
+    /// of their transactions, in the order each was queued, as from `run_interleaved`.
+    pub fn run<T, U>(&mut self, adi: &mut ArmDebugInterface<T>) -> Vec<(u32, Vec<Result<u32, u8>>)>
+    where
+        T: DerefMut<Target = U>,
+        U: Cable + ?Sized,
+    {
+        let (stream, from) = self.interleave();
+        let results = adi.run_interleaved(&stream);
+        let mut per_ap: Vec<_> = std::mem::take(&mut self.queues)
+            .into_iter()
+            .map(|(apsel, ops)| (apsel, Vec::with_capacity(ops.len())))
+            .collect();
+        for (q, result) in from.into_iter().zip(results) {
+            per_ap[q].1.push(result);
+        }
+        per_ap
+    }
+}
@@ -1,20 +1,235 @@
 //! This crate allows for interacting with ARM Debug Interface components over JTAG, such as the
 //! Mem AP for accessing memory-mapped resources.  It uses the jtag-taps library for the link layer
 //! and so supports all cables supported by that crate.
+//!
+//! Without the default `std` feature, only the DP, AP and MemAP layers are built, with `alloc`
+//! instead of `std`, and the application provides the clock, see `time`.  jtag-taps itself still
+//! needs `std`, so this only narrows what the crate pulls in on a hosted target; it doesn't yet
+//! build for a probe's firmware.
 
-use std::cell::RefCell;
-use std::ops::DerefMut;
-use std::rc::Rc;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell, RefMut};
+use core::fmt;
+use core::ops::{BitOr, Deref, DerefMut};
+use core::time::Duration;
 
 use jtag_taps::cable::Cable;
+use jtag_taps::statemachine::JtagState;
 use jtag_taps::taps::Taps;
 
-/// Selects between Debug Port (DP) and Access Port (AP)
+use crate::time::Instant;
+use crate::wire_log::{Scan, ScanHook, ScanKind};
+
+pub mod ap;
+#[cfg(feature = "std")]
+pub mod armv7;
+#[cfg(feature = "std")]
+pub mod armv8;
+#[cfg(feature = "std")]
+pub mod backtrace;
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod benchmark;
+#[cfg(feature = "std")]
+pub mod coredump;
+#[cfg(feature = "std")]
+pub mod coresight;
+#[cfg(feature = "elf")]
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod cortex_m;
+#[cfg(feature = "std")]
+pub mod crc;
+pub mod csw;
+#[cfg(feature = "std")]
+pub mod dcc;
+#[cfg(feature = "defmt")]
+pub mod defmt;
+#[cfg(feature = "description")]
+pub mod description;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "elf")]
+pub mod elf;
+#[cfg(feature = "std")]
+pub mod etm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod flash;
+pub mod idcode;
+#[cfg(feature = "std")]
+pub mod image;
+#[cfg(feature = "std")]
+pub mod interleave;
+#[cfg(feature = "std")]
+pub mod linux;
+#[cfg(feature = "std")]
+pub mod memory;
+pub mod memory_map;
+#[cfg(feature = "std")]
+pub mod memtest;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod reconnect;
+pub mod register;
+#[cfg(feature = "std")]
+pub mod remote;
+#[cfg(feature = "std")]
+pub mod rom_table;
+#[cfg(feature = "std")]
+pub mod rtos;
+#[cfg(feature = "std")]
+pub mod rtt;
+#[cfg(feature = "std")]
+pub mod self_test;
+#[cfg(feature = "std")]
+pub mod session;
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod soc;
+#[cfg(feature = "std")]
+pub mod stm;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "svd")]
+pub mod svd;
+#[cfg(feature = "std")]
+pub mod target;
+#[cfg(feature = "elf")]
+pub mod test_runner;
+pub mod time;
+#[cfg(feature = "std")]
+pub mod trace;
+pub mod transfer;
+#[cfg(feature = "std")]
+pub mod trustzone;
+#[cfg(feature = "std")]
+pub mod vendor;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "std")]
+pub mod watch;
+#[cfg(feature = "std")]
+pub mod watchdog;
+pub mod wire_log;
+
+/// Error returned when the debug port doesn't acknowledge a power or reset request in time
+pub const ERR_TIMEOUT: u8 = 8;
+
+/// Error returned by a `MemAP` access that stalled the bus.  See `MemAP::bus_stall`.
+pub const ERR_BUS_STALL: u8 = 10;
+
+/// Error returned by a `MemAP` access that faulted on the bus, setting STICKYERR.  See
+/// `MemAP::sticky_error`.
+pub const ERR_STICKY: u8 = 5;
+/// Error returned by a `MemAP` access that overran the one before it, setting STICKYORUN
+pub const ERR_STICKY_ORUN: u8 = 11;
+/// Error returned by a `MemAP` access after a pushed compare matched, setting STICKYCMP
+pub const ERR_STICKY_CMP: u8 = 12;
+/// Error returned by a `MemAP` write whose data was corrupted on the way to the DP, setting
+/// WDATAERR
+pub const ERR_WDATAERR: u8 = 13;
+
+/// WAIT acknowledge
+const ACK_WAIT: u8 = 1;
+
+/// How long an AP may keep answering WAIT before its transaction is taken to have stalled the
+/// bus
+const STALL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long to wait for CDBGRSTACK to follow CDBGRSTREQ
+const DEBUG_RESET_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// DP bank holding EVENTSTAT at the CTRL/STAT address
+const EVENTSTAT_BANK: u32 = 4;
+
+/// Selects between Debug Port (DP) and Access Port (AP).  The values are the DPACC and APACC
+/// instructions of a standard ARM JTAG-DP; see `IrConfig` for DAPs that use others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Port {
     DP = 10,
     AP = 11,
 }
 
+/// The instruction register of a DAP's TAP.  The default is the standard ARM layout, a 4-bit IR;
+/// some vendor integrations use a longer IR or other opcodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrConfig {
+    /// Length of the instruction register in bits, from 1 to 32
+    pub length: usize,
+    pub dpacc: u32,
+    pub apacc: u32,
+    pub abort: u32,
+    pub idcode: u32,
+}
+
+impl Default for IrConfig {
+    fn default() -> Self {
+        Self {
+            length: 4,
+            dpacc: Port::DP as u32,
+            apacc: Port::AP as u32,
+            abort: wire_log::IR_ABORT as u32,
+            idcode: wire_log::IR_IDCODE as u32,
+        }
+    }
+}
+
+impl IrConfig {
+    pub fn port(&self, port: Port) -> u32 {
+        match port {
+            Port::DP => self.dpacc,
+            Port::AP => self.apacc,
+        }
+    }
+
+    /// Return true if the length is supported and every opcode fits in it
+    pub fn is_valid(&self) -> bool {
+        let mask = match self.length {
+            1..=31 => (1 << self.length) - 1,
+            32 => u32::MAX,
+            _ => return false,
+        };
+        [self.dpacc, self.apacc, self.abort, self.idcode]
+            .iter()
+            .all(|op| op & !mask == 0)
+    }
+
+    /// Number of bytes shifted for an instruction
+    fn bytes(&self) -> usize {
+        self.length.div_ceil(8)
+    }
+
+    /// Identify the instruction shifted as `ir`, as one of the `wire_log::IR_*` constants
+    fn decode(&self, ir: &[u8]) -> Option<u8> {
+        let val = ir
+            .iter()
+            .take(4)
+            .enumerate()
+            .fold(0, |val, (i, byte)| val | (*byte as u32) << (8 * i));
+        match val {
+            _ if ir.is_empty() => None,
+            v if v == self.dpacc => Some(wire_log::IR_DPACC),
+            v if v == self.apacc => Some(wire_log::IR_APACC),
+            v if v == self.abort => Some(wire_log::IR_ABORT),
+            v if v == self.idcode => Some(wire_log::IR_IDCODE),
+            v if v.count_ones() as usize == self.length => Some(wire_log::IR_BYPASS),
+            _ => None,
+        }
+    }
+}
+
 /// Debug Port registers
 pub enum DPReg {
     Abort = 0,
@@ -23,10 +238,332 @@ pub enum DPReg {
     Rdbuff = 3,
 }
 
+/// Flags written to the DP ABORT register.  Flags can be combined with `|`.
+///
+/// Only `DAPABORT` is implemented by a JTAG-DP.  The other flags are for SW-DPs; on a JTAG-DP the
+/// sticky flags are cleared by writing one to them in CTRL/STAT instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Abort(pub u32);
+
+impl Abort {
+    /// Abort the current AP transaction
+    pub const DAPABORT: Abort = Abort(1 << 0);
+    /// Clear STICKYCMP
+    pub const STKCMPCLR: Abort = Abort(1 << 1);
+    /// Clear STICKYERR
+    pub const STKERRCLR: Abort = Abort(1 << 2);
+    /// Clear WDATAERR
+    pub const WDERRCLR: Abort = Abort(1 << 3);
+    /// Clear STICKYORUN
+    pub const ORUNERRCLR: Abort = Abort(1 << 4);
+
+    /// Clear all of the sticky error flags
+    pub const CLEAR_ALL: Abort = Abort(0x1e);
+
+    pub fn contains(self, other: Abort) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Abort {
+    type Output = Abort;
+
+    fn bitor(self, rhs: Abort) -> Abort {
+        Abort(self.0 | rhs.0)
+    }
+}
+
+/// Decoded value of the DP CTRL/STAT register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CtrlStat {
+    pub csyspwrupack: bool,
+    pub csyspwrupreq: bool,
+    pub cdbgpwrupack: bool,
+    pub cdbgpwrupreq: bool,
+    pub cdbgrstack: bool,
+    pub cdbgrstreq: bool,
+    pub trncnt: u16,
+    pub masklane: u8,
+    pub wdataerr: bool,
+    pub readok: bool,
+    pub stickyerr: bool,
+    pub stickycmp: bool,
+    pub trnmode: u8,
+    pub stickyorun: bool,
+    pub orundetect: bool,
+}
+
+impl From<u32> for CtrlStat {
+    fn from(val: u32) -> Self {
+        let bit = |n: u32| val & (1 << n) != 0;
+        Self {
+            csyspwrupack: bit(31),
+            csyspwrupreq: bit(30),
+            cdbgpwrupack: bit(29),
+            cdbgpwrupreq: bit(28),
+            cdbgrstack: bit(27),
+            cdbgrstreq: bit(26),
+            trncnt: ((val >> 12) & 0xfff) as u16,
+            masklane: ((val >> 8) & 0xf) as u8,
+            wdataerr: bit(7),
+            readok: bit(6),
+            stickyerr: bit(5),
+            stickycmp: bit(4),
+            trnmode: ((val >> 2) & 3) as u8,
+            stickyorun: bit(1),
+            orundetect: bit(0),
+        }
+    }
+}
+
+impl From<CtrlStat> for u32 {
+    fn from(cs: CtrlStat) -> Self {
+        (cs.csyspwrupack as u32) << 31
+            | (cs.csyspwrupreq as u32) << 30
+            | (cs.cdbgpwrupack as u32) << 29
+            | (cs.cdbgpwrupreq as u32) << 28
+            | (cs.cdbgrstack as u32) << 27
+            | (cs.cdbgrstreq as u32) << 26
+            | (cs.trncnt as u32 & 0xfff) << 12
+            | (cs.masklane as u32 & 0xf) << 8
+            | (cs.wdataerr as u32) << 7
+            | (cs.readok as u32) << 6
+            | (cs.stickyerr as u32) << 5
+            | (cs.stickycmp as u32) << 4
+            | (cs.trnmode as u32 & 3) << 2
+            | (cs.stickyorun as u32) << 1
+            | cs.orundetect as u32
+    }
+}
+
+/// Access to DP and AP registers.  This is implemented by `ArmDebugInterface` for a locally
+/// attached cable, and by `remote::RemoteDap` for a probe shared over the network, so that
+/// `MemAP` and the drivers built on it work with either.
+pub trait Transport {
+    /// Read register `reg` from AP `apsel` and `port`.
+    fn read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8>;
+
+    /// Queue a read of register `reg` from AP `apsel` and `port`.  The result is retrieved with
+    /// `finish_read`.  Returns false if the read could not be queued.
+    fn queue_read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> bool;
+
+    /// Return the result of the oldest read queued by `queue_read_adi`.
+    fn finish_read(&mut self) -> Result<u32, u8>;
+
+    /// Write `val` to register `reg` of AP `apsel` and `port`.
+    fn write_adi(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8>;
+
+    /// Write `val` to register `reg` of AP `apsel` and `port` without checking for success.
+    fn write_adi_nocheck(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8>;
+
+    /// Read multiple registers.  See `ArmDebugInterface::read_adi_pipelined`.
+    fn read_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>>;
+
+    /// Write multiple registers.  See `ArmDebugInterface::write_adi_pipelined`.
+    fn write_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[(u8, u32)])
+        -> Result<(), u8>;
+
+    /// Read registers of any APs and ports, given as `(apsel, port, reg)`, returning one result
+    /// per read in order.  By default each is read with `read_adi`; `ArmDebugInterface` runs them
+    /// as one `batch::Batch`, with SELECT written in the stream.
+    fn read_adi_batch(&mut self, reads: &[(u32, Port, u8)]) -> Vec<Result<u32, u8>> {
+        reads
+            .iter()
+            .map(|&(apsel, port, reg)| self.read_adi(apsel, port, reg))
+            .collect()
+    }
+
+    /// Read and decode the DP CTRL/STAT register
+    fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8>;
+
+    /// Abort an AP transaction that is stuck returning WAIT.  By default this writes DAPABORT
+    /// with `write_adi_nocheck`.
+    fn abort_transaction(&mut self) -> Result<(), u8> {
+        self.write_adi_nocheck(0, Port::DP, DPReg::Abort as u8, Abort::DAPABORT.0)
+    }
+
+    /// Return the fraction of transactions that got a WAIT response, if the transport measures it
+    fn wait_ratio(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// Number of acknowledged transactions between adjustments of the adaptive pacing
+const PACING_WINDOW: u32 = 256;
+/// Most idle clocks adaptive pacing will add after an AP access
+const PACING_MAX: usize = 1024;
+
+/// A limit on the rate of DAP transactions, for targets that lock up if their debug bus is
+/// accessed too quickly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Average number of transactions per second
+    pub per_second: u32,
+    /// Number of transactions that may be issued back to back before the rate applies
+    pub burst: u32,
+}
+
+/// Token bucket enforcing a `RateLimit`
+struct Throttle {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Wait until a transaction may be issued, and take its token
+    fn take(&mut self) {
+        let rate = self.limit.per_second.max(1) as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(self.limit.burst.max(1) as f64);
+        self.last = now;
+        if self.tokens < 1.0 {
+            time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// WAIT statistics and the idle clocks added after each AP access
+#[derive(Default)]
+struct Pacing {
+    adaptive: bool,
+    clocks: usize,
+    /// Sleep between retries of a transaction that got a WAIT
+    backoff: Option<Duration>,
+    throttle: Option<Throttle>,
+    transactions: u64,
+    waits: u64,
+    window_transactions: u32,
+    window_waits: u32,
+}
+
+impl Pacing {
+    fn record(&mut self, ack: u8) {
+        let wait = ack == 1;
+        self.transactions += 1;
+        self.waits += wait as u64;
+        self.window_transactions += 1;
+        self.window_waits += wait as u32;
+        if self.window_transactions < PACING_WINDOW {
+            return;
+        }
+
+        if self.adaptive {
+            // Back off quickly while more than one in eight transactions gets a WAIT, and speed up
+            // again slowly once they are rare
+            if self.window_waits * 8 > self.window_transactions {
+                self.clocks = (self.clocks * 2).clamp(1, PACING_MAX);
+            } else if self.window_waits * 64 < self.window_transactions {
+                self.clocks /= 2;
+            }
+        }
+        self.window_transactions = 0;
+        self.window_waits = 0;
+    }
+}
+
+/// A scan chain shared by several `ArmDebugInterface`s, for chains with more than one DAP.  Each
+/// interface is bound to one TAP and selects it again, with the other TAPs in bypass, whenever it
+/// is used after another interface on the same chain.
+pub struct SharedTaps<T> {
+    taps: Rc<RefCell<Taps<T>>>,
+    /// The TAP currently selected by one of the interfaces
+    selected: Rc<Cell<Option<usize>>>,
+}
+
+impl<T> SharedTaps<T> {
+    /// `taps` should already have been scanned with `Taps::detect`
+    pub fn new(taps: Taps<T>) -> Self {
+        Self {
+            taps: Rc::new(RefCell::new(taps)),
+            selected: Rc::new(Cell::new(None)),
+        }
+    }
+}
+
+impl<T> Clone for SharedTaps<T> {
+    fn clone(&self) -> Self {
+        Self {
+            taps: self.taps.clone(),
+            selected: self.selected.clone(),
+        }
+    }
+}
+
+/// Cached DP state of an `ArmDebugInterface`, saved with `export_state`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DapState {
+    /// The cached SELECT, or 0xff if it isn't known
+    pub select: u32,
+}
+
+/// Cached state of a `MemAP`, saved with `export_state`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemAPState {
+    pub apsel: u32,
+    pub csw: u32,
+    pub tar: u32,
+}
+
+/// Raw access to the scan chain of an `ArmDebugInterface`, returned by `raw_taps`.  The
+/// interface's cached IR and SELECT values are invalidated when the guard is dropped.
+pub struct TapsGuard<'a, T> {
+    taps: RefMut<'a, Taps<T>>,
+    lastbank: &'a mut u32,
+    lastir: &'a mut Vec<u8>,
+    selected: &'a Cell<Option<usize>>,
+}
+
+impl<T> Deref for TapsGuard<'_, T> {
+    type Target = Taps<T>;
+
+    fn deref(&self) -> &Taps<T> {
+        &self.taps
+    }
+}
+
+impl<T> DerefMut for TapsGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Taps<T> {
+        &mut self.taps
+    }
+}
+
+impl<T> Drop for TapsGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.lastbank = 0xff;
+        self.lastir.clear();
+        self.selected.set(None);
+    }
+}
+
 pub struct ArmDebugInterface<T> {
-    taps: Taps<T>,
+    taps: Rc<RefCell<Taps<T>>>,
+    selected: Rc<Cell<Option<usize>>>,
+    /// TAP to select before use, if the chain is shared
+    tap_index: Option<usize>,
     lastbank: u32,
     lastir: Vec<u8>,
+    ir: IrConfig,
+    /// TCK cycles given to the DAP after each APACC scan
+    idle_clocks: usize,
+    pacing: Pacing,
+    /// Receives every scan, if set
+    scan_hook: Option<ScanHook>,
+    /// TDI of the queued DR scans, reported with their TDO while there is a scan hook
+    queued_scans: VecDeque<Option<[u8; 5]>>,
 }
 
 impl<T, U> ArmDebugInterface<T>
@@ -34,18 +571,52 @@ where
     T: DerefMut<Target = U>,
     U: Cable + ?Sized,
 {
+    /// Create an interface for the DAP currently selected in `taps`.  Panics if the DAP doesn't
+    /// respond; see `try_new`.
     pub fn new(taps: Taps<T>) -> Self {
+        Self::try_new(taps).expect("power up DAP")
+    }
+
+    /// Create an interface for the DAP currently selected in `taps`, returning the ACK or error if
+    /// the DAP doesn't respond, for example because the target is disconnected
+    pub fn try_new(taps: Taps<T>) -> Result<Self, u8> {
+        Self::init(SharedTaps::new(taps), None, IrConfig::default())
+    }
+
+    /// Create an interface for the DAP at `tap_index` on a chain shared with other interfaces.
+    /// Reads queued with `queue_read_adi` must be finished before another interface on the chain
+    /// is used.
+    pub fn new_shared(chain: &SharedTaps<T>, tap_index: usize) -> Self {
+        Self::init(chain.clone(), Some(tap_index), IrConfig::default()).expect("power up DAP")
+    }
+
+    /// Create an interface for a DAP whose TAP doesn't have the standard ARM instruction
+    /// register.  `tap_index` is as for `new_shared`, or None as for `new`.  Panics if `ir` isn't
+    /// valid.
+    pub fn new_with_ir(chain: &SharedTaps<T>, tap_index: Option<usize>, ir: IrConfig) -> Self {
+        assert!(ir.is_valid(), "invalid IR configuration");
+        Self::init(chain.clone(), tap_index, ir).expect("power up DAP")
+    }
+
+    fn init(chain: SharedTaps<T>, tap_index: Option<usize>, ir: IrConfig) -> Result<Self, u8> {
         let mut adi = Self {
-            taps,
+            taps: chain.taps,
+            selected: chain.selected,
+            tap_index,
             lastbank: 0xff,
             lastir: vec![],
+            ir,
+            idle_clocks: 0,
+            pacing: Pacing::default(),
+            scan_hook: None,
+            queued_scans: VecDeque::new(),
         };
 
         // Force bank selects to known values
-        adi.bank_select(0, 0, 0);
+        adi.bank_select(0, 0, 0)?;
 
         // Abort any in-progress transactions
-        adi.write_adi_nobank(Port::DP, DPReg::Abort as u8, 0, true).expect("abort");
+        adi.abort_transaction()?;
 
         // Make sure everything is powered up and STICKY errors are cleared
         adi.write_adi_nobank(
@@ -53,16 +624,197 @@ where
             DPReg::CtrlStat as u8,
             1 << 30 | 1 << 28 | 1 << 24 | 1 << 5 | 1 << 1,
             true,
-        )
-        .expect("clear errors");
+        )?;
 
-        adi
+        Ok(adi)
     }
 
     fn write_ir(&mut self, ir: &[u8]) {
+        if let Some(tap) = self.tap_index {
+            if self.selected.get() != Some(tap) {
+                // Another interface has used the chain, leaving this TAP in bypass
+                self.taps.borrow_mut().select_tap(tap, ir);
+                self.selected.set(Some(tap));
+                self.lastir = ir.to_vec();
+                self.log_scan(ScanKind::Ir, self.ir.length, Some(ir), None);
+                return;
+            }
+        }
         if self.lastir != ir {
-            self.taps.write_ir(ir);
+            self.taps.borrow_mut().write_ir(ir);
             self.lastir = ir.to_vec();
+            self.log_scan(ScanKind::Ir, self.ir.length, Some(ir), None);
+        }
+    }
+
+    /// Shift `instruction` into IR, if it isn't there already
+    fn write_instruction(&mut self, instruction: u32) {
+        let bytes = instruction.to_le_bytes();
+        self.write_ir(&bytes[..self.ir.bytes()]);
+    }
+
+    /// The instruction register layout the interface was created with
+    pub fn ir_config(&self) -> IrConfig {
+        self.ir
+    }
+
+    /// Give the DAP at least `clocks` TCK cycles after each APACC scan, before the next scan
+    /// captures its result, for targets whose APs are slow to complete a transaction.  The cycles
+    /// are spent in Run-Test/Idle, where the scan leaves the TAP.
+    pub fn set_idle_clocks(&mut self, clocks: usize) {
+        self.idle_clocks = clocks;
+    }
+
+    pub fn idle_clocks(&self) -> usize {
+        self.idle_clocks
+    }
+
+    /// Give the DAP the idle clocks, and any added by adaptive pacing, after a scan of `port`, if
+    /// it is the AP
+    fn idle_after(&mut self, port: Port) {
+        let clocks = self.idle_clocks + self.pacing.clocks;
+        if port != Port::AP || clocks == 0 {
+            return;
+        }
+        // Holding TMS low keeps the TAP in Run-Test/Idle for every clock
+        let mut taps = self.taps.borrow_mut();
+        taps.sm.change_mode(JtagState::Idle);
+        taps.sm.cable.change_mode(&vec![0; clocks], true);
+    }
+
+    /// Report a scan to the scan hook, if there is one
+    fn log_scan(&mut self, kind: ScanKind, bits: usize, tdi: Option<&[u8]>, tdo: Option<&[u8]>) {
+        let ir = self.ir.decode(&self.lastir);
+        let select = self.current_select();
+        if let Some(hook) = &mut self.scan_hook {
+            hook(&Scan::decode(kind, bits, tdi, tdo, ir, select));
+        }
+    }
+
+    /// Note the TDI of a queued DR scan, to report it when its TDO is collected
+    fn queue_scan(&mut self, tdi: Option<[u8; 5]>) {
+        if self.scan_hook.is_some() {
+            self.queued_scans.push_back(tdi);
+        }
+    }
+
+    /// Report a queued DR scan whose TDO has been collected
+    fn log_queued_scan(&mut self, tdo: &[u8]) {
+        let tdi = self.queued_scans.pop_front().flatten();
+        self.log_scan(
+            ScanKind::Dr,
+            wire_log::ACC_BITS,
+            tdi.as_ref().map(|t| &t[..]),
+            Some(tdo),
+        );
+    }
+
+    /// Pass every IR and DR scan the interface makes to `hook`, or stop with None.  See
+    /// `wire_log`.
+    pub fn set_scan_hook(&mut self, hook: Option<ScanHook>) {
+        self.scan_hook = hook;
+        self.queued_scans.clear();
+    }
+
+    /// Save the cached DP state, so it can be restored with `import_state` after reconnecting
+    pub fn export_state(&self) -> DapState {
+        DapState {
+            select: self.lastbank,
+        }
+    }
+
+    /// Restore DP state saved by `export_state`, writing SELECT so the DAP matches the cache.  If
+    /// SELECT wasn't known when the state was saved, nothing is written and it is written before
+    /// the next access instead.
+    pub fn import_state(&mut self, state: DapState) -> Result<(), u8> {
+        self.lastbank = 0xff;
+        let select = state.select;
+        if select == 0xff {
+            return Ok(());
+        }
+        self.bank_select(select >> 24, (select >> 4) & 0xf, select & 0xf)
+    }
+
+    /// Forget the cached IR and SELECT values, so they are written again before the next access.
+    /// Call this after using the scan chain behind the interface's back.  If a different TAP was
+    /// selected, the DAP's TAP must be selected again first unless the interface was created with
+    /// `new_shared`.  `MemAP`s keep their own caches of CSW and TAR, which this doesn't affect.
+    pub fn invalidate_caches(&mut self) {
+        self.lastbank = 0xff;
+        self.lastir.clear();
+        self.selected.set(None);
+    }
+
+    /// Borrow the underlying scan chain, for example to access another TAP.  The interface's
+    /// caches are invalidated when the returned guard is dropped.
+    pub fn raw_taps(&mut self) -> TapsGuard<'_, T> {
+        TapsGuard {
+            taps: self.taps.borrow_mut(),
+            lastbank: &mut self.lastbank,
+            lastir: &mut self.lastir,
+            selected: &self.selected,
+        }
+    }
+
+    /// Enable or disable adaptive pacing.  When enabled, the WAIT rate is monitored and
+    /// Run-Test/Idle clocks are added after each APACC scan while the target can't keep up, so
+    /// that fewer scans are wasted on retries.  The clocks are given on top of those set with
+    /// `set_idle_clocks`, and are queued with the scans, so they also space out pipelined
+    /// accesses.  Disabling it removes any added clocks.
+    pub fn set_adaptive_pacing(&mut self, enable: bool) {
+        self.pacing.adaptive = enable;
+        if !enable {
+            self.pacing.clocks = 0;
+        }
+    }
+
+    /// Return the idle clocks currently added after each APACC scan by adaptive pacing
+    pub fn pacing_clocks(&self) -> usize {
+        self.pacing.clocks
+    }
+
+    /// Limit the rate of transactions, or remove the limit with None.  Every DP and AP access
+    /// goes through the interface, so everything built on it is limited, whatever the access
+    /// pattern.  The limit throttles the host, on top of any adaptive pacing.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.pacing.throttle = limit.map(Throttle::new);
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.pacing.throttle.as_ref().map(|t| t.limit)
+    }
+
+    /// Sleep for `backoff` before retrying a checked write that got a WAIT, instead of retrying
+    /// at full speed, or retry immediately with None.  This gives a slow AP time to finish without
+    /// the retries keeping the cable busy.
+    pub fn set_wait_backoff(&mut self, backoff: Option<Duration>) {
+        self.pacing.backoff = backoff;
+    }
+
+    pub fn wait_backoff(&self) -> Option<Duration> {
+        self.pacing.backoff
+    }
+
+    /// Return the fraction of transactions that got a WAIT response since the interface was
+    /// created or `reset_wait_stats` was called.  A high ratio means TCK is faster than the target
+    /// can service AP accesses.
+    pub fn wait_ratio(&self) -> f32 {
+        if self.pacing.transactions == 0 {
+            return 0.0;
+        }
+        self.pacing.waits as f32 / self.pacing.transactions as f32
+    }
+
+    pub fn reset_wait_stats(&mut self) {
+        self.pacing.transactions = 0;
+        self.pacing.waits = 0;
+    }
+
+    /// Wait for the rate limit before a transaction.  This throttles the host, so it only spaces
+    /// out scans on the wire for a cable that doesn't queue them.
+    fn pace(&mut self) {
+        if let Some(throttle) = &mut self.pacing.throttle {
+            throttle.take();
         }
     }
 
@@ -82,15 +834,22 @@ where
     }
 
     pub fn queue_read_adi_nobank(&mut self, port: Port, reg: u8) -> bool {
-        let ir = [port as u8];
-        self.write_ir(&ir);
+        self.pace();
+        self.write_instruction(self.ir.port(port));
         let buf = [(reg << 1) | 1, 0, 0, 0, 0];
-        self.taps.write_dr(&buf, 3);
-        self.taps.queue_dr_read(35)
+        self.taps.borrow_mut().write_dr(&buf, 3);
+        self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, Some(&buf), None);
+        self.idle_after(port);
+        let queued = self.taps.borrow_mut().queue_dr_read(35);
+        if queued {
+            self.queue_scan(None);
+        }
+        queued
     }
 
     pub fn finish_read(&mut self) -> Result<u32, u8> {
-        let mut dr = self.taps.finish_dr_read(35);
+        let mut dr = self.taps.borrow_mut().finish_dr_read(35);
+        self.log_queued_scan(&dr);
 
         dr.push(0);
         dr.push(0);
@@ -99,6 +858,7 @@ where
         let val = val & ((1 << 35) - 1);
 
         let ack = val & 7;
+        self.pacing.record(ack as u8);
         if ack != 2 {
             return Err(ack as u8);
         }
@@ -118,7 +878,7 @@ where
     /// Write `val` to register `reg` on `port`.  This function assumes that the correct bank is already
     /// selected.  If `check` is true then the return code of the write will be verified, however
     /// this comes at a performance penalty. You probably want `write_adi` unless you know what
-    /// you're doing.
+    /// you're doing.  A checked write is retried while it gets WAIT, for up to `STALL_TIMEOUT`.
     pub fn write_adi_nobank(
         &mut self,
         port: Port,
@@ -126,20 +886,23 @@ where
         val: u32,
         check: bool,
     ) -> Result<(), u8> {
-        let ir = [port as u8];
-
         let mut val = val as u64;
         val <<= 3;
         val |= (reg << 1) as u64;
 
         let bytes = val.to_le_bytes();
+        let start = Instant::now();
         loop {
-            self.write_ir(&ir);
-            self.taps.write_dr(&bytes[0..5], 3);
+            self.pace();
+            self.write_instruction(self.ir.port(port));
+            self.taps.borrow_mut().write_dr(&bytes[0..5], 3);
+            self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, Some(&bytes[0..5]), None);
+            self.idle_after(port);
             if !check {
                 return Ok(());
             } else {
-                let mut dr = self.taps.read_dr(35);
+                let mut dr = self.taps.borrow_mut().read_dr(35);
+                self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, None, Some(&dr));
 
                 dr.push(0);
                 dr.push(0);
@@ -148,10 +911,14 @@ where
                 let val = val & ((1 << 35) - 1);
 
                 let ack = val & 7;
+                self.pacing.record(ack as u8);
                 if ack == 2 {
                     return Ok(());
                 }
-                if ack == 1 {
+                if ack == ACK_WAIT as u64 && start.elapsed() < STALL_TIMEOUT {
+                    if let Some(backoff) = self.pacing.backoff {
+                        time::sleep(backoff);
+                    }
                     continue;
                 }
                 return Err(ack as u8);
@@ -159,29 +926,147 @@ where
         }
     }
 
-    /// Select the given access port and banks on the access port and debug port.
-    pub fn bank_select(&mut self, apsel: u32, apbank: u32, dpbank: u32) {
+    /// Write `flags` to the DP ABORT register.  The write isn't checked, as an AP that is stuck
+    /// would answer WAIT forever.
+    pub fn write_abort(&mut self, flags: Abort) -> Result<(), u8> {
+        self.write_adi_nobank(Port::DP, DPReg::Abort as u8, flags.0, false)
+    }
+
+    /// Abort an AP transaction that is stuck returning WAIT, so that the DP can be used again
+    pub fn abort_transaction(&mut self) -> Result<(), u8> {
+        self.write_abort(Abort::DAPABORT)
+    }
+
+    /// Abort an AP transaction through the JTAG-DP's ABORT scan chain instead of DPACC.  This
+    /// still works when DPACC itself is wedged, so it is a stronger recovery than
+    /// `abort_transaction`.  Only DAPABORT can be set this way.
+    pub fn jtag_abort(&mut self) {
+        let bytes = ((Abort::DAPABORT.0 as u64) << 3).to_le_bytes();
+        self.pace();
+        self.write_instruction(self.ir.abort);
+        self.taps.borrow_mut().write_dr(&bytes[0..5], 3);
+        self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, Some(&bytes[0..5]), None);
+    }
+
+    /// Select the given access port and banks on the access port and debug port.  If the write
+    /// to SELECT fails, the cached value is invalidated.
+    pub fn bank_select(&mut self, apsel: u32, apbank: u32, dpbank: u32) -> Result<(), u8> {
         let val = (apsel << 24) | (apbank << 4) | dpbank;
         if val != self.lastbank {
-            self.write_adi_nobank(Port::DP, DPReg::Select as u8, val, true)
-                .expect("bank sel");
+            self.lastbank = 0xff;
+            self.write_adi_nobank(Port::DP, DPReg::Select as u8, val, true)?;
             self.lastbank = val;
         }
+        Ok(())
+    }
+
+    /// Write SELECT with APSEL `apsel`, APBANKSEL `apbank` and DPBANKSEL `dpbank`, whatever the
+    /// cached value, and without checking that the fields make sense for the DAP, for example to
+    /// reach registers of a vendor AP that banks them differently.  The cached SELECT is updated,
+    /// so later accesses write SELECT again if they need a different value.  If the write fails
+    /// the cache is invalidated.
+    pub fn select_raw(&mut self, apsel: u32, apbank: u32, dpbank: u32) -> Result<(), u8> {
+        let val = (apsel & 0xff) << 24 | (apbank & 0xf) << 4 | dpbank & 0xf;
+        self.lastbank = 0xff;
+        self.write_adi_nobank(Port::DP, DPReg::Select as u8, val, true)?;
+        self.lastbank = val;
+        Ok(())
+    }
+
+    /// Return the cached value of SELECT, or None if it isn't known, for example after
+    /// `invalidate_caches`
+    pub fn current_select(&self) -> Option<u32> {
+        (self.lastbank != 0xff).then_some(self.lastbank)
+    }
+
+    /// Wait for CTRL/STAT bit `bit` to become `set`, giving up after `DEBUG_RESET_TIMEOUT`
+    fn wait_ctrl_stat(&mut self, bit: u32, set: bool) -> Result<(), u8> {
+        let start = Instant::now();
+        loop {
+            let stat = self.read_adi_nobank(Port::DP, DPReg::CtrlStat as u8)?;
+            if (stat & bit != 0) == set {
+                return Ok(());
+            }
+            if start.elapsed() > DEBUG_RESET_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+    }
+
+    /// Reset the debug domain with CDBGRSTREQ, to recover debug logic that has stopped responding
+    /// without power-cycling the target.  The power-up requests are left as they were and sticky
+    /// errors are cleared.  Returns `ERR_TIMEOUT` if the reset isn't acknowledged.
+    pub fn debug_reset(&mut self) -> Result<(), u8> {
+        // SELECT may not survive the reset, so don't trust the cached value before or after
+        self.lastbank = 0xff;
+        self.bank_select(0, 0, 0)?;
+
+        let stat = self.read_adi_nobank(Port::DP, DPReg::CtrlStat as u8)?;
+        let ctrl = (stat & (1 << 30 | 1 << 28)) | 1 << 5 | 1 << 1;
+        self.write_adi_nobank(Port::DP, DPReg::CtrlStat as u8, ctrl | 1 << 26, true)?;
+        let result = self.wait_ctrl_stat(1 << 27, true);
+
+        // Always drop the request, even if it wasn't acknowledged
+        self.write_adi_nobank(Port::DP, DPReg::CtrlStat as u8, ctrl, true)?;
+        result?;
+        self.wait_ctrl_stat(1 << 27, false)?;
+
+        self.lastbank = 0xff;
+        self.bank_select(0, 0, 0)
+    }
+
+    /// Read and decode the DP CTRL/STAT register
+    pub fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8> {
+        let lastbank = self.lastbank;
+        self.bank_select(lastbank >> 24, (lastbank >> 4) & 0xf, 0)?;
+        let val = self.read_adi_nobank(Port::DP, DPReg::CtrlStat as u8)?;
+        Ok(val.into())
+    }
+
+    /// Read the DP EVENTSTAT register, which is bank 4 of CTRL/STAT.  It is only implemented by
+    /// DPv1 and later.
+    pub fn read_eventstat(&mut self) -> Result<u32, u8> {
+        let lastbank = self.lastbank;
+        self.bank_select(lastbank >> 24, (lastbank >> 4) & 0xf, EVENTSTAT_BANK)?;
+        self.read_adi_nobank(Port::DP, DPReg::CtrlStat as u8)
+    }
+
+    /// Return true if the system has signalled an event to the debugger
+    pub fn event_pending(&mut self) -> Result<bool, u8> {
+        // EA is active low
+        Ok(self.read_eventstat()? & 1 == 0)
+    }
+
+    /// Poll EVENTSTAT until the system signals an event or `timeout` passes.  Returns true if an
+    /// event was seen.
+    pub fn wait_event(&mut self, timeout: Duration) -> Result<bool, u8> {
+        let start = Instant::now();
+        loop {
+            if self.event_pending()? {
+                return Ok(true);
+            }
+            if start.elapsed() > timeout {
+                return Ok(false);
+            }
+        }
     }
 
     /// Read register `reg` from AP `apsel` and `port`.
     pub fn read_adi(&mut self, apsel: u32, port: Port, mut reg: u8) -> Result<u32, u8> {
         let bank = reg >> 2;
         reg &= 3;
-        self.bank_select(apsel, bank as u32, 0);
+        self.bank_select(apsel, bank as u32, 0)?;
         self.read_adi_nobank(port, reg)
     }
 
-    /// Read register `reg` from AP `apsel` and `port`.
+    /// Queue a read of register `reg` from AP `apsel` and `port`.  Returns false if it couldn't be
+    /// queued, including if SELECT couldn't be written, which a plain `read_adi` will report.
     pub fn queue_read_adi(&mut self, apsel: u32, port: Port, mut reg: u8) -> bool {
         let bank = reg >> 2;
         reg &= 3;
-        self.bank_select(apsel, bank as u32, 0);
+        if self.bank_select(apsel, bank as u32, 0).is_err() {
+            return false;
+        }
         self.queue_read_adi_nobank(port, reg)
     }
 
@@ -189,7 +1074,7 @@ where
     pub fn write_adi(&mut self, apsel: u32, port: Port, mut reg: u8, val: u32) -> Result<(), u8> {
         let bank = reg >> 2;
         reg &= 3;
-        self.bank_select(apsel, bank as u32, bank as u32);
+        self.bank_select(apsel, bank as u32, bank as u32)?;
         self.write_adi_nobank(port, reg, val, true)
     }
 
@@ -204,49 +1089,72 @@ where
     ) -> Result<(), u8> {
         let bank = reg >> 2;
         reg &= 3;
-        self.bank_select(apsel, bank as u32, bank as u32);
+        self.bank_select(apsel, bank as u32, bank as u32)?;
         self.write_adi_nobank(port, reg, val, false)
     }
 
     /// Read multiple registers.  `reg` is an array of register values to access.  The result is
     /// returned in the corresponding index of the returned Vec.  This function makes more
-    /// efficient use of the JTAG bus when there are multiple reads to perform.
+    /// efficient use of the JTAG bus when there are multiple reads to perform.  Each run of
+    /// registers in the same bank is read in one pipeline.  If the cable's queue fills up, the
+    /// results stop short, at the last register read.
     pub fn read_adi_pipelined(
         &mut self,
         apsel: u32,
         port: Port,
         reg: &[u8],
     ) -> Vec<Result<u32, u8>> {
+        let mut data = Vec::with_capacity(reg.len());
+        for run in transfer::split_banks(reg) {
+            let end = run.end;
+            data.extend(self.read_bank_pipelined(apsel, port, &reg[run]));
+            if data.len() < end {
+                break;
+            }
+        }
+        data
+    }
+
+    /// Read registers in the same bank in one pipeline
+    fn read_bank_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>> {
         let bank = reg[0] >> 2;
-        self.bank_select(apsel, bank as u32, 0);
+        if let Err(e) = self.bank_select(apsel, bank as u32, 0) {
+            return vec![Err(e)];
+        }
 
-        let ir = [port as u8];
-        self.write_ir(&ir);
+        self.write_instruction(self.ir.port(port));
         let buf = [((reg[0] & 3) << 1) | 1, 0, 0, 0, 0];
-        self.taps.write_dr(&buf, 3);
+        self.pace();
+        self.taps.borrow_mut().write_dr(&buf, 3);
+        self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, Some(&buf), None);
+        self.idle_after(port);
 
         let mut count = 0;
         let mut queue_full = false;
         for r in &reg[1..] {
-            // Make sure all registers are in the same bank
-            assert_eq!(r >> 2, reg[0] >> 2);
             let buf = [((r & 3) << 1) | 1, 0, 0, 0, 0];
-            if !self.taps.queue_dr_read_write(&buf, 3) {
+            self.pace();
+            if !self.taps.borrow_mut().queue_dr_read_write(&buf, 3) {
                 queue_full = true;
                 break;
             }
+            self.queue_scan(Some(buf));
+            self.idle_after(port);
             count += 1;
         }
 
-        if !queue_full {
-            if self.taps.queue_dr_read(35) {
-                count += 1;
-            }
+        if !queue_full && self.taps.borrow_mut().queue_dr_read(35) {
+            self.queue_scan(None);
+            count += 1;
         }
 
         let mut data = vec![];
         for _ in 0..count {
-            data.push(Self::parse_ack(self.taps.finish_dr_read(35)));
+            let dr = self.taps.borrow_mut().finish_dr_read(35);
+            self.log_queued_scan(&dr);
+            let result = Self::parse_ack(dr);
+            self.pacing.record(result.err().unwrap_or(2));
+            data.push(result);
         }
 
         data
@@ -254,73 +1162,379 @@ where
 
     /// Write multiple registers.  Each item of `reg` is a tuple consisting of the register address
     /// and the value to write.  This function makes more efficient use of the JTAG bus when there
-    /// are multiple reads to perform.
+    /// are multiple reads to perform.  Each run of registers in the same bank is written in one
+    /// pipeline.
     pub fn write_adi_pipelined(
         &mut self,
         apsel: u32,
         port: Port,
         reg: &[(u8, u32)],
     ) -> Result<(), u8> {
+        let regs: Vec<u8> = reg.iter().map(|(r, _)| *r).collect();
+        for run in transfer::split_banks(&regs) {
+            self.write_bank_pipelined(apsel, port, &reg[run])?;
+        }
+        Ok(())
+    }
+
+    /// Write registers in the same bank in one pipeline
+    fn write_bank_pipelined(&mut self, apsel: u32, port: Port, reg: &[(u8, u32)]) -> Result<(), u8> {
         let bank = reg[0].0 >> 2;
-        self.bank_select(apsel, bank as u32, 0);
+        self.bank_select(apsel, bank as u32, 0)?;
 
-        let ir = [port as u8];
-        self.write_ir(&ir);
+        self.write_instruction(self.ir.port(port));
 
         for (r, val) in reg {
-            // Make sure all registers are in the same bank
-            assert_eq!(r >> 2, reg[0].0 >> 2);
-
             let mut val = *val as u64;
             val <<= 3;
             val |= ((r & 3) << 1) as u64;
 
             let bytes = val.to_le_bytes();
-            self.taps.write_dr(&bytes[0..5], 3);
+            self.pace();
+            self.taps.borrow_mut().write_dr(&bytes[0..5], 3);
+            self.log_scan(ScanKind::Dr, wire_log::ACC_BITS, Some(&bytes[0..5]), None);
+            self.idle_after(port);
         }
         Ok(())
     }
 }
 
+impl<T, U> Transport for ArmDebugInterface<T>
+where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    fn read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8> {
+        ArmDebugInterface::read_adi(self, apsel, port, reg)
+    }
+
+    fn queue_read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> bool {
+        ArmDebugInterface::queue_read_adi(self, apsel, port, reg)
+    }
+
+    fn finish_read(&mut self) -> Result<u32, u8> {
+        ArmDebugInterface::finish_read(self)
+    }
+
+    fn write_adi(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        ArmDebugInterface::write_adi(self, apsel, port, reg, val)
+    }
+
+    fn write_adi_nocheck(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        ArmDebugInterface::write_adi_nocheck(self, apsel, port, reg, val)
+    }
+
+    fn read_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>> {
+        ArmDebugInterface::read_adi_pipelined(self, apsel, port, reg)
+    }
+
+    fn write_adi_pipelined(
+        &mut self,
+        apsel: u32,
+        port: Port,
+        reg: &[(u8, u32)],
+    ) -> Result<(), u8> {
+        ArmDebugInterface::write_adi_pipelined(self, apsel, port, reg)
+    }
+
+    fn read_adi_batch(&mut self, reads: &[(u32, Port, u8)]) -> Vec<Result<u32, u8>> {
+        let mut batch = batch::Batch::new();
+        for &(apsel, port, reg) in reads {
+            batch.read(apsel, port, reg);
+        }
+        batch.execute(self)
+    }
+
+    fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8> {
+        ArmDebugInterface::read_ctrl_stat(self)
+    }
+
+    fn abort_transaction(&mut self) -> Result<(), u8> {
+        ArmDebugInterface::abort_transaction(self)
+    }
+
+    fn wait_ratio(&self) -> Option<f32> {
+        Some(ArmDebugInterface::wait_ratio(self))
+    }
+}
+
+/// TAR auto-increment is only guaranteed to work within a 1kB block
+const AUTOINC_BLOCK: u32 = 0x400;
+
+/// Return TAR after `words` auto-incremented word accesses from `addr`, taking the increment to
+/// wrap within the block as `reconnect` does, rather than carry into the next one
+fn autoinc_tar(addr: u32, words: usize) -> u32 {
+    addr & !(AUTOINC_BLOCK - 1) | addr.wrapping_add(4 * words as u32) & (AUTOINC_BLOCK - 1)
+}
+
+/// CSW.Size field
+const CSW_SIZE_MASK: u32 = 7;
+const CSW_SIZE_8: u32 = 0;
+const CSW_SIZE_16: u32 = 1;
+
 #[allow(clippy::upper_case_acronyms)]
 enum MemAPReg {
     CSW = 0,
     TAR = 1,
     DRW = 3,
-    //Base0 = 0xf0 >> 2,
-    //CFG = 0xf4 >> 2,
-    //Base1 = 0xf8 >> 2,
-    //IDR = 0xfc >> 2,
+    Base0 = 0xf0 >> 2,
+    CFG = 0xf4 >> 2,
+    Base1 = 0xf8 >> 2,
+    IDR = 0xfc >> 2,
+}
+
+/// A MemAP access that kept getting WAIT, as happens when accessing a peripheral whose clock is
+/// gated.  The transaction was aborted, and CSW and TAR restored, so the AP can be used again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusStall {
+    pub addr: u32,
+}
+
+impl fmt::Display for BusStall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bus stalled accessing 0x{:08x}", self.addr)
+    }
+}
+
+impl core::error::Error for BusStall {}
+
+/// A sticky flag found set in CTRL/STAT after a `MemAP` access.  `addr` is the address accessed,
+/// or the first address of a block transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StickyError {
+    /// STICKYERR: the access faulted on the bus
+    Bus { addr: u32 },
+    /// WDATAERR: the write data was corrupted, so the write may have gone anywhere
+    WriteData { addr: u32 },
+    /// STICKYORUN: a transaction overran the last one, which is an artifact of scanning too fast
+    /// rather than a problem with the target
+    Overrun { addr: u32 },
+    /// STICKYCMP: a pushed compare matched
+    Compare { addr: u32 },
 }
 
+impl StickyError {
+    /// Decode the sticky flags in the CTRL/STAT value `stat` after an access to `addr`.  If more
+    /// than one is set, the most serious is returned.
+    pub fn from_ctrl_stat(stat: u32, addr: u32) -> Option<Self> {
+        let stat = CtrlStat::from(stat);
+        if stat.stickyerr {
+            Some(StickyError::Bus { addr })
+        } else if stat.wdataerr {
+            Some(StickyError::WriteData { addr })
+        } else if stat.stickyorun {
+            Some(StickyError::Overrun { addr })
+        } else if stat.stickycmp {
+            Some(StickyError::Compare { addr })
+        } else {
+            None
+        }
+    }
+
+    pub fn addr(&self) -> u32 {
+        match *self {
+            StickyError::Bus { addr }
+            | StickyError::WriteData { addr }
+            | StickyError::Overrun { addr }
+            | StickyError::Compare { addr } => addr,
+        }
+    }
+
+    /// The error code returned for this flag
+    pub fn code(&self) -> u8 {
+        match self {
+            StickyError::Bus { .. } => ERR_STICKY,
+            StickyError::WriteData { .. } => ERR_WDATAERR,
+            StickyError::Overrun { .. } => ERR_STICKY_ORUN,
+            StickyError::Compare { .. } => ERR_STICKY_CMP,
+        }
+    }
+}
+
+impl fmt::Display for StickyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self {
+            StickyError::Bus { .. } => "bus error",
+            StickyError::WriteData { .. } => "write data error",
+            StickyError::Overrun { .. } => "overrun",
+            StickyError::Compare { .. } => "pushed compare match",
+        };
+        write!(f, "{} accessing 0x{:08x}", what, self.addr())
+    }
+}
+
+impl core::error::Error for StickyError {}
+
 /// Functions for interacting with a Memory Access Port
-pub struct MemAP<T> {
-    adi: Rc<RefCell<ArmDebugInterface<T>>>,
+pub struct MemAP<T: ?Sized> {
+    adi: Rc<RefCell<T>>,
     apsel: u32,
     csw: u32,
     tar: u32,
+    /// The last access that failed with `ERR_BUS_STALL`
+    stall: Option<BusStall>,
+    /// The sticky flag that failed the last access that failed with one
+    sticky: Option<StickyError>,
+    /// Re-issue reads dropped by WAIT in `read_multi`, sleeping this long first
+    reissue_waits: Option<Duration>,
+    map: Option<Rc<memory_map::MemoryMap>>,
+    /// Writes to protected regions of the memory map are allowed
+    unprotected: bool,
+    /// Checks made before each access, if enabled
+    preflight: Option<csw::Preflight>,
+    /// Why the last access that failed with `ERR_AP_DISABLED` wasn't allowed
+    disabled: Option<csw::ApDisabled>,
+    /// Most words `read_memory` and `write_memory` put in one block
+    max_block: Option<usize>,
+    /// `csw` and `tar` haven't been read from the AP yet, see `new_lazy`
+    unloaded: bool,
+    /// Program `csw::initial_profile` when CSW is first read
+    configure_csw: bool,
 }
 
-impl<T, U> MemAP<T>
+impl<T> MemAP<T>
 where
-    T: DerefMut<Target = U>,
-    U: Cable + ?Sized,
+    T: Transport + ?Sized,
 {
-    pub fn new(adi: Rc<RefCell<ArmDebugInterface<T>>>, apsel: u32) -> Self {
-        let csw = adi
-            .borrow_mut()
-            .read_adi(apsel, Port::AP, MemAPReg::CSW as u8)
-            .expect("read csw");
-        let tar = adi
-            .borrow_mut()
-            .read_adi(apsel, Port::AP, MemAPReg::TAR as u8)
-            .expect("read tar");
-        Self { adi, apsel, csw, tar }
+    /// CSW is programmed with `csw::initial_profile`, so that accesses don't depend on how the
+    /// boot ROM or a previous debugger left it, if IDR says the AP is a MEM-AP.  Panics if CSW and
+    /// TAR can't be read.  See `new_lazy` for a MemAP that can be created before the AP is
+    /// accessible.
+    pub fn new(adi: Rc<RefCell<T>>, apsel: u32) -> Self {
+        Self::try_new(adi, apsel).expect("read csw and tar")
+    }
+
+    /// As `new`, but return the error if CSW and TAR can't be read
+    pub fn try_new(adi: Rc<RefCell<T>>, apsel: u32) -> Result<Self, u8> {
+        let mut mem = Self::new_lazy(adi, apsel);
+        mem.load_caches()?;
+        Ok(mem)
+    }
+
+    /// As `new`, but keep the CSW the AP has
+    pub fn new_keep_csw(adi: Rc<RefCell<T>>, apsel: u32) -> Self {
+        let mut mem = Self::new_lazy(adi, apsel);
+        mem.set_configure_csw(false);
+        mem.load_caches().expect("read csw and tar");
+        mem
+    }
+
+    /// Create a MemAP without accessing the AP, for example before the target is powered up.  CSW
+    /// and TAR are read by the first access that needs them, which returns any error in reading
+    /// them.
+    pub fn new_lazy(adi: Rc<RefCell<T>>, apsel: u32) -> Self {
+        Self {
+            adi,
+            apsel,
+            csw: 0,
+            tar: 0,
+            stall: None,
+            sticky: None,
+            reissue_waits: None,
+            map: None,
+            unprotected: false,
+            preflight: None,
+            disabled: None,
+            max_block: None,
+            unloaded: true,
+            configure_csw: true,
+        }
+    }
+
+    /// Create a MemAP from state saved by `export_state`, for example after reconnecting to the
+    /// target.  CSW and TAR are written with the saved values instead of being read back.
+    pub fn from_state(adi: Rc<RefCell<T>>, state: MemAPState) -> Result<Self, u8> {
+        {
+            let mut adi = adi.borrow_mut();
+            adi.write_adi(state.apsel, Port::AP, MemAPReg::CSW as u8, state.csw)?;
+            adi.write_adi(state.apsel, Port::AP, MemAPReg::TAR as u8, state.tar)?;
+        }
+        Ok(Self {
+            adi,
+            apsel: state.apsel,
+            csw: state.csw,
+            tar: state.tar,
+            stall: None,
+            sticky: None,
+            reissue_waits: None,
+            map: None,
+            unprotected: false,
+            preflight: None,
+            disabled: None,
+            max_block: None,
+            unloaded: false,
+            configure_csw: false,
+        })
+    }
+
+    /// Program `csw::initial_profile` when CSW is first read, which is on by default.  This only
+    /// has an effect on a MemAP created with `new_lazy` that hasn't made an access yet.
+    pub fn set_configure_csw(&mut self, enable: bool) {
+        self.configure_csw = enable;
+    }
+
+    /// Read CSW and TAR into the caches, if they haven't been read yet
+    fn load_caches(&mut self) -> Result<(), u8> {
+        if !self.unloaded {
+            return Ok(());
+        }
+        let mut adi = self.adi.borrow_mut();
+        self.csw = adi.read_adi(self.apsel, Port::AP, MemAPReg::CSW as u8)?;
+        self.tar = adi.read_adi(self.apsel, Port::AP, MemAPReg::TAR as u8)?;
+        if self.configure_csw {
+            let idr = adi.read_adi(self.apsel, Port::AP, MemAPReg::IDR as u8)?;
+            let info = ap::ApInfo {
+                apsel: self.apsel,
+                idr,
+            };
+            // Register 0 is only CSW on a MEM-AP, so leave other APs as they are
+            if info.is_mem_ap() {
+                let csw = csw::initial_profile(info.ap_type()).apply(self.csw);
+                if csw != self.csw {
+                    adi.write_adi(self.apsel, Port::AP, MemAPReg::CSW as u8, csw)?;
+                    self.csw = csw;
+                }
+            }
+        }
+        drop(adi);
+        self.unloaded = false;
+        Ok(())
+    }
+
+    /// Save the cached CSW and TAR values.  For a MemAP created with `new_lazy`, they are only
+    /// meaningful once it has made an access.
+    pub fn export_state(&self) -> MemAPState {
+        MemAPState {
+            apsel: self.apsel,
+            csw: self.csw,
+            tar: self.tar,
+        }
+    }
+
+    /// Return the index of the access port
+    pub fn apsel(&self) -> u32 {
+        self.apsel
+    }
+
+    /// Clear the DP's sticky error flags after a failed access.  The flags are write-one-to-clear,
+    /// and the rest of CTRL/STAT is written back unchanged.
+    pub fn clear_sticky_errors(&mut self) -> Result<(), u8> {
+        let mut adi = self.adi.borrow_mut();
+        let stat = adi.read_adi(0, Port::DP, DPReg::CtrlStat as u8)?;
+        adi.write_adi(0, Port::DP, DPReg::CtrlStat as u8, stat)
+    }
+
+    /// Return the control and status word.  The value is cached, but a MemAP created with
+    /// `new_lazy` reads it from the AP first if it hasn't made an access yet.
+    pub fn csw(&mut self) -> Result<u32, u8> {
+        self.load_caches()?;
+        Ok(self.csw)
     }
 
     /// Set the control and status word of the MemAP.  `MemAP` caches the value of this register,
     /// so it should not be modified other than by this function.
     pub fn write_csw(&mut self, csw: u32) -> Result<(), u8> {
+        self.load_caches()?;
         if csw != self.csw {
             self.adi
                 .borrow_mut()
@@ -330,8 +1544,87 @@ where
         Ok(())
     }
 
+    /// Return the last access that failed with `ERR_BUS_STALL`
+    pub fn bus_stall(&self) -> Option<BusStall> {
+        self.stall
+    }
+
+    /// Return the sticky flag that failed the last access with `ERR_STICKY`, `ERR_WDATAERR`,
+    /// `ERR_STICKY_ORUN` or `ERR_STICKY_CMP`, and the address it was accessing
+    pub fn sticky_error(&self) -> Option<StickyError> {
+        self.sticky
+    }
+
+    /// Read CTRL/STAT after an access to `addr`, and fail if it left a sticky flag set
+    fn check_status(&mut self, addr: u32) -> Result<(), u8> {
+        let stat = self
+            .adi
+            .borrow_mut()
+            .read_adi(self.apsel, Port::DP, DPReg::CtrlStat as u8)?;
+        match StickyError::from_ctrl_stat(stat, addr) {
+            Some(e) => {
+                self.sticky = Some(e);
+                Err(e.code())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Make `read_multi` re-issue the reads that were dropped because they got a WAIT, sleeping
+    /// for the given time before each retry, so that it always returns the number of words asked
+    /// for.  If the reads are still answered with WAIT after `STALL_TIMEOUT`, it fails with
+    /// `ERR_BUS_STALL`.  With None, the default, the words that got a WAIT are left out of the
+    /// result.
+    pub fn set_reissue_waits(&mut self, backoff: Option<Duration>) {
+        self.reissue_waits = backoff;
+    }
+
+    /// Limit the blocks `read_memory` and `write_memory` split transfers into to `words` each, for
+    /// example to fit the cable's queue.  None allows a whole TAR auto-increment block.
+    pub fn set_max_block(&mut self, words: Option<usize>) {
+        self.max_block = words;
+    }
+
+    pub fn max_block(&self) -> Option<usize> {
+        self.max_block
+    }
+
+    /// Read DRW, retrying while the AP answers WAIT.  If it still does after `STALL_TIMEOUT`, the
+    /// access to `addr` has stalled the bus.
+    fn read_drw(&mut self, addr: u32) -> Result<u32, u8> {
+        let start = Instant::now();
+        loop {
+            let result = self
+                .adi
+                .borrow_mut()
+                .read_adi(self.apsel, Port::AP, MemAPReg::DRW as u8);
+            match result {
+                Err(ACK_WAIT) if start.elapsed() < STALL_TIMEOUT => {}
+                Err(ACK_WAIT) => return Err(self.recover_stall(addr)),
+                result => return result,
+            }
+        }
+    }
+
+    /// Abort a transaction at `addr` that is stuck returning WAIT, clear the sticky errors and
+    /// write back the cached CSW and TAR, in case the AP lost them.  Returns `ERR_BUS_STALL`, or
+    /// the error that prevented recovery.
+    fn recover_stall(&mut self, addr: u32) -> u8 {
+        self.stall = Some(BusStall { addr });
+        let result = (|| -> Result<(), u8> {
+            self.adi.borrow_mut().abort_transaction()?;
+            self.clear_sticky_errors()?;
+            let mut adi = self.adi.borrow_mut();
+            adi.write_adi(self.apsel, Port::AP, MemAPReg::CSW as u8, self.csw)?;
+            adi.write_adi(self.apsel, Port::AP, MemAPReg::TAR as u8, self.tar)
+        })();
+        result.err().unwrap_or(ERR_BUS_STALL)
+    }
+
     /// Read a single 32-bit quantity from `addr`
     pub fn read(&mut self, addr: u32) -> Result<u32, u8> {
+        self.load_caches()?;
+        self.preflight()?;
         // Make sure we're not in auto-increment mode
         self.write_csw(self.csw & !(1 << 4))?;
         if self.tar != addr {
@@ -340,21 +1633,13 @@ where
                 .write_adi(self.apsel, Port::AP, MemAPReg::TAR as u8, addr)?;
             self.tar = addr;
         }
-        let val = self
-            .adi
-            .borrow_mut()
-            .read_adi(self.apsel, Port::AP, MemAPReg::DRW as u8)?;
-        let stat = self
-            .adi
-            .borrow_mut()
-            .read_adi(self.apsel, Port::DP, DPReg::CtrlStat as u8)?;
-        if stat & 5 != 0 {
-            return Err(5);
-        }
+        let val = self.read_drw(addr)?;
+        self.check_status(addr)?;
         Ok(val)
     }
 
     pub fn queue_read(&mut self, addr: u32) -> Result<bool, u8> {
+        self.load_caches()?;
         // Make sure we're not in auto-increment mode
         self.write_csw(self.csw & !(1 << 4))?;
         if self.tar != addr {
@@ -379,8 +1664,51 @@ where
         Ok(val)
     }
 
+    /// Perform `op` with CSW.Size set to `size`, then set it back
+    fn with_size<R>(
+        &mut self,
+        size: u32,
+        op: impl FnOnce(&mut Self) -> Result<R, u8>,
+    ) -> Result<R, u8> {
+        self.load_caches()?;
+        let csw = self.csw;
+        self.write_csw(csw & !CSW_SIZE_MASK | size)?;
+        let result = op(self);
+        self.write_csw(csw)?;
+        result
+    }
+
+    /// Read the byte at `addr` with a single byte-sized access
+    pub fn read_byte(&mut self, addr: u32) -> Result<u8, u8> {
+        let val = self.with_size(CSW_SIZE_8, |mem| mem.read(addr))?;
+        Ok((val >> (8 * (addr & 3))) as u8)
+    }
+
+    /// Write the byte `value` to `addr` with a single byte-sized access, for devices that are
+    /// sensitive to the access size
+    pub fn write_byte(&mut self, addr: u32, value: u8) -> Result<(), u8> {
+        // The AP takes the data from the byte lane selected by the address
+        let lanes = u32::from_le_bytes([value; 4]);
+        self.with_size(CSW_SIZE_8, |mem| mem.write(addr, lanes))
+    }
+
+    /// Read the halfword at `addr`, which must be halfword aligned
+    pub fn read_halfword(&mut self, addr: u32) -> Result<u16, u8> {
+        let val = self.with_size(CSW_SIZE_16, |mem| mem.read(addr))?;
+        Ok((val >> (8 * (addr & 2))) as u16)
+    }
+
+    /// Write the halfword `value` to `addr`, which must be halfword aligned
+    pub fn write_halfword(&mut self, addr: u32, value: u16) -> Result<(), u8> {
+        let lanes = (value as u32) << 16 | value as u32;
+        self.with_size(CSW_SIZE_16, |mem| mem.write(addr, lanes))
+    }
+
     /// Write `value` to `addr`
     pub fn write(&mut self, addr: u32, value: u32) -> Result<(), u8> {
+        self.load_caches()?;
+        self.check_write(addr, 1 << (self.csw & CSW_SIZE_MASK))?;
+        self.preflight()?;
         // Make sure we're not in auto-increment mode
         self.write_csw(self.csw & !(1 << 4))?;
         if self.tar != addr {
@@ -389,17 +1717,15 @@ where
                 .write_adi(self.apsel, Port::AP, MemAPReg::TAR as u8, addr)?;
             self.tar = addr;
         }
-        self.adi
-            .borrow_mut()
-            .write_adi(self.apsel, Port::AP, MemAPReg::DRW as u8, value)?;
-        let stat = self
+        let result = self
             .adi
             .borrow_mut()
-            .read_adi(self.apsel, Port::DP, DPReg::CtrlStat as u8)?;
-        if stat & 5 != 0 {
-            return Err(5);
+            .write_adi(self.apsel, Port::AP, MemAPReg::DRW as u8, value);
+        if result == Err(ACK_WAIT) {
+            return Err(self.recover_stall(addr));
         }
-        Ok(())
+        result?;
+        self.check_status(addr)
     }
 
     /// Read multiple values from memory.  If `check_status` is true, then the CTRL/STAT
@@ -413,6 +1739,8 @@ where
         auto_increment: bool,
         check_status: bool,
     ) -> Result<Vec<u32>, u8> {
+        self.load_caches()?;
+        self.preflight()?;
         // Enable auto-increment mode
         if auto_increment {
             self.write_csw(self.csw | (1 << 4))?;
@@ -424,36 +1752,45 @@ where
             self.adi
                 .borrow_mut()
                 .write_adi(self.apsel, Port::AP, MemAPReg::TAR as u8, addr)?;
-            self.tar = addr;
+        }
+        self.tar = addr;
+
+        let start = Instant::now();
+        let mut result = Vec::with_capacity(count);
+        loop {
+            let reg = vec![MemAPReg::DRW as u8; count - result.len()];
+            let val = self
+                .adi
+                .borrow_mut()
+                .read_adi_pipelined(self.apsel, Port::AP, &reg);
+
+            // Since we are always reading from the same register, any WAIT acks can be dropped.
+            // The AP didn't do those reads, so TAR has only advanced past the others.
+            for item in val {
+                match item {
+                    Ok(x) => result.push(x),
+                    Err(ACK_WAIT) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
             if auto_increment {
-                self.tar += 4 * count as u32;
+                self.tar = autoinc_tar(addr, result.len());
             }
-        }
 
-        let reg = vec![MemAPReg::DRW as u8; count];
-        let val = self
-            .adi
-            .borrow_mut()
-            .read_adi_pipelined(self.apsel, Port::AP, &reg);
-
-        // Since we are always reading from the same register, any WAIT acks can be dropped
-        let mut result = vec![];
-        for item in val {
-            match item {
-                Ok(x) => result.push(x),
-                Err(1) => continue,
-                Err(e) => return Err(e),
+            let Some(backoff) = self.reissue_waits else {
+                break;
+            };
+            if result.len() >= count {
+                break;
+            }
+            if start.elapsed() >= STALL_TIMEOUT {
+                return Err(self.recover_stall(self.tar));
             }
+            time::sleep(backoff);
         }
 
         if check_status {
-            let stat =
-                self.adi
-                    .borrow_mut()
-                    .read_adi(self.apsel, Port::DP, DPReg::CtrlStat as u8)?;
-            if stat & 5 != 0 {
-                return Err(5);
-            }
+            self.check_status(addr)?;
         }
         Ok(result)
     }
@@ -467,14 +1804,46 @@ where
         count: usize,
         check_status: bool,
     ) -> Result<Vec<u32>, u8> {
+        self.check_block(addr, count, false)?;
         self.read_multi(addr, count, true, check_status)
     }
 
+    /// Read `count` consecutive words starting at `addr`.  Unlike `read_block`, the transfer may
+    /// be any length: it is split wherever TAR auto-increment would wrap or the block would
+    /// exceed `max_block`, and the CTRL/STAT register is checked once at the end.  Regions of the
+    /// memory map that need byte accesses are read a byte at a time.
+    pub fn read_memory(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, u8> {
+        let mut result = Vec::with_capacity(count);
+        for (start, n, attributes) in self.map_runs(addr, count) {
+            if attributes.byte_only && !attributes.read_sensitive {
+                result.extend(self.read_words_bytewise(start, n)?);
+                continue;
+            }
+            for chunk in transfer::plan(start, n, self.max_block.unwrap_or(usize::MAX)) {
+                let end = result.len() + chunk.count;
+                while result.len() < end {
+                    let addr = addr.wrapping_add(4 * result.len() as u32);
+                    let n = end - result.len();
+                    let data = self.read_block(addr, n, n == count - result.len())?;
+                    // Reads that got a WAIT are dropped, so fewer words may come back than were
+                    // asked for.  If none came back, the AP is stuck.
+                    if data.is_empty() {
+                        return Err(self.recover_stall(addr));
+                    }
+                    result.extend(data);
+                }
+            }
+        }
+        Ok(result)
+    }
 
     /// Write `data` starting at `addr`.  If `check_status` is true, then the CTRL/STAT
     /// register is checked for errors at the end of the transaction, which comes with a slight
     /// performance penalty.
     pub fn write_block(&mut self, addr: u32, data: &[u32], check_status: bool) -> Result<(), u8> {
+        self.check_block(addr, data.len(), true)?;
+        self.load_caches()?;
+        self.preflight()?;
         // Enable auto-increment mode
         self.write_csw(self.csw | (1 << 4))?;
 
@@ -482,8 +1851,8 @@ where
             self.adi
                 .borrow_mut()
                 .write_adi(self.apsel, Port::AP, MemAPReg::TAR as u8, addr)?;
-            self.tar = addr + 4 * data.len() as u32;
         }
+        self.tar = autoinc_tar(addr, data.len());
 
         let reg: Vec<(u8, u32)> = data.iter().map(|x| (MemAPReg::DRW as u8, *x)).collect();
         self.adi
@@ -491,12 +1860,29 @@ where
             .write_adi_pipelined(self.apsel, Port::AP, &reg)?;
 
         if check_status {
-            let stat =
-                self.adi
-                    .borrow_mut()
-                    .read_adi(self.apsel, Port::DP, DPReg::CtrlStat as u8)?;
-            if stat & 5 != 0 {
-                return Err(5);
+            self.check_status(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at `addr`.  Unlike `write_block`, the transfer may be any length: it
+    /// is split wherever TAR auto-increment would wrap or the block would exceed `max_block`, and
+    /// the CTRL/STAT register is checked once at the end.  Regions of the memory map that need
+    /// byte accesses are written a byte at a time.
+    pub fn write_memory(&mut self, addr: u32, data: &[u32]) -> Result<(), u8> {
+        let mut remaining = data.len();
+        for (start, n, attributes) in self.map_runs(addr, data.len()) {
+            let offset = data.len() - remaining;
+            let run = &data[offset..offset + n];
+            remaining -= n;
+            if attributes.byte_only && !attributes.read_only {
+                self.write_words_bytewise(start, run)?;
+                continue;
+            }
+            for chunk in transfer::plan(start, n, self.max_block.unwrap_or(usize::MAX)) {
+                let last = chunk.offset + chunk.count == n && remaining == 0;
+                let words = &run[chunk.offset..chunk.offset + chunk.count];
+                self.write_block(chunk.addr, words, last)?;
             }
         }
         Ok(())
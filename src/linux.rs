@@ -0,0 +1,373 @@
+//! Post-mortem helpers for halted AArch64 Linux targets: reading the kernel log and listing
+//! tasks from kernel memory.  Memory is read through a MemAP, so kernel virtual addresses are
+//! translated to physical ones with a `LinearMap` describing where the kernel placed its linear
+//! map and its image.  Only physical addresses below 4GB can be read.
+//!
+//! The kernel log is read from the lockless printk ring buffer of Linux 5.10 and later.  The
+//! offsets of `task_struct` fields depend on the kernel version and configuration, so they are
+//! described by a `TaskLayout`, which can be found with `pahole` or GDB on the vmlinux file.
+
+use std::fmt;
+
+use crate::{MemAP, Transport};
+
+/// Error returned when a kernel address can't be translated or is out of reach of the MemAP
+pub const ERR_UNMAPPED: u8 = 0x40;
+
+/// Longest list walked, in case the list is corrupt
+const MAX_TASKS: usize = 1 << 16;
+/// Largest printk descriptor ring followed, as log2 of its size.  The largest log buffer the
+/// kernel config allows has 2^20 descriptors; this leaves room for `log_buf_len=` on top.
+const MAX_COUNT_BITS: u32 = 24;
+
+// struct prb_desc_ring
+const DESC_RING_COUNT_BITS: u64 = 0;
+const DESC_RING_DESCS: u64 = 8;
+const DESC_RING_INFOS: u64 = 16;
+const DESC_RING_HEAD_ID: u64 = 24;
+const DESC_RING_TAIL_ID: u64 = 32;
+
+// struct prb_data_ring
+const DATA_RING_SIZE_BITS: u64 = 0;
+const DATA_RING_DATA: u64 = 8;
+
+// struct prb_desc
+const DESC_SIZE: u64 = 24;
+const DESC_STATE: u64 = 0;
+const DESC_BEGIN: u64 = 8;
+const DESC_NEXT: u64 = 16;
+
+// struct printk_info
+const INFO_SIZE: u64 = 88;
+const INFO_SEQ: u64 = 0;
+const INFO_TS_NSEC: u64 = 8;
+const INFO_TEXT_LEN: u64 = 16;
+const INFO_FLAGS: u64 = 19;
+
+const DESC_FLAGS_SHIFT: u32 = 62;
+const DESC_ID_MASK: u64 = (1 << DESC_FLAGS_SHIFT) - 1;
+const DESC_COMMITTED: u64 = 1;
+const DESC_FINALIZED: u64 = 2;
+/// Set in `begin` of a data block that couldn't be allocated
+const FAILED_LPOS: u64 = 1;
+
+/// arm64 `struct cpu_context`: X19-X28, FP, SP and PC
+const CPU_CONTEXT_SP: u64 = 11 * 8;
+const CPU_CONTEXT_PC: u64 = 12 * 8;
+
+/// How kernel virtual addresses map to physical addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinearMap {
+    /// Start of the linear map, PAGE_OFFSET
+    pub page_offset: u64,
+    /// End of the linear map, PAGE_END
+    pub page_end: u64,
+    /// Physical address mapped at `page_offset`, PHYS_OFFSET or `memstart_addr`
+    pub phys_offset: u64,
+    /// Offset of the kernel image's virtual addresses from its physical ones, `kimage_voffset`
+    pub kimage_voffset: u64,
+}
+
+impl LinearMap {
+    /// Describe the layout of Linux 5.4 and later with `va_bits` bits of kernel virtual address,
+    /// where the linear map fills the lower half of the kernel's address space
+    pub fn new(va_bits: u32, phys_offset: u64, kimage_voffset: u64) -> Self {
+        Self {
+            page_offset: 0u64.wrapping_sub(1 << va_bits),
+            page_end: 0u64.wrapping_sub(1 << (va_bits - 1)),
+            phys_offset,
+            kimage_voffset,
+        }
+    }
+
+    /// Like `new`, but read the physical offset from the kernel's `memstart_addr` variable at
+    /// virtual address `memstart_addr`
+    pub fn from_memstart_addr<T>(
+        mem: &mut MemAP<T>,
+        va_bits: u32,
+        kimage_voffset: u64,
+        memstart_addr: u64,
+    ) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let mut map = Self::new(va_bits, 0, kimage_voffset);
+        map.phys_offset = map.read_u64(mem, memstart_addr)?;
+        Ok(map)
+    }
+
+    /// Translate a kernel virtual address in the linear map or the kernel image to a physical
+    /// address.  Returns None for user addresses.
+    pub fn virt_to_phys(&self, va: u64) -> Option<u64> {
+        if va >> 63 == 0 {
+            None
+        } else if (self.page_offset..self.page_end).contains(&va) {
+            Some(va - self.page_offset + self.phys_offset)
+        } else {
+            Some(va.wrapping_sub(self.kimage_voffset))
+        }
+    }
+
+    /// Translate a physical address to its address in the linear map
+    pub fn phys_to_virt(&self, pa: u64) -> u64 {
+        pa.wrapping_sub(self.phys_offset)
+            .wrapping_add(self.page_offset)
+    }
+
+    /// Read `len` bytes at kernel virtual address `va`
+    pub fn read_bytes<T>(&self, mem: &mut MemAP<T>, va: u64, len: usize) -> Result<Vec<u8>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let pa = self.virt_to_phys(va).ok_or(ERR_UNMAPPED)?;
+        if pa.saturating_add(len as u64) > 1 << 32 {
+            return Err(ERR_UNMAPPED);
+        }
+        let first = pa & !3;
+        let words = (pa - first) as usize + len;
+        let data = mem.read_memory(first as u32, words.div_ceil(4))?;
+        let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        Ok(bytes[(pa - first) as usize..][..len].to_vec())
+    }
+
+    pub fn read_u64<T>(&self, mem: &mut MemAP<T>, va: u64) -> Result<u64, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let bytes = self.read_bytes(mem, va, 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u32<T>(&self, mem: &mut MemAP<T>, va: u64) -> Result<u32, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let bytes = self.read_bytes(mem, va, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Addresses of the kernel variables used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// `init_task`
+    pub init_task: u64,
+    /// `prb`, the pointer to the printk ring buffer
+    pub prb: u64,
+}
+
+#[cfg(feature = "elf")]
+impl Symbols {
+    /// Find the kernel's variables in the symbol table of vmlinux.  Returns None if any are
+    /// missing.
+    pub fn from_elf(elf: &crate::elf::Symbols) -> Option<Self> {
+        Some(Self {
+            init_task: elf.address("init_task")?,
+            prb: elf.address("prb")?,
+        })
+    }
+}
+
+/// Offsets of the fields of `task_struct`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskLayout {
+    /// `__state`, or `state` before Linux 5.14
+    pub state: u64,
+    /// `tasks`, the list of all tasks
+    pub tasks: u64,
+    pub pid: u64,
+    pub tgid: u64,
+    pub comm: u64,
+    /// `thread.cpu_context`, holding the registers saved when the task was switched out
+    pub cpu_context: Option<u64>,
+}
+
+/// A task found by walking the task list
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    /// Address of the `task_struct`
+    pub address: u64,
+    pub pid: i32,
+    pub tgid: i32,
+    pub comm: String,
+    /// Raw task state, 0 for runnable
+    pub state: u32,
+    /// Stack pointer saved when the task was switched out, which is stale for running tasks
+    pub sp: Option<u64>,
+    /// Where the task will resume, normally in `__switch_to`
+    pub pc: Option<u64>,
+}
+
+/// Offsets of fields of the printk ring buffer which moved between kernel versions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrbLayout {
+    /// Offset of `text_data_ring` in `struct printk_ringbuffer`.  This is 48 in kernels where
+    /// `prb_desc_ring` has a `last_finalized_seq` field.
+    pub text_data_ring: u64,
+}
+
+impl Default for PrbLayout {
+    fn default() -> Self {
+        Self { text_data_ring: 40 }
+    }
+}
+
+/// A message from the kernel log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    pub seq: u64,
+    /// Time since boot in nanoseconds
+    pub timestamp_ns: u64,
+    /// Log level, 0 for KERN_EMERG to 7 for KERN_DEBUG
+    pub level: u8,
+    pub text: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.timestamp_ns / 1_000_000_000;
+        let usecs = self.timestamp_ns % 1_000_000_000 / 1000;
+        write!(f, "[{:5}.{:06}] {}", secs, usecs, self.text)
+    }
+}
+
+/// Reads kernel data structures from the memory of a halted Linux target
+pub struct Linux {
+    map: LinearMap,
+    symbols: Symbols,
+    prb_layout: PrbLayout,
+}
+
+impl Linux {
+    pub fn new(map: LinearMap, symbols: Symbols) -> Self {
+        Self {
+            map,
+            symbols,
+            prb_layout: PrbLayout::default(),
+        }
+    }
+
+    pub fn set_prb_layout(&mut self, layout: PrbLayout) {
+        self.prb_layout = layout;
+    }
+
+    pub fn linear_map(&self) -> &LinearMap {
+        &self.map
+    }
+
+    /// Read the records in the kernel log, oldest first.  Records which are still being written
+    /// are skipped.
+    pub fn dmesg<T>(&self, mem: &mut MemAP<T>) -> Result<Vec<LogRecord>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let map = &self.map;
+        let prb = map.read_u64(mem, self.symbols.prb)?;
+        let desc_ring = prb;
+        let data_ring = prb + self.prb_layout.text_data_ring;
+
+        let count_bits = map.read_u32(mem, desc_ring + DESC_RING_COUNT_BITS)?;
+        let descs = map.read_u64(mem, desc_ring + DESC_RING_DESCS)?;
+        let infos = map.read_u64(mem, desc_ring + DESC_RING_INFOS)?;
+        let head_id = map.read_u64(mem, desc_ring + DESC_RING_HEAD_ID)? & DESC_ID_MASK;
+        let tail_id = map.read_u64(mem, desc_ring + DESC_RING_TAIL_ID)? & DESC_ID_MASK;
+        let size_bits = map.read_u32(mem, data_ring + DATA_RING_SIZE_BITS)?;
+        let data = map.read_u64(mem, data_ring + DATA_RING_DATA)?;
+        if count_bits > MAX_COUNT_BITS || size_bits >= 32 {
+            return Err(ERR_UNMAPPED);
+        }
+        let data_size = 1u64 << size_bits;
+
+        let mut records = vec![];
+        let mut id = tail_id;
+        for _ in 0..1u64 << count_bits {
+            let index = id & ((1 << count_bits) - 1);
+            let desc = map.read_bytes(mem, descs + index * DESC_SIZE, DESC_SIZE as usize)?;
+            let word = |off: u64| u64::from_le_bytes(desc[off as usize..][..8].try_into().unwrap());
+            let state = word(DESC_STATE);
+            let (begin, next) = (word(DESC_BEGIN), word(DESC_NEXT));
+
+            let committed = matches!(state >> DESC_FLAGS_SHIFT, DESC_COMMITTED | DESC_FINALIZED);
+            if state & DESC_ID_MASK == id && committed && begin & FAILED_LPOS == 0 && begin != next
+            {
+                // A block which would wrap is stored at the start of the ring instead
+                let (block, len) = if begin >> size_bits == next >> size_bits {
+                    (data + (begin & (data_size - 1)), next - begin)
+                } else {
+                    (data, next & (data_size - 1))
+                };
+
+                let info = map.read_bytes(mem, infos + index * INFO_SIZE, INFO_SIZE as usize)?;
+                let field = |off: u64| info[off as usize..][..8].try_into().unwrap();
+                let text_len = INFO_TEXT_LEN as usize;
+                let text_len = u16::from_le_bytes([info[text_len], info[text_len + 1]]) as u64;
+                // The block starts with the descriptor ID
+                let len = text_len.min(len.saturating_sub(8));
+                let text = map.read_bytes(mem, block + 8, len as usize)?;
+                records.push(LogRecord {
+                    seq: u64::from_le_bytes(field(INFO_SEQ)),
+                    timestamp_ns: u64::from_le_bytes(field(INFO_TS_NSEC)),
+                    level: info[INFO_FLAGS as usize] >> 5,
+                    text: String::from_utf8_lossy(&text).into_owned(),
+                });
+            }
+
+            if id == head_id {
+                break;
+            }
+            id = (id + 1) & DESC_ID_MASK;
+        }
+        Ok(records)
+    }
+
+    fn read_task<T>(&self, mem: &mut MemAP<T>, task: u64, layout: &TaskLayout) -> Result<Task, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let map = &self.map;
+        let comm = map.read_bytes(mem, task + layout.comm, 16)?;
+        let len = comm.iter().position(|&b| b == 0).unwrap_or(comm.len());
+        let (sp, pc) = match layout.cpu_context {
+            Some(context) => (
+                Some(map.read_u64(mem, task + context + CPU_CONTEXT_SP)?),
+                Some(map.read_u64(mem, task + context + CPU_CONTEXT_PC)?),
+            ),
+            None => (None, None),
+        };
+        Ok(Task {
+            address: task,
+            pid: map.read_u32(mem, task + layout.pid)? as i32,
+            tgid: map.read_u32(mem, task + layout.tgid)? as i32,
+            comm: String::from_utf8_lossy(&comm[..len]).into_owned(),
+            state: map.read_u32(mem, task + layout.state)?,
+            sp,
+            pc,
+        })
+    }
+
+    /// Return the tasks on the list starting at `init_task.tasks`, beginning with `init_task`.
+    /// Only thread group leaders are on this list.
+    pub fn tasks<T>(&self, mem: &mut MemAP<T>, layout: &TaskLayout) -> Result<Vec<Task>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let init_task = self.symbols.init_task;
+        let mut tasks = vec![self.read_task(mem, init_task, layout)?];
+        let mut task = init_task;
+        while tasks.len() < MAX_TASKS {
+            // tasks.next points at the tasks field of the next task_struct
+            task = self
+                .map
+                .read_u64(mem, task + layout.tasks)?
+                .wrapping_sub(layout.tasks);
+            if task == init_task {
+                break;
+            }
+            tasks.push(self.read_task(mem, task, layout)?);
+        }
+        Ok(tasks)
+    }
+}
@@ -0,0 +1,68 @@
+//! A common interface to target memory, so that callers can choose per operation whether to
+//! access memory through a MEM-AP or through a halted core.
+//!
+//! A `MemAP` accesses physical memory directly from the bus, without disturbing the core, but
+//! bypasses the core's caches and MMU.  A `Core` performs the accesses with loads and stores
+//! executed on the core, so they are translated by the MMU and coherent with its caches, at the
+//! cost of speed and of needing the core to be halted.
+
+use crate::armv8::Core;
+use crate::{MemAP, Transport};
+
+/// Error returned when an address can't be reached through the interface
+pub const ERR_ADDRESS_RANGE: u8 = 0x41;
+
+/// Word-sized access to target memory.  Addresses must be word aligned.
+pub trait MemoryInterface {
+    /// Read `count` consecutive words starting at `addr`
+    fn read_words(&mut self, addr: u64, count: usize) -> Result<Vec<u32>, u8>;
+
+    /// Write `data` starting at `addr`
+    fn write_words(&mut self, addr: u64, data: &[u32]) -> Result<(), u8>;
+
+    fn read_word(&mut self, addr: u64) -> Result<u32, u8> {
+        Ok(self.read_words(addr, 1)?[0])
+    }
+
+    fn write_word(&mut self, addr: u64, val: u32) -> Result<(), u8> {
+        self.write_words(addr, &[val])
+    }
+}
+
+/// Accesses physical addresses below 4GB through the MEM-AP
+impl<T> MemoryInterface for MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    fn read_words(&mut self, addr: u64, count: usize) -> Result<Vec<u32>, u8> {
+        let addr = u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?;
+        self.read_memory(addr, count)
+    }
+
+    fn write_words(&mut self, addr: u64, data: &[u32]) -> Result<(), u8> {
+        let addr = u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?;
+        self.write_memory(addr, data)
+    }
+
+    fn read_word(&mut self, addr: u64) -> Result<u32, u8> {
+        self.read(u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?)
+    }
+
+    fn write_word(&mut self, addr: u64, val: u32) -> Result<(), u8> {
+        self.write(u32::try_from(addr).map_err(|_| ERR_ADDRESS_RANGE)?, val)
+    }
+}
+
+/// Accesses virtual addresses with loads and stores executed on the halted core
+impl<T> MemoryInterface for Core<T>
+where
+    T: Transport + ?Sized,
+{
+    fn read_words(&mut self, addr: u64, count: usize) -> Result<Vec<u32>, u8> {
+        self.read_memory(addr, count)
+    }
+
+    fn write_words(&mut self, addr: u64, data: &[u32]) -> Result<(), u8> {
+        self.write_memory(addr, data)
+    }
+}
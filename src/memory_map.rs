@@ -0,0 +1,243 @@
+//! A description of the target's memory installed on a `MemAP`, so that block transfers don't
+//! disturb memory that only tolerates certain accesses.  A block read that strays into a FIFO
+//! would pop it, and a word access to a peripheral that only decodes byte accesses may fault or
+//! write the wrong lanes.
+//!
+//! `read_memory` and `write_memory` use byte accesses for regions that need them, and
+//! `read_block`, `write_block` and the others built on them refuse to transfer a block that
+//! overlaps a region it mustn't touch.  Single-word accesses with `read` and `write` are always
+//! made as asked, except that writes to protected regions, such as flash, OTP or secure RAM, are
+//! refused unless they are made inside `MemAP::unprotected`.
+
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{MemAP, Transport};
+
+/// Error returned by a block read of a read-sensitive region
+pub const ERR_READ_SENSITIVE: u8 = 0x44;
+/// Error returned by a block write to a read-only region
+pub const ERR_READ_ONLY: u8 = 0x45;
+/// Error returned by a block transfer of words in a region that needs byte accesses
+pub const ERR_BYTE_ONLY: u8 = 0x46;
+/// Error returned by a write to a protected region outside `MemAP::unprotected`
+pub const ERR_WRITE_PROTECTED: u8 = 0x47;
+
+/// How a region of memory may be accessed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attributes {
+    /// Only byte accesses work
+    pub byte_only: bool,
+    /// Reads have side effects, such as popping a FIFO, so the region is never read
+    /// speculatively or as part of a larger block
+    pub read_sensitive: bool,
+    /// Writes are ignored or fault
+    pub read_only: bool,
+    /// Writes are refused unless made inside `MemAP::unprotected`, as a stray write could brick
+    /// the board
+    pub protected: bool,
+}
+
+impl Attributes {
+    /// The attributes of memory covered by both `self` and `other`
+    fn union(self, other: Attributes) -> Attributes {
+        Attributes {
+            byte_only: self.byte_only || other.byte_only,
+            read_sensitive: self.read_sensitive || other.read_sensitive,
+            read_only: self.read_only || other.read_only,
+            protected: self.protected || other.protected,
+        }
+    }
+}
+
+/// A range of addresses with the same attributes
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub attributes: Attributes,
+}
+
+impl Region {
+    /// Return the address after the end of the region
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.size as u64
+    }
+
+    /// Return true if the region overlaps the `len` bytes at `addr`
+    pub fn overlaps(&self, addr: u64, len: u64) -> bool {
+        addr < self.end() && (self.start as u64) < addr + len
+    }
+}
+
+/// Regions of memory with their attributes.  Memory outside every region may be accessed in
+/// any way.  Where regions overlap, the memory has the attributes of all of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the region of `size` bytes at `start` with `attributes`
+    pub fn add(&mut self, name: &str, start: u32, size: u32, attributes: Attributes) {
+        self.regions.push(Region {
+            name: name.to_string(),
+            start,
+            size,
+            attributes,
+        });
+    }
+
+    /// Add a protected region of `size` bytes at `start`
+    pub fn protect(&mut self, name: &str, start: u32, size: u32) {
+        let attributes = Attributes {
+            protected: true,
+            ..Default::default()
+        };
+        self.add(name, start, size, attributes);
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Return the first region containing `addr`
+    pub fn region(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().find(|r| r.overlaps(addr as u64, 1))
+    }
+
+    /// Return the attributes of the `len` bytes at `addr`, combined over every region they
+    /// overlap
+    pub fn attributes(&self, addr: u32, len: u32) -> Attributes {
+        self.regions
+            .iter()
+            .filter(|r| r.overlaps(addr as u64, len as u64))
+            .fold(Attributes::default(), |a, r| a.union(r.attributes))
+    }
+
+    /// Split the `count` words at `addr` into runs of words with the same attributes
+    fn split(&self, addr: u32, count: usize) -> Vec<(u32, usize, Attributes)> {
+        let start = addr as u64;
+        let end = start + 4 * count as u64;
+        // Word indices where a region starts or ends
+        let mut bounds = vec![0, count];
+        for r in self
+            .regions
+            .iter()
+            .filter(|r| r.overlaps(start, end - start))
+        {
+            if r.start as u64 > start {
+                bounds.push(((r.start as u64 - start) / 4) as usize);
+            }
+            if r.end() < end {
+                bounds.push(((r.end() - start).div_ceil(4)) as usize);
+            }
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut runs: Vec<(u32, usize, Attributes)> = vec![];
+        for pair in bounds.windows(2) {
+            let (first, n) = (pair[0], pair[1] - pair[0]);
+            let run_addr = addr.wrapping_add(4 * first as u32);
+            let attributes = self.attributes(run_addr, 4 * n as u32);
+            match runs.last_mut() {
+                Some(last) if last.2 == attributes => last.1 += n,
+                _ => runs.push((run_addr, n, attributes)),
+            }
+        }
+        runs
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Install `map`, or remove the installed map with None
+    pub fn set_memory_map(&mut self, map: Option<MemoryMap>) {
+        self.map = map.map(Rc::new);
+    }
+
+    pub fn memory_map(&self) -> Option<&MemoryMap> {
+        self.map.as_deref()
+    }
+
+    /// Split the `count` words at `addr` into runs with the same attributes in the memory map
+    pub(crate) fn map_runs(&self, addr: u32, count: usize) -> Vec<(u32, usize, Attributes)> {
+        match &self.map {
+            Some(map) => map.split(addr, count),
+            None => vec![(addr, count, Attributes::default())],
+        }
+    }
+
+    /// Check that a block transfer of `count` words at `addr` doesn't touch memory it mustn't
+    pub(crate) fn check_block(&self, addr: u32, count: usize, write: bool) -> Result<(), u8> {
+        let Some(map) = &self.map else {
+            return Ok(());
+        };
+        let attributes = map.attributes(addr, 4 * count as u32);
+        if write && attributes.protected && !self.unprotected {
+            Err(ERR_WRITE_PROTECTED)
+        } else if write && attributes.read_only {
+            Err(ERR_READ_ONLY)
+        } else if !write && attributes.read_sensitive {
+            Err(ERR_READ_SENSITIVE)
+        } else if attributes.byte_only {
+            Err(ERR_BYTE_ONLY)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the `len` bytes at `addr` may be written
+    pub(crate) fn check_write(&self, addr: u32, len: u32) -> Result<(), u8> {
+        match &self.map {
+            Some(map) if !self.unprotected && map.attributes(addr, len).protected => {
+                Err(ERR_WRITE_PROTECTED)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Perform `op` with writes to protected regions allowed
+    pub fn unprotected<R>(&mut self, op: impl FnOnce(&mut Self) -> R) -> R {
+        let unprotected = core::mem::replace(&mut self.unprotected, true);
+        let result = op(self);
+        self.unprotected = unprotected;
+        result
+    }
+
+    /// Read the `count` words at `addr` a byte at a time
+    pub(crate) fn read_words_bytewise(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, u8> {
+        let mut words = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut bytes = [0; 4];
+            for (j, byte) in bytes.iter_mut().enumerate() {
+                *byte = self.read_byte(addr.wrapping_add((4 * i + j) as u32))?;
+            }
+            words.push(u32::from_le_bytes(bytes));
+        }
+        Ok(words)
+    }
+
+    /// Write `data` at `addr` a byte at a time
+    pub(crate) fn write_words_bytewise(&mut self, addr: u32, data: &[u32]) -> Result<(), u8> {
+        for (i, word) in data.iter().enumerate() {
+            for (j, byte) in word.to_le_bytes().into_iter().enumerate() {
+                self.write_byte(addr.wrapping_add((4 * i + j) as u32), byte)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+//! RAM test patterns for board bring-up.  Each pattern fills a range of memory with block writes,
+//! reads it back with block reads and reports the words that differ.  The previous contents of
+//! the range are lost.
+
+use crate::{MemAP, Transport};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// A single set bit, rotated by one position per word and per pass
+    WalkingOnes,
+    /// A single clear bit, rotated by one position per word and per pass
+    WalkingZeros,
+    /// Each word holds its own address, then its complement
+    AddressInAddress,
+    /// Alternating 0x55555555 and 0xaaaaaaaa, then the inverse
+    Checkerboard,
+}
+
+impl Pattern {
+    pub const ALL: [Pattern; 4] = [
+        Pattern::WalkingOnes,
+        Pattern::WalkingZeros,
+        Pattern::AddressInAddress,
+        Pattern::Checkerboard,
+    ];
+
+    fn passes(self) -> u32 {
+        match self {
+            Pattern::WalkingOnes | Pattern::WalkingZeros => 32,
+            Pattern::AddressInAddress | Pattern::Checkerboard => 2,
+        }
+    }
+
+    /// Value of word `index` at `addr` in pass `pass`
+    fn word(self, pass: u32, index: usize, addr: u32) -> u32 {
+        let invert = if pass & 1 == 0 { 0 } else { !0 };
+        match self {
+            Pattern::WalkingOnes => 1 << ((index as u32 + pass) % 32),
+            Pattern::WalkingZeros => !(1 << ((index as u32 + pass) % 32)),
+            Pattern::AddressInAddress => addr ^ invert,
+            Pattern::Checkerboard => {
+                let word = if index & 1 == 0 {
+                    0x55555555
+                } else {
+                    0xaaaaaaaa
+                };
+                word ^ invert
+            }
+        }
+    }
+}
+
+/// A word that didn't read back as written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Failure {
+    pub pattern: Pattern,
+    pub address: u32,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl Failure {
+    /// Return the bits that differ
+    pub fn bits(&self) -> u32 {
+        self.expected ^ self.actual
+    }
+}
+
+/// Run `pattern` over `count` words starting at `addr`, returning the words that failed
+pub fn run<T>(
+    mem: &mut MemAP<T>,
+    addr: u32,
+    count: usize,
+    pattern: Pattern,
+) -> Result<Vec<Failure>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut failures = vec![];
+    for pass in 0..pattern.passes() {
+        let expected: Vec<u32> = (0..count)
+            .map(|i| pattern.word(pass, i, addr.wrapping_add(4 * i as u32)))
+            .collect();
+        mem.write_memory(addr, &expected)?;
+        let actual = mem.read_memory(addr, count)?;
+        for (i, (&expected, &actual)) in expected.iter().zip(&actual).enumerate() {
+            if expected != actual {
+                failures.push(Failure {
+                    pattern,
+                    address: addr.wrapping_add(4 * i as u32),
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Run all of the patterns over `count` words starting at `addr`
+pub fn run_all<T>(mem: &mut MemAP<T>, addr: u32, count: usize) -> Result<Vec<Failure>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut failures = vec![];
+    for pattern in Pattern::ALL {
+        failures.extend(run(mem, addr, count, pattern)?);
+    }
+    Ok(failures)
+}
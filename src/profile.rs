@@ -0,0 +1,233 @@
+//! Statistical profiling by sampling the PC of a running core.  Samples are either bare PCs,
+//! read without disturbing the core, or full stacks, taken by briefly halting an ARMv8-A core
+//! and walking its frame records.  A `Profile` can be written as folded stacks for inferno or
+//! flamegraph.pl, or summarized as a table of hits per function.
+//!
+//! On targets running an OS, ARMv8-A PC samples can be taken with their context ID and VMID,
+//! and split into a profile per process or virtual machine.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::armv8::{Core, PcSample};
+use crate::backtrace::backtrace;
+use crate::cortex_m::CortexM;
+#[cfg(feature = "elf")]
+use crate::elf::Symbols;
+use crate::{MemAP, Transport};
+
+/// A core whose PC can be sampled while it runs
+pub trait PcSampler {
+    /// Read the current PC, or None if no sample is available
+    fn sample_pc(&mut self) -> Result<Option<u64>, u8>;
+}
+
+impl<T> PcSampler for Core<T>
+where
+    T: Transport + ?Sized,
+{
+    fn sample_pc(&mut self) -> Result<Option<u64>, u8> {
+        Core::sample_pc(self)
+    }
+}
+
+impl<T> PcSampler for CortexM<T>
+where
+    T: Transport + ?Sized,
+{
+    fn sample_pc(&mut self) -> Result<Option<u64>, u8> {
+        Ok(CortexM::sample_pc(self)?.map(|pc| pc as u64))
+    }
+}
+
+/// Hits of one function in a `Profile`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionHits {
+    pub name: String,
+    /// Samples taken in the function itself
+    pub own: u64,
+    /// Samples with the function anywhere on the stack
+    pub total: u64,
+}
+
+/// A set of samples.  Each is a stack of addresses, newest first; PC samples have only one.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    stacks: HashMap<Vec<u64>, u64>,
+    samples: u64,
+    /// Attempts that didn't produce a sample
+    missed: u64,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sample, newest address first
+    pub fn add(&mut self, stack: Vec<u64>) {
+        *self.stacks.entry(stack).or_default() += 1;
+        self.samples += 1;
+    }
+
+    /// Number of samples taken
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Number of attempts that didn't produce a sample, for example because the core was halted
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Count of each distinct stack, with addresses replaced by `name(addr)` and the oldest frame
+    /// first
+    fn named_stacks(&self, name: &impl Fn(u64) -> String) -> BTreeMap<Vec<String>, u64> {
+        let mut named: BTreeMap<Vec<String>, u64> = BTreeMap::new();
+        for (stack, count) in &self.stacks {
+            let frames = stack.iter().rev().map(|&a| name(a)).collect();
+            *named.entry(frames).or_default() += count;
+        }
+        named
+    }
+
+    /// Write the samples in the folded stack format read by inferno and flamegraph.pl, naming
+    /// each address with `name`, such as `symbol_name`
+    pub fn write_folded(
+        &self,
+        out: &mut dyn Write,
+        name: impl Fn(u64) -> String,
+    ) -> io::Result<()> {
+        for (frames, count) in self.named_stacks(&name) {
+            writeln!(out, "{} {}", frames.join(";"), count)?;
+        }
+        Ok(())
+    }
+
+    /// Return the hits of every function, naming each address with `name`, with the most
+    /// frequently sampled function first
+    pub fn functions(&self, name: impl Fn(u64) -> String) -> Vec<FunctionHits> {
+        let mut hits: HashMap<String, FunctionHits> = HashMap::new();
+        for (frames, count) in self.named_stacks(&name) {
+            let mut seen: Vec<&String> = vec![];
+            for (i, f) in frames.iter().rev().enumerate() {
+                let entry = hits.entry(f.clone()).or_insert_with(|| FunctionHits {
+                    name: f.clone(),
+                    own: 0,
+                    total: 0,
+                });
+                if i == 0 {
+                    entry.own += count;
+                }
+                // Count recursive functions once per sample
+                if !seen.contains(&f) {
+                    entry.total += count;
+                    seen.push(f);
+                }
+            }
+        }
+        let mut hits: Vec<FunctionHits> = hits.into_values().collect();
+        hits.sort_by(|a, b| b.own.cmp(&a.own).then(b.total.cmp(&a.total)));
+        hits
+    }
+}
+
+/// Name addresses with the function containing them, falling back to the bare address
+#[cfg(feature = "elf")]
+pub fn symbol_name(symbols: &Symbols) -> impl Fn(u64) -> String + '_ {
+    |addr| match symbols.lookup(addr) {
+        Some((sym, _)) => sym.name.clone(),
+        None => format!("{:#x}", addr),
+    }
+}
+
+/// Name addresses in hex, when no symbols are available
+pub fn address_name(addr: u64) -> String {
+    format!("{:#x}", addr)
+}
+
+/// Sample the PC of `sampler` every `interval` for `duration`.  The core isn't disturbed.
+pub fn sample_pcs(
+    sampler: &mut impl PcSampler,
+    duration: Duration,
+    interval: Duration,
+) -> Result<Profile, u8> {
+    let mut profile = Profile::new();
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        match sampler.sample_pc()? {
+            Some(pc) => profile.add(vec![pc]),
+            None => profile.missed += 1,
+        }
+        thread::sleep(interval);
+    }
+    Ok(profile)
+}
+
+/// Sample the PC, context ID and VMID of a running ARMv8-A core every `interval` for
+/// `duration`.  Attempts which don't produce a sample are skipped.
+pub fn sample_contexts<T>(
+    core: &mut Core<T>,
+    duration: Duration,
+    interval: Duration,
+) -> Result<Vec<PcSample>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut samples = vec![];
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        samples.extend(core.sample()?);
+        thread::sleep(interval);
+    }
+    Ok(samples)
+}
+
+/// Split `samples` into a profile for each value of `key`, such as
+/// `|s| s.context_id` for a profile per process or `|s| s.vmid` per virtual machine
+pub fn split_samples<K>(samples: &[PcSample], key: impl Fn(&PcSample) -> K) -> HashMap<K, Profile>
+where
+    K: Eq + Hash,
+{
+    let mut profiles: HashMap<K, Profile> = HashMap::new();
+    for s in samples {
+        profiles.entry(key(s)).or_default().add(vec![s.pc]);
+    }
+    profiles
+}
+
+/// Sample the stack of a running ARMv8-A core every `interval` for `duration`, by halting it,
+/// walking up to `max_depth` frame records through `mem` and resuming it.  This perturbs the
+/// timing of the program, so use a longer interval than for `sample_pcs`.
+pub fn sample_stacks<T>(
+    core: &mut Core<T>,
+    mem: &mut MemAP<T>,
+    duration: Duration,
+    interval: Duration,
+    max_depth: usize,
+) -> Result<Profile, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut profile = Profile::new();
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        // A core that won't halt, for example because it has powered down, misses the sample
+        if core.halt_and_wait().is_err() {
+            profile.missed += 1;
+            thread::sleep(interval);
+            continue;
+        }
+        let stack = backtrace(core, mem, max_depth, false);
+        core.resume()?;
+        match stack {
+            Ok(stack) => profile.add(stack),
+            Err(_) => profile.missed += 1,
+        }
+        thread::sleep(interval);
+    }
+    Ok(profile)
+}
@@ -0,0 +1,288 @@
+//! Python bindings, enabled with the `python` feature.  Build the extension module with maturin
+//! and use it like:
+//!
+//! ```python
+//! import jtag_adi
+//! adi = jtag_adi.ArmDebugInterface("ftdi", 1000000)
+//! mem = jtag_adi.MemAP(adi, 0)
+//! hex(mem.read(0x80000000))
+//! open("sram.bin", "wb").write(mem.read_bytes(0x20000000, 0x10000))
+//! ```
+//!
+//! Errors from the debug interface are raised as `AdiError`, with the crate's error code as the
+//! argument, and invalid arguments as `ValueError`.  Objects hold a reference to the interface
+//! they came from and must stay on the thread which created them.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use jtag_taps::cable;
+use jtag_taps::statemachine::JtagSM;
+use jtag_taps::taps::Taps;
+
+use crate::armv8;
+use crate::remote::RemoteDap;
+use crate::{Port, Transport};
+
+create_exception!(jtag_adi, AdiError, PyException);
+
+fn check<T>(result: Result<T, u8>) -> PyResult<T> {
+    result.map_err(AdiError::new_err)
+}
+
+fn port(port: &str) -> PyResult<Port> {
+    match port {
+        "dp" | "DP" => Ok(Port::DP),
+        "ap" | "AP" => Ok(Port::AP),
+        _ => Err(PyValueError::new_err(format!("bad port {}", port))),
+    }
+}
+
+/// Check that `n` names one of X0-X30, which the core asserts
+fn gpr(n: u32) -> PyResult<u32> {
+    match n {
+        0..=30 => Ok(n),
+        _ => Err(PyValueError::new_err(format!("bad register X{}", n))),
+    }
+}
+
+/// Check that the fields of a system register encoding fit, as larger values would encode a
+/// different instruction
+fn sysreg(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32) -> PyResult<()> {
+    if op0 > 3 || op1 > 7 || crn > 15 || crm > 15 || op2 > 7 {
+        return Err(PyValueError::new_err(format!(
+            "bad system register S{}_{}_C{}_C{}_{}",
+            op0, op1, crn, crm, op2
+        )));
+    }
+    Ok(())
+}
+
+/// Connection to a debug port, either through a local cable or a `jtag-adi serve` instance
+#[pyclass(unsendable)]
+pub struct ArmDebugInterface {
+    adi: Rc<RefCell<dyn Transport>>,
+}
+
+#[pymethods]
+impl ArmDebugInterface {
+    #[new]
+    #[pyo3(signature = (cable, baud, tap_index = 0))]
+    fn new(cable: &str, baud: u32, tap_index: usize) -> PyResult<Self> {
+        let cable = cable::new_from_string(cable, baud).map_err(PyIOError::new_err)?;
+        let jtag = JtagSM::new(cable);
+        let mut taps = Taps::new(jtag);
+        taps.detect();
+
+        // IDCODE instruction
+        let ir = vec![14];
+        taps.select_tap(tap_index, &ir);
+        let dap = check(crate::ArmDebugInterface::try_new(taps))?;
+        Ok(Self {
+            adi: Rc::new(RefCell::new(dap)),
+        })
+    }
+
+    /// Connect to a debug interface shared by `jtag-adi serve` at `addr`
+    #[staticmethod]
+    fn remote(addr: &str) -> PyResult<Self> {
+        let dap = RemoteDap::connect(addr)?;
+        Ok(Self {
+            adi: Rc::new(RefCell::new(dap)),
+        })
+    }
+
+    /// Read register `reg` of the DP or AP.  `port` is "dp" or "ap".
+    fn read_adi(&self, apsel: u32, port: &str, reg: u8) -> PyResult<u32> {
+        let port = self::port(port)?;
+        check(self.adi.borrow_mut().read_adi(apsel, port, reg))
+    }
+
+    /// Write `val` to register `reg` of the DP or AP.  `port` is "dp" or "ap".
+    fn write_adi(&self, apsel: u32, port: &str, reg: u8, val: u32) -> PyResult<()> {
+        let port = self::port(port)?;
+        check(self.adi.borrow_mut().write_adi(apsel, port, reg, val))
+    }
+
+    /// Return the raw value of the DP CTRL/STAT register
+    fn ctrl_stat(&self) -> PyResult<u32> {
+        check(self.adi.borrow_mut().read_ctrl_stat()).map(u32::from)
+    }
+}
+
+/// Memory Access Port
+#[pyclass(unsendable)]
+pub struct MemAP {
+    mem: Rc<RefCell<crate::MemAP<dyn Transport>>>,
+}
+
+#[pymethods]
+impl MemAP {
+    #[new]
+    fn new(adi: &ArmDebugInterface, apsel: u32) -> PyResult<Self> {
+        let mem = check(crate::MemAP::try_new(adi.adi.clone(), apsel))?;
+        Ok(Self {
+            mem: Rc::new(RefCell::new(mem)),
+        })
+    }
+
+    #[getter]
+    fn csw(&self) -> PyResult<u32> {
+        check(self.mem.borrow_mut().csw())
+    }
+
+    #[setter]
+    fn set_csw(&self, csw: u32) -> PyResult<()> {
+        check(self.mem.borrow_mut().write_csw(csw))
+    }
+
+    /// Read the word at `addr`
+    fn read(&self, addr: u32) -> PyResult<u32> {
+        check(self.mem.borrow_mut().read(addr))
+    }
+
+    /// Write `value` to the word at `addr`
+    fn write(&self, addr: u32, value: u32) -> PyResult<()> {
+        check(self.mem.borrow_mut().write(addr, value))
+    }
+
+    /// Read `count` consecutive words starting at `addr` as a list
+    fn read_block(&self, addr: u32, count: usize) -> PyResult<Vec<u32>> {
+        check(self.mem.borrow_mut().read_memory(addr, count))
+    }
+
+    /// Write a list of words to consecutive addresses starting at `addr`
+    fn write_block(&self, addr: u32, data: Vec<u32>) -> PyResult<()> {
+        check(self.mem.borrow_mut().write_memory(addr, &data))
+    }
+
+    /// Read `length` bytes starting at `addr`.  Both must be word aligned.
+    fn read_bytes<'py>(
+        &self,
+        py: Python<'py>,
+        addr: u32,
+        length: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        if addr & 3 != 0 || length & 3 != 0 {
+            return Err(PyValueError::new_err(
+                "address and length must be word aligned",
+            ));
+        }
+        let words = check(self.mem.borrow_mut().read_memory(addr, length / 4))?;
+        let bytes: Vec<u8> = words.iter().flat_map(|x| x.to_le_bytes()).collect();
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Write `data` starting at `addr`.  Both must be word aligned.
+    fn write_bytes(&self, addr: u32, data: &[u8]) -> PyResult<()> {
+        if addr & 3 != 0 || data.len() & 3 != 0 {
+            return Err(PyValueError::new_err(
+                "address and length must be word aligned",
+            ));
+        }
+        let words: Vec<u32> = data
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect();
+        check(self.mem.borrow_mut().write_memory(addr, &words))
+    }
+}
+
+/// Run control for an ARMv8-A core.  The debug registers are unlocked when it is created.
+#[pyclass(unsendable)]
+pub struct Core {
+    core: armv8::Core<dyn Transport>,
+}
+
+#[pymethods]
+impl Core {
+    #[new]
+    fn new(mem: &MemAP, debug_base: u32, cti_base: u32) -> PyResult<Self> {
+        let mut core = armv8::Core::new(mem.mem.clone(), debug_base, cti_base);
+        check(core.unlock())?;
+        Ok(Self { core })
+    }
+
+    fn is_halted(&mut self) -> PyResult<bool> {
+        check(self.core.is_halted())
+    }
+
+    fn halt(&mut self) -> PyResult<()> {
+        check(self.core.halt())
+    }
+
+    fn resume(&mut self) -> PyResult<()> {
+        check(self.core.resume())
+    }
+
+    fn step(&mut self) -> PyResult<()> {
+        check(self.core.step())
+    }
+
+    /// Execute the A64 instruction `instr` on the halted core
+    fn execute(&mut self, instr: u32) -> PyResult<()> {
+        check(self.core.execute(instr))
+    }
+
+    /// Read general purpose register X`n`
+    fn read_reg(&mut self, n: u32) -> PyResult<u64> {
+        check(self.core.read_reg(gpr(n)?))
+    }
+
+    /// Write `val` to general purpose register X`n`
+    fn write_reg(&mut self, n: u32, val: u64) -> PyResult<()> {
+        check(self.core.write_reg(gpr(n)?, val))
+    }
+
+    fn read_sysreg(&mut self, op0: u32, op1: u32, crn: u32, crm: u32, op2: u32) -> PyResult<u64> {
+        sysreg(op0, op1, crn, crm, op2)?;
+        check(self.core.read_sysreg(op0, op1, crn, crm, op2))
+    }
+
+    fn write_sysreg(
+        &mut self,
+        op0: u32,
+        op1: u32,
+        crn: u32,
+        crm: u32,
+        op2: u32,
+        val: u64,
+    ) -> PyResult<()> {
+        sysreg(op0, op1, crn, crm, op2)?;
+        check(self.core.write_sysreg(op0, op1, crn, crm, op2, val))
+    }
+
+    #[getter]
+    fn pc(&mut self) -> PyResult<u64> {
+        check(self.core.read_pc())
+    }
+
+    #[setter]
+    fn set_pc(&mut self, pc: u64) -> PyResult<()> {
+        check(self.core.write_pc(pc))
+    }
+
+    #[getter]
+    fn pstate(&mut self) -> PyResult<u64> {
+        check(self.core.read_pstate())
+    }
+
+    /// Return X0-X30
+    fn read_regs(&mut self) -> PyResult<Vec<u64>> {
+        check(self.core.read_regs())
+    }
+}
+
+#[pymodule]
+fn jtag_adi(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("AdiError", m.py().get_type::<AdiError>())?;
+    m.add_class::<ArmDebugInterface>()?;
+    m.add_class::<MemAP>()?;
+    m.add_class::<Core>()?;
+    Ok(())
+}
@@ -0,0 +1,291 @@
+//! Automatic reattach after the link to the target is lost.  `Reconnecting` wraps another
+//! `Transport` together with a function that opens it.  When an access fails with an ACK that a
+//! JTAG-DP never sends, which is what a cable reads once the target is gone, or with
+//! `remote::ERR_DISCONNECTED`, the transport is opened again and the access retried, up to the
+//! limit set by the `ReconnectPolicy`.  The open function should return None rather than panic
+//! if the transport can't be opened, for example using `ArmDebugInterface::try_new`.  Panics,
+//! such as those of a USB cable whose device has gone away, are not caught.
+//!
+//! Opening the transport again powers up the DP and resets its SELECT cache.  The CSW and TAR of
+//! MEM-APs registered with `track_mem_ap` are also restored, so that a `MemAP` using the
+//! wrapper stays consistent with the hardware.  Packed transfers are not accounted for when
+//! tracking TAR.
+//!
+//! Retried accesses are repeated in full, so a pipelined write interrupted part way through may
+//! write some registers twice.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use crate::remote::{ERR_DISCONNECTED, ERR_NOT_QUEUED};
+use crate::{CtrlStat, Port, Transport};
+
+/// Error returned when the link could not be restored within the retry limit
+pub const ERR_LINK_LOST: u8 = 9;
+
+/// When and how often to try to reattach
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Number of times a failed access is retried, each after reopening the transport
+    pub max_retries: u32,
+    /// How long to wait before reopening, to let the probe or target settle
+    pub delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// ACK read when TDO is stuck low, as when the target has lost power
+const ACK_STUCK_LOW: u8 = 0b000;
+/// ACK read when TDO is pulled high with nothing driving it, as when the target is disconnected
+const ACK_STUCK_HIGH: u8 = 0b111;
+
+/// Return true if `ack` means the DP didn't answer at all rather than reporting an error
+fn is_link_error(ack: u8) -> bool {
+    matches!(ack, ACK_STUCK_LOW | ACK_STUCK_HIGH | ERR_DISCONNECTED)
+}
+
+/// CSW and TAR values last written to a MEM-AP, to be restored after reconnecting
+struct ApCache {
+    apsel: u32,
+    csw: Option<u32>,
+    tar: Option<u32>,
+}
+
+impl ApCache {
+    /// Account for a successful DRW access, which may have incremented TAR
+    fn drw_access(&mut self) {
+        let (Some(csw), Some(tar)) = (self.csw, self.tar) else {
+            return;
+        };
+        if (csw >> 4) & 3 == 1 {
+            // Auto-increment only carries within the bottom 10 bits
+            let size = 1 << (csw & 7);
+            self.tar = Some((tar & !0x3ff) | (tar.wrapping_add(size) & 0x3ff));
+        }
+    }
+}
+
+/// A `Transport` which reopens the transport it wraps when the link is lost
+pub struct Reconnecting<T, F> {
+    open: F,
+    dap: T,
+    policy: ReconnectPolicy,
+    aps: Vec<ApCache>,
+    /// Reads queued by `queue_read_adi` not yet returned by `finish_read`
+    queued: VecDeque<(u32, Port, u8)>,
+    /// Number of the oldest `queued` reads which were lost by reconnecting and must be reissued
+    lost: usize,
+    reconnects: u32,
+}
+
+impl<T, F> Reconnecting<T, F>
+where
+    T: Transport,
+    F: FnMut() -> Option<T>,
+{
+    /// Open the transport with `open`.  Returns None if it can't be opened.
+    pub fn new(mut open: F, policy: ReconnectPolicy) -> Option<Self> {
+        let dap = open()?;
+        Some(Self {
+            open,
+            dap,
+            policy,
+            aps: vec![],
+            queued: VecDeque::new(),
+            lost: 0,
+            reconnects: 0,
+        })
+    }
+
+    /// Restore the CSW and TAR of MEM-AP `apsel` after reconnecting
+    pub fn track_mem_ap(&mut self, apsel: u32) {
+        if !self.aps.iter().any(|ap| ap.apsel == apsel) {
+            self.aps.push(ApCache {
+                apsel,
+                csw: None,
+                tar: None,
+            });
+        }
+    }
+
+    /// Return the number of times the transport has been reopened
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    fn reconnect(&mut self) -> bool {
+        thread::sleep(self.policy.delay);
+        let Some(dap) = (self.open)() else {
+            return false;
+        };
+        self.dap = dap;
+        self.reconnects += 1;
+        self.lost = self.queued.len();
+
+        for ap in &self.aps {
+            let restore = [(0, ap.csw), (1, ap.tar)];
+            for (reg, val) in restore {
+                if let Some(val) = val {
+                    if self.dap.write_adi(ap.apsel, Port::AP, reg, val).is_err() {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Run `op`, reconnecting and retrying it if the link is lost
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut T) -> Result<R, u8>) -> Result<R, u8> {
+        for attempt in 0..=self.policy.max_retries {
+            if attempt > 0 && !self.reconnect() {
+                continue;
+            }
+            match op(&mut self.dap) {
+                Err(e) if is_link_error(e) => {}
+                result => return result,
+            }
+        }
+        Err(ERR_LINK_LOST)
+    }
+
+    fn ap_cache(&mut self, apsel: u32, port: Port) -> Option<&mut ApCache> {
+        if port != Port::AP {
+            return None;
+        }
+        self.aps.iter_mut().find(|ap| ap.apsel == apsel)
+    }
+
+    fn track_write(&mut self, apsel: u32, port: Port, reg: u8, val: u32) {
+        if let Some(ap) = self.ap_cache(apsel, port) {
+            match reg {
+                0 => ap.csw = Some(val),
+                1 => ap.tar = Some(val),
+                3 => ap.drw_access(),
+                _ => {}
+            }
+        }
+    }
+
+    fn track_read(&mut self, apsel: u32, port: Port, reg: u8) {
+        if reg == 3 {
+            if let Some(ap) = self.ap_cache(apsel, port) {
+                ap.drw_access();
+            }
+        }
+    }
+}
+
+impl<T, F> Transport for Reconnecting<T, F>
+where
+    T: Transport,
+    F: FnMut() -> Option<T>,
+{
+    fn read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8> {
+        let val = self.retry(|dap| dap.read_adi(apsel, port, reg))?;
+        self.track_read(apsel, port, reg);
+        Ok(val)
+    }
+
+    fn queue_read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> bool {
+        // Reads queued before a reconnect are reissued one at a time by finish_read, so later ones
+        // must be too to keep the results in order
+        if self.lost > 0 {
+            self.queued.push_back((apsel, port, reg));
+            self.lost += 1;
+            return true;
+        }
+        let queued = self.retry(|dap| Ok(dap.queue_read_adi(apsel, port, reg)));
+        if queued == Ok(true) {
+            self.queued.push_back((apsel, port, reg));
+        }
+        queued == Ok(true)
+    }
+
+    fn finish_read(&mut self) -> Result<u32, u8> {
+        let Some((apsel, port, reg)) = self.queued.pop_front() else {
+            return Err(ERR_NOT_QUEUED);
+        };
+        if self.lost > 0 {
+            self.lost -= 1;
+        } else {
+            match self.dap.finish_read() {
+                Err(e) if is_link_error(e) => {}
+                result => {
+                    if result.is_ok() {
+                        self.track_read(apsel, port, reg);
+                    }
+                    return result;
+                }
+            }
+            // The reads queued after this one were lost along with the link
+            if !self.reconnect() {
+                self.lost = self.queued.len();
+            }
+        }
+        self.read_adi(apsel, port, reg)
+    }
+
+    fn write_adi(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        self.retry(|dap| dap.write_adi(apsel, port, reg, val))?;
+        self.track_write(apsel, port, reg, val);
+        Ok(())
+    }
+
+    fn write_adi_nocheck(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        self.retry(|dap| dap.write_adi_nocheck(apsel, port, reg, val))?;
+        self.track_write(apsel, port, reg, val);
+        Ok(())
+    }
+
+    fn read_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>> {
+        let results = self
+            .retry(|dap| {
+                let results = dap.read_adi_pipelined(apsel, port, reg);
+                match results
+                    .iter()
+                    .find_map(|r| r.err().filter(|&e| is_link_error(e)))
+                {
+                    Some(e) => Err(e),
+                    None => Ok(results),
+                }
+            })
+            .unwrap_or_else(|e| vec![Err(e)]);
+
+        // Only DRW reads that succeeded move TAR
+        for (r, result) in reg.iter().zip(&results) {
+            if result.is_ok() {
+                self.track_read(apsel, port, *r);
+            }
+        }
+        results
+    }
+
+    fn write_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[(u8, u32)]) -> Result<(), u8> {
+        self.retry(|dap| dap.write_adi_pipelined(apsel, port, reg))?;
+        for (r, val) in reg {
+            self.track_write(apsel, port, *r, *val);
+        }
+        Ok(())
+    }
+
+    fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8> {
+        self.retry(|dap| dap.read_ctrl_stat())
+    }
+
+    fn abort_transaction(&mut self) -> Result<(), u8> {
+        self.retry(|dap| dap.abort_transaction())
+    }
+
+    fn wait_ratio(&self) -> Option<f32> {
+        self.dap.wait_ratio()
+    }
+}
@@ -0,0 +1,120 @@
+//! Typed memory-mapped registers.  The `register!` macro defines a register of a CoreSight
+//! block as a newtype over its value, with its offset in the block and a getter and setter for
+//! each named field, so that drivers and user code can read, modify and write registers by
+//! field name rather than by offset, shift and mask:
+//!
+//! ```ignore
+//! register! {
+//!     /// Example control register
+//!     pub struct Ctrl @ 0x000 {
+//!         /// Enable
+//!         en, set_en: 0;
+//!         mode, set_mode: 4..=7;
+//!     }
+//! }
+//!
+//! mem.modify_register::<Ctrl, _>(base, |ctrl| {
+//!     ctrl.set_en(true);
+//!     ctrl.set_mode(2);
+//! })?;
+//! ```
+//!
+//! A field given as a single bit is a `bool`; a field given as an inclusive bit range is a
+//! `u32`, and its setter ignores bits of the value that don't fit.
+
+use crate::{MemAP, Transport};
+
+/// A 32-bit register at a fixed offset in its block
+pub trait Register: Copy {
+    /// Offset of the register from the base of its block
+    const OFFSET: u32;
+
+    fn from_bits(bits: u32) -> Self;
+
+    fn bits(self) -> u32;
+}
+
+/// Define a register type.  See the module documentation.
+#[macro_export]
+macro_rules! register {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident @ $offset:literal {
+            $(
+                $(#[$fmeta:meta])*
+                $get:ident, $set:ident: $lsb:literal $(..= $msb:literal)?;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+        $vis struct $name(pub u32);
+
+        impl $crate::register::Register for $name {
+            const OFFSET: u32 = $offset;
+
+            fn from_bits(bits: u32) -> Self {
+                Self(bits)
+            }
+
+            fn bits(self) -> u32 {
+                self.0
+            }
+        }
+
+        impl $name {
+            $(
+                $crate::register!(@field $(#[$fmeta])* $get $set $lsb $($msb)?);
+            )*
+        }
+    };
+
+    (@field $(#[$fmeta:meta])* $get:ident $set:ident $bit:literal) => {
+        $(#[$fmeta])*
+        pub fn $get(&self) -> bool {
+            self.0 & (1 << $bit) != 0
+        }
+
+        pub fn $set(&mut self, val: bool) {
+            self.0 = self.0 & !(1 << $bit) | (val as u32) << $bit;
+        }
+    };
+
+    (@field $(#[$fmeta:meta])* $get:ident $set:ident $lsb:literal $msb:literal) => {
+        $(#[$fmeta])*
+        pub fn $get(&self) -> u32 {
+            (self.0 >> $lsb) & (u32::MAX >> (31 - $msb + $lsb))
+        }
+
+        pub fn $set(&mut self, val: u32) {
+            let mask = (u32::MAX >> (31 - $msb + $lsb)) << $lsb;
+            self.0 = self.0 & !mask | (val << $lsb) & mask;
+        }
+    };
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Read register `R` of the block at `base`
+    pub fn read_register<R: Register>(&mut self, base: u32) -> Result<R, u8> {
+        Ok(R::from_bits(self.read(base + R::OFFSET)?))
+    }
+
+    /// Write register `R` of the block at `base`
+    pub fn write_register<R: Register>(&mut self, base: u32, reg: R) -> Result<(), u8> {
+        self.write(base + R::OFFSET, reg.bits())
+    }
+
+    /// Read register `R` of the block at `base`, change it with `f` and write it back
+    pub fn modify_register<R, F>(&mut self, base: u32, f: F) -> Result<(), u8>
+    where
+        R: Register,
+        F: FnOnce(&mut R),
+    {
+        let mut reg = self.read_register::<R>(base)?;
+        f(&mut reg);
+        self.write_register(base, reg)
+    }
+}
@@ -0,0 +1,595 @@
+//! Sharing a debug interface over TCP.  `serve` runs in the process attached to the probe and
+//! answers register requests from any number of clients.  `RemoteDap` is the client side; it
+//! implements `Transport`, so `MemAP` and everything built on it work the same as with a local
+//! cable.
+//!
+//! Requests are a one byte opcode followed by little-endian arguments.  Every request except
+//! `OP_WRITE_NOCHECK` gets a response.  Results are encoded as a status byte, 0 for success or 1
+//! for an error, followed by a 32-bit value which is the error code in the failure case.
+//!
+//! The client buffers requests which don't need an answer straight away, such as unchecked
+//! writes and queued reads, and sends them together with the next request that does, so a
+//! sequence of small requests costs one round trip.  With the `compression` feature, a client can
+//! ask with `OP_OPTIONS` for the bodies of pipelined requests and responses to be LZ4-compressed.
+//! A compressed body is sent as its 32-bit length followed by the LZ4 block, which holds the
+//! uncompressed length and then the data.  Bodies are limited to `MAX_BODY` bytes, compressed or
+//! not; the server answers a larger request with `ERR_BAD_REQUEST` and disconnects the client.
+//! A pipelined request holds from 1 to `MAX_PIPELINED` registers, all in the same bank, which
+//! the client arranges by splitting larger transfers.
+//!
+//! If the connection to the server fails, the client's accesses return `ERR_DISCONNECTED`, so
+//! that `reconnect::Reconnecting` can connect again.
+//!
+//! Clients are served one request at a time, so requests from different clients never
+//! interleave on the wire.  Each client's `MemAP` caches CSW and TAR, so clients sharing a
+//! server should use different APs, or avoid modifying each other's CSW.  A client which takes
+//! longer than `CLIENT_TIMEOUT` to send the rest of a request, or to accept its response, is
+//! disconnected so that it can't stall the others.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use crate::{transfer, CtrlStat, Port, Transport};
+
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_WRITE_NOCHECK: u8 = 3;
+const OP_READ_PIPELINED: u8 = 4;
+const OP_WRITE_PIPELINED: u8 = 5;
+const OP_CTRL_STAT: u8 = 6;
+const OP_OPTIONS: u8 = 7;
+
+/// `OP_OPTIONS` flag for LZ4 compression of pipelined request and response bodies
+const OPT_LZ4: u32 = 1 << 0;
+
+/// Options this build of the server accepts
+#[cfg(feature = "compression")]
+const SUPPORTED_OPTIONS: u32 = OPT_LZ4;
+#[cfg(not(feature = "compression"))]
+const SUPPORTED_OPTIONS: u32 = 0;
+
+/// Error returned when the server doesn't support a requested option
+pub const ERR_UNSUPPORTED: u8 = 0x43;
+
+/// Error returned when the server rejects a request, for example because its body is too large
+pub const ERR_BAD_REQUEST: u8 = 0x49;
+
+/// Error returned by `RemoteDap` when the connection to the server fails
+pub const ERR_DISCONNECTED: u8 = 0x4b;
+
+/// Error returned by `RemoteDap::finish_read` when no read is queued
+pub const ERR_NOT_QUEUED: u8 = 0x4a;
+
+/// Largest body of a pipelined request or response, after decompression
+pub const MAX_BODY: usize = 1 << 20;
+/// Most registers in one pipelined request, which keeps a write request within `MAX_BODY`
+pub const MAX_PIPELINED: usize = (MAX_BODY - 4) / 5;
+/// Largest LZ4 block a `MAX_BODY` body can compress to, with its length prefix
+const MAX_PACKED: usize = 4 + MAX_BODY + MAX_BODY / 255 + 16;
+
+/// Flush buffered requests once this many bytes are waiting
+const BATCH_LIMIT: usize = 4096;
+
+/// How long `serve` waits for a client to send the rest of a request or accept a response
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn read_u8(r: &mut (impl Read + ?Sized)) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(r: &mut (impl Read + ?Sized)) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_port(r: &mut (impl Read + ?Sized)) -> io::Result<Port> {
+    match read_u8(r)? {
+        x if x == Port::DP as u8 => Ok(Port::DP),
+        x if x == Port::AP as u8 => Ok(Port::AP),
+        x => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("bad port {}", x),
+        )),
+    }
+}
+
+fn put_result(buf: &mut Vec<u8>, result: Result<u32, u8>) {
+    match result {
+        Ok(val) => {
+            buf.push(0);
+            buf.extend(val.to_le_bytes());
+        }
+        Err(e) => {
+            buf.push(1);
+            buf.extend((e as u32).to_le_bytes());
+        }
+    }
+}
+
+fn get_result(r: &mut (impl Read + ?Sized)) -> io::Result<Result<u32, u8>> {
+    let status = read_u8(r)?;
+    let val = read_u32(r)?;
+    if status == 0 {
+        Ok(Ok(val))
+    } else {
+        Ok(Err(val as u8))
+    }
+}
+
+fn unit_result(result: Result<(), u8>) -> Result<u32, u8> {
+    result.map(|_| 0)
+}
+
+#[cfg(feature = "compression")]
+fn compress(data: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress_prepend_size(data)
+}
+
+/// Decompress an LZ4 block, refusing one that claims to hold more than `MAX_BODY` bytes
+#[cfg(feature = "compression")]
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid =
+        |e: lz4_flex::block::DecompressError| io::Error::new(ErrorKind::InvalidData, e.to_string());
+    let (size, block) = lz4_flex::block::uncompressed_size(data).map_err(invalid)?;
+    if size > MAX_BODY {
+        return Err(too_large(size));
+    }
+    lz4_flex::block::decompress(block, size).map_err(invalid)
+}
+
+// Compression is never negotiated without the feature
+#[cfg(not(feature = "compression"))]
+fn compress(_: &[u8]) -> Vec<u8> {
+    unreachable!()
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_: &[u8]) -> io::Result<Vec<u8>> {
+    unreachable!()
+}
+
+fn too_large(len: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("body of {} bytes is too large", len),
+    )
+}
+
+/// Append the body of a pipelined request or response to `buf`, compressed if `options` say so
+fn put_body(buf: &mut Vec<u8>, body: &[u8], options: u32) {
+    if options & OPT_LZ4 != 0 {
+        let packed = compress(body);
+        buf.extend((packed.len() as u32).to_le_bytes());
+        buf.extend(packed);
+    } else {
+        buf.extend(body);
+    }
+}
+
+/// Read a compressed body from `r` if `options` say the body is compressed, otherwise return
+/// None, and the body is read directly from `r`
+fn get_body(r: &mut impl Read, options: u32) -> io::Result<Option<Vec<u8>>> {
+    if options & OPT_LZ4 == 0 {
+        return Ok(None);
+    }
+    let len = read_u32(r)? as usize;
+    if len > MAX_PACKED {
+        return Err(too_large(len));
+    }
+    let mut packed = vec![0; len];
+    r.read_exact(&mut packed)?;
+    decompress(&packed).map(Some)
+}
+
+/// The response to a pipelined request `op` that was rejected with `ERR_BAD_REQUEST`
+fn bad_request(op: u8, options: u32) -> Vec<u8> {
+    let mut resp = vec![];
+    if op == OP_READ_PIPELINED {
+        let mut body = 1u32.to_le_bytes().to_vec();
+        put_result(&mut body, Err(ERR_BAD_REQUEST));
+        put_body(&mut resp, &body, options);
+    } else {
+        put_result(&mut resp, Err(ERR_BAD_REQUEST));
+    }
+    resp
+}
+
+/// Answer a pipelined request `op` with `ERR_BAD_REQUEST`, then return `why`, so that the client
+/// is disconnected, as the rest of its request can't be found in the stream
+fn reject(stream: &mut TcpStream, op: u8, options: u32, why: io::Error) -> io::Result<()> {
+    stream.write_all(&bad_request(op, options))?;
+    Err(why)
+}
+
+/// Check the register count of a pipelined request
+fn check_count(count: u32) -> io::Result<usize> {
+    match count as usize {
+        0 => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "empty pipelined request",
+        )),
+        n if n > MAX_PIPELINED => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} registers in a pipelined request", n),
+        )),
+        n => Ok(n),
+    }
+}
+
+/// Read the arguments for `op` from `stream`, perform it on `dap`, and send back the response.
+/// `options` are the client's options set with `OP_OPTIONS`.
+fn handle<T>(dap: &mut T, stream: &mut TcpStream, op: u8, options: &mut u32) -> io::Result<()>
+where
+    T: Transport + ?Sized,
+{
+    let mut resp = vec![];
+    match op {
+        OP_READ => {
+            let apsel = read_u32(stream)?;
+            let port = read_port(stream)?;
+            let reg = read_u8(stream)?;
+            put_result(&mut resp, dap.read_adi(apsel, port, reg));
+        }
+        OP_WRITE | OP_WRITE_NOCHECK => {
+            let apsel = read_u32(stream)?;
+            let port = read_port(stream)?;
+            let reg = read_u8(stream)?;
+            let val = read_u32(stream)?;
+            if op == OP_WRITE {
+                put_result(&mut resp, unit_result(dap.write_adi(apsel, port, reg, val)));
+            } else {
+                // Errors are dropped, as they would be locally
+                let _ = dap.write_adi_nocheck(apsel, port, reg, val);
+            }
+        }
+        OP_READ_PIPELINED => {
+            let apsel = read_u32(stream)?;
+            let port = read_port(stream)?;
+            let packed = match get_body(stream, *options) {
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    return reject(stream, op, *options, e)
+                }
+                result => result?,
+            };
+            let mut body = packed.as_deref().unwrap_or_default();
+            let r: &mut dyn Read = if packed.is_some() { &mut body } else { stream };
+            let count = match check_count(read_u32(r)?) {
+                Ok(count) => count,
+                Err(e) => return reject(stream, op, *options, e),
+            };
+            let mut reg = vec![0; count];
+            r.read_exact(&mut reg)?;
+            if transfer::split_banks(&reg).len() != 1 {
+                resp = bad_request(op, *options);
+            } else {
+                let data = dap.read_adi_pipelined(apsel, port, &reg);
+                let mut body = (data.len() as u32).to_le_bytes().to_vec();
+                for item in data {
+                    put_result(&mut body, item);
+                }
+                put_body(&mut resp, &body, *options);
+            }
+        }
+        OP_WRITE_PIPELINED => {
+            let apsel = read_u32(stream)?;
+            let port = read_port(stream)?;
+            let packed = match get_body(stream, *options) {
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    return reject(stream, op, *options, e)
+                }
+                result => result?,
+            };
+            let mut body = packed.as_deref().unwrap_or_default();
+            let r: &mut dyn Read = if packed.is_some() { &mut body } else { stream };
+            let count = match check_count(read_u32(r)?) {
+                Ok(count) => count,
+                Err(e) => return reject(stream, op, *options, e),
+            };
+            let mut reg = Vec::with_capacity(count);
+            for _ in 0..count {
+                let r8 = read_u8(r)?;
+                let val = read_u32(r)?;
+                reg.push((r8, val));
+            }
+            if reg.iter().any(|(r, _)| r >> 2 != reg[0].0 >> 2) {
+                resp = bad_request(op, *options);
+            } else {
+                put_result(
+                    &mut resp,
+                    unit_result(dap.write_adi_pipelined(apsel, port, &reg)),
+                );
+            }
+        }
+        OP_CTRL_STAT => {
+            put_result(&mut resp, dap.read_ctrl_stat().map(u32::from));
+        }
+        OP_OPTIONS => {
+            let requested = read_u32(stream)?;
+            if requested & !SUPPORTED_OPTIONS != 0 {
+                put_result(&mut resp, Err(ERR_UNSUPPORTED));
+            } else {
+                *options = requested;
+                put_result(&mut resp, Ok(requested));
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("bad opcode {}", op),
+            ))
+        }
+    }
+    stream.write_all(&resp)
+}
+
+/// Serve requests for `dap` from clients connecting to `listener`.  This only returns if
+/// accepting a connection fails.
+pub fn serve<T>(dap: &mut T, listener: TcpListener) -> io::Result<()>
+where
+    T: Transport + ?Sized,
+{
+    listener.set_nonblocking(true)?;
+    // Each client's stream and options
+    let mut clients: Vec<(TcpStream, u32)> = vec![];
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                // Only apply while a request is handled, when the stream is blocking
+                stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                clients.push((stream, 0));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut idle = true;
+        clients.retain_mut(|(stream, options)| {
+            let mut op = [0; 1];
+            match stream.read(&mut op) {
+                // Connection closed
+                Ok(0) => false,
+                Ok(_) => {
+                    idle = false;
+                    // The rest of the request follows immediately, so block until it arrives
+                    stream.set_nonblocking(false).is_ok()
+                        && handle(dap, stream, op[0], options).is_ok()
+                        && stream.set_nonblocking(true).is_ok()
+                }
+                Err(e) => e.kind() == ErrorKind::WouldBlock,
+            }
+        });
+
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Client for a debug interface shared with `serve`
+pub struct RemoteDap {
+    stream: TcpStream,
+    /// Requests which haven't been sent yet
+    out: Vec<u8>,
+    /// Options accepted by the server
+    options: u32,
+    /// Number of reads queued by `queue_read_adi` whose response hasn't been received
+    pending: usize,
+    /// Responses to queued reads which have been received but not yet returned by `finish_read`
+    received: VecDeque<Result<u32, u8>>,
+}
+
+impl RemoteDap {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            out: vec![],
+            options: 0,
+            pending: 0,
+            received: VecDeque::new(),
+        })
+    }
+
+    fn request(apsel: u32, op: u8, port: Port) -> Vec<u8> {
+        let mut req = vec![op];
+        req.extend(apsel.to_le_bytes());
+        req.push(port as u8);
+        req
+    }
+
+    /// Ask the server to LZ4-compress pipelined transfers.  Returns `ERR_UNSUPPORTED` if the
+    /// server was built without the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, enable: bool) -> Result<(), u8> {
+        let options = if enable { OPT_LZ4 } else { 0 };
+        self.drain();
+        let mut req = vec![OP_OPTIONS];
+        req.extend(options.to_le_bytes());
+        self.send(&req)?;
+        self.receive()?;
+        self.options = options;
+        Ok(())
+    }
+
+    /// Queue `req`, sending it with the next request that needs a response
+    fn send(&mut self, req: &[u8]) -> Result<(), u8> {
+        self.out.extend(req);
+        if self.out.len() >= BATCH_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send the buffered requests.  If that fails they are dropped, as the connection is lost.
+    fn flush(&mut self) -> Result<(), u8> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let result = self.stream.write_all(&self.out);
+        self.out.clear();
+        result.map_err(|_| ERR_DISCONNECTED)
+    }
+
+    fn receive(&mut self) -> Result<u32, u8> {
+        self.flush()?;
+        get_result(&mut self.stream).unwrap_or(Err(ERR_DISCONNECTED))
+    }
+
+    /// Read registers in the same bank with one `OP_READ_PIPELINED` request
+    fn read_pipelined_request(
+        &mut self,
+        apsel: u32,
+        port: Port,
+        reg: &[u8],
+    ) -> Vec<Result<u32, u8>> {
+        self.drain();
+        let mut req = Self::request(apsel, OP_READ_PIPELINED, port);
+        let mut body = (reg.len() as u32).to_le_bytes().to_vec();
+        body.extend(reg);
+        put_body(&mut req, &body, self.options);
+        if let Err(e) = self.send(&req).and_then(|_| self.flush()) {
+            return vec![Err(e)];
+        }
+        self.receive_pipelined()
+            .unwrap_or_else(|_| vec![Err(ERR_DISCONNECTED)])
+    }
+
+    /// Receive the response to an `OP_READ_PIPELINED` request
+    fn receive_pipelined(&mut self) -> io::Result<Vec<Result<u32, u8>>> {
+        let packed = get_body(&mut self.stream, self.options)?;
+        let mut body = packed.as_deref().unwrap_or_default();
+        let r: &mut dyn Read = if packed.is_some() {
+            &mut body
+        } else {
+            &mut self.stream
+        };
+        let count = read_u32(r)?;
+        (0..count).map(|_| get_result(r)).collect()
+    }
+
+    /// Write registers in the same bank with one `OP_WRITE_PIPELINED` request
+    fn write_pipelined_request(
+        &mut self,
+        apsel: u32,
+        port: Port,
+        reg: &[(u8, u32)],
+    ) -> Result<(), u8> {
+        self.drain();
+        let mut req = Self::request(apsel, OP_WRITE_PIPELINED, port);
+        let mut body = (reg.len() as u32).to_le_bytes().to_vec();
+        for (r, val) in reg {
+            body.push(*r);
+            body.extend(val.to_le_bytes());
+        }
+        put_body(&mut req, &body, self.options);
+        self.send(&req)?;
+        self.receive().map(|_| ())
+    }
+
+    /// Receive the responses to any queued reads, so the next response read is for a new request
+    fn drain(&mut self) {
+        while self.pending > 0 {
+            let result = self.receive();
+            self.received.push_back(result);
+            self.pending -= 1;
+        }
+    }
+}
+
+impl Transport for RemoteDap {
+    fn read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8> {
+        self.drain();
+        let mut req = Self::request(apsel, OP_READ, port);
+        req.push(reg);
+        self.send(&req)?;
+        self.receive()
+    }
+
+    fn queue_read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> bool {
+        let mut req = Self::request(apsel, OP_READ, port);
+        req.push(reg);
+        if self.send(&req).is_err() {
+            return false;
+        }
+        self.pending += 1;
+        true
+    }
+
+    fn finish_read(&mut self) -> Result<u32, u8> {
+        if let Some(result) = self.received.pop_front() {
+            return result;
+        }
+        if self.pending == 0 {
+            return Err(ERR_NOT_QUEUED);
+        }
+        self.pending -= 1;
+        self.receive()
+    }
+
+    fn write_adi(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        self.drain();
+        let mut req = Self::request(apsel, OP_WRITE, port);
+        req.push(reg);
+        req.extend(val.to_le_bytes());
+        self.send(&req)?;
+        self.receive().map(|_| ())
+    }
+
+    fn write_adi_nocheck(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        let mut req = Self::request(apsel, OP_WRITE_NOCHECK, port);
+        req.push(reg);
+        req.extend(val.to_le_bytes());
+        self.send(&req)
+    }
+
+    /// Each run of registers in the same bank is sent as a separate request, split further into
+    /// requests of at most `MAX_PIPELINED` registers.  If a request comes back short, the results
+    /// stop there.
+    fn read_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>> {
+        let mut data = Vec::with_capacity(reg.len());
+        for run in transfer::split_banks(reg) {
+            for chunk in reg[run].chunks(MAX_PIPELINED) {
+                let end = data.len() + chunk.len();
+                data.extend(self.read_pipelined_request(apsel, port, chunk));
+                if data.len() < end {
+                    return data;
+                }
+            }
+        }
+        data
+    }
+
+    /// Split as `read_adi_pipelined` is
+    fn write_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[(u8, u32)]) -> Result<(), u8> {
+        let regs: Vec<u8> = reg.iter().map(|(r, _)| *r).collect();
+        for run in transfer::split_banks(&regs) {
+            for chunk in reg[run].chunks(MAX_PIPELINED) {
+                self.write_pipelined_request(apsel, port, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8> {
+        self.drain();
+        self.send(&[OP_CTRL_STAT])?;
+        self.receive().map(CtrlStat::from)
+    }
+}
+
+impl Drop for RemoteDap {
+    /// Send any buffered unchecked writes
+    fn drop(&mut self) {
+        let _ = self.stream.write_all(&self.out);
+    }
+}
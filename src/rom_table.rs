@@ -0,0 +1,355 @@
+//! Discovery of CoreSight components by walking ROM tables.  A scan can be saved as a
+//! `Snapshot` and compared with a later one, to see which components a power domain change hid
+//! or revealed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, BufRead, ErrorKind, Write};
+
+use crate::coresight::id::{Architecture, DevArch, DeviceType};
+use crate::{MemAP, Transport};
+
+/// Component class, from bits [7:4] of CIDR1
+pub const CLASS_ROM_TABLE: u32 = 0x1;
+pub const CLASS_CORESIGHT: u32 = 0x9;
+
+/// Deepest nesting of ROM tables followed, well beyond any real system's
+const MAX_DEPTH: usize = 16;
+
+/// A component found while walking a ROM table
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Component {
+    /// Base address of the component's 4kB register block
+    pub base: u32,
+    /// Number of ROM tables traversed to reach this component
+    pub depth: usize,
+    /// Component class, from CIDR1
+    pub class: u32,
+    /// Power domain ID from the referencing ROM table entry, if valid
+    pub power_domain: Option<u8>,
+    /// Peripheral ID registers PIDR0-7 combined into a single value
+    pub pidr: u64,
+    /// DEVTYPE, for CoreSight components
+    pub devtype: u32,
+    /// DEVARCH, for CoreSight components
+    pub devarch: u32,
+    /// DEVAFF0 and DEVAFF1, for CoreSight components
+    pub devaff: [u32; 2],
+    /// AUTHSTATUS, for CoreSight components
+    pub authstatus: u32,
+}
+
+impl Component {
+    /// Return the JEP106 designer code and part number from the peripheral ID
+    pub fn part(&self) -> (u16, u16) {
+        let part = (self.pidr & 0xfff) as u16;
+        let designer = ((self.pidr >> 12) & 0x7f) as u16 | (((self.pidr >> 32) & 0xf) as u16) << 7;
+        (designer, part)
+    }
+
+    /// Decoded DEVTYPE, for CoreSight components
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.devtype)
+    }
+
+    /// Decoded DEVARCH, for CoreSight components
+    pub fn dev_arch(&self) -> DevArch {
+        DevArch::from(self.devarch)
+    }
+
+    /// The identification fields compared by `Snapshot::diff`, other than the power domain
+    fn registers(&self) -> [(&'static str, u64); 8] {
+        [
+            ("depth", self.depth as u64),
+            ("class", self.class as u64),
+            ("pidr", self.pidr),
+            ("devtype", self.devtype as u64),
+            ("devarch", self.devarch as u64),
+            ("devaff0", self.devaff[0] as u64),
+            ("devaff1", self.devaff[1] as u64),
+            ("authstatus", self.authstatus as u64),
+        ]
+    }
+}
+
+/// The components of one CPU, matched by `match_cores`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoreComponents {
+    /// DEVAFF0 and DEVAFF1 shared by the components, normally the core's MPIDR
+    pub affinity: [u32; 2],
+    /// Base address of the core's debug registers
+    pub debug: u32,
+    /// Architecture of the debug registers, if DEVARCH identifies it
+    pub arch: Option<Architecture>,
+    pub cti: Option<u32>,
+    pub etm: Option<u32>,
+}
+
+/// Pair each CPU debug component in `components` with the CTI and ETM that have the same
+/// affinity in DEVAFF0 and DEVAFF1.  Components are recognised by DEVARCH, or by DEVTYPE for those
+/// without one.  Debug components with no affinity can't be matched and are left out.
+pub fn match_cores(components: &[Component]) -> Vec<CoreComponents> {
+    let is = |c: &Component, arch: Architecture, devtype: DeviceType| match c
+        .dev_arch()
+        .architecture()
+    {
+        Some(a) => a == arch,
+        None => c.device_type() == devtype,
+    };
+    let find = |affinity: [u32; 2], arch: Architecture, devtype: DeviceType| {
+        components
+            .iter()
+            .find(|c| c.devaff == affinity && is(c, arch, devtype))
+            .map(|c| c.base)
+    };
+    components
+        .iter()
+        .filter(|c| c.class == CLASS_CORESIGHT && c.devaff != [0, 0])
+        .filter(|c| c.device_type() == DeviceType::CpuDebug)
+        .map(|c| CoreComponents {
+            affinity: c.devaff,
+            debug: c.base,
+            arch: c.dev_arch().architecture(),
+            cti: find(c.devaff, Architecture::Cti, DeviceType::TriggerMatrix),
+            etm: find(c.devaff, Architecture::Etm, DeviceType::CpuTrace),
+        })
+        .collect()
+}
+
+/// Return a human-readable description of a DEVTYPE value
+pub fn devtype_to_str(devtype: u32) -> String {
+    DeviceType::from(devtype).to_string()
+}
+
+fn read_component<T>(
+    mem: &mut MemAP<T>,
+    base: u32,
+    depth: usize,
+    power_domain: Option<u8>,
+) -> Result<Component, u8>
+where
+    T: Transport + ?Sized,
+{
+    let cidr1 = mem.read(base + 0xff4)?;
+    let pidr = mem.read_block(base + 0xfd0, 8, true)?;
+    let pidr = [4, 5, 6, 7, 0, 1, 2, 3]
+        .iter()
+        .enumerate()
+        .fold(0u64, |acc, (i, &reg)| {
+            acc | ((pidr[reg] & 0xff) as u64) << (8 * i)
+        });
+    let mut component = Component {
+        base,
+        depth,
+        class: (cidr1 >> 4) & 0xf,
+        power_domain,
+        pidr,
+        devtype: 0,
+        devarch: 0,
+        devaff: [0, 0],
+        authstatus: 0,
+    };
+
+    if component.class == CLASS_CORESIGHT {
+        component.authstatus = mem.read(base + 0xfb8)?;
+        component.devaff = [mem.read(base + 0xfa8)?, mem.read(base + 0xfac)?];
+        component.devarch = mem.read(base + 0xfbc)?;
+        component.devtype = mem.read(base + 0xfcc)?;
+    }
+    Ok(component)
+}
+
+fn walk<T>(
+    mem: &mut MemAP<T>,
+    base: u32,
+    depth: usize,
+    power_domain: Option<u8>,
+    visited: &mut BTreeSet<u32>,
+    result: &mut Vec<Component>,
+) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    let component = read_component(mem, base, depth, power_domain)?;
+    let is_rom_table = component.class == CLASS_ROM_TABLE;
+    result.push(component);
+
+    if is_rom_table {
+        for i in 0..960 {
+            let romentry = mem.read(base + i * 4)?;
+            if romentry == 0 {
+                break;
+            }
+
+            if romentry & 1 != 0 {
+                let pd = if romentry & (1 << 2) != 0 {
+                    Some(((romentry >> 4) & 0x1f) as u8)
+                } else {
+                    None
+                };
+                // The offset is signed, so let it wrap
+                let addr = base.wrapping_add(romentry & !0xfff);
+                // Skip entries that lead back to a table already walked, or too deep
+                if depth >= MAX_DEPTH || !visited.insert(addr) {
+                    continue;
+                }
+                // A component that can't be read, for example because its power domain is off,
+                // is left out so that the rest of the table is still walked
+                if walk(mem, addr, depth + 1, pd, visited, result).is_err() {
+                    mem.clear_sticky_errors()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Walk the ROM table at `base`, and any ROM tables it references, returning every component
+/// found.  The ROM tables themselves are included in the result.  Each component is read once,
+/// even if several entries refer to it, and components whose registers can't be read are left
+/// out.
+pub fn parse_rom_table<T>(mem: &mut MemAP<T>, base: u32) -> Result<Vec<Component>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut result = vec![];
+    let mut visited = BTreeSet::from([base]);
+    walk(mem, base, 0, None, &mut visited, &mut result)?;
+    Ok(result)
+}
+
+/// A difference between two scans, for the component at one base address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The component is only in the later scan
+    Appeared(Component),
+    /// The component is only in the earlier scan
+    Disappeared(Component),
+    /// The component's registers or ROM table entry differ
+    Changed { before: Component, after: Component },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Appeared(c) => write!(f, "+ {:08x}: {}", c.base, c.device_type()),
+            Change::Disappeared(c) => {
+                write!(f, "- {:08x}: {}", c.base, c.device_type())
+            }
+            Change::Changed { before, after } => {
+                write!(f, "~ {:08x}:", after.base)?;
+                if before.power_domain != after.power_domain {
+                    write!(
+                        f,
+                        " power domain {:?} -> {:?}",
+                        before.power_domain, after.power_domain
+                    )?;
+                }
+                for ((name, b), (_, a)) in before.registers().into_iter().zip(after.registers()) {
+                    if b != a {
+                        write!(f, " {} {:x} -> {:x}", name, b, a)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The components found by a scan, which can be saved to a file and compared with a later scan
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub components: Vec<Component>,
+}
+
+impl Snapshot {
+    /// Scan the ROM table at `base`, as `parse_rom_table`
+    pub fn scan<T>(mem: &mut MemAP<T>, base: u32) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        Ok(Self {
+            components: parse_rom_table(mem, base)?,
+        })
+    }
+
+    /// Write the snapshot as text, one component per line
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        for c in &self.components {
+            let pd = c.power_domain.map_or("-".to_string(), |pd| pd.to_string());
+            writeln!(
+                out,
+                "{:08x} {} {:x} {} {:016x} {:08x} {:08x} {:08x} {:08x} {:08x}",
+                c.base,
+                c.depth,
+                c.class,
+                pd,
+                c.pidr,
+                c.devtype,
+                c.devarch,
+                c.devaff[0],
+                c.devaff[1],
+                c.authstatus
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Read a snapshot written by `write`
+    pub fn read(input: &mut dyn BufRead) -> io::Result<Self> {
+        let bad = |line: &str| io::Error::new(ErrorKind::InvalidData, format!("bad line {}", line));
+        let mut components = vec![];
+        for line in input.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 10 {
+                return Err(bad(&line));
+            }
+            let hex = |i: usize| u64::from_str_radix(fields[i], 16).map_err(|_| bad(&line));
+            let power_domain = match fields[3] {
+                "-" => None,
+                pd => Some(pd.parse().map_err(|_| bad(&line))?),
+            };
+            components.push(Component {
+                base: hex(0)? as u32,
+                depth: fields[1].parse().map_err(|_| bad(&line))?,
+                class: hex(2)? as u32,
+                power_domain,
+                pidr: hex(4)?,
+                devtype: hex(5)? as u32,
+                devarch: hex(6)? as u32,
+                devaff: [hex(7)? as u32, hex(8)? as u32],
+                authstatus: hex(9)? as u32,
+            });
+        }
+        Ok(Self { components })
+    }
+
+    /// Compare with a `later` scan, returning the changes in order of base address
+    pub fn diff(&self, later: &Snapshot) -> Vec<Change> {
+        let before: BTreeMap<u32, &Component> =
+            self.components.iter().map(|c| (c.base, c)).collect();
+        let after: BTreeMap<u32, &Component> =
+            later.components.iter().map(|c| (c.base, c)).collect();
+
+        let mut bases: Vec<u32> = before.keys().chain(after.keys()).copied().collect();
+        bases.sort_unstable();
+        bases.dedup();
+        bases
+            .into_iter()
+            .filter_map(|base| match (before.get(&base), after.get(&base)) {
+                (Some(&b), None) => Some(Change::Disappeared(b.clone())),
+                (None, Some(&a)) => Some(Change::Appeared(a.clone())),
+                (Some(&b), Some(&a)) if b != a => Some(Change::Changed {
+                    before: b.clone(),
+                    after: a.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
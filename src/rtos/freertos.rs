@@ -0,0 +1,181 @@
+//! FreeRTOS thread awareness for 32-bit Cortex-M targets.  Threads are found by walking the
+//! kernel's task lists, starting from `pxReadyTasksLists` and the other lists in tasks.c.
+//!
+//! The offsets of TCB fields depend on the kernel configuration, so they are described by a
+//! `TcbLayout`; the default matches a configuration without MPU wrappers or list integrity
+//! checks.
+
+use super::{cortex_m_context, read_string, Thread, ThreadState};
+use crate::{MemAP, Transport};
+
+/// Size of `List_t`
+const LIST_SIZE: u32 = 20;
+/// Offset of `xListEnd` in `List_t`
+const LIST_END: u32 = 8;
+/// Offset of `pxNext` in `ListItem_t` and `MiniListItem_t`
+const ITEM_NEXT: u32 = 4;
+/// Offset of `pvOwner` in `ListItem_t`
+const ITEM_OWNER: u32 = 12;
+
+/// Offsets of the fields of a TCB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcbLayout {
+    pub top_of_stack: u32,
+    pub priority: u32,
+    pub stack: u32,
+    pub name: u32,
+    /// configMAX_TASK_NAME_LEN
+    pub name_len: usize,
+}
+
+impl Default for TcbLayout {
+    fn default() -> Self {
+        Self {
+            top_of_stack: 0,
+            priority: 44,
+            stack: 48,
+            name: 52,
+            name_len: 16,
+        }
+    }
+}
+
+/// Addresses of the kernel's task lists
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// `pxReadyTasksLists`
+    pub ready_lists: u32,
+    /// configMAX_PRIORITIES, the number of ready lists
+    pub max_priorities: u32,
+    /// `pxCurrentTCB`
+    pub current_tcb: u32,
+    /// `xDelayedTaskList1` and `xDelayedTaskList2`
+    pub delayed_lists: Vec<u32>,
+    /// `xPendingReadyList`
+    pub pending_ready_list: Option<u32>,
+    /// `xSuspendedTaskList`, if INCLUDE_vTaskSuspend is enabled
+    pub suspended_list: Option<u32>,
+    /// `xTasksWaitingTermination`, if INCLUDE_vTaskDelete is enabled
+    pub terminating_list: Option<u32>,
+}
+
+#[cfg(feature = "elf")]
+impl Symbols {
+    /// Find the task lists in the symbol table of the FreeRTOS application.  Returns None if the
+    /// required symbols are missing.
+    pub fn from_elf(elf: &crate::elf::Symbols) -> Option<Self> {
+        let addr = |name| elf.address(name).map(|a| a as u32);
+        let ready = elf.lookup(elf.address("pxReadyTasksLists")?)?.0;
+        Some(Self {
+            ready_lists: ready.address as u32,
+            max_priorities: (ready.size / LIST_SIZE as u64) as u32,
+            current_tcb: addr("pxCurrentTCB")?,
+            delayed_lists: ["xDelayedTaskList1", "xDelayedTaskList2"]
+                .into_iter()
+                .filter_map(addr)
+                .collect(),
+            pending_ready_list: addr("xPendingReadyList"),
+            suspended_list: addr("xSuspendedTaskList"),
+            terminating_list: addr("xTasksWaitingTermination"),
+        })
+    }
+}
+
+/// Reads FreeRTOS threads through a MemAP
+pub struct FreeRtos {
+    pub symbols: Symbols,
+    pub layout: TcbLayout,
+    /// True if the port saves EXC_RETURN and the FPU context, as the ARM_CM4F and later ports do
+    pub fpu: bool,
+}
+
+impl FreeRtos {
+    pub fn new(symbols: Symbols, fpu: bool) -> Self {
+        Self {
+            symbols,
+            layout: TcbLayout::default(),
+            fpu,
+        }
+    }
+
+    /// Return the TCBs on the list at `list`
+    fn walk_list<T>(mem: &mut MemAP<T>, list: u32) -> Result<Vec<u32>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let count = mem.read(list)?;
+        let end = list + LIST_END;
+        let mut tcbs = vec![];
+        let mut item = mem.read(end + ITEM_NEXT)?;
+        // Bound the walk by the item count, in case the list is corrupt
+        while item != end && tcbs.len() < count as usize {
+            tcbs.push(mem.read(item + ITEM_OWNER)?);
+            item = mem.read(item + ITEM_NEXT)?;
+        }
+        Ok(tcbs)
+    }
+
+    fn read_thread<T>(&self, mem: &mut MemAP<T>, tcb: u32, state: ThreadState) -> Result<Thread, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let layout = &self.layout;
+        let stack_pointer = mem.read(tcb + layout.top_of_stack)?;
+        let registers = if state == ThreadState::Running {
+            None
+        } else {
+            Some(cortex_m_context(mem, stack_pointer, self.fpu)?)
+        };
+        Ok(Thread {
+            id: tcb,
+            name: read_string(mem, tcb + layout.name, layout.name_len)?,
+            priority: mem.read(tcb + layout.priority)? as i32,
+            state,
+            stack_pointer,
+            stack_base: Some(mem.read(tcb + layout.stack)?),
+            stack_size: None,
+            entry: None,
+            registers,
+        })
+    }
+
+    /// Return all of the threads known to the kernel
+    pub fn threads<T>(&self, mem: &mut MemAP<T>) -> Result<Vec<Thread>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let sym = &self.symbols;
+        let current = mem.read(sym.current_tcb)?;
+
+        let mut lists = vec![];
+        for prio in 0..sym.max_priorities {
+            lists.push((sym.ready_lists + prio * LIST_SIZE, ThreadState::Ready));
+        }
+        lists.extend(sym.pending_ready_list.map(|l| (l, ThreadState::Ready)));
+        lists.extend(sym.delayed_lists.iter().map(|&l| (l, ThreadState::Blocked)));
+        lists.extend(sym.suspended_list.map(|l| (l, ThreadState::Suspended)));
+        lists.extend(sym.terminating_list.map(|l| (l, ThreadState::Deleted)));
+
+        let mut threads: Vec<Thread> = vec![];
+        for (list, state) in lists {
+            for tcb in Self::walk_list(mem, list)? {
+                if threads.iter().any(|t| t.id == tcb) {
+                    continue;
+                }
+                let state = if tcb == current {
+                    ThreadState::Running
+                } else {
+                    state
+                };
+                threads.push(self.read_thread(mem, tcb, state)?);
+            }
+        }
+
+        // The current task is normally on a ready list, but may have been removed from all of
+        // them if the core was halted part way through a context switch
+        if current != 0 && !threads.iter().any(|t| t.id == current) {
+            threads.push(self.read_thread(mem, current, ThreadState::Running)?);
+        }
+        Ok(threads)
+    }
+}
@@ -0,0 +1,111 @@
+//! RTOS awareness: finding the threads of an RTOS by reading its data structures from target
+//! memory, so run-control tools can show per-thread state.  The kernel's symbols are located
+//! with an ELF file or given directly.  This crate has no GDB server, so it is up to the
+//! caller to present the threads, for example as GDB threads.
+
+pub mod freertos;
+pub mod zephyr;
+
+use crate::{MemAP, Transport};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadState {
+    /// The thread running on the core
+    Running,
+    Ready,
+    /// Waiting for a timeout or an event
+    Blocked,
+    Suspended,
+    /// Exited, but not yet cleaned up
+    Deleted,
+    Unknown,
+}
+
+/// A thread found in target memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Thread {
+    /// Address of the kernel's thread structure, which identifies the thread
+    pub id: u32,
+    pub name: String,
+    pub priority: i32,
+    pub state: ThreadState,
+    /// Saved stack pointer, which is stale for the running thread
+    pub stack_pointer: u32,
+    /// Lowest address of the thread's stack, if known
+    pub stack_base: Option<u32>,
+    /// Size of the thread's stack, if known
+    pub stack_size: Option<u32>,
+    /// Address of the thread's entry function, if known
+    pub entry: Option<u32>,
+    /// Saved R0-R12, SP, LR, PC and xPSR of a thread that isn't running, if the context could be
+    /// decoded.  The running thread's registers are in the core.
+    pub registers: Option<[u32; 17]>,
+}
+
+/// Read a NUL-terminated string of at most `max` bytes at `addr`, which need not be aligned
+pub(crate) fn read_string<T>(mem: &mut MemAP<T>, addr: u32, max: usize) -> Result<String, u8>
+where
+    T: Transport + ?Sized,
+{
+    let first = addr & !3;
+    let words = (addr as usize - first as usize + max).div_ceil(4);
+    let data = mem.read_memory(first, words)?;
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let bytes = &bytes[(addr - first) as usize..][..max];
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(max);
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+/// Decode the exception frame pushed by a Cortex-M core at `frame` into `regs`, including the
+/// stack pointer from before the exception.  `extended` is true if the frame includes S0-S15
+/// and FPSCR, which is when bit 4 of EXC_RETURN is clear.
+pub(crate) fn cortex_m_exception_frame<T>(
+    mem: &mut MemAP<T>,
+    frame: u32,
+    extended: bool,
+    regs: &mut [u32; 17],
+) -> Result<(), u8>
+where
+    T: Transport + ?Sized,
+{
+    let hw = mem.read_memory(frame, 8)?;
+    regs[..4].copy_from_slice(&hw[..4]);
+    regs[12] = hw[4];
+    regs[14] = hw[5];
+    regs[15] = hw[6];
+    regs[16] = hw[7];
+    let mut top = frame + 32;
+    if extended {
+        // S0-S15, FPSCR and a reserved word
+        top += 18 * 4;
+    }
+    // xPSR bit 9 records that the frame was aligned with a padding word
+    if hw[7] & (1 << 9) != 0 {
+        top += 4;
+    }
+    regs[13] = top;
+    Ok(())
+}
+
+/// Decode the context of a Cortex-M thread saved at `sp`: R4-R11 saved by the kernel followed by
+/// the exception frame pushed by the core.  If `fpu` is true, the kernel also saved EXC_RETURN
+/// after R4-R11, and S16-S31 after that if the thread had used the FPU.
+pub(crate) fn cortex_m_context<T>(mem: &mut MemAP<T>, sp: u32, fpu: bool) -> Result<[u32; 17], u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut regs = [0; 17];
+    let saved = mem.read_memory(sp, 8 + fpu as usize)?;
+    regs[4..12].copy_from_slice(&saved[..8]);
+    let mut frame = sp + 32;
+    let mut extended = false;
+    if fpu {
+        frame += 4;
+        if saved[8] & (1 << 4) == 0 {
+            frame += 16 * 4;
+            extended = true;
+        }
+    }
+    cortex_m_exception_frame(mem, frame, extended, &mut regs)?;
+    Ok(regs)
+}
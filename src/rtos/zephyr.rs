@@ -0,0 +1,197 @@
+//! Zephyr thread awareness for 32-bit Cortex-M targets.  Threads are found by walking the
+//! `_kernel.threads` list, which needs CONFIG_THREAD_MONITOR.  The offsets of the kernel's
+//! fields are read from the `_kernel_thread_info_offsets` table, which is built in with
+//! CONFIG_DEBUG_THREAD_INFO, so the kernel configuration doesn't need to be known.
+
+use super::{cortex_m_exception_frame, read_string, Thread, ThreadState};
+use crate::{MemAP, Transport};
+
+// Indexes into `_kernel_thread_info_offsets`
+const OFFSET_K_CURR_THREAD: usize = 1;
+const OFFSET_K_THREADS: usize = 2;
+const OFFSET_T_ENTRY: usize = 3;
+const OFFSET_T_NEXT_THREAD: usize = 4;
+const OFFSET_T_STATE: usize = 5;
+const OFFSET_T_PRIO: usize = 7;
+const OFFSET_T_STACK_PTR: usize = 8;
+const OFFSET_T_NAME: usize = 9;
+const OFFSET_T_ARM_EXC_RETURN: usize = 13;
+const OFFSET_T_STACK_INFO_START: usize = 15;
+const OFFSET_T_STACK_INFO_SIZE: usize = 16;
+
+/// Value of an offset for a field that isn't present in this configuration
+const UNIMPLEMENTED: u32 = u32::MAX;
+
+// Bits of `thread_base.thread_state`
+const THREAD_PRESTART: u8 = 1 << 2;
+const THREAD_DEAD: u8 = 1 << 3;
+const THREAD_SUSPENDED: u8 = 1 << 4;
+const THREAD_ABORTING: u8 = 1 << 5;
+const THREAD_QUEUED: u8 = 1 << 7;
+
+/// Longest thread name read, CONFIG_THREAD_MAX_NAME_LEN
+const NAME_LEN: usize = 32;
+
+/// Reads Zephyr threads through a MemAP
+pub struct Zephyr {
+    /// Address of `_kernel`
+    kernel: u32,
+    offsets: Vec<u32>,
+}
+
+/// Read the byte at `addr`, which need not be aligned
+fn read_u8<T>(mem: &mut MemAP<T>, addr: u32) -> Result<u8, u8>
+where
+    T: Transport + ?Sized,
+{
+    let word = mem.read(addr & !3)?;
+    Ok((word >> (8 * (addr & 3))) as u8)
+}
+
+impl Zephyr {
+    /// `kernel` is the address of `_kernel`, `offsets` the address of
+    /// `_kernel_thread_info_offsets` and `num_offsets` the address of
+    /// `_kernel_thread_info_num_offsets`
+    pub fn new<T>(
+        mem: &mut MemAP<T>,
+        kernel: u32,
+        offsets: u32,
+        num_offsets: u32,
+    ) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let count = mem.read(num_offsets)?;
+        let offsets = mem.read_memory(offsets, count as usize)?;
+        Ok(Self { kernel, offsets })
+    }
+
+    /// Find the kernel's symbols in the Zephyr ELF file and read the offsets table.  Returns
+    /// None if the symbols are missing.
+    #[cfg(feature = "elf")]
+    pub fn from_elf<T>(mem: &mut MemAP<T>, elf: &crate::elf::Symbols) -> Option<Result<Self, u8>>
+    where
+        T: Transport + ?Sized,
+    {
+        let addr = |name| elf.address(name).map(|a| a as u32);
+        Some(Self::new(
+            mem,
+            addr("_kernel")?,
+            addr("_kernel_thread_info_offsets")?,
+            addr("_kernel_thread_info_num_offsets")?,
+        ))
+    }
+
+    /// Return the offset at `index` of the offsets table, if the field exists
+    fn offset(&self, index: usize) -> Option<u32> {
+        self.offsets
+            .get(index)
+            .copied()
+            .filter(|&o| o != UNIMPLEMENTED)
+    }
+
+    fn read_thread<T>(&self, mem: &mut MemAP<T>, thread: u32, current: u32) -> Result<Thread, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let field = |index| self.offset(index).map(|o| thread + o);
+
+        let flags = match field(OFFSET_T_STATE) {
+            Some(addr) => read_u8(mem, addr)?,
+            None => 0,
+        };
+        let state = if thread == current {
+            ThreadState::Running
+        } else if flags & (THREAD_DEAD | THREAD_ABORTING) != 0 {
+            ThreadState::Deleted
+        } else if flags & THREAD_SUSPENDED != 0 {
+            ThreadState::Suspended
+        } else if flags & THREAD_PRESTART != 0 {
+            ThreadState::Unknown
+        } else if flags & THREAD_QUEUED != 0 {
+            ThreadState::Ready
+        } else {
+            // Pending on an object, or sleeping
+            ThreadState::Blocked
+        };
+
+        let name = match field(OFFSET_T_NAME) {
+            Some(addr) => read_string(mem, addr, NAME_LEN)?,
+            None => String::new(),
+        };
+        let priority = match field(OFFSET_T_PRIO) {
+            Some(addr) => read_u8(mem, addr)? as i8 as i32,
+            None => 0,
+        };
+        let mut read_field = |index| -> Result<Option<u32>, u8> {
+            field(index).map(|addr| mem.read(addr)).transpose()
+        };
+        let stack_pointer = read_field(OFFSET_T_STACK_PTR)?.unwrap_or(0);
+        let stack_base = read_field(OFFSET_T_STACK_INFO_START)?;
+        let stack_size = read_field(OFFSET_T_STACK_INFO_SIZE)?;
+        let entry = read_field(OFFSET_T_ENTRY)?;
+
+        let registers = if state == ThreadState::Running || stack_pointer == 0 {
+            None
+        } else {
+            Some(self.read_context(mem, thread, stack_pointer)?)
+        };
+
+        Ok(Thread {
+            id: thread,
+            name,
+            priority,
+            state,
+            stack_pointer,
+            stack_base,
+            stack_size,
+            entry,
+            registers,
+        })
+    }
+
+    /// Decode the saved context of a thread that isn't running.  R4-R11 are saved in
+    /// `callee_saved` just before the saved PSP, and the rest are in the exception frame.
+    fn read_context<T>(&self, mem: &mut MemAP<T>, thread: u32, psp: u32) -> Result<[u32; 17], u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let mut regs = [0; 17];
+        if let Some(psp_offset) = self.offset(OFFSET_T_STACK_PTR) {
+            let saved = mem.read_memory(thread + psp_offset - 32, 8)?;
+            regs[4..12].copy_from_slice(&saved);
+        }
+        let extended = match self.offset(OFFSET_T_ARM_EXC_RETURN) {
+            Some(o) => read_u8(mem, thread + o)? & (1 << 4) == 0,
+            None => false,
+        };
+        cortex_m_exception_frame(mem, psp, extended, &mut regs)?;
+        Ok(regs)
+    }
+
+    /// Return all of the threads on `_kernel.threads`
+    pub fn threads<T>(&self, mem: &mut MemAP<T>) -> Result<Vec<Thread>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let (Some(k_threads), Some(next)) = (
+            self.offset(OFFSET_K_THREADS),
+            self.offset(OFFSET_T_NEXT_THREAD),
+        ) else {
+            // Built without CONFIG_THREAD_MONITOR
+            return Ok(vec![]);
+        };
+        let current = match self.offset(OFFSET_K_CURR_THREAD) {
+            Some(o) => mem.read(self.kernel + o)?,
+            None => 0,
+        };
+
+        let mut threads: Vec<Thread> = vec![];
+        let mut thread = mem.read(self.kernel + k_threads)?;
+        while thread != 0 && !threads.iter().any(|t| t.id == thread) {
+            threads.push(self.read_thread(mem, thread, current)?);
+            thread = mem.read(thread + next)?;
+        }
+        Ok(threads)
+    }
+}
@@ -0,0 +1,173 @@
+//! SEGGER RTT (Real Time Transfer): ring buffers in target RAM, described by a control block,
+//! which the target writes log output to and reads input from while running.  The buffers are
+//! accessed through a MEM-AP without halting the core.
+
+use crate::image::{patch_region, read_bytes};
+use crate::{MemAP, Transport};
+
+/// Error returned when no RTT control block was found
+pub const ERR_NO_RTT: u8 = 0x42;
+
+const ID: &[u8] = b"SEGGER RTT";
+/// Size of the ID field at the start of the control block
+const ID_LEN: u32 = 16;
+/// Size of a buffer descriptor
+const DESC_SIZE: u32 = 24;
+const DESC_NAME: u32 = 0;
+const DESC_BUFFER: u32 = 4;
+const DESC_SIZE_OF_BUFFER: u32 = 8;
+const DESC_WR_OFF: u32 = 12;
+const DESC_RD_OFF: u32 = 16;
+/// Longest channel name read
+const MAX_NAME: usize = 32;
+/// Bytes searched per read when scanning for the control block
+const SCAN_CHUNK: u32 = 1024;
+
+/// An RTT up (target to host) or down (host to target) buffer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub name: Option<String>,
+    /// Address of the buffer descriptor in the control block
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+}
+
+/// An RTT control block found in target memory
+#[derive(Clone, Debug)]
+pub struct Rtt {
+    address: u32,
+    up: Vec<Channel>,
+    down: Vec<Channel>,
+}
+
+impl Rtt {
+    /// Read the control block at `address`.  Returns `ERR_NO_RTT` if there isn't one.
+    pub fn attach<T>(mem: &mut MemAP<T>, address: u32) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let header = read_bytes(mem, address, ID_LEN as usize + 8)?;
+        if !header.starts_with(ID) {
+            return Err(ERR_NO_RTT);
+        }
+        let num_up = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        let num_down = u32::from_le_bytes(header[20..24].try_into().unwrap());
+        // A control block that hasn't been initialised yet may have garbage counts
+        if num_up > 32 || num_down > 32 {
+            return Err(ERR_NO_RTT);
+        }
+
+        let mut channel = |i: u32| -> Result<Channel, u8> {
+            let descriptor = address + ID_LEN + 8 + i * DESC_SIZE;
+            let desc = mem.read_memory(descriptor, DESC_SIZE as usize / 4)?;
+            let name_ptr = desc[(DESC_NAME / 4) as usize];
+            let name = if name_ptr == 0 {
+                None
+            } else {
+                let bytes = read_bytes(mem, name_ptr, MAX_NAME)?;
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(MAX_NAME);
+                Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+            };
+            Ok(Channel {
+                name,
+                descriptor,
+                buffer: desc[(DESC_BUFFER / 4) as usize],
+                size: desc[(DESC_SIZE_OF_BUFFER / 4) as usize],
+            })
+        };
+        let up = (0..num_up).map(&mut channel).collect::<Result<_, _>>()?;
+        let down = (num_up..num_up + num_down)
+            .map(&mut channel)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { address, up, down })
+    }
+
+    /// Search `start..end` for the control block, as the target's RTT symbol isn't always known
+    pub fn find<T>(mem: &mut MemAP<T>, start: u32, end: u32) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let mut addr = start & !3;
+        while addr < end {
+            // Overlap the chunks so an ID straddling two of them is found
+            let len = SCAN_CHUNK.min(end - addr) + ID.len() as u32;
+            let data = read_bytes(mem, addr, len as usize)?;
+            for (i, window) in data.windows(ID.len()).enumerate().step_by(4) {
+                if window == ID {
+                    if let Ok(rtt) = Self::attach(mem, addr + i as u32) {
+                        return Ok(rtt);
+                    }
+                }
+            }
+            addr += SCAN_CHUNK;
+        }
+        Err(ERR_NO_RTT)
+    }
+
+    /// Address of the control block
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn up_channels(&self) -> &[Channel] {
+        &self.up
+    }
+
+    pub fn down_channels(&self) -> &[Channel] {
+        &self.down
+    }
+
+    /// Read everything the target has written to up channel `index`, and mark it as read
+    pub fn read<T>(&self, mem: &mut MemAP<T>, index: usize) -> Result<Vec<u8>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let ch = self.up.get(index).ok_or(ERR_NO_RTT)?;
+        let wr = mem.read(ch.descriptor + DESC_WR_OFF)?;
+        let rd = mem.read(ch.descriptor + DESC_RD_OFF)?;
+        if wr >= ch.size || rd >= ch.size {
+            return Err(ERR_NO_RTT);
+        }
+        let mut data = vec![];
+        if wr < rd {
+            data = read_bytes(mem, ch.buffer + rd, (ch.size - rd) as usize)?;
+            data.extend(read_bytes(mem, ch.buffer, wr as usize)?);
+        } else if wr > rd {
+            data = read_bytes(mem, ch.buffer + rd, (wr - rd) as usize)?;
+        }
+        if wr != rd {
+            mem.write(ch.descriptor + DESC_RD_OFF, wr)?;
+        }
+        Ok(data)
+    }
+
+    /// Write as much of `data` as fits to down channel `index`, returning the number of bytes
+    /// written
+    pub fn write<T>(&self, mem: &mut MemAP<T>, index: usize, data: &[u8]) -> Result<usize, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let ch = self.down.get(index).ok_or(ERR_NO_RTT)?;
+        let mut wr = mem.read(ch.descriptor + DESC_WR_OFF)?;
+        let rd = mem.read(ch.descriptor + DESC_RD_OFF)?;
+        if wr >= ch.size || rd >= ch.size {
+            return Err(ERR_NO_RTT);
+        }
+        // One byte is always left free, so that a full buffer can be told from an empty one
+        let free = if rd > wr {
+            rd - wr - 1
+        } else {
+            ch.size - wr + rd - 1
+        };
+        let mut rest = &data[..data.len().min(free as usize)];
+        while !rest.is_empty() {
+            let n = rest.len().min((ch.size - wr) as usize);
+            patch_region(mem, ch.buffer + wr, &rest[..n], false)?;
+            wr = (wr + n as u32) % ch.size;
+            rest = &rest[n..];
+        }
+        mem.write(ch.descriptor + DESC_WR_OFF, wr)?;
+        Ok(data.len().min(free as usize))
+    }
+}
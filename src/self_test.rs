@@ -0,0 +1,195 @@
+//! Connection self test.  `ArmDebugInterface::self_test` checks each layer of the connection in
+//! turn, from the scan chain up to target memory, and stops at the first one that fails, so that
+//! a cable or wiring problem can be told apart from a target that is held in reset, powered
+//! down or otherwise not responding.
+
+use std::fmt;
+use std::ops::DerefMut;
+
+use jtag_taps::cable::Cable;
+
+use crate::{ArmDebugInterface, DPReg, MemAPReg, Port, ERR_STICKY};
+
+/// IDCODE bits identifying an ARM DAP: designer 0x23b and the mandatory bit 0
+const IDCODE_ARM_MASK: u32 = 0xfff;
+const IDCODE_ARM: u32 = 0x477;
+
+/// SELECT values written and read back to check DP accesses.  Only APSEL and APBANKSEL are
+/// used, as DPBANKSEL is reserved before DPv1.
+const SELECT_PATTERNS: [u32; 2] = [0xa5 << 24 | 0x5 << 4, 0x5a << 24 | 0xa << 4];
+
+/// Words written to the scratch address and read back
+const RAM_PATTERNS: [u32; 4] = [0x55aa_55aa, 0xaa55_aa55, 0x0000_0000, 0xffff_ffff];
+
+/// CTRL/STAT.STICKYERR, set by a faulting memory access
+const STICKYERR: u32 = 1 << 5;
+
+/// CSW.AddrInc and CSW.Size
+const CSW_INC_SIZE_MASK: u32 = 0x37;
+/// CSW.Size of a 32-bit access, with no address increment
+const CSW_WORD: u32 = 2;
+
+/// What the self test found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTest {
+    pub idcode: u32,
+    /// IDR of the AP tested
+    pub idr: u32,
+}
+
+/// The first layer of the connection that failed the self test
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The TAP's IDCODE isn't that of an ARM DAP.  All zeros or all ones usually means the
+    /// cable isn't connected to the target, or the target isn't powered.
+    Idcode(u32),
+    /// A DP register access was not acknowledged, with the ACK or error returned
+    DpAccess(u8),
+    /// A value written to SELECT read back differently
+    DpLoopback { wrote: u32, read: u32 },
+    /// Reading the AP's IDR failed, with the ACK or error returned
+    ApAccess(u8),
+    /// The AP's IDR reads as zero, so there is no AP at that APSEL
+    NoAp(u32),
+    /// Accessing the scratch address through the AP failed, with the ACK or error returned
+    MemAccess(u8),
+    /// A word written to the scratch address read back differently
+    MemLoopback { addr: u32, wrote: u32, read: u32 },
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelfTestError::Idcode(idcode) => {
+                write!(f, "cable: unexpected IDCODE 0x{:08x}", idcode)
+            }
+            SelfTestError::DpAccess(e) => write!(f, "DP: access error {}", e),
+            SelfTestError::DpLoopback { wrote, read } => write!(
+                f,
+                "DP: SELECT read back 0x{:08x} after writing 0x{:08x}",
+                read, wrote
+            ),
+            SelfTestError::ApAccess(e) => write!(f, "AP: access error {} reading IDR", e),
+            SelfTestError::NoAp(apsel) => write!(f, "AP: no AP at APSEL {}", apsel),
+            SelfTestError::MemAccess(e) => write!(f, "memory: access error {}", e),
+            SelfTestError::MemLoopback { addr, wrote, read } => write!(
+                f,
+                "memory: 0x{:08x} read back 0x{:08x} after writing 0x{:08x}",
+                addr, read, wrote
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SelfTestError {}
+
+impl<T, U> ArmDebugInterface<T>
+where
+    T: DerefMut<Target = U>,
+    U: Cable + ?Sized,
+{
+    /// Check the connection layer by layer: the TAP's IDCODE, DP register writes and reads,
+    /// the IDR of AP `apsel`, and, if `scratch` is given, writing and reading back a word of
+    /// RAM at that address through the AP, which must be a MEM-AP.  The word at `scratch` and
+    /// the AP's CSW and TAR are restored afterwards.
+    pub fn self_test(
+        &mut self,
+        apsel: u32,
+        scratch: Option<u32>,
+    ) -> Result<SelfTest, SelfTestError> {
+        self.write_instruction(self.ir.idcode);
+        let dr = self.taps.borrow_mut().read_dr(32);
+        let idcode = dr
+            .try_into()
+            .map(u32::from_le_bytes)
+            .map_err(|_| SelfTestError::Idcode(0))?;
+        if idcode & IDCODE_ARM_MASK != IDCODE_ARM {
+            return Err(SelfTestError::Idcode(idcode));
+        }
+
+        let result = self.test_dp();
+        // Don't trust the cached SELECT after writing it behind bank_select's back
+        self.lastbank = 0xff;
+        let select = self.bank_select(0, 0, 0);
+        result?;
+        select.map_err(SelfTestError::DpAccess)?;
+
+        let idr = self
+            .read_adi(apsel, Port::AP, MemAPReg::IDR as u8)
+            .map_err(SelfTestError::ApAccess)?;
+        if idr == 0 {
+            return Err(SelfTestError::NoAp(apsel));
+        }
+
+        if let Some(addr) = scratch {
+            self.test_ram(apsel, addr)?;
+        }
+        Ok(SelfTest { idcode, idr })
+    }
+
+    fn test_dp(&mut self) -> Result<(), SelfTestError> {
+        let select = DPReg::Select as u8;
+        for wrote in SELECT_PATTERNS {
+            self.write_adi_nobank(Port::DP, select, wrote, true)
+                .map_err(SelfTestError::DpAccess)?;
+            let read = self
+                .read_adi_nobank(Port::DP, select)
+                .map_err(SelfTestError::DpAccess)?;
+            if read != wrote {
+                return Err(SelfTestError::DpLoopback { wrote, read });
+            }
+        }
+        Ok(())
+    }
+
+    fn test_ram(&mut self, apsel: u32, addr: u32) -> Result<(), SelfTestError> {
+        let mut ap = |reg: MemAPReg| self.read_adi(apsel, Port::AP, reg as u8);
+        let csw = ap(MemAPReg::CSW).map_err(SelfTestError::MemAccess)?;
+        let tar = ap(MemAPReg::TAR).map_err(SelfTestError::MemAccess)?;
+
+        let mut result = self.loopback(apsel, addr, csw & !CSW_INC_SIZE_MASK | CSW_WORD);
+        let ctrl_stat = DPReg::CtrlStat as u8;
+        let stat = self
+            .read_adi(apsel, Port::DP, ctrl_stat)
+            .map_err(SelfTestError::MemAccess)?;
+        if stat & STICKYERR != 0 {
+            // Clear the error, so the AP can be used to restore CSW and TAR
+            self.write_adi(apsel, Port::DP, ctrl_stat, stat)
+                .map_err(SelfTestError::MemAccess)?;
+            result = result.and(Err(SelfTestError::MemAccess(ERR_STICKY)));
+        }
+
+        self.write_adi(apsel, Port::AP, MemAPReg::CSW as u8, csw)
+            .map_err(SelfTestError::MemAccess)?;
+        self.write_adi(apsel, Port::AP, MemAPReg::TAR as u8, tar)
+            .map_err(SelfTestError::MemAccess)?;
+        result
+    }
+
+    fn loopback(&mut self, apsel: u32, addr: u32, csw: u32) -> Result<(), SelfTestError> {
+        let write = |adi: &mut Self, reg: MemAPReg, val: u32| {
+            adi.write_adi(apsel, Port::AP, reg as u8, val)
+                .map_err(SelfTestError::MemAccess)
+        };
+        write(self, MemAPReg::CSW, csw)?;
+        write(self, MemAPReg::TAR, addr)?;
+        let drw = MemAPReg::DRW as u8;
+        let original = self
+            .read_adi(apsel, Port::AP, drw)
+            .map_err(SelfTestError::MemAccess)?;
+
+        let mut result = Ok(());
+        for wrote in RAM_PATTERNS {
+            write(self, MemAPReg::DRW, wrote)?;
+            let read = self
+                .read_adi(apsel, Port::AP, drw)
+                .map_err(SelfTestError::MemAccess)?;
+            if read != wrote {
+                result = Err(SelfTestError::MemLoopback { addr, wrote, read });
+                break;
+            }
+        }
+        write(self, MemAPReg::DRW, original)?;
+        result
+    }
+}
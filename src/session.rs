@@ -0,0 +1,245 @@
+//! Run-control event loop.  A `DebugSession` polls the state of a set of cores and turns changes
+//! into `Event`s, delivered to a callback or sent over a channel, so interactive tools don't each
+//! need their own polling loop.  The debug interface isn't `Send`, so polling happens on the
+//! calling thread; a channel lets another thread consume the events.  For the same reason, a
+//! board watchdog set with `set_watchdog` is serviced from the polling loop.
+//!
+//! A core that halts again before the next poll looks as if it never ran, so halted cores should
+//! be resumed with `DebugSession::resume`, which lets the session report the next halt.
+
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use crate::armv8::{Core, HaltReason};
+use crate::cortex_m::CortexM;
+use crate::watchdog::Watchdog;
+use crate::Transport;
+
+/// Run state of a core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreStatus {
+    Running,
+    Halted(Option<HaltReason>),
+    PoweredDown,
+}
+
+/// A core that a `DebugSession` can watch
+pub trait RunControl {
+    /// Read the core's current state
+    fn status(&mut self) -> Result<CoreStatus, u8>;
+
+    /// Return true if the halted core stopped on a semihosting call
+    fn is_semihosting(&mut self) -> Result<bool, u8>;
+
+    /// Restart the halted core
+    fn resume(&mut self) -> Result<(), u8>;
+}
+
+impl<T> RunControl for Core<T>
+where
+    T: Transport + ?Sized,
+{
+    fn status(&mut self) -> Result<CoreStatus, u8> {
+        // Restores the debug state if the core has been powered down since the last poll
+        if !self.check_power()? {
+            return Ok(CoreStatus::PoweredDown);
+        }
+        match self.halt_reason()? {
+            Some(reason) => Ok(CoreStatus::Halted(Some(reason))),
+            None => Ok(CoreStatus::Running),
+        }
+    }
+
+    fn is_semihosting(&mut self) -> Result<bool, u8> {
+        Ok(self.halt_reason()? == Some(HaltReason::Semihosting))
+    }
+
+    fn resume(&mut self) -> Result<(), u8> {
+        Core::resume(self)
+    }
+}
+
+impl<T> RunControl for CortexM<T>
+where
+    T: Transport + ?Sized,
+{
+    fn status(&mut self) -> Result<CoreStatus, u8> {
+        match self.halt_reason()? {
+            Some(reason) => Ok(CoreStatus::Halted(Some(reason))),
+            None => Ok(CoreStatus::Running),
+        }
+    }
+
+    fn is_semihosting(&mut self) -> Result<bool, u8> {
+        self.at_semihosting_call()
+    }
+
+    fn resume(&mut self) -> Result<(), u8> {
+        CortexM::resume(self)
+    }
+}
+
+impl<R> RunControl for Box<R>
+where
+    R: RunControl + ?Sized,
+{
+    fn status(&mut self) -> Result<CoreStatus, u8> {
+        (**self).status()
+    }
+
+    fn is_semihosting(&mut self) -> Result<bool, u8> {
+        (**self).is_semihosting()
+    }
+
+    fn resume(&mut self) -> Result<(), u8> {
+        (**self).resume()
+    }
+}
+
+/// A change in the state of one of the session's cores, identified by its index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The core halted, for example on a breakpoint or watchpoint
+    Halted {
+        core: usize,
+        reason: Option<HaltReason>,
+    },
+    /// The core halted on a semihosting call, which the handler should service before
+    /// resuming it
+    Semihosting {
+        core: usize,
+    },
+    Resumed {
+        core: usize,
+    },
+    /// The core's power domain turned off
+    PoweredDown {
+        core: usize,
+    },
+    PoweredUp {
+        core: usize,
+    },
+}
+
+impl Event {
+    /// Index of the core the event concerns
+    pub fn core(&self) -> usize {
+        match *self {
+            Event::Halted { core, .. }
+            | Event::Semihosting { core }
+            | Event::Resumed { core }
+            | Event::PoweredDown { core }
+            | Event::PoweredUp { core } => core,
+        }
+    }
+}
+
+/// Watches a set of cores for changes in their run state
+pub struct DebugSession<C> {
+    cores: Vec<C>,
+    /// State from the last poll, None before the first
+    last: Vec<Option<CoreStatus>>,
+    /// Services the board's watchdog while a core is halted
+    watchdog: Option<Box<dyn FnMut() -> Result<bool, u8>>>,
+}
+
+impl<C> DebugSession<C>
+where
+    C: RunControl,
+{
+    /// The first poll reports the initial state of each core that isn't running
+    pub fn new(cores: Vec<C>) -> Self {
+        let last = vec![None; cores.len()];
+        Self {
+            cores,
+            last,
+            watchdog: None,
+        }
+    }
+
+    /// Service `watchdog` on each poll while any of the cores is halted.  The polling interval
+    /// must be shorter than the watchdog's period for it to be serviced on time.
+    pub fn set_watchdog<T>(&mut self, mut watchdog: Watchdog<T>)
+    where
+        T: Transport + ?Sized + 'static,
+    {
+        self.watchdog = Some(Box::new(move || watchdog.service()));
+    }
+
+    pub fn core(&mut self, index: usize) -> &mut C {
+        &mut self.cores[index]
+    }
+
+    pub fn into_cores(self) -> Vec<C> {
+        self.cores
+    }
+
+    /// Resume a halted core.  The core is taken to be running from now on, so a halt is reported
+    /// by the next poll even if the core has already halted again, as it may on back to back
+    /// semihosting calls.
+    pub fn resume(&mut self, index: usize) -> Result<(), u8> {
+        self.cores[index].resume()?;
+        self.last[index] = Some(CoreStatus::Running);
+        Ok(())
+    }
+
+    /// Read the state of each core and return the events since the last poll
+    pub fn poll(&mut self) -> Result<Vec<Event>, u8> {
+        let mut events = vec![];
+        for (core, (c, last)) in self.cores.iter_mut().zip(&mut self.last).enumerate() {
+            let status = c.status()?;
+            let was = last.replace(status);
+            // A halt reason read twice may differ, as reading DFSR clears it on Cortex-M
+            let changed = match (was, status) {
+                (Some(CoreStatus::Halted(_)), CoreStatus::Halted(_)) => false,
+                (was, status) => was != Some(status),
+            };
+            if !changed {
+                continue;
+            }
+            let event = match status {
+                CoreStatus::Running if was.is_none() => continue,
+                CoreStatus::Running if was == Some(CoreStatus::PoweredDown) => {
+                    Event::PoweredUp { core }
+                }
+                CoreStatus::Running => Event::Resumed { core },
+                CoreStatus::PoweredDown => Event::PoweredDown { core },
+                CoreStatus::Halted(Some(HaltReason::Semihosting)) => Event::Semihosting { core },
+                CoreStatus::Halted(reason) => Event::Halted { core, reason },
+            };
+            events.push(event);
+        }
+
+        let halted = self
+            .last
+            .iter()
+            .any(|s| matches!(s, Some(CoreStatus::Halted(_))));
+        if let Some(service) = self.watchdog.as_mut().filter(|_| halted) {
+            service()?;
+        }
+        Ok(events)
+    }
+
+    /// Poll every `interval`, calling `handler` with the session and each event, until `handler`
+    /// returns false.  The handler can reach the event's core with `core(event.core())`, and
+    /// should resume halted cores with `resume`.
+    pub fn run<F>(&mut self, interval: Duration, mut handler: F) -> Result<(), u8>
+    where
+        F: FnMut(&mut Self, Event) -> bool,
+    {
+        loop {
+            for event in self.poll()? {
+                if !handler(self, event) {
+                    return Ok(());
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    /// Poll every `interval` and send the events to `tx`, until the receiver is dropped
+    pub fn run_with_channel(&mut self, interval: Duration, tx: &Sender<Event>) -> Result<(), u8> {
+        self.run(interval, |_, event| tx.send(event).is_ok())
+    }
+}
@@ -0,0 +1,407 @@
+//! A simulated DAP with one MEM-AP in front of a sparse memory, implementing `Transport`, so that
+//! `MemAP` and the drivers built on it can run without a target.  Faults are scripted with
+//! `SimDap::inject`, to exercise the WAIT retries, stall recovery and sticky error handling the
+//! same way every run:
+//!
+//! ```ignore
+//! let sim = Rc::new(RefCell::new(SimDap::new(0)));
+//! sim.borrow_mut().inject(Fault::Wait { count: 3 });
+//! let mut mem = MemAP::new(sim.clone(), 0);
+//! mem.read(0x2000_0000)?;
+//! ```
+//!
+//! Only what `MemAP` relies on is modelled: CSW with its Size and AddrInc fields, TAR with
+//! auto-increment wrapping within a 1KB block, DRW, the banked data registers, IDR, and the power,
+//! sticky and abort behaviour of a JTAG-DP.
+//!
+//! As with a real cable, reads queued with `queue_read_adi` must be finished before a checked
+//! transaction, or before SELECT is written for another AP or bank.  The simulation panics if they
+//! aren't, as jtag-taps does.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{Abort, CtrlStat, DPReg, MemAPReg, Port, Transport, ACK_WAIT, AUTOINC_BLOCK};
+
+/// IDR of the simulated MEM-AP, an AHB3-AP
+pub const SIM_IDR: u32 = 0x2477_0011;
+
+/// Register number of BD0
+const BD0: u8 = 4;
+/// CTRL/STAT power-up requests, and their acknowledgements one bit above each
+const CDBGPWRUPREQ: u32 = 1 << 28;
+const CSYSPWRUPREQ: u32 = 1 << 30;
+const STICKYORUN: u32 = 1 << 1;
+const STICKYCMP: u32 = 1 << 4;
+const STICKYERR: u32 = 1 << 5;
+const WDATAERR: u32 = 1 << 7;
+/// CSW.DeviceEn, always set as the simulated AP is never disabled
+const CSW_DEVICE_EN: u32 = 1 << 6;
+/// Writable bits of CSW: Size, AddrInc and the Prot bits
+const CSW_WRITABLE: u32 = 0xff00_0037;
+
+/// A fault to inject, with `SimDap::inject`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer WAIT to the next `count` AP transactions
+    Wait { count: u32 },
+    /// Answer WAIT to every AP transaction until the transaction is aborted with DAPABORT, as when
+    /// an access hangs on a peripheral whose clock is gated
+    Stall,
+    /// Set STICKYERR on DRW and banked data accesses to `range`, which are otherwise ignored
+    BusError { range: Range<u32> },
+    /// Power down the debug domain after `after` more AP transactions.  CTRL/STAT stops
+    /// acknowledging the power-up requests and clears them, AP accesses set STICKYERR and are
+    /// ignored, and the AP's registers are reset.  Power comes back when the requests are written
+    /// to CTRL/STAT again.
+    PowerDown { after: u32 },
+}
+
+/// A simulated DAP, see the module documentation
+#[derive(Clone, Debug)]
+pub struct SimDap {
+    apsel: u32,
+    /// Sparse memory, by word address
+    memory: BTreeMap<u32, u32>,
+    csw: u32,
+    tar: u32,
+    /// Power-up requests and sticky flags of CTRL/STAT
+    ctrl_stat: u32,
+    powered: bool,
+    waits: u32,
+    stalled: bool,
+    bus_errors: Vec<Range<u32>>,
+    power_down_after: Option<u32>,
+    queued: VecDeque<Result<u32, u8>>,
+    /// APSEL and APBANKSEL of SELECT
+    select: u32,
+    transactions: u64,
+    waited: u64,
+}
+
+impl SimDap {
+    /// A DAP with its MEM-AP at `apsel`, powered up, with all memory reading as zero
+    pub fn new(apsel: u32) -> Self {
+        Self {
+            apsel,
+            memory: BTreeMap::new(),
+            csw: 0,
+            tar: 0,
+            ctrl_stat: CDBGPWRUPREQ | CSYSPWRUPREQ,
+            powered: true,
+            waits: 0,
+            stalled: false,
+            bus_errors: Vec::new(),
+            power_down_after: None,
+            queued: VecDeque::new(),
+            select: 0,
+            transactions: 0,
+            waited: 0,
+        }
+    }
+
+    /// Script `fault` to happen.  Faults of different kinds combine.
+    pub fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::Wait { count } => self.waits += count,
+            Fault::Stall => self.stalled = true,
+            Fault::BusError { range } => self.bus_errors.push(range),
+            Fault::PowerDown { after } => self.power_down_after = Some(after),
+        }
+    }
+
+    /// Remove every fault that hasn't happened yet, and the bus error ranges
+    pub fn clear_faults(&mut self) {
+        self.waits = 0;
+        self.stalled = false;
+        self.bus_errors.clear();
+        self.power_down_after = None;
+    }
+
+    /// Read the word at `addr`, which must be word aligned, without a transaction
+    pub fn peek(&self, addr: u32) -> u32 {
+        self.memory.get(&(addr & !3)).copied().unwrap_or(0)
+    }
+
+    /// Write the word at `addr`, which must be word aligned, without a transaction
+    pub fn poke(&mut self, addr: u32, val: u32) {
+        self.memory.insert(addr & !3, val);
+    }
+
+    /// Return true if the debug domain is powered
+    pub fn powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Number of transactions made, including those answered with WAIT
+    pub fn transactions(&self) -> u64 {
+        self.transactions
+    }
+
+    /// Number of transactions answered with WAIT
+    pub fn waits(&self) -> u64 {
+        self.waited
+    }
+
+    /// Count an AP transaction, and decide whether the AP answers WAIT or has lost power
+    fn ap_transaction(&mut self) -> Result<bool, u8> {
+        self.transactions += 1;
+        if let Some(after) = self.power_down_after {
+            if after == 0 {
+                self.power_down_after = None;
+                self.powered = false;
+                self.ctrl_stat &= !(CDBGPWRUPREQ | CSYSPWRUPREQ);
+                self.csw = 0;
+                self.tar = 0;
+            } else {
+                self.power_down_after = Some(after - 1);
+            }
+        }
+        if !self.powered {
+            self.ctrl_stat |= STICKYERR;
+            return Ok(false);
+        }
+        if self.stalled || self.waits > 0 {
+            self.waits = self.waits.saturating_sub(1);
+            self.waited += 1;
+            return Err(ACK_WAIT);
+        }
+        Ok(true)
+    }
+
+    /// Check an access to `addr` against the bus error ranges, setting STICKYERR if it fails
+    fn bus_ok(&mut self, addr: u32) -> bool {
+        if self.bus_errors.iter().any(|r| r.contains(&addr)) {
+            self.ctrl_stat |= STICKYERR;
+            return false;
+        }
+        true
+    }
+
+    /// Advance TAR after a DRW access, if CSW.AddrInc is single
+    fn increment_tar(&mut self) {
+        if (self.csw >> 4) & 3 == 1 {
+            let size = 1 << (self.csw & 7).min(2);
+            let offset = (self.tar.wrapping_add(size)) % AUTOINC_BLOCK;
+            self.tar = self.tar & !(AUTOINC_BLOCK - 1) | offset;
+        }
+    }
+
+    fn read_ap(&mut self, reg: u8) -> Result<u32, u8> {
+        if !self.ap_transaction()? {
+            return Ok(0);
+        }
+        let val = match reg {
+            r if r == MemAPReg::CSW as u8 => self.csw | CSW_DEVICE_EN,
+            r if r == MemAPReg::TAR as u8 => self.tar,
+            r if r == MemAPReg::DRW as u8 => {
+                let addr = self.tar;
+                let val = match self.bus_ok(addr) {
+                    true => self.peek(addr),
+                    false => 0,
+                };
+                self.increment_tar();
+                val
+            }
+            BD0..=7 => {
+                let addr = self.tar & !0xf | ((reg as u32 - BD0 as u32) * 4);
+                match self.bus_ok(addr) {
+                    true => self.peek(addr),
+                    false => 0,
+                }
+            }
+            r if r == MemAPReg::IDR as u8 => SIM_IDR,
+            _ => 0,
+        };
+        Ok(val)
+    }
+
+    fn write_ap(&mut self, reg: u8, val: u32) -> Result<(), u8> {
+        if !self.ap_transaction()? {
+            return Ok(());
+        }
+        match reg {
+            r if r == MemAPReg::CSW as u8 => self.csw = val & CSW_WRITABLE,
+            r if r == MemAPReg::TAR as u8 => self.tar = val,
+            r if r == MemAPReg::DRW as u8 => {
+                let addr = self.tar;
+                if self.bus_ok(addr) {
+                    self.write_lanes(addr, val);
+                }
+                self.increment_tar();
+            }
+            BD0..=7 => {
+                let addr = self.tar & !0xf | ((reg as u32 - BD0 as u32) * 4);
+                if self.bus_ok(addr) {
+                    self.poke(addr, val);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Write the byte lanes of `val` selected by CSW.Size and `addr`
+    fn write_lanes(&mut self, addr: u32, val: u32) {
+        let mask = match self.csw & 7 {
+            0 => 0xff << (8 * (addr & 3)),
+            1 => 0xffff << (8 * (addr & 2)),
+            _ => 0xffff_ffff,
+        };
+        let old = self.peek(addr);
+        self.poke(addr, old & !mask | val & mask);
+    }
+
+    /// Panic if reads are queued, as the cable would on a checked scan
+    fn check_queue_empty(&self) {
+        assert!(self.queued.is_empty(), "checked scan while reads are queued");
+    }
+
+    /// Write SELECT for an access to `reg` of AP `apsel`, if it isn't selected already
+    fn bank_select(&mut self, apsel: u32, port: Port, reg: u8) {
+        let select = apsel << 24 | ((reg >> 2) as u32) << 4;
+        if port == Port::AP && select != self.select {
+            assert!(
+                self.queued.is_empty(),
+                "SELECT written while reads are queued"
+            );
+            self.transactions += 1;
+            self.select = select;
+        }
+    }
+
+    /// Make one read transaction, after selecting the AP and bank
+    fn read_once(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8> {
+        self.bank_select(apsel, port, reg);
+        match port {
+            Port::DP => Ok(self.read_dp(reg)),
+            Port::AP if apsel == self.apsel => self.read_ap(reg),
+            Port::AP => {
+                self.transactions += 1;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Make one write transaction, which may get WAIT
+    fn write_adi_once(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        self.bank_select(apsel, port, reg);
+        match port {
+            Port::DP => {
+                self.write_dp(reg, val);
+                Ok(())
+            }
+            Port::AP if apsel == self.apsel => self.write_ap(reg, val),
+            Port::AP => {
+                self.transactions += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn read_dp(&mut self, reg: u8) -> u32 {
+        self.transactions += 1;
+        match reg {
+            r if r == DPReg::CtrlStat as u8 => {
+                let reqs = self.ctrl_stat & (CDBGPWRUPREQ | CSYSPWRUPREQ);
+                match self.powered {
+                    true => self.ctrl_stat | reqs << 1,
+                    false => self.ctrl_stat,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_dp(&mut self, reg: u8, val: u32) {
+        self.transactions += 1;
+        match reg {
+            r if r == DPReg::Abort as u8 => {
+                let abort = Abort(val);
+                if abort.contains(Abort::DAPABORT) {
+                    self.stalled = false;
+                    self.waits = 0;
+                }
+                let clear = [
+                    (Abort::STKCMPCLR, STICKYCMP),
+                    (Abort::STKERRCLR, STICKYERR),
+                    (Abort::WDERRCLR, WDATAERR),
+                    (Abort::ORUNERRCLR, STICKYORUN),
+                ];
+                for (flag, bit) in clear {
+                    if abort.contains(flag) {
+                        self.ctrl_stat &= !bit;
+                    }
+                }
+            }
+            r if r == DPReg::CtrlStat as u8 => {
+                // The sticky flags are write-one-to-clear on a JTAG-DP
+                let sticky = STICKYORUN | STICKYCMP | STICKYERR | WDATAERR;
+                let reqs = CDBGPWRUPREQ | CSYSPWRUPREQ;
+                self.ctrl_stat = self.ctrl_stat & sticky & !val | val & reqs;
+                if val & reqs == reqs {
+                    self.powered = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Transport for SimDap {
+    fn read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> Result<u32, u8> {
+        self.check_queue_empty();
+        self.read_once(apsel, port, reg)
+    }
+
+    fn queue_read_adi(&mut self, apsel: u32, port: Port, reg: u8) -> bool {
+        let result = self.read_once(apsel, port, reg);
+        self.queued.push_back(result);
+        true
+    }
+
+    fn finish_read(&mut self) -> Result<u32, u8> {
+        self.queued.pop_front().unwrap_or(Ok(0))
+    }
+
+    /// A checked write is retried while it gets WAIT, as `ArmDebugInterface` does, and fails
+    /// with WAIT while the AP is stalled
+    fn write_adi(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        self.check_queue_empty();
+        loop {
+            match self.write_adi_once(apsel, port, reg, val) {
+                Err(ACK_WAIT) if !self.stalled => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn write_adi_nocheck(&mut self, apsel: u32, port: Port, reg: u8, val: u32) -> Result<(), u8> {
+        // A write that gets WAIT is dropped, and nobody looks at the ACK
+        let _ = self.write_adi_once(apsel, port, reg, val);
+        Ok(())
+    }
+
+    fn read_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[u8]) -> Vec<Result<u32, u8>> {
+        reg.iter().map(|r| self.read_adi(apsel, port, *r)).collect()
+    }
+
+    fn write_adi_pipelined(&mut self, apsel: u32, port: Port, reg: &[(u8, u32)]) -> Result<(), u8> {
+        for (r, val) in reg {
+            self.write_adi_nocheck(apsel, port, *r, *val)?;
+        }
+        Ok(())
+    }
+
+    fn read_ctrl_stat(&mut self) -> Result<CtrlStat, u8> {
+        Ok(self.read_dp(DPReg::CtrlStat as u8).into())
+    }
+
+    fn wait_ratio(&self) -> Option<f32> {
+        match self.transactions {
+            0 => Some(0.0),
+            n => Some(self.waited as f32 / n as f32),
+        }
+    }
+}
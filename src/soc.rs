@@ -0,0 +1,272 @@
+//! Built-in database of the debug layout of common SoCs.  Parts are recognised by the TARGETID of
+//! their debug port where it has one, otherwise by its JTAG IDCODE and the part number of the debug
+//! component of their first core.  SoCs that share a DAP design and core layout are told apart by
+//! a debug component or AP that only one of them has.  `target::Target::attach` uses the layout
+//! of a recognised SoC instead of walking its ROM tables.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{DPReg, MemAP, Port, Transport};
+
+/// JEP106 code for ARM, in the form returned by `Component::part`
+const DESIGNER_ARM: u16 = 0x23b;
+
+/// DPIDR is read at the address of ABORT
+const DPIDR: u8 = 0;
+/// DPBANKSEL of TARGETID, which is read at the address of CTRL/STAT
+const TARGETID_BANK: u32 = 2;
+/// TARGETID without TREVISION, which changes between steppings of the same part
+const TARGETID_PART_MASK: u32 = 0x0fff_ffff;
+
+/// Architecture of a SoC's application cores
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    Armv7A,
+    Armv8A,
+}
+
+/// The debug registers of one core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocCore {
+    pub debug_base: u32,
+    pub cti_base: u32,
+}
+
+/// Something only one of several SoCs with the same DAP and core layout has
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Marker {
+    /// An ARM debug component with this part number at `base` on the debug AP
+    Component { base: u32, part: u16 },
+    /// An AP at this APSEL
+    Ap(u32),
+}
+
+/// Debug layout of a known SoC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Soc {
+    pub name: &'static str,
+    /// JTAG IDCODE of the debug port
+    pub idcode: u32,
+    /// TARGETID of the debug port without TREVISION, for parts with a DPv2 debug port
+    pub targetid: Option<u32>,
+    /// Checked after the core part number, for SoCs that share a layout with another
+    pub marker: Option<Marker>,
+    /// Part number from the peripheral ID of the cores' debug components
+    pub core_part: u16,
+    pub arch: Arch,
+    /// AP used for system memory
+    pub mem_ap: u32,
+    /// AP used for the debug components
+    pub debug_ap: u32,
+    /// Address of the top-level ROM table on `debug_ap`
+    pub rom_base: u32,
+    pub cores: &'static [SocCore],
+}
+
+/// Cores at 1MB intervals from 0x80410000, shared by several Cortex-A53/A72 designs
+const CLUSTER_4X: &[SocCore] = &[
+    SocCore {
+        debug_base: 0x80410000,
+        cti_base: 0x80420000,
+    },
+    SocCore {
+        debug_base: 0x80510000,
+        cti_base: 0x80520000,
+    },
+    SocCore {
+        debug_base: 0x80610000,
+        cti_base: 0x80620000,
+    },
+    SocCore {
+        debug_base: 0x80710000,
+        cti_base: 0x80720000,
+    },
+];
+
+/// Xilinx Zynq-7000, with two Cortex-A9 cores
+pub const ZYNQ_7000: Soc = Soc {
+    name: "Zynq-7000",
+    idcode: 0x4ba00477,
+    targetid: None,
+    marker: None,
+    core_part: 0xc09,
+    arch: Arch::Armv7A,
+    mem_ap: 0,
+    debug_ap: 1,
+    rom_base: 0x80000000,
+    cores: &[
+        SocCore {
+            debug_base: 0x80090000,
+            cti_base: 0x80098000,
+        },
+        SocCore {
+            debug_base: 0x80092000,
+            cti_base: 0x80099000,
+        },
+    ],
+};
+
+pub const SOCS: &[Soc] = &[
+    ZYNQ_7000,
+    // The MPSoC and i.MX8M have the same DAP and Cortex-A53 layout.  The MPSoC also has the
+    // Cortex-R5 cores of its RPU on the debug AP, and the i.MX8M has its Cortex-M4 on AP 4.
+    Soc {
+        name: "Zynq UltraScale+ MPSoC",
+        idcode: 0x5ba00477,
+        targetid: None,
+        marker: Some(Marker::Component {
+            base: 0x803f0000,
+            part: 0xc15,
+        }),
+        core_part: 0xd03,
+        arch: Arch::Armv8A,
+        mem_ap: 0,
+        debug_ap: 1,
+        rom_base: 0x80000000,
+        cores: CLUSTER_4X,
+    },
+    Soc {
+        name: "i.MX8M",
+        idcode: 0x5ba00477,
+        targetid: None,
+        marker: Some(Marker::Ap(4)),
+        core_part: 0xd03,
+        arch: Arch::Armv8A,
+        mem_ap: 0,
+        debug_ap: 1,
+        rom_base: 0x80000000,
+        cores: CLUSTER_4X,
+    },
+    Soc {
+        name: "BCM2711",
+        idcode: 0x4ba00477,
+        targetid: None,
+        marker: None,
+        core_part: 0xd08,
+        arch: Arch::Armv8A,
+        mem_ap: 0,
+        debug_ap: 0,
+        rom_base: 0x80000000,
+        cores: CLUSTER_4X,
+    },
+    Soc {
+        name: "STM32MP15",
+        idcode: 0x6ba00477,
+        // Device ID 0x500 and ST's JEP106 code
+        targetid: Some(0x0050_0041),
+        marker: None,
+        core_part: 0xc07,
+        arch: Arch::Armv7A,
+        mem_ap: 0,
+        debug_ap: 1,
+        rom_base: 0xe0000000,
+        cores: &[
+            SocCore {
+                debug_base: 0xe00d0000,
+                cti_base: 0xe00d8000,
+            },
+            SocCore {
+                debug_base: 0xe00d2000,
+                cti_base: 0xe00d9000,
+            },
+        ],
+    },
+];
+
+/// Read the designer and part number of the component at `base`
+fn read_part<T>(mem: &mut MemAP<T>, base: u32) -> Result<(u16, u16), u8>
+where
+    T: Transport + ?Sized,
+{
+    let pidr = mem.read_block(base + 0xfe0, 3, true)?;
+    let pidr4 = mem.read(base + 0xfd0)?;
+    let part = (pidr[0] & 0xff) as u16 | ((pidr[1] & 0xf) as u16) << 8;
+    let designer = ((pidr[1] >> 4) & 0xf) as u16 | ((pidr[2] & 7) as u16) << 4;
+    Ok((designer | ((pidr4 & 0xf) as u16) << 7, part))
+}
+
+/// Return true if AP `apsel` is implemented
+fn ap_present<T>(adi: &Rc<RefCell<T>>, apsel: u32) -> bool
+where
+    T: Transport + ?Sized,
+{
+    matches!(adi.borrow_mut().read_adi(apsel, Port::AP, 0xfc >> 2), Ok(idr) if idr != 0)
+}
+
+/// Return true if the ARM debug component at `base` is present and has part number `part`
+fn component_present<T>(mem: &mut MemAP<T>, base: u32, part: u16) -> bool
+where
+    T: Transport + ?Sized,
+{
+    let found = read_part(mem, base);
+
+    // Nothing may be mapped at that address on other SoCs, so clear any sticky errors caused by
+    // looking
+    if found.is_err() {
+        let _ = mem.clear_sticky_errors();
+    }
+    found == Ok((DESIGNER_ARM, part))
+}
+
+/// Return true if the debug component of `soc`'s first core is present and has the expected
+/// part number, and the SoC's marker, if any, is present
+fn probe<T>(adi: &Rc<RefCell<T>>, soc: &Soc) -> bool
+where
+    T: Transport + ?Sized,
+{
+    // Make sure the AP exists before creating a MemAP for it, as that expects to succeed
+    if !ap_present(adi, soc.debug_ap) {
+        return false;
+    }
+    let mut mem = MemAP::new(adi.clone(), soc.debug_ap);
+    if !component_present(&mut mem, soc.cores[0].debug_base, soc.core_part) {
+        return false;
+    }
+    match soc.marker {
+        Some(Marker::Component { base, part }) => component_present(&mut mem, base, part),
+        Some(Marker::Ap(apsel)) => ap_present(adi, apsel),
+        None => true,
+    }
+}
+
+/// Read the TARGETID of the debug port, or None if it is older than DPv2 and doesn't have one
+pub fn read_targetid<T>(adi: &mut T) -> Result<Option<u32>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let dpidr = adi.read_adi(0, Port::DP, DPIDR)?;
+    if (dpidr >> 12) & 0xf < 2 {
+        return Ok(None);
+    }
+    // read_adi only banks AP registers, so select the DP bank by hand and then put back the
+    // SELECT the transport wrote for APSEL 0
+    let select = DPReg::Select as u8;
+    adi.write_adi(0, Port::DP, select, TARGETID_BANK)?;
+    let targetid = adi.read_adi(0, Port::DP, DPReg::CtrlStat as u8);
+    adi.write_adi(0, Port::DP, select, 0)?;
+    targetid.map(Some)
+}
+
+/// Return the known SoCs whose debug port has the JTAG IDCODE `idcode`
+pub fn candidates(idcode: u32) -> impl Iterator<Item = &'static Soc> {
+    SOCS.iter().filter(move |soc| soc.idcode == idcode)
+}
+
+/// Identify the SoC whose debug port has the JTAG IDCODE `idcode` and is reached through `adi`.
+/// If the debug port has a TARGETID, it alone identifies SoCs that list one.  Otherwise each SoC
+/// with a matching IDCODE is checked by reading the peripheral ID of its first core's debug
+/// component and looking for its marker.
+pub fn identify<T>(adi: &Rc<RefCell<T>>, idcode: u32) -> Option<&'static Soc>
+where
+    T: Transport + ?Sized,
+{
+    let targetid = read_targetid(&mut *adi.borrow_mut()).ok().flatten();
+    if let Some(targetid) = targetid {
+        let part = targetid & TARGETID_PART_MASK;
+        if let Some(soc) = SOCS.iter().find(|soc| soc.targetid == Some(part)) {
+            return Some(soc);
+        }
+    }
+    candidates(idcode).find(|soc| probe(adi, soc))
+}
@@ -0,0 +1,230 @@
+//! Driver for the CoreSight System Trace Macrocell (STM).  The STM turns writes to its stimulus
+//! ports into STPv2 packets on the trace bus, so a debugger can inject software-instrumentation
+//! messages that are captured alongside target-generated trace.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+const STMSPER: u32 = 0xe00;
+const STMSPTER: u32 = 0xe20;
+const STMSPSCR: u32 = 0xe60;
+const STMTCSR: u32 = 0xe80;
+const STMSYNCR: u32 = 0xe90;
+const STMFEAT3R: u32 = 0xea8;
+const LAR: u32 = 0xfb0;
+
+/// STMTCSR.BUSY, set while packets are still being output
+const TCSR_BUSY: u32 = 1 << 23;
+/// How long to wait for pending packets to drain when disabling the STM
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Size of a single port in the extended stimulus port region
+const PORT_SIZE: u32 = 0x100;
+
+/// Packet type generated by a stimulus port write.  Each type is a different offset within the
+/// port's 256-byte window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StimulusKind {
+    /// Data packet
+    Data,
+    /// Data packet with a marker
+    DataMarked,
+    /// Data packet followed by a timestamp
+    DataTimestamped,
+    /// Data packet with a marker, followed by a timestamp
+    DataMarkedTimestamped,
+    /// FLAG packet.  The value written is ignored.
+    Flag,
+    /// FLAG packet followed by a timestamp.  The value written is ignored.
+    FlagTimestamped,
+    /// TRIG packet
+    Trigger,
+    /// TRIG packet followed by a timestamp
+    TriggerTimestamped,
+}
+
+impl StimulusKind {
+    fn offset(self) -> u32 {
+        match self {
+            StimulusKind::DataMarkedTimestamped => 0x00,
+            StimulusKind::DataMarked => 0x08,
+            StimulusKind::DataTimestamped => 0x10,
+            StimulusKind::Data => 0x18,
+            StimulusKind::FlagTimestamped => 0x60,
+            StimulusKind::Flag => 0x68,
+            StimulusKind::TriggerTimestamped => 0x70,
+            StimulusKind::Trigger => 0x78,
+        }
+    }
+}
+
+/// STPv2 opcodes.  Opcodes are a sequence of 4-bit nibbles; single nibble opcodes are below 0x10,
+/// two nibble opcodes are 0xf1-0xff and three nibble opcodes are 0xf00-0xf0f.
+pub mod stpv2 {
+    pub const NULL: u16 = 0x0;
+    pub const M8: u16 = 0x1;
+    pub const MERR: u16 = 0x2;
+    pub const C8: u16 = 0x3;
+    pub const FLAG_TS: u16 = 0xe;
+    pub const M16: u16 = 0xf1;
+    pub const GERR: u16 = 0xf2;
+    pub const C16: u16 = 0xf3;
+    pub const FLAG: u16 = 0xfe;
+    pub const VERSION: u16 = 0xf00;
+    pub const NULL_TS: u16 = 0xf01;
+    pub const USER: u16 = 0xf06;
+    pub const USER_TS: u16 = 0xf07;
+    pub const TIME: u16 = 0xf08;
+    pub const TIME_TS: u16 = 0xf09;
+    pub const TRIG: u16 = 0xf0a;
+    pub const TRIG_TS: u16 = 0xf0b;
+    pub const FREQ: u16 = 0xf0c;
+    pub const FREQ_TS: u16 = 0xf0d;
+    pub const XSYNC: u16 = 0xf0e;
+
+    /// Number of 0xf nibbles preceding the terminating 0x0 nibble of an ASYNC sequence
+    pub const ASYNC_NIBBLES: usize = 21;
+
+    /// Return the opcode for a data packet carrying `size` bytes, or None if `size` is not 1, 2,
+    /// 4 or 8.
+    pub fn data_opcode(size: usize, marked: bool, timestamped: bool) -> Option<u16> {
+        let index = match size {
+            1 => 0,
+            2 => 1,
+            4 => 2,
+            8 => 3,
+            _ => return None,
+        };
+        let op = match (marked, timestamped) {
+            (false, false) => 0x4,
+            (true, true) => 0x8,
+            (false, true) => 0xf4,
+            (true, false) => 0xf8,
+        };
+        Some(op + index)
+    }
+
+    /// Return the number of nibbles used to encode `opcode`
+    pub fn opcode_nibbles(opcode: u16) -> usize {
+        if opcode < 0x10 {
+            1
+        } else if opcode < 0x100 {
+            2
+        } else {
+            3
+        }
+    }
+}
+
+/// Functions for interacting with a System Trace Macrocell
+pub struct Stm<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+    stim_mem: Rc<RefCell<MemAP<T>>>,
+    stim_base: u32,
+}
+
+impl<T> Stm<T>
+where
+    T: Transport + ?Sized,
+{
+    /// `base` is the address of the STM configuration registers, accessed through `mem`.
+    /// `stim_base` is the address of the extended stimulus port region, accessed through
+    /// `stim_mem`.  These are typically on different buses, so are given separate `MemAP`s,
+    /// although they may be the same one.
+    pub fn new(
+        mem: Rc<RefCell<MemAP<T>>>,
+        base: u32,
+        stim_mem: Rc<RefCell<MemAP<T>>>,
+        stim_base: u32,
+    ) -> Self {
+        Self {
+            mem,
+            base,
+            stim_mem,
+            stim_base,
+        }
+    }
+
+    /// Return the number of masters supported by the STM
+    pub fn num_masters(&mut self) -> Result<u32, u8> {
+        let feat3 = self.mem.borrow_mut().read(self.base + STMFEAT3R)?;
+        Ok((feat3 & 0x7f) + 1)
+    }
+
+    /// Unlock and enable the STM, emitting packets with trace ID `trace_id`.  All stimulus ports
+    /// are enabled.  If `timestamps` is true then timestamped stimulus writes will carry a
+    /// timestamp.
+    pub fn enable(&mut self, trace_id: u8, timestamps: bool) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        mem.write(self.base + LAR, 0xC5ACCE55)?;
+
+        // STMSPER and STMSPTER apply to all ports
+        mem.write(self.base + STMSPSCR, 0)?;
+        mem.write(self.base + STMSPER, 0xffffffff)?;
+        mem.write(self.base + STMSPTER, 0)?;
+
+        // Request an ASYNC every 4kB of trace
+        mem.write(self.base + STMSYNCR, 0x1000)?;
+
+        let mut tcsr = (trace_id as u32 & 0x7f) << 16 | 1 << 2 | 1;
+        if timestamps {
+            tcsr |= 1 << 1;
+        }
+        mem.write(self.base + STMTCSR, tcsr)
+    }
+
+    /// Disable the STM and wait for any pending packets to drain.  Returns `ERR_TIMEOUT` if they
+    /// haven't within `DRAIN_TIMEOUT`, for example because the trace sink has stopped accepting
+    /// data.
+    pub fn disable(&mut self) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        let tcsr = mem.read(self.base + STMTCSR)?;
+        mem.write(self.base + STMTCSR, tcsr & !1)?;
+
+        let start = Instant::now();
+        while mem.read(self.base + STMTCSR)? & TCSR_BUSY != 0 {
+            if start.elapsed() > DRAIN_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` to stimulus port `channel`, generating a guaranteed D32 packet
+    pub fn write_stimulus(&mut self, channel: u16, data: u32) -> Result<(), u8> {
+        self.write_stimulus_kind(channel, data, StimulusKind::Data, true)
+    }
+
+    /// Write `data` to stimulus port `channel`, generating a packet of type `kind`.  If
+    /// `guaranteed` is false then the invariant-timing port is used, and the STM may drop the
+    /// packet rather than stall the bus when its FIFO is full.
+    pub fn write_stimulus_kind(
+        &mut self,
+        channel: u16,
+        data: u32,
+        kind: StimulusKind,
+        guaranteed: bool,
+    ) -> Result<(), u8> {
+        let mut addr = self.stim_base + channel as u32 * PORT_SIZE + kind.offset();
+        if !guaranteed {
+            addr += 0x80;
+        }
+        self.stim_mem.borrow_mut().write(addr, data)
+    }
+
+    /// Write a sequence of words to stimulus port `channel`, with a marker on the final word so
+    /// that the message boundary is visible in the captured trace.
+    pub fn write_message(&mut self, channel: u16, data: &[u32]) -> Result<(), u8> {
+        if let Some((last, rest)) = data.split_last() {
+            for x in rest {
+                self.write_stimulus(channel, *x)?;
+            }
+            self.write_stimulus_kind(channel, *last, StimulusKind::DataMarked, true)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Streaming data out of a circular buffer in target memory, for logging channels implemented in
+//! plain shared memory.  The target writes bytes at the head offset and advances it; the host
+//! reads the bytes between the tail and head offsets and advances the tail.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+use crate::{MemAP, Transport};
+
+/// Error streaming from a ring buffer
+#[derive(Debug)]
+pub enum StreamError {
+    /// Error from the debug interface
+    Access(u8),
+    /// Error writing to the sink
+    Io(io::Error),
+    /// The head or tail offset is outside the buffer
+    BadOffset(u32),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Access(e) => write!(f, "access error {}", e),
+            StreamError::Io(e) => write!(f, "{}", e),
+            StreamError::BadOffset(x) => write!(f, "ring buffer offset {:#x} out of range", x),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<u8> for StreamError {
+    fn from(e: u8) -> Self {
+        StreamError::Access(e)
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+/// Location of a ring buffer in target memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RingBuffer {
+    /// Address of the buffer, which must be word aligned
+    pub base: u32,
+    /// Size of the buffer in bytes, which must be a multiple of 4
+    pub size: u32,
+    /// Address of the word holding the byte offset the target writes at next
+    pub head_addr: u32,
+    /// Address of the word holding the byte offset the host reads from next
+    pub tail_addr: u32,
+}
+
+impl RingBuffer {
+    /// Read `len` bytes starting at byte offset `start`, which don't wrap
+    fn read_bytes<T>(&self, mem: &mut MemAP<T>, start: u32, len: u32) -> Result<Vec<u8>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let first = start & !3;
+        let words = (start + len - first).div_ceil(4);
+        let data = mem.read_memory(self.base + first, words as usize)?;
+        let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let skip = (start - first) as usize;
+        Ok(bytes[skip..skip + len as usize].to_vec())
+    }
+
+    /// Copy any bytes waiting in the buffer to `sink` and release them to the target.  Returns
+    /// the number of bytes copied.
+    pub fn poll<T, W>(&self, mem: &mut MemAP<T>, sink: &mut W) -> Result<usize, StreamError>
+    where
+        T: Transport + ?Sized,
+        W: Write,
+    {
+        let head = mem.read(self.head_addr)?;
+        let tail = mem.read(self.tail_addr)?;
+        for offset in [head, tail] {
+            if offset >= self.size {
+                return Err(StreamError::BadOffset(offset));
+            }
+        }
+        if head == tail {
+            return Ok(0);
+        }
+
+        let mut data = if head > tail {
+            self.read_bytes(mem, tail, head - tail)?
+        } else {
+            self.read_bytes(mem, tail, self.size - tail)?
+        };
+        if head < tail && head > 0 {
+            data.extend(self.read_bytes(mem, 0, head)?);
+        }
+
+        sink.write_all(&data)?;
+        mem.write(self.tail_addr, head)?;
+        Ok(data.len())
+    }
+
+    /// Copy data from the buffer to `sink` as it arrives, checking every `interval`, until
+    /// `keep_going` returns false
+    pub fn stream<T, W, F>(
+        &self,
+        mem: &mut MemAP<T>,
+        sink: &mut W,
+        interval: Duration,
+        mut keep_going: F,
+    ) -> Result<(), StreamError>
+    where
+        T: Transport + ?Sized,
+        W: Write,
+        F: FnMut() -> bool,
+    {
+        while keep_going() {
+            if self.poll(mem, sink)? == 0 {
+                thread::sleep(interval);
+            } else {
+                sink.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
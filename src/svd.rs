@@ -0,0 +1,510 @@
+//! Register access by name using a CMSIS-SVD description of the target, enabled with the `svd`
+//! feature.  Registers are named `PERIPHERAL.REGISTER`, and fields within them
+//! `PERIPHERAL.REGISTER.FIELD`:
+//!
+//! ```no_run
+//! # use std::cell::RefCell;
+//! # use std::rc::Rc;
+//! # use jtag_adi::svd::{Device, Registers};
+//! # fn example(mem: Rc<RefCell<jtag_adi::MemAP<dyn jtag_adi::Transport>>>) -> Result<(), jtag_adi::svd::SvdError> {
+//! let device = Device::parse(&std::fs::read_to_string("nrf52840.svd").unwrap())?;
+//! let mut target = Registers::new(mem, device);
+//! target.write("UART0.BAUDRATE", 0x1D7E)?;
+//! let enabled = target.read("UART0.ENABLE.ENABLE")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Register arrays (`dim` elements) are expanded into individual registers.  Clusters are not
+//! supported; registers inside them are skipped.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use roxmltree::Node;
+
+use crate::{MemAP, Transport};
+
+/// Error from parsing an SVD file or accessing a register by name
+#[derive(Debug)]
+pub enum SvdError {
+    /// The file isn't valid XML, or is missing a required element
+    Parse(String),
+    /// No register or field has the given name
+    UnknownName(String),
+    /// The register access failed with the given error code
+    Access(u8),
+}
+
+impl fmt::Display for SvdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SvdError::Parse(e) => write!(f, "bad SVD file: {}", e),
+            SvdError::UnknownName(name) => write!(f, "no register or field named {}", name),
+            SvdError::Access(e) => write!(f, "access failed with error {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SvdError {}
+
+impl From<u8> for SvdError {
+    fn from(e: u8) -> Self {
+        SvdError::Access(e)
+    }
+}
+
+/// A bitfield within a register
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub description: Option<String>,
+    pub bit_offset: u32,
+    pub bit_width: u32,
+}
+
+impl Field {
+    /// Mask of the field's bits, in position within the register.  Bits beyond 32 are dropped.
+    pub fn mask(&self) -> u32 {
+        let bits = if self.bit_width >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.bit_width) - 1
+        };
+        bits.checked_shl(self.bit_offset).unwrap_or(0)
+    }
+
+    /// Extract the field's value from the register value `reg`
+    pub fn extract(&self, reg: u32) -> u32 {
+        (reg & self.mask())
+            .checked_shr(self.bit_offset)
+            .unwrap_or(0)
+    }
+
+    /// Return `reg` with the field replaced by `value`
+    pub fn insert(&self, reg: u32, value: u32) -> u32 {
+        let value = value.checked_shl(self.bit_offset).unwrap_or(0);
+        (reg & !self.mask()) | (value & self.mask())
+    }
+}
+
+/// A memory-mapped register
+#[derive(Clone, Debug)]
+pub struct Register {
+    pub name: String,
+    pub description: Option<String>,
+    /// Offset from the peripheral's base address
+    pub offset: u32,
+    /// Width in bits
+    pub size: u32,
+    pub reset_value: Option<u32>,
+    pub fields: Vec<Field>,
+}
+
+impl Register {
+    /// Extract the register's value from `word`, the aligned word containing `addr`.  Registers
+    /// narrower than 32 bits are shifted down from their byte lane.
+    pub fn from_word(&self, addr: u32, word: u32) -> u32 {
+        if self.size >= 32 {
+            return word;
+        }
+        (word >> ((addr & 3) * 8)) & ((1 << self.size) - 1)
+    }
+
+    /// Return the value of each field within the register value `val`
+    pub fn decode(&self, val: u32) -> Vec<(String, u32)> {
+        self.fields
+            .iter()
+            .map(|f| (f.name.clone(), f.extract(val)))
+            .collect()
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields
+            .iter()
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A peripheral and its registers
+#[derive(Clone, Debug)]
+pub struct Peripheral {
+    pub name: String,
+    pub description: Option<String>,
+    pub base_address: u32,
+    pub registers: Vec<Register>,
+}
+
+impl Peripheral {
+    pub fn register(&self, name: &str) -> Option<&Register> {
+        self.registers
+            .iter()
+            .find(|r| r.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The contents of an SVD file
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub name: String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+/// Parse an SVD scaledNonNegativeInteger: decimal, 0x-prefixed hex or #-prefixed binary
+fn parse_number(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix('#') {
+        // 'x' marks a don't care bit in enumerated values
+        u64::from_str_radix(&bin.replace(['x', 'X'], "0"), 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn child<'a>(node: Node<'a, '_>, name: &str) -> Option<Node<'a, 'a>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+fn child_number(node: Node, name: &str) -> Result<Option<u64>, SvdError> {
+    match child_text(node, name) {
+        Some(text) => parse_number(&text)
+            .map(Some)
+            .ok_or_else(|| SvdError::Parse(format!("bad {} {}", name, text))),
+        None => Ok(None),
+    }
+}
+
+fn required<T>(val: Option<T>, what: &str, node: Node) -> Result<T, SvdError> {
+    val.ok_or_else(|| {
+        let name = child_text(node, "name").unwrap_or_default();
+        SvdError::Parse(format!(
+            "missing {} in {} {}",
+            what,
+            node.tag_name().name(),
+            name
+        ))
+    })
+}
+
+fn parse_field(node: Node) -> Result<Field, SvdError> {
+    let name = required(child_text(node, "name"), "name", node)?;
+    let (bit_offset, bit_width) = if let Some(offset) = child_number(node, "bitOffset")? {
+        let width = child_number(node, "bitWidth")?.unwrap_or(1);
+        (offset as u32, width as u32)
+    } else if let Some(lsb) = child_number(node, "lsb")? {
+        let msb = required(child_number(node, "msb")?, "msb", node)?;
+        if msb < lsb {
+            return Err(SvdError::Parse(format!(
+                "{} has msb {} below lsb {}",
+                name, msb, lsb
+            )));
+        }
+        (lsb as u32, (msb - lsb + 1) as u32)
+    } else {
+        // [msb:lsb]
+        let range = required(child_text(node, "bitRange"), "bit range", node)?;
+        let bad = || SvdError::Parse(format!("bad bitRange {}", range));
+        let (msb, lsb) = range
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .split_once(':')
+            .ok_or_else(bad)?;
+        let msb: u32 = msb.parse().map_err(|_| bad())?;
+        let lsb: u32 = lsb.parse().map_err(|_| bad())?;
+        if msb < lsb {
+            return Err(bad());
+        }
+        (lsb, msb - lsb + 1)
+    };
+    Ok(Field {
+        name,
+        description: child_text(node, "description"),
+        bit_offset,
+        bit_width,
+    })
+}
+
+/// Parse a register, returning one register per element if it is an array
+fn parse_register(node: Node, default_size: u32) -> Result<Vec<Register>, SvdError> {
+    let name = required(child_text(node, "name"), "name", node)?;
+    let offset = required(child_number(node, "addressOffset")?, "addressOffset", node)? as u32;
+    let size = child_number(node, "size")?
+        .map(|x| x as u32)
+        .unwrap_or(default_size);
+    if !matches!(size, 8 | 16 | 32) {
+        return Err(SvdError::Parse(format!(
+            "{} has unsupported size {}",
+            name, size
+        )));
+    }
+    let mut fields = vec![];
+    if let Some(list) = child(node, "fields") {
+        for f in list.children().filter(|n| n.has_tag_name("field")) {
+            let field = parse_field(f)?;
+            if field.bit_width == 0
+                || field.bit_offset as u64 + field.bit_width as u64 > size as u64
+            {
+                return Err(SvdError::Parse(format!(
+                    "field {} of {} doesn't fit in {} bits",
+                    field.name, name, size
+                )));
+            }
+            fields.push(field);
+        }
+    }
+    let reg = Register {
+        name,
+        description: child_text(node, "description"),
+        offset,
+        size,
+        reset_value: child_number(node, "resetValue")?.map(|x| x as u32),
+        fields,
+    };
+
+    let Some(dim) = child_number(node, "dim")? else {
+        return Ok(vec![reg]);
+    };
+    let increment = required(child_number(node, "dimIncrement")?, "dimIncrement", node)? as u32;
+    let indices: Vec<String> = match child_text(node, "dimIndex") {
+        Some(list) if list.contains('-') => {
+            let (first, last) = list.split_once('-').unwrap();
+            match (first.trim().parse::<u32>(), last.trim().parse::<u32>()) {
+                (Ok(first), Ok(last)) => (first..=last).map(|i| i.to_string()).collect(),
+                _ => return Err(SvdError::Parse(format!("bad dimIndex {}", list))),
+            }
+        }
+        Some(list) => list.split(',').map(|s| s.trim().to_string()).collect(),
+        None => (0..dim).map(|i| i.to_string()).collect(),
+    };
+    Ok(indices
+        .iter()
+        .enumerate()
+        .map(|(i, index)| Register {
+            name: reg.name.replace("[%s]", index).replace("%s", index),
+            offset: reg.offset + i as u32 * increment,
+            ..reg.clone()
+        })
+        .collect())
+}
+
+impl Device {
+    /// Parse the contents of an SVD file
+    pub fn parse(text: &str) -> Result<Self, SvdError> {
+        let doc = roxmltree::Document::parse(text).map_err(|e| SvdError::Parse(e.to_string()))?;
+        let root = doc.root_element();
+        let name = child_text(root, "name").unwrap_or_default();
+        let default_size = child_number(root, "size")?.unwrap_or(32) as u32;
+
+        let mut peripherals: Vec<Peripheral> = vec![];
+        let Some(list) = child(root, "peripherals") else {
+            return Ok(Self { name, peripherals });
+        };
+        for node in list.children().filter(|n| n.has_tag_name("peripheral")) {
+            let name = required(child_text(node, "name"), "name", node)?;
+            let base_address =
+                required(child_number(node, "baseAddress")?, "baseAddress", node)? as u32;
+            let size = child_number(node, "size")?
+                .map(|x| x as u32)
+                .unwrap_or(default_size);
+
+            // Derived peripherals inherit everything they don't override
+            let mut registers = vec![];
+            let mut description = child_text(node, "description");
+            if let Some(base) = node.attribute("derivedFrom") {
+                let base = peripherals.iter().find(|p| p.name == base).ok_or_else(|| {
+                    SvdError::Parse(format!("{} derived from unknown {}", name, base))
+                })?;
+                registers = base.registers.clone();
+                description = description.or_else(|| base.description.clone());
+            }
+            if let Some(regs) = child(node, "registers") {
+                registers.clear();
+                for r in regs.children().filter(|n| n.has_tag_name("register")) {
+                    registers.extend(parse_register(r, size)?);
+                }
+            }
+
+            peripherals.push(Peripheral {
+                name,
+                description,
+                base_address,
+                registers,
+            });
+        }
+        Ok(Self { name, peripherals })
+    }
+
+    pub fn peripheral(&self, name: &str) -> Option<&Peripheral> {
+        self.peripherals
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Look up `PERIPHERAL.REGISTER` or `PERIPHERAL.REGISTER.FIELD`, returning the register's
+    /// address, the register, and the field if one was named
+    pub fn lookup(&self, name: &str) -> Result<(u32, &Register, Option<&Field>), SvdError> {
+        let unknown = || SvdError::UnknownName(name.to_string());
+        let mut parts = name.split('.');
+        let (Some(periph), Some(reg)) = (parts.next(), parts.next()) else {
+            return Err(unknown());
+        };
+        let periph = self.peripheral(periph).ok_or_else(unknown)?;
+        let reg = periph.register(reg).ok_or_else(unknown)?;
+        let field = match parts.next() {
+            Some(field) => Some(reg.field(field).ok_or_else(unknown)?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(unknown());
+        }
+        Ok((periph.base_address.wrapping_add(reg.offset), reg, field))
+    }
+}
+
+/// Access to a target's registers by the names in its SVD file
+pub struct Registers<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    device: Device,
+}
+
+impl<T> Registers<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, device: Device) -> Self {
+        Self { mem, device }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Read the register at `addr` with an access of its size, so that its neighbours aren't
+    /// read too
+    fn read_reg(&mut self, addr: u32, reg: &Register) -> Result<u32, u8> {
+        let mut mem = self.mem.borrow_mut();
+        match reg.size {
+            8 => mem.read_byte(addr).map(u32::from),
+            16 => mem.read_halfword(addr).map(u32::from),
+            _ => mem.read(addr),
+        }
+    }
+
+    /// Write `value` to the register at `addr` with an access of its size, so that its
+    /// neighbours are left alone
+    fn write_reg(&mut self, addr: u32, reg: &Register, value: u32) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        match reg.size {
+            8 => mem.write_byte(addr, value as u8),
+            16 => mem.write_halfword(addr, value as u16),
+            _ => mem.write(addr, value),
+        }
+    }
+
+    /// Read a register, or the value of a field within it
+    pub fn read(&mut self, name: &str) -> Result<u32, SvdError> {
+        let (addr, reg, field) = self.device.lookup(name)?;
+        let reg = reg.clone();
+        let field = field.cloned();
+        let val = self.read_reg(addr, &reg)?;
+        Ok(field.map_or(val, |f| f.extract(val)))
+    }
+
+    /// Write a register.  If `name` is a field, then the register is read and only the field is
+    /// modified.
+    pub fn write(&mut self, name: &str, value: u32) -> Result<(), SvdError> {
+        let (addr, reg, field) = self.device.lookup(name)?;
+        let reg = reg.clone();
+        let value = match field.cloned() {
+            Some(f) => f.insert(self.read_reg(addr, &reg)?, value),
+            None => value,
+        };
+        self.write_reg(addr, &reg, value)?;
+        Ok(())
+    }
+
+    /// Read the register `PERIPHERAL.REGISTER` and return the value of each of its fields
+    pub fn read_fields(&mut self, name: &str) -> Result<Vec<(String, u32)>, SvdError> {
+        let (addr, reg, field) = self.device.lookup(name)?;
+        if field.is_some() {
+            return Err(SvdError::UnknownName(name.to_string()));
+        }
+        let reg = reg.clone();
+        let val = self.read_reg(addr, &reg)?;
+        Ok(reg.decode(val))
+    }
+
+    /// Read every register of the peripheral `name` with a single block transfer covering its
+    /// address range.  Note that this also reads registers with read side effects, such as FIFOs
+    /// and clear-on-read status registers.
+    pub fn dump_peripheral(&mut self, name: &str) -> Result<Vec<RegisterValue>, SvdError> {
+        let periph = self
+            .device
+            .peripheral(name)
+            .ok_or_else(|| SvdError::UnknownName(name.to_string()))?;
+        let Some(first) = periph.registers.iter().map(|r| r.offset).min() else {
+            return Ok(vec![]);
+        };
+        let end = periph
+            .registers
+            .iter()
+            .map(|r| r.offset + r.size.div_ceil(8))
+            .max()
+            .unwrap();
+        let start = periph.base_address.wrapping_add(first) & !3;
+        let count = (periph.base_address.wrapping_add(end) - start).div_ceil(4);
+        let data = self.mem.borrow_mut().read_memory(start, count as usize)?;
+
+        let mut values: Vec<RegisterValue> = periph
+            .registers
+            .iter()
+            .map(|reg| {
+                let address = periph.base_address.wrapping_add(reg.offset);
+                let word = data[((address & !3) - start) as usize / 4];
+                let value = reg.from_word(address, word);
+                RegisterValue {
+                    name: format!("{}.{}", periph.name, reg.name),
+                    address,
+                    value,
+                    fields: reg.decode(value),
+                }
+            })
+            .collect();
+        values.sort_by_key(|v| v.address);
+        Ok(values)
+    }
+}
+
+/// The value of a register read by `Registers::dump_peripheral`
+#[derive(Clone, Debug)]
+pub struct RegisterValue {
+    /// `PERIPHERAL.REGISTER`
+    pub name: String,
+    pub address: u32,
+    pub value: u32,
+    /// Name and value of each field
+    pub fields: Vec<(String, u32)>,
+}
+
+impl fmt::Display for RegisterValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:08x} {:<24} {:08x}",
+            self.address, self.name, self.value
+        )?;
+        for (name, val) in &self.fields {
+            write!(f, "\n    {:<20} {:#x}", name, val)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Object model of a discovered target.  `Target::discover` enumerates the APs behind a DP, walks
+//! the ROM table of each MEM-AP and constructs a `Core` for each ARMv8-A core it finds, so an
+//! application gets one object owning the whole debug infrastructure instead of creating and
+//! sharing each `Rc<RefCell<_>>` handle itself.  `Target::attach` does the same from the built-in
+//! layout of a SoC that `soc::identify` recognises.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ap::{self, ApInfo};
+use crate::armv8::Core;
+use crate::coresight::id::Architecture;
+use crate::rom_table::{self, Component, CoreComponents};
+use crate::soc::{self, Arch, Soc};
+use crate::{MemAP, Transport};
+
+/// An access port of the target, with the components found through it
+pub struct AccessPort<T: ?Sized> {
+    info: ApInfo,
+    /// The MEM-AP, if this is one
+    mem: Option<Rc<RefCell<MemAP<T>>>>,
+    /// Address of the top-level ROM table, if the AP has one within the 32-bit address space
+    rom_base: Option<u32>,
+    components: Vec<Component>,
+    /// The CPUs whose debug components were found through this AP
+    cpus: Vec<CoreComponents>,
+}
+
+impl<T> AccessPort<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn info(&self) -> ApInfo {
+        self.info
+    }
+
+    pub fn mem(&self) -> Option<Rc<RefCell<MemAP<T>>>> {
+        self.mem.clone()
+    }
+
+    pub fn rom_base(&self) -> Option<u32> {
+        self.rom_base
+    }
+
+    /// The components found by walking the ROM table, including the ROM tables themselves
+    pub fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    /// The components of each CPU found, matched by affinity
+    pub fn cpus(&self) -> &[CoreComponents] {
+        &self.cpus
+    }
+}
+
+/// A target's DP, its APs and the cores found through them
+pub struct Target<T: ?Sized> {
+    adi: Rc<RefCell<T>>,
+    aps: Vec<AccessPort<T>>,
+    cores: Vec<Core<T>>,
+    /// The AP and components of each of `cores`
+    core_components: Vec<(u32, CoreComponents)>,
+    /// The SoC whose layout `attach` used
+    soc: Option<&'static Soc>,
+}
+
+impl<T> Target<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Enumerate the APs behind `adi` and walk the ROM table of each MEM-AP.  The components of
+    /// each CPU are matched with `rom_table::match_cores`, and a `Core` is constructed for each
+    /// ARMv8-A core with a CTI.
+    pub fn discover(adi: Rc<RefCell<T>>) -> Result<Self, u8> {
+        let infos = ap::enumerate_aps(&mut *adi.borrow_mut())?;
+        let mut aps = Vec::with_capacity(infos.len());
+        let mut cores = vec![];
+        let mut core_components = vec![];
+        for info in infos {
+            if !info.is_mem_ap() {
+                aps.push(AccessPort {
+                    info,
+                    mem: None,
+                    rom_base: None,
+                    components: vec![],
+                    cpus: vec![],
+                });
+                continue;
+            }
+
+            let mem = Rc::new(RefCell::new(MemAP::new_lazy(adi.clone(), info.apsel)));
+            let rom_base = mem
+                .borrow_mut()
+                .rom_base()?
+                .and_then(|base| u32::try_from(base).ok());
+            let components = match rom_base {
+                Some(base) => rom_table::parse_rom_table(&mut mem.borrow_mut(), base)?,
+                None => vec![],
+            };
+            let cpus = rom_table::match_cores(&components);
+            for cpu in &cpus {
+                let (Some(Architecture::DebugV8A(_)), Some(cti)) = (cpu.arch, cpu.cti) else {
+                    continue;
+                };
+                cores.push(Core::new(mem.clone(), cpu.debug, cti));
+                core_components.push((info.apsel, *cpu));
+            }
+            aps.push(AccessPort {
+                info,
+                mem: Some(mem),
+                rom_base,
+                components,
+                cpus,
+            });
+        }
+        Ok(Self {
+            adi,
+            aps,
+            cores,
+            core_components,
+            soc: None,
+        })
+    }
+
+    /// If `soc::identify` recognises the SoC from the IDCODE `idcode` of the debug port, create
+    /// the target from its built-in layout, with a `Core` for each core of an ARMv8-A SoC.  Only
+    /// the memory and debug APs are opened and no ROM table is walked, so `components` is empty.
+    /// Otherwise the target is found with `discover`.
+    pub fn attach(adi: Rc<RefCell<T>>, idcode: u32) -> Result<Self, u8> {
+        let Some(soc) = soc::identify(&adi, idcode) else {
+            return Self::discover(adi);
+        };
+
+        let debug_mem = Rc::new(RefCell::new(MemAP::new_lazy(adi.clone(), soc.debug_ap)));
+        let info = debug_mem.borrow_mut().idr()?;
+        let mut aps = vec![AccessPort {
+            info,
+            mem: Some(debug_mem.clone()),
+            rom_base: Some(soc.rom_base),
+            components: vec![],
+            cpus: vec![],
+        }];
+        if soc.mem_ap != soc.debug_ap {
+            let mem = Rc::new(RefCell::new(MemAP::new_lazy(adi.clone(), soc.mem_ap)));
+            let info = mem.borrow_mut().idr()?;
+            aps.push(AccessPort {
+                info,
+                mem: Some(mem),
+                rom_base: None,
+                components: vec![],
+                cpus: vec![],
+            });
+            aps.sort_by_key(|ap| ap.info.apsel);
+        }
+
+        let cores = match soc.arch {
+            Arch::Armv8A => soc
+                .cores
+                .iter()
+                .map(|core| Core::new(debug_mem.clone(), core.debug_base, core.cti_base))
+                .collect(),
+            Arch::Armv7A => vec![],
+        };
+        Ok(Self {
+            adi,
+            aps,
+            cores,
+            core_components: vec![],
+            soc: Some(soc),
+        })
+    }
+
+    /// The SoC whose built-in layout `attach` used, if it recognised one
+    pub fn soc(&self) -> Option<&'static Soc> {
+        self.soc
+    }
+
+    /// The debug port the target was discovered through
+    pub fn adi(&self) -> Rc<RefCell<T>> {
+        self.adi.clone()
+    }
+
+    pub fn aps(&self) -> &[AccessPort<T>] {
+        &self.aps
+    }
+
+    /// Return the AP selected by `apsel`, if it is implemented
+    pub fn ap(&self, apsel: u32) -> Option<&AccessPort<T>> {
+        self.aps.iter().find(|ap| ap.info.apsel == apsel)
+    }
+
+    /// Every component found, with the AP it was found through
+    pub fn components(&self) -> impl Iterator<Item = (u32, &Component)> {
+        self.aps
+            .iter()
+            .flat_map(|ap| ap.components.iter().map(move |c| (ap.info.apsel, c)))
+    }
+
+    /// The cores, in the order their debug components were found
+    pub fn cores(&mut self) -> &mut [Core<T>] {
+        &mut self.cores
+    }
+
+    pub fn core(&mut self, index: usize) -> Option<&mut Core<T>> {
+        self.cores.get_mut(index)
+    }
+
+    /// The AP and the debug, CTI and ETM components of the core at `index`.  None for the cores
+    /// of a SoC recognised by `attach`, as their components aren't read.
+    pub fn core_components(&self, index: usize) -> Option<(u32, CoreComponents)> {
+        self.core_components.get(index).copied()
+    }
+
+    pub fn into_cores(self) -> Vec<Core<T>> {
+        self.cores
+    }
+}
@@ -0,0 +1,297 @@
+//! Running test programs on an ARMv8-A core for on-target CI, enabled with the `elf` feature.
+//! `run_test` loads an ELF file through the core, starts it at its entry point and services
+//! semihosting calls until the program exits, reaches an exit symbol or times out, returning its
+//! console output and exit status.
+//!
+//! Only the semihosting console calls are implemented; other calls fail with -1 returned to the
+//! program.  RTT output isn't captured.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::armv8::{BreakpointKind, Core, HaltReason};
+use crate::elf::{self, Symbols};
+use crate::session::RunControl;
+use crate::Transport;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+/// SYS_EXIT reason for a normal exit, with the exit code as subcode
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Handles returned by SYS_OPEN of ":tt"
+const HANDLE_STDOUT: u64 = 1;
+const HANDLE_STDERR: u64 = 2;
+
+/// Longest string read for SYS_WRITE0 or a file name
+const MAX_STRING: usize = 4096;
+
+/// Error running a test
+#[derive(Debug)]
+pub enum TestError {
+    /// Error from the debug interface
+    Access(u8),
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TestError::Access(e) => write!(f, "access error {}", e),
+            TestError::Io(e) => write!(f, "{}", e),
+            TestError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TestError {}
+
+impl From<u8> for TestError {
+    fn from(e: u8) -> Self {
+        TestError::Access(e)
+    }
+}
+
+impl From<io::Error> for TestError {
+    fn from(e: io::Error) -> Self {
+        TestError::Io(e)
+    }
+}
+
+impl From<elf::ElfError> for TestError {
+    fn from(e: elf::ElfError) -> Self {
+        TestError::Parse(e.to_string())
+    }
+}
+
+/// How a test ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestExit {
+    /// The program exited normally with SYS_EXIT or SYS_EXIT_EXTENDED
+    Exited(u64),
+    /// The program called SYS_EXIT with a reason other than a normal exit
+    Aborted(u64),
+    /// The program reached the exit symbol.  X0, the first argument of a function, is the exit
+    /// code.
+    ReachedSymbol(u64),
+    /// The core halted for another reason
+    Stopped { pc: u64, reason: Option<HaltReason> },
+    /// The program was still running at the timeout, and has been halted
+    TimedOut,
+}
+
+/// Result of `run_test`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    /// Everything written to the semihosting console, stdout and stderr combined
+    pub output: Vec<u8>,
+    pub exit: TestExit,
+}
+
+impl TestResult {
+    /// Return true if the program exited with code 0
+    pub fn passed(&self) -> bool {
+        matches!(self.exit, TestExit::Exited(0) | TestExit::ReachedSymbol(0))
+    }
+}
+
+fn read_bytes<T>(core: &mut Core<T>, addr: u64, len: usize) -> Result<Vec<u8>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let first = addr & !3;
+    let skip = (addr - first) as usize;
+    let words = core.read_memory(first, (skip + len).div_ceil(4))?;
+    let bytes: Vec<u8> = words.iter().flat_map(|x| x.to_le_bytes()).collect();
+    Ok(bytes[skip..skip + len].to_vec())
+}
+
+fn read_u64<T>(core: &mut Core<T>, addr: u64) -> Result<u64, u8>
+where
+    T: Transport + ?Sized,
+{
+    let words = core.read_memory(addr, 2)?;
+    Ok((words[1] as u64) << 32 | words[0] as u64)
+}
+
+fn read_string<T>(core: &mut Core<T>, addr: u64) -> Result<Vec<u8>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let mut s = vec![];
+    while s.len() < MAX_STRING {
+        let chunk = read_bytes(core, addr + s.len() as u64, 64)?;
+        match chunk.iter().position(|&b| b == 0) {
+            Some(end) => {
+                s.extend_from_slice(&chunk[..end]);
+                break;
+            }
+            None => s.extend(chunk),
+        }
+    }
+    Ok(s)
+}
+
+/// Write `data` at `addr` through the core, preserving the bytes after it in the last word
+fn write_bytes<T>(core: &mut Core<T>, addr: u64, data: &[u8]) -> Result<(), TestError>
+where
+    T: Transport + ?Sized,
+{
+    if addr & 3 != 0 {
+        return Err(TestError::Parse(format!(
+            "segment at {:#x} not aligned",
+            addr
+        )));
+    }
+    let mut bytes = data.to_vec();
+    let tail = data.len() & 3;
+    if tail != 0 {
+        let last = addr + (data.len() - tail) as u64;
+        bytes.extend_from_slice(&read_bytes(core, last + tail as u64, 4 - tail)?);
+    }
+    let words: Vec<u32> = bytes
+        .chunks(4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    core.write_memory(addr, &words)?;
+    core.sync_icache(addr, data.len() as u64)?;
+    Ok(())
+}
+
+/// Service the semihosting call the core is halted on.  Returns the exit status if the call
+/// was an exit.
+fn semihost<T>(core: &mut Core<T>, output: &mut Vec<u8>) -> Result<Option<TestExit>, u8>
+where
+    T: Transport + ?Sized,
+{
+    let op = core.read_reg(0)? & 0xffff_ffff;
+    let param = core.read_reg(1)?;
+    let ret = match op {
+        SYS_OPEN => {
+            let name = read_u64(core, param)?;
+            let mode = read_u64(core, param + 8)?;
+            let len = read_u64(core, param + 16)? as usize;
+            if read_bytes(core, name, len.min(MAX_STRING))? != b":tt" {
+                u64::MAX
+            } else if mode >= 8 {
+                HANDLE_STDERR
+            } else {
+                HANDLE_STDOUT
+            }
+        }
+        SYS_CLOSE => 0,
+        SYS_WRITEC => {
+            output.extend(read_bytes(core, param, 1)?);
+            0
+        }
+        SYS_WRITE0 => {
+            output.extend(read_string(core, param)?);
+            0
+        }
+        SYS_WRITE => {
+            let handle = read_u64(core, param)?;
+            let buf = read_u64(core, param + 8)?;
+            let len = read_u64(core, param + 16)?;
+            if handle == HANDLE_STDOUT || handle == HANDLE_STDERR {
+                output.extend(read_bytes(core, buf, len as usize)?);
+                0
+            } else {
+                len
+            }
+        }
+        SYS_ISTTY => 1,
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let reason = read_u64(core, param)?;
+            let code = read_u64(core, param + 8)?;
+            return Ok(Some(if reason == ADP_STOPPED_APPLICATION_EXIT {
+                TestExit::Exited(code)
+            } else {
+                TestExit::Aborted(reason)
+            }));
+        }
+        _ => u64::MAX,
+    };
+    core.write_reg(0, ret)?;
+    // Continue after the HLT
+    let pc = core.read_pc()?;
+    core.write_pc(pc + 4)?;
+    Ok(None)
+}
+
+/// Load the ELF file at `elf` through the halted core, start it at its entry point and run it
+/// until it exits with semihosting, reaches `exit_symbol` if given, or runs for `timeout`.  The
+/// core is halted when this returns, or `ERR_TIMEOUT` is returned if it won't halt after the
+/// timeout.  The core isn't reset, so the program must not depend on state left by an earlier
+/// one.
+pub fn run_test<T>(
+    core: &mut Core<T>,
+    elf: &Path,
+    exit_symbol: Option<&str>,
+    timeout: Duration,
+) -> Result<TestResult, TestError>
+where
+    T: Transport + ?Sized,
+{
+    let data = fs::read(elf)?;
+    let exit_addr = match exit_symbol {
+        Some(name) => Some(
+            Symbols::parse(&data)?
+                .address(name)
+                .ok_or_else(|| TestError::Parse(format!("no symbol {}", name)))?,
+        ),
+        None => None,
+    };
+
+    for segment in elf::load_segments(&data)? {
+        write_bytes(core, segment.address, &segment.data)?;
+    }
+    core.write_pc(elf::entry_point(&data)?)?;
+    if let Some(addr) = exit_addr {
+        core.set_breakpoint(addr, BreakpointKind::Any)?;
+    }
+
+    let mut output = vec![];
+    let start = Instant::now();
+    let result = (|| -> Result<TestExit, u8> {
+        loop {
+            core.resume()?;
+            while !core.is_halted()? {
+                if start.elapsed() > timeout {
+                    core.halt_and_wait()?;
+                    return Ok(TestExit::TimedOut);
+                }
+            }
+            if core.is_semihosting()? {
+                if let Some(exit) = semihost(core, &mut output)? {
+                    return Ok(exit);
+                }
+                continue;
+            }
+            let pc = core.read_pc()?;
+            let reason = core.halt_reason()?;
+            if Some(pc) == exit_addr {
+                return Ok(TestExit::ReachedSymbol(core.read_reg(0)?));
+            }
+            return Ok(TestExit::Stopped { pc, reason });
+        }
+    })();
+
+    if let Some(addr) = exit_addr {
+        core.clear_breakpoint(addr)?;
+    }
+    Ok(TestResult {
+        output,
+        exit: result?,
+    })
+}
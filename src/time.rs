@@ -0,0 +1,60 @@
+//! The clock used for timeouts and pacing.  With the `std` feature this is the system clock.
+//! Without it, the application provides the clock by defining these two functions:
+//!
+//! ```ignore
+//! #[no_mangle]
+//! fn _jtag_adi_now() -> core::time::Duration {
+//!     // Time since an arbitrary point, such as boot, that never goes backwards
+//! }
+//!
+//! #[no_mangle]
+//! fn _jtag_adi_sleep(duration: core::time::Duration) {
+//!     // Busy-wait or yield for at least `duration`
+//! }
+//! ```
+
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+/// Block for at least `duration`
+#[cfg(feature = "std")]
+pub fn sleep(duration: Duration) {
+    std::thread::sleep(duration)
+}
+
+#[cfg(not(feature = "std"))]
+extern "Rust" {
+    fn _jtag_adi_now() -> Duration;
+    fn _jtag_adi_sleep(duration: Duration);
+}
+
+/// A point in time of the application's clock
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    pub fn now() -> Self {
+        // SAFETY: the application defines the function, as documented above
+        Self(unsafe { _jtag_adi_now() })
+    }
+
+    /// Return the time from `earlier` to `self`, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+/// Block for at least `duration`
+#[cfg(not(feature = "std"))]
+pub fn sleep(duration: Duration) {
+    // SAFETY: the application defines the function, as documented above
+    unsafe { _jtag_adi_sleep(duration) }
+}
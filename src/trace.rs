@@ -0,0 +1,483 @@
+//! Trace capture through a CoreSight Trace Memory Controller (TMC).  The trace path from the
+//! sources to the sink is configured through its funnels, and the TMC, either an ETF or an ETR,
+//! is run in software FIFO mode so that its buffer can be drained continuously while the target
+//! runs.  A full buffer stalls the trace bus, throttling the sources rather than silently
+//! overwriting trace, so a slow consumer only delays capture.
+//!
+//! The trace is written out either raw, as the formatted 16-byte frames produced by the TMC, or
+//! deformatted to the bytes of a single trace ID.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{MemAP, Transport, ERR_TIMEOUT};
+
+use self::regs::{FunnelCtrl, TmcCtl, TmcFfcr, TmcFfsr, TmcMode, TmcSts};
+
+const TMC_RSZ: u32 = 0x004;
+const TMC_RRD: u32 = 0x010;
+const TMC_CBUFLEVEL: u32 = 0x030;
+const TMC_AXICTL: u32 = 0x110;
+const TMC_DBALO: u32 = 0x118;
+const TMC_DBAHI: u32 = 0x11c;
+const LAR: u32 = 0xfb0;
+
+/// TMC.MODE of software FIFO mode
+const MODE_SOFTWARE_FIFO: u32 = 1;
+
+/// Non-secure accesses with a burst length of 16
+const AXICTL_DEFAULT: u32 = 0xf << 8 | 1 << 1;
+
+/// Hold time of 4 transactions, the reset value
+const FUNNEL_HOLD_TIME: u32 = 3;
+
+/// Size of a formatter frame
+const FRAME_SIZE: usize = 16;
+/// A frame synchronization packet, which may appear between frames
+const FRAME_SYNC: [u8; 4] = [0xff, 0xff, 0xff, 0x7f];
+/// Trace ID of null data
+const NULL_ID: u8 = 0;
+
+/// Most words read from the TMC per access
+const DRAIN_CHUNK: u32 = 1024;
+
+/// How long to wait for a TMC to become ready or finish a flush
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// TMC and funnel registers with named fields, relative to the base of the block
+pub mod regs {
+    use crate::register;
+
+    register! {
+        /// TMC Status Register
+        pub struct TmcSts @ 0x00c {
+            full, set_full: 0;
+            triggered, set_triggered: 1;
+            tmcready, set_tmcready: 2;
+            ftempty, set_ftempty: 3;
+            empty, set_empty: 4;
+            memerr, set_memerr: 5;
+        }
+    }
+
+    register! {
+        /// TMC Control Register
+        pub struct TmcCtl @ 0x020 {
+            /// Trace capture enable
+            tracecapten, set_tracecapten: 0;
+        }
+    }
+
+    register! {
+        /// TMC Mode Register
+        pub struct TmcMode @ 0x028 {
+            /// 0 circular buffer, 1 software FIFO, 2 hardware FIFO
+            mode, set_mode: 0..=1;
+        }
+    }
+
+    register! {
+        /// TMC Formatter and Flush Status Register
+        pub struct TmcFfsr @ 0x300 {
+            /// Flush in progress
+            flinprog, set_flinprog: 0;
+            /// Formatter stopped
+            ftstopped, set_ftstopped: 1;
+        }
+    }
+
+    register! {
+        /// TMC Formatter and Flush Control Register
+        pub struct TmcFfcr @ 0x304 {
+            /// Enable formatting
+            enft, set_enft: 0;
+            /// Insert trigger packets into the formatted stream
+            enti, set_enti: 1;
+            fonflin, set_fonflin: 4;
+            fontrigevt, set_fontrigevt: 5;
+            /// Manually generate a flush
+            flushman, set_flushman: 6;
+            trigontrigin, set_trigontrigin: 8;
+            trigontrigevt, set_trigontrigevt: 9;
+            trigonfl, set_trigonfl: 10;
+            stoponfl, set_stoponfl: 12;
+            stopontrigevt, set_stopontrigevt: 13;
+        }
+    }
+
+    register! {
+        /// Funnel Control Register
+        pub struct FunnelCtrl @ 0x000 {
+            /// One bit per input port
+            enables, set_enables: 0..=7;
+            /// Number of transactions, less one, before switching input port
+            hold_time, set_hold_time: 8..=11;
+        }
+    }
+}
+
+/// Error while capturing trace
+#[derive(Debug)]
+pub enum TraceError {
+    /// Error from the debug interface
+    Access(u8),
+    /// Error writing the trace out
+    Io(io::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Access(e) => write!(f, "access error {}", e),
+            TraceError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<u8> for TraceError {
+    fn from(e: u8) -> Self {
+        TraceError::Access(e)
+    }
+}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        TraceError::Io(e)
+    }
+}
+
+/// A CoreSight trace funnel, which merges trace from several input ports
+pub struct Funnel<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+}
+
+impl<T> Funnel<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, base: u32) -> Self {
+        Self { mem, base }
+    }
+
+    /// Enable input `port`, leaving the other ports as they are
+    pub fn enable_port(&mut self, port: u8) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        mem.write(self.base + LAR, 0xC5ACCE55)?;
+        mem.modify_register(self.base, |ctrl: &mut FunnelCtrl| {
+            if ctrl.hold_time() == 0 {
+                ctrl.set_hold_time(FUNNEL_HOLD_TIME);
+            }
+            ctrl.set_enables(ctrl.enables() | 1 << port);
+        })
+    }
+
+    pub fn disable_port(&mut self, port: u8) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        mem.modify_register(self.base, |ctrl: &mut FunnelCtrl| {
+            ctrl.set_enables(ctrl.enables() & !(1 << port))
+        })
+    }
+}
+
+/// A Trace Memory Controller configured as an ETF or ETR
+pub struct Tmc<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    base: u32,
+}
+
+impl<T> Tmc<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, base: u32) -> Self {
+        Self { mem, base }
+    }
+
+    /// Set the system memory buffer of an ETR to `size` bytes at `addr`.  This isn't needed for
+    /// an ETF, whose buffer is internal.
+    pub fn set_buffer(&mut self, addr: u64, size: u32) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        mem.write(self.base + LAR, 0xC5ACCE55)?;
+        mem.write(self.base + TMC_AXICTL, AXICTL_DEFAULT)?;
+        mem.write(self.base + TMC_DBALO, addr as u32)?;
+        mem.write(self.base + TMC_DBAHI, (addr >> 32) as u32)?;
+        mem.write(self.base + TMC_RSZ, size / 4)
+    }
+
+    /// Start capturing in software FIFO mode, with the formatter enabled.  Returns `ERR_TIMEOUT`
+    /// if the TMC doesn't become ready within `FLUSH_TIMEOUT`.
+    pub fn start(&mut self) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        mem.write(self.base + LAR, 0xC5ACCE55)?;
+        mem.write_register(self.base, TmcCtl(0))?;
+        let start = Instant::now();
+        while !mem.read_register::<TmcSts>(self.base)?.tmcready() {
+            if start.elapsed() > FLUSH_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        let mut mode = TmcMode::default();
+        mode.set_mode(MODE_SOFTWARE_FIFO);
+        mem.write_register(self.base, mode)?;
+        let mut ffcr = TmcFfcr::default();
+        ffcr.set_enft(true);
+        ffcr.set_enti(true);
+        mem.write_register(self.base, ffcr)?;
+        let mut ctl = TmcCtl::default();
+        ctl.set_tracecapten(true);
+        mem.write_register(self.base, ctl)
+    }
+
+    /// Flush the trace path and stop the formatter.  Trace already captured can still be read.
+    /// Returns `ERR_TIMEOUT` if the flush doesn't complete within `FLUSH_TIMEOUT`.
+    pub fn stop(&mut self) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        let mut ffcr = mem.read_register::<TmcFfcr>(self.base)?;
+        ffcr.set_stoponfl(true);
+        mem.write_register(self.base, ffcr)?;
+        ffcr.set_flushman(true);
+        mem.write_register(self.base, ffcr)?;
+        let start = Instant::now();
+        while mem.read_register::<TmcFfsr>(self.base)?.flinprog()
+            || !mem.read_register::<TmcSts>(self.base)?.tmcready()
+        {
+            if start.elapsed() > FLUSH_TIMEOUT {
+                return Err(ERR_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Disable capture, discarding anything not yet read
+    pub fn disable(&mut self) -> Result<(), u8> {
+        self.mem.borrow_mut().write_register(self.base, TmcCtl(0))
+    }
+
+    /// Remove and return up to `max` words of trace from the buffer
+    pub fn read(&mut self, max: u32) -> Result<Vec<u32>, u8> {
+        let mut mem = self.mem.borrow_mut();
+        let level = mem.read(self.base + TMC_CBUFLEVEL)?.min(max);
+        if level == 0 {
+            return Ok(vec![]);
+        }
+        mem.read_multi(self.base + TMC_RRD, level as usize, false, true)
+    }
+}
+
+/// Splits the formatted frames written by a TMC into the bytes of each trace ID
+#[derive(Clone, Debug)]
+pub struct Deformatter {
+    id: u8,
+    pending: Vec<u8>,
+}
+
+impl Default for Deformatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deformatter {
+    pub fn new() -> Self {
+        Self {
+            id: NULL_ID,
+            pending: vec![],
+        }
+    }
+
+    /// Add `data` to the stream, calling `emit` with the ID and value of each complete byte
+    pub fn push(&mut self, data: &[u8], mut emit: impl FnMut(u8, u8)) {
+        self.pending.extend_from_slice(data);
+        let mut start = 0;
+        loop {
+            let rest = &self.pending[start..];
+            if rest.starts_with(&FRAME_SYNC) {
+                start += FRAME_SYNC.len();
+                continue;
+            }
+            if rest.len() < FRAME_SIZE {
+                break;
+            }
+            let frame: [u8; FRAME_SIZE] = rest[..FRAME_SIZE].try_into().unwrap();
+            self.frame(&frame, &mut emit);
+            start += FRAME_SIZE;
+        }
+        self.pending.drain(..start);
+    }
+
+    fn frame(&mut self, frame: &[u8; FRAME_SIZE], emit: &mut impl FnMut(u8, u8)) {
+        let aux = frame[FRAME_SIZE - 1];
+        let mut out = |id: u8, byte: u8| {
+            if id != NULL_ID {
+                emit(id, byte)
+            }
+        };
+        for k in 0..FRAME_SIZE / 2 {
+            let even = frame[2 * k];
+            let odd = (k < FRAME_SIZE / 2 - 1).then(|| frame[2 * k + 1]);
+            let aux_bit = aux >> k & 1;
+            if even & 1 != 0 {
+                // An ID change.  The aux bit says whether the following byte is still from the
+                // old ID.
+                let old = self.id;
+                self.id = even >> 1;
+                if let Some(odd) = odd {
+                    out(if aux_bit != 0 { old } else { self.id }, odd);
+                }
+            } else {
+                out(self.id, even | aux_bit);
+                if let Some(odd) = odd {
+                    out(self.id, odd);
+                }
+            }
+        }
+    }
+}
+
+/// Write all of `data`, waiting while a non-blocking writer can't accept more
+fn write_all(out: &mut dyn Write, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match out.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A trace capture from a TMC, through zero or more funnels, streamed to a writer
+pub struct TraceSession<T: ?Sized> {
+    sink: Tmc<T>,
+    funnels: Vec<(Funnel<T>, u8)>,
+    filter: Option<u8>,
+    deformatter: Deformatter,
+    captured: u64,
+}
+
+impl<T> TraceSession<T>
+where
+    T: Transport + ?Sized,
+{
+    pub fn new(sink: Tmc<T>) -> Self {
+        Self {
+            sink,
+            funnels: vec![],
+            filter: None,
+            deformatter: Deformatter::new(),
+            captured: 0,
+        }
+    }
+
+    /// Add a funnel on the path from the source to the sink, with the source's trace arriving
+    /// on input `port`
+    pub fn add_funnel(&mut self, funnel: Funnel<T>, port: u8) {
+        self.funnels.push((funnel, port));
+    }
+
+    /// Write only the deformatted bytes of trace ID `id`, or the raw frames if None
+    pub fn set_filter(&mut self, id: Option<u8>) {
+        self.filter = id;
+    }
+
+    /// Number of bytes read from the sink so far
+    pub fn captured(&self) -> u64 {
+        self.captured
+    }
+
+    /// Enable the funnel ports on the path and start the sink.  The trace sources are enabled
+    /// separately, after this.
+    pub fn start(&mut self) -> Result<(), u8> {
+        self.sink.start()?;
+        for (funnel, port) in &mut self.funnels {
+            funnel.enable_port(*port)?;
+        }
+        Ok(())
+    }
+
+    /// Read whatever trace is in the sink and write it to `out`.  Returns the number of bytes
+    /// read from the sink.
+    pub fn drain(&mut self, out: &mut dyn Write) -> Result<usize, TraceError> {
+        let mut total = 0;
+        loop {
+            let words = self.sink.read(DRAIN_CHUNK)?;
+            if words.is_empty() {
+                break;
+            }
+            let bytes: Vec<u8> = words.iter().flat_map(|x| x.to_le_bytes()).collect();
+            total += bytes.len();
+            match self.filter {
+                None => write_all(out, &bytes)?,
+                Some(filter) => {
+                    let mut data = vec![];
+                    self.deformatter.push(&bytes, |id, byte| {
+                        if id == filter {
+                            data.push(byte);
+                        }
+                    });
+                    write_all(out, &data)?;
+                }
+            }
+            if words.len() < DRAIN_CHUNK as usize {
+                break;
+            }
+        }
+        self.captured += total as u64;
+        Ok(total)
+    }
+
+    /// Drain the sink to `out` every `interval` until `done` returns true, then stop the
+    /// capture and write out the rest.  The writer is flushed after each drain.
+    pub fn stream(
+        &mut self,
+        out: &mut dyn Write,
+        interval: Duration,
+        mut done: impl FnMut() -> bool,
+    ) -> Result<(), TraceError> {
+        while !done() {
+            if self.drain(out)? > 0 {
+                out.flush()?;
+            } else {
+                thread::sleep(interval);
+            }
+        }
+        self.stop(out)
+    }
+
+    /// Like `stream`, writing to a TCP connection to `addr`
+    pub fn stream_tcp(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        interval: Duration,
+        done: impl FnMut() -> bool,
+    ) -> Result<(), TraceError> {
+        let mut socket = TcpStream::connect(addr)?;
+        socket.set_nodelay(true)?;
+        self.stream(&mut socket, interval, done)
+    }
+
+    /// Flush the trace path, write the remaining trace to `out` and disable the sink and the
+    /// funnel ports
+    pub fn stop(&mut self, out: &mut dyn Write) -> Result<(), TraceError> {
+        self.sink.stop()?;
+        self.drain(out)?;
+        out.flush()?;
+        self.sink.disable()?;
+        for (funnel, port) in &mut self.funnels {
+            funnel.disable_port(*port)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+//! Planning of pipelined transfers, kept apart from the I/O that carries them out so that it can
+//! be tested on its own.  A block transfer through a MEM-AP is split into chunks that each stay
+//! within one TAR auto-increment block and fit in the cable's queue, and a list of registers read
+//! or written in one pipeline is split into runs that share a SELECT bank.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::AUTOINC_BLOCK;
+
+/// One pipelined block transfer of a plan
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Address of the first word
+    pub addr: u32,
+    /// Index of the first word in the whole transfer
+    pub offset: usize,
+    /// Number of words
+    pub count: usize,
+}
+
+/// Split a transfer of `count` words starting at `addr` into chunks of at most `capacity` words,
+/// none of which crosses a TAR auto-increment block.  The chunks are in order and cover the
+/// transfer without gaps.  A `capacity` of zero is taken as one.
+pub fn plan(addr: u32, count: usize, capacity: usize) -> Vec<Chunk> {
+    let capacity = capacity.max(1);
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < count {
+        let addr = addr.wrapping_add(4 * offset as u32);
+        // An unaligned address still makes progress, a word at a time
+        let room = (((AUTOINC_BLOCK - addr % AUTOINC_BLOCK) / 4) as usize).max(1);
+        let n = room.min(capacity).min(count - offset);
+        chunks.push(Chunk {
+            addr,
+            offset,
+            count: n,
+        });
+        offset += n;
+    }
+    chunks
+}
+
+/// Split `regs`, numbered as for `read_adi`, into runs of consecutive registers in the same bank
+pub fn split_banks(regs: &[u8]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (i, reg) in regs.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if regs[run.start] >> 2 == reg >> 2 => run.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+    runs
+}
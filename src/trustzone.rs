@@ -0,0 +1,152 @@
+//! Explicitly Secure or Non-secure memory accesses on TrustZone systems.  A MEM-AP access is
+//! normally made in whichever security state CSW gives it, and on many TrustZone-M devices a
+//! peripheral appears at two addresses, one for each state.  `WorldAccess` takes the state as a
+//! parameter of each access, and selects it either with the Non-secure bit of CSW or by moving
+//! the address to the alias for that state.
+
+use std::fmt;
+
+use crate::csw::{CswPreset, CswProfile};
+use crate::{MemAP, Transport};
+
+/// Address bit that selects the Secure alias on devices using the IDAU layout ARM suggests for
+/// ARMv8-M, such as those built on the SSE-200 subsystem
+pub const ARMV8M_SECURE_ALIAS_BIT: u32 = 1 << 28;
+
+/// The security state of an access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum World {
+    Secure,
+    NonSecure,
+}
+
+impl fmt::Display for World {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            World::Secure => write!(f, "Secure"),
+            World::NonSecure => write!(f, "Non-secure"),
+        }
+    }
+}
+
+/// How the security state of an access is chosen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorldSelect {
+    /// With the Non-secure bit of CSW, HNONSEC on AHB or AxPROT[1] on AXI, whose bits are given
+    Csw(CswProfile),
+    /// By address: the Secure alias of an address has this bit set, and the Non-secure alias
+    /// has it clear.  The AP must be able to make Secure accesses.
+    Alias(u32),
+}
+
+/// Makes memory accesses through a `MemAP` in a given security state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorldAccess {
+    select: WorldSelect,
+}
+
+impl WorldAccess {
+    /// Select the security state with CSW, using the Non-secure bit for the type of `mem`'s AP
+    pub fn csw<T>(mem: &mut MemAP<T>) -> Result<Self, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let ap_type = mem.idr()?.ap_type();
+        Ok(Self {
+            select: WorldSelect::Csw(CswPreset::NonSecure.profile(ap_type)),
+        })
+    }
+
+    /// Select the security state with address bit `bit`, for example
+    /// `ARMV8M_SECURE_ALIAS_BIT`
+    pub fn alias(bit: u32) -> Self {
+        Self {
+            select: WorldSelect::Alias(bit),
+        }
+    }
+
+    pub fn select(&self) -> WorldSelect {
+        self.select
+    }
+
+    /// Return the address to access for `addr` in `world`.  Only an alias moves the address.
+    pub fn address(&self, addr: u32, world: World) -> u32 {
+        match (self.select, world) {
+            (WorldSelect::Alias(bit), World::Secure) => addr | bit,
+            (WorldSelect::Alias(bit), World::NonSecure) => addr & !bit,
+            (WorldSelect::Csw(_), _) => addr,
+        }
+    }
+
+    /// Perform `op` at the address for `addr` in `world`, with CSW set for `world` if needed and
+    /// restored afterwards
+    fn with_world<T, R>(
+        &self,
+        mem: &mut MemAP<T>,
+        addr: u32,
+        world: World,
+        op: impl FnOnce(&mut MemAP<T>, u32) -> Result<R, u8>,
+    ) -> Result<R, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        let addr = self.address(addr, world);
+        let WorldSelect::Csw(nonsec) = self.select else {
+            return op(mem, addr);
+        };
+        let csw = mem.csw()?;
+        let bits = match world {
+            World::Secure => 0,
+            World::NonSecure => nonsec.bits,
+        };
+        mem.apply_csw_profile(CswProfile::new(bits, nonsec.mask))?;
+        let result = op(mem, addr);
+        mem.write_csw(csw)?;
+        result
+    }
+
+    /// Read the word at `addr` in `world`
+    pub fn read<T>(&self, mem: &mut MemAP<T>, addr: u32, world: World) -> Result<u32, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        self.with_world(mem, addr, world, |mem, addr| mem.read(addr))
+    }
+
+    /// Write `val` to the word at `addr` in `world`
+    pub fn write<T>(&self, mem: &mut MemAP<T>, addr: u32, val: u32, world: World) -> Result<(), u8>
+    where
+        T: Transport + ?Sized,
+    {
+        self.with_world(mem, addr, world, |mem, addr| mem.write(addr, val))
+    }
+
+    /// Read `count` words starting at `addr` in `world`
+    pub fn read_memory<T>(
+        &self,
+        mem: &mut MemAP<T>,
+        addr: u32,
+        count: usize,
+        world: World,
+    ) -> Result<Vec<u32>, u8>
+    where
+        T: Transport + ?Sized,
+    {
+        self.with_world(mem, addr, world, |mem, addr| mem.read_memory(addr, count))
+    }
+
+    /// Write `data` starting at `addr` in `world`
+    pub fn write_memory<T>(
+        &self,
+        mem: &mut MemAP<T>,
+        addr: u32,
+        data: &[u32],
+        world: World,
+    ) -> Result<(), u8>
+    where
+        T: Transport + ?Sized,
+    {
+        self.with_world(mem, addr, world, |mem, addr| mem.write_memory(addr, data))
+    }
+}
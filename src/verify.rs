@@ -0,0 +1,70 @@
+//! Reads that check themselves, for links that corrupt data silently.  With a marginal cable or
+//! a TCK too fast for the wiring, a bit can flip on the way back from the target without any
+//! error being reported.  `MemAP::read_memory_verified` reads a block twice and re-reads the
+//! words where the two passes disagree, reporting which words needed it.
+
+use crate::{MemAP, Transport};
+
+/// Most times a word that differed between the passes is re-read before giving up on it
+const REREAD_LIMIT: usize = 4;
+
+/// The result of `MemAP::read_memory_verified`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifiedRead {
+    pub data: Vec<u32>,
+    /// Indices of the words that differed between the passes, then read the same twice in a row
+    pub corrected: Vec<usize>,
+    /// Indices of the words that never read the same twice in a row, such as a free-running
+    /// counter.  `data` holds the last value read.
+    pub unstable: Vec<usize>,
+}
+
+impl VerifiedRead {
+    /// Return true if both passes agreed on every word
+    pub fn is_clean(&self) -> bool {
+        self.corrected.is_empty() && self.unstable.is_empty()
+    }
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Read `count` words starting at `addr` with `read_memory` twice, and re-read each word
+    /// where the two passes differ until it reads the same twice in a row.  This takes more than
+    /// twice as long as `read_memory`, and memory with read side effects shouldn't be read this
+    /// way.
+    pub fn read_memory_verified(&mut self, addr: u32, count: usize) -> Result<VerifiedRead, u8> {
+        let first = self.read_memory(addr, count)?;
+        let mut data = self.read_memory(addr, count)?;
+        let mut corrected = vec![];
+        let mut unstable = vec![];
+        for i in 0..count {
+            if first[i] == data[i] {
+                continue;
+            }
+            let word = addr.wrapping_add(4 * i as u32);
+            let mut last = self.read_memory(word, 1)?[0];
+            let mut stable = false;
+            for _ in 0..REREAD_LIMIT {
+                let val = self.read_memory(word, 1)?[0];
+                stable = val == last;
+                last = val;
+                if stable {
+                    break;
+                }
+            }
+            data[i] = last;
+            if stable {
+                corrected.push(i);
+            } else {
+                unstable.push(i);
+            }
+        }
+        Ok(VerifiedRead {
+            data,
+            corrected,
+            unstable,
+        })
+    }
+}
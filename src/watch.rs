@@ -0,0 +1,90 @@
+//! Polling memory for changes, for monitoring status registers and mailbox words.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{MemAP, MemAPReg, Port, Transport};
+
+/// Register number of the MEM-AP banked data register BD0
+const BD0: u8 = 0x10 >> 2;
+
+/// A change of a watched word
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// When the new value was read
+    pub time: Instant,
+    pub addr: u32,
+    /// The previous value, or None for the first read
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+impl<T> MemAP<T>
+where
+    T: Transport + ?Sized,
+{
+    /// Read the words at `addrs` every `interval` and call `callback` for each one whose value
+    /// changed, including the first read of each.  Polling stops when `callback` returns false.
+    ///
+    /// If all of the addresses are in the same 16-byte block they are read together through the
+    /// banked data registers, without rewriting TAR.
+    pub fn watch<F>(&mut self, addrs: &[u32], interval: Duration, mut callback: F) -> Result<(), u8>
+    where
+        F: FnMut(Change) -> bool,
+    {
+        let block = addrs[0] & !0xf;
+        let banked = addrs.iter().all(|a| a & !0xf == block && a & 3 == 0);
+        let regs: Vec<u8> = addrs.iter().map(|a| BD0 + ((a & 0xf) >> 2) as u8).collect();
+        let mut values: Vec<Option<u32>> = vec![None; addrs.len()];
+
+        loop {
+            let time = Instant::now();
+            let results = if banked {
+                self.load_caches()?;
+                self.write_csw(self.csw & !(1 << 4))?;
+                if self.tar != block {
+                    self.adi.borrow_mut().write_adi(
+                        self.apsel,
+                        Port::AP,
+                        MemAPReg::TAR as u8,
+                        block,
+                    )?;
+                    self.tar = block;
+                }
+                self.adi
+                    .borrow_mut()
+                    .read_adi_pipelined(self.apsel, Port::AP, &regs)
+            } else {
+                addrs.iter().map(|&a| self.read(a)).collect()
+            };
+
+            // A WAIT in a pipelined read leaves the later results out of step, so drop the sample
+            if banked && (results.len() != addrs.len() || results.contains(&Err(1))) {
+                thread::sleep(interval);
+                continue;
+            }
+
+            for (i, result) in results.into_iter().enumerate() {
+                let new = match result {
+                    Ok(new) => new,
+                    // Try again on the next poll
+                    Err(1) => continue,
+                    Err(e) => return Err(e),
+                };
+                if values[i] != Some(new) {
+                    let change = Change {
+                        time,
+                        addr: addrs[i],
+                        old: values[i],
+                        new,
+                    };
+                    values[i] = Some(new);
+                    if !callback(change) {
+                        return Ok(());
+                    }
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+}
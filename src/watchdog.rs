@@ -0,0 +1,75 @@
+//! Keeping an external watchdog serviced while a core is halted.  Firmware normally services the
+//! watchdog itself, so halting a core for longer than the watchdog period resets the board in
+//! the middle of a debug session.  A `Watchdog` writes the board's service sequence through a
+//! MEM-AP on behalf of the halted firmware.
+//!
+//! The debug interface isn't `Send`, so the watchdog can't be serviced from another thread.
+//! Instead `service` is called from whatever loop the tool is already running, and
+//! `DebugSession` calls it on each poll while any of its cores are halted.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{MemAP, Transport};
+
+/// Services a watchdog by writing a fixed sequence of registers
+pub struct Watchdog<T: ?Sized> {
+    mem: Rc<RefCell<MemAP<T>>>,
+    /// Address and value of each write, in order
+    writes: Vec<(u32, u32)>,
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl<T> Watchdog<T>
+where
+    T: Transport + ?Sized,
+{
+    /// `writes` is the sequence of (address, value) writes that services the watchdog, for
+    /// example a key register followed by a reload register.  `period` is how often to service
+    /// it, which should leave a good margin below the watchdog's timeout.
+    pub fn new(mem: Rc<RefCell<MemAP<T>>>, writes: Vec<(u32, u32)>, period: Duration) -> Self {
+        Self {
+            mem,
+            writes,
+            period,
+            last: None,
+        }
+    }
+
+    /// Write the service sequence now
+    pub fn pet(&mut self) -> Result<(), u8> {
+        let mut mem = self.mem.borrow_mut();
+        for &(addr, value) in &self.writes {
+            mem.write(addr, value)?;
+        }
+        self.last = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Write the service sequence if a period has passed since it was last written.  Returns
+    /// true if it was written.
+    pub fn service(&mut self) -> Result<bool, u8> {
+        if self.last.is_some_and(|last| last.elapsed() < self.period) {
+            return Ok(false);
+        }
+        self.pet()?;
+        Ok(true)
+    }
+
+    /// Keep the watchdog serviced for `duration`, blocking the calling thread
+    pub fn hold(&mut self, duration: Duration) -> Result<(), u8> {
+        let end = Instant::now() + duration;
+        loop {
+            self.service()?;
+            let now = Instant::now();
+            if now >= end {
+                return Ok(());
+            }
+            let next = self.last.map_or(now, |last| last + self.period);
+            thread::sleep(next.min(end).saturating_duration_since(now));
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Logging of the JTAG scans made by an `ArmDebugInterface`, for cross-checking this crate against
+//! other tools when they disagree about a target.  A hook set with
+//! `ArmDebugInterface::set_scan_hook` receives each IR and DR scan with its raw bits and the
+//! DPACC, APACC or ABORT request and response they carry.  `ScanFormatter` turns the scans into
+//! one line each, in the spirit of OpenOCD's debug output:
+//!
+//! ```text
+//! IR  4 TDI b                         APACC
+//! DR 35 TDI 000000007                 -> AP[0] R DRW
+//! DR 35 TDI - TDO 091a2b3c2           <- OK AP[0] R DRW = 0x12345678
+//! ```
+//!
+//! A queued read is reported when its result is collected, so that its scan has both TDI and
+//! TDO.  Scans made through `ArmDebugInterface::raw_taps` aren't reported.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::fmt::Write;
+
+use crate::Port;
+
+/// IR instructions of a JTAG-DP
+pub const IR_ABORT: u8 = 0x8;
+pub const IR_DPACC: u8 = Port::DP as u8;
+pub const IR_APACC: u8 = Port::AP as u8;
+pub const IR_IDCODE: u8 = 0xe;
+pub const IR_BYPASS: u8 = 0xf;
+
+/// Width of the raw part of a formatted scan, so that the meanings line up
+const RAW_WIDTH: usize = 36;
+
+/// Length of the DPACC, APACC and ABORT scan chains
+pub(crate) const ACC_BITS: usize = 35;
+
+/// A hook receiving scans, set with `ArmDebugInterface::set_scan_hook`
+pub type ScanHook = Box<dyn FnMut(&Scan)>;
+
+/// The register scanned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanKind {
+    Ir,
+    Dr,
+}
+
+/// One scan of the DAP's TAP
+#[derive(Clone, Copy, Debug)]
+pub struct Scan<'a> {
+    pub kind: ScanKind,
+    pub bits: usize,
+    /// Bits shifted in, least significant first, or None if the cable chose them
+    pub tdi: Option<&'a [u8]>,
+    /// Bits shifted out, least significant first, or None if they weren't captured
+    pub tdo: Option<&'a [u8]>,
+    /// The instruction in IR, as one of the `IR_*` constants, if it is one the interface uses.
+    /// For an IR scan, this is the instruction shifted in.
+    pub ir: Option<u8>,
+    /// The request shifted in, for a DR scan of DPACC, APACC or ABORT
+    pub request: Option<Request>,
+    /// The response to the previous request, shifted out by a DR scan of DPACC or APACC
+    pub response: Option<Response>,
+}
+
+impl<'a> Scan<'a> {
+    /// Decode a scan made with `ir` in the instruction register and `select` in SELECT, if known
+    pub(crate) fn decode(
+        kind: ScanKind,
+        bits: usize,
+        tdi: Option<&'a [u8]>,
+        tdo: Option<&'a [u8]>,
+        ir: Option<u8>,
+        select: Option<u32>,
+    ) -> Self {
+        let acc = kind == ScanKind::Dr
+            && bits == ACC_BITS
+            && matches!(ir, Some(IR_ABORT | IR_DPACC | IR_APACC));
+        let request = match (acc, tdi, ir) {
+            (true, Some(tdi), Some(instruction)) => {
+                let val = to_u64(tdi);
+                Some(Request {
+                    instruction,
+                    select,
+                    addr: ((val >> 1) & 3) as u8 * 4,
+                    read: val & 1 != 0,
+                    value: (val >> 3) as u32,
+                })
+            }
+            _ => None,
+        };
+        let response = match (acc && ir != Some(IR_ABORT), tdo) {
+            (true, Some(tdo)) => {
+                let val = to_u64(tdo);
+                Some(Response {
+                    ack: (val & 7) as u8,
+                    value: (val >> 3) as u32,
+                })
+            }
+            _ => None,
+        };
+        Self {
+            kind,
+            bits,
+            tdi,
+            tdo,
+            ir,
+            request,
+            response,
+        }
+    }
+}
+
+/// A DPACC, APACC or ABORT request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    /// The instruction selecting the scan chain: `IR_ABORT`, `IR_DPACC` or `IR_APACC`
+    pub instruction: u8,
+    /// SELECT when the request was made, if known
+    pub select: Option<u32>,
+    /// A[3:2] of the request, as a byte address in the bank
+    pub addr: u8,
+    pub read: bool,
+    /// The value written, meaningless for reads
+    pub value: u32,
+}
+
+impl Request {
+    pub fn port(&self) -> Option<Port> {
+        match self.instruction {
+            IR_DPACC => Some(Port::DP),
+            IR_APACC => Some(Port::AP),
+            _ => None,
+        }
+    }
+
+    /// The name of the register accessed
+    pub fn register(&self) -> String {
+        let select = self.select.unwrap_or(0);
+        match (self.port(), self.addr) {
+            (None, _) => String::from("ABORT"),
+            (Some(Port::DP), 0x0) => String::from("DPIDR"),
+            (Some(Port::DP), 0x4) => match select & 0xf {
+                0 => String::from("CTRL/STAT"),
+                4 => String::from("EVENTSTAT"),
+                bank => format!("CTRL/STAT bank {}", bank),
+            },
+            (Some(Port::DP), 0x8) => String::from("SELECT"),
+            (Some(Port::DP), _) => String::from("RDBUFF"),
+            (Some(Port::AP), addr) => {
+                let addr = (select & 0xf0) as u8 | addr;
+                match addr {
+                    0x00 => String::from("CSW"),
+                    0x04 => String::from("TAR"),
+                    0x0c => String::from("DRW"),
+                    0x10..=0x1c => format!("BD{}", (addr - 0x10) / 4),
+                    0xf0 | 0xf8 => String::from("BASE"),
+                    0xf4 => String::from("CFG"),
+                    0xfc => String::from("IDR"),
+                    _ => format!("{:#04x}", addr),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.port(), self.select) {
+            (None, _) => return write!(f, "ABORT {:#010x}", self.value),
+            (Some(Port::DP), _) => write!(f, "DP")?,
+            (Some(Port::AP), Some(select)) => write!(f, "AP[{}]", select >> 24)?,
+            (Some(Port::AP), None) => write!(f, "AP[?]")?,
+        }
+        match self.read {
+            true => write!(f, " R {}", self.register()),
+            false => write!(f, " W {} {:#010x}", self.register(), self.value),
+        }
+    }
+}
+
+/// The acknowledgement of a request and the data of the last read, shifted out of DPACC or
+/// APACC by the scan after the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub ack: u8,
+    pub value: u32,
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ack {
+            1 => write!(f, "WAIT"),
+            2 => write!(f, "OK"),
+            ack => write!(f, "ack {}", ack),
+        }
+    }
+}
+
+/// Formats scans one per line, pairing each response with the request it answers
+#[derive(Clone, Debug, Default)]
+pub struct ScanFormatter {
+    /// The request of the last DR scan, answered by the next one
+    last: Option<Request>,
+}
+
+impl ScanFormatter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn format(&mut self, scan: &Scan) -> String {
+        let kind = match scan.kind {
+            ScanKind::Ir => "IR",
+            ScanKind::Dr => "DR",
+        };
+        let mut line = format!("{} {:2} TDI {}", kind, scan.bits, hex(scan.tdi, scan.bits));
+        if let Some(tdo) = scan.tdo {
+            let _ = write!(line, " TDO {}", hex(Some(tdo), scan.bits));
+        }
+
+        let mut meaning = String::new();
+        if scan.kind == ScanKind::Ir {
+            meaning += match scan.ir {
+                Some(IR_ABORT) => "ABORT",
+                Some(IR_DPACC) => "DPACC",
+                Some(IR_APACC) => "APACC",
+                Some(IR_IDCODE) => "IDCODE",
+                Some(IR_BYPASS) => "BYPASS",
+                _ => "",
+            };
+        } else {
+            let last = self.last.take();
+            if let Some(response) = scan.response {
+                let _ = write!(meaning, "<- {}", response);
+                if let Some(last) = last {
+                    let _ = write!(meaning, " {}", last);
+                    if last.read && response.ack == 2 {
+                        let _ = write!(meaning, " = {:#010x}", response.value);
+                    }
+                }
+            }
+            if let Some(request) = scan.request {
+                if !meaning.is_empty() {
+                    meaning.push(' ');
+                }
+                let _ = write!(meaning, "-> {}", request);
+            }
+            self.last = scan.request;
+        }
+        if meaning.is_empty() {
+            return line;
+        }
+        format!("{:<width$}{}", line, meaning, width = RAW_WIDTH)
+    }
+}
+
+/// The scan's bits as a hex number, or `-` if they aren't known
+fn hex(bytes: Option<&[u8]>, bits: usize) -> String {
+    let Some(bytes) = bytes else {
+        return String::from("-");
+    };
+    let mut s = String::new();
+    for (i, byte) in bytes.iter().enumerate().rev() {
+        let mask = match bits.saturating_sub(8 * i) {
+            n @ 0..=7 => (1u16 << n) as u8 - 1,
+            _ => 0xff,
+        };
+        let _ = write!(s, "{:02x}", byte & mask);
+    }
+    let digits = bits.div_ceil(4).max(1);
+    s.split_off(s.len().saturating_sub(digits))
+}
+
+fn to_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .enumerate()
+        .fold(0, |val, (i, byte)| val | (*byte as u64) << (8 * i))
+}
@@ -0,0 +1,149 @@
+//! Retry, timeout and recovery behaviour of `MemAP`, against the simulated DAP with scripted
+//! faults
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use jtag_adi::ap;
+use jtag_adi::sim::{Fault, SimDap, SIM_IDR};
+use jtag_adi::{MemAP, Port, StickyError, Transport, ERR_BUS_STALL, ERR_STICKY};
+
+fn setup() -> (Rc<RefCell<SimDap>>, MemAP<SimDap>) {
+    let sim = Rc::new(RefCell::new(SimDap::new(0)));
+    for i in 0..0x1000 {
+        sim.borrow_mut().poke(0x2000_0000 + 4 * i, 0x1000_0000 + i);
+    }
+    let mut mem = MemAP::new(sim.clone(), 0);
+    mem.write_csw(0x2300_0002).unwrap();
+    (sim, mem)
+}
+
+#[test]
+fn read_retries_wait() {
+    let (sim, mut mem) = setup();
+    sim.borrow_mut().inject(Fault::Wait { count: 3 });
+    assert_eq!(mem.read(0x2000_0010), Ok(0x1000_0004));
+    assert_eq!(sim.borrow().waits(), 3);
+}
+
+#[test]
+fn write_retries_wait() {
+    let (sim, mut mem) = setup();
+    sim.borrow_mut().inject(Fault::Wait { count: 2 });
+    assert_eq!(mem.write(0x2000_0020, 0xdead_beef), Ok(()));
+    assert_eq!(sim.borrow().peek(0x2000_0020), 0xdead_beef);
+    assert_eq!(sim.borrow().waits(), 2);
+}
+
+#[test]
+fn write_stall_is_recovered() {
+    let (sim, mut mem) = setup();
+    mem.write(0x2000_0020, 0).unwrap();
+    sim.borrow_mut().inject(Fault::Stall);
+    assert_eq!(mem.write(0x2000_0020, 1), Err(ERR_BUS_STALL));
+    assert_eq!(mem.write(0x2000_0024, 2), Ok(()));
+    assert_eq!(sim.borrow().peek(0x2000_0024), 2);
+}
+
+#[test]
+fn stall_is_recovered() {
+    let (sim, mut mem) = setup();
+    mem.read(0x2000_0000).unwrap();
+    sim.borrow_mut().inject(Fault::Stall);
+    assert_eq!(mem.read(0x2000_0000), Err(ERR_BUS_STALL));
+    assert_eq!(mem.bus_stall().map(|s| s.addr), Some(0x2000_0000));
+
+    // The abort cleared the stall, and CSW and TAR were written back
+    assert_eq!(mem.read(0x2000_0004), Ok(0x1000_0001));
+}
+
+#[test]
+fn bus_error_sets_sticky() {
+    let (sim, mut mem) = setup();
+    sim.borrow_mut().inject(Fault::BusError {
+        range: 0x2000_0100..0x2000_0200,
+    });
+    assert_eq!(mem.read(0x2000_0180), Err(ERR_STICKY));
+    assert_eq!(
+        mem.sticky_error(),
+        Some(StickyError::Bus { addr: 0x2000_0180 })
+    );
+
+    mem.clear_sticky_errors().unwrap();
+    assert_eq!(mem.read(0x2000_0200), Ok(0x1000_0080));
+}
+
+#[test]
+fn read_memory_splits_at_wrap() {
+    let (_sim, mut mem) = setup();
+    let data = mem.read_memory(0x2000_03f0, 16).unwrap();
+    let expected: Vec<u32> = (0xfc..0x10c).map(|i| 0x1000_0000 + i).collect();
+    assert_eq!(data, expected);
+}
+
+#[test]
+fn write_memory_splits_at_wrap() {
+    let (sim, mut mem) = setup();
+    let data: Vec<u32> = (0..32).collect();
+    mem.write_memory(0x2000_07c0, &data).unwrap();
+    for (i, val) in data.iter().enumerate() {
+        assert_eq!(sim.borrow().peek(0x2000_07c0 + 4 * i as u32), *val);
+    }
+}
+
+#[test]
+fn read_multi_reissues_waits() {
+    let (sim, mut mem) = setup();
+    mem.set_reissue_waits(Some(Duration::ZERO));
+    // Leave CSW and TAR set up, so that the WAITs go to the pipelined reads
+    mem.read_block(0x2000_0000, 1, true).unwrap();
+    sim.borrow_mut().inject(Fault::Wait { count: 5 });
+    let data = mem.read_block(0x2000_0004, 8, true).unwrap();
+    let expected: Vec<u32> = (1..9).map(|i| 0x1000_0000 + i).collect();
+    assert_eq!(data, expected);
+    assert_eq!(sim.borrow().waits(), 5);
+}
+
+#[test]
+fn read_multi_drops_waits() {
+    let (sim, mut mem) = setup();
+    mem.read_block(0x2000_0000, 1, true).unwrap();
+    sim.borrow_mut().inject(Fault::Wait { count: 3 });
+    let data = mem.read_block(0x2000_0004, 8, true).unwrap();
+    let expected: Vec<u32> = (1..6).map(|i| 0x1000_0000 + i).collect();
+    assert_eq!(data, expected);
+}
+
+#[test]
+fn power_down_mid_burst() {
+    let (sim, mut mem) = setup();
+    sim.borrow_mut().inject(Fault::PowerDown { after: 4 });
+    let data: Vec<u32> = (0x100..0x110).collect();
+    assert_eq!(mem.write_block(0x2000_0000, &data, true), Err(ERR_STICKY));
+    assert!(!sim.borrow().powered());
+    // CSW and TAR took two of the transactions, so two words landed before the power went down
+    assert_eq!(sim.borrow().peek(0x2000_0004), 0x101);
+    assert_eq!(sim.borrow().peek(0x2000_0008), 0x1000_0002);
+
+    let stat = sim.borrow_mut().read_ctrl_stat().unwrap();
+    assert!(!stat.cdbgpwrupack);
+    assert!(stat.stickyerr);
+}
+
+#[test]
+#[should_panic(expected = "SELECT written while reads are queued")]
+fn select_with_queued_reads_panics() {
+    let mut sim = SimDap::new(0);
+    assert!(sim.queue_read_adi(0, Port::AP, 0xfc >> 2));
+    sim.queue_read_adi(1, Port::AP, 0xfc >> 2);
+}
+
+#[test]
+fn enumerate_aps_finishes_reads_before_select() {
+    let mut sim = SimDap::new(3);
+    let aps = ap::enumerate_aps(&mut sim).unwrap();
+    assert_eq!(aps.len(), 1);
+    assert_eq!(aps[0].apsel, 3);
+    assert_eq!(aps[0].idr, SIM_IDR);
+}
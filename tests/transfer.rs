@@ -0,0 +1,62 @@
+//! Properties of the transfer plans in `jtag_adi::transfer`, for any address, length and queue
+//! capacity
+
+use proptest::prelude::*;
+
+use jtag_adi::transfer::{plan, split_banks};
+
+/// Size of a TAR auto-increment block, in bytes
+const BLOCK: u32 = 0x400;
+
+proptest! {
+    #[test]
+    fn plan_is_gapless(addr in any::<u32>(), count in 0usize..4096, capacity in 0usize..2048) {
+        let chunks = plan(addr, count, capacity);
+        let mut offset = 0;
+        for chunk in &chunks {
+            prop_assert_eq!(chunk.offset, offset);
+            prop_assert!(chunk.count >= 1);
+            prop_assert!(chunk.count <= capacity.max(1));
+            prop_assert_eq!(chunk.addr, addr.wrapping_add(4 * chunk.offset as u32));
+            offset += chunk.count;
+        }
+        prop_assert_eq!(offset, count);
+    }
+
+    #[test]
+    fn plan_stays_in_block(addr in any::<u32>(), count in 0usize..4096, capacity in 0usize..2048) {
+        let addr = addr & !3;
+        for chunk in plan(addr, count, capacity) {
+            let last = chunk.addr.wrapping_add(4 * (chunk.count as u32 - 1));
+            prop_assert_eq!(chunk.addr / BLOCK, last / BLOCK);
+        }
+    }
+
+    #[test]
+    fn plan_fills_blocks(addr in any::<u32>(), count in 0usize..4096) {
+        // With an unlimited queue, only the first and last chunks fall short of a whole block
+        let addr = addr & !3;
+        let chunks = plan(addr, count, usize::MAX);
+        for chunk in chunks.iter().skip(1) {
+            prop_assert_eq!(chunk.addr % BLOCK, 0);
+        }
+    }
+
+    #[test]
+    fn banks_are_gapless(regs in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let runs = split_banks(&regs);
+        let mut end = 0;
+        for run in &runs {
+            prop_assert_eq!(run.start, end);
+            prop_assert!(run.end > run.start);
+            for reg in &regs[run.clone()] {
+                prop_assert_eq!(reg >> 2, regs[run.start] >> 2);
+            }
+            end = run.end;
+        }
+        prop_assert_eq!(end, regs.len());
+        for pair in runs.windows(2) {
+            prop_assert_ne!(regs[pair[0].start] >> 2, regs[pair[1].start] >> 2);
+        }
+    }
+}